@@ -0,0 +1,166 @@
+use crate::geometry::*;
+use crate::image_buffer;
+use crate::lock;
+use crate::util;
+
+use image::GenericImageView;
+
+use std::io::{Read, Write};
+
+/// Destination for completed blocks, e.g. for a remote viewer.
+pub trait BlockSink: Send {
+    fn send_block(
+        &mut self,
+        block: ScreenBlock,
+        block_buffer: &image::RgbaImage,
+    ) -> util::SimpleResult;
+}
+
+/// Block sink that serializes the blocks into a byte stream.
+/// Each block is stored as its coordinates (min x, min y, max x, max y), length of the PNG data
+/// and the PNG data itself. All numbers are little endian u32.
+pub struct PngStreamSink<W: Write + Send>(pub W);
+
+impl<W: Write + Send> BlockSink for PngStreamSink<W> {
+    fn send_block(
+        &mut self,
+        block: ScreenBlock,
+        block_buffer: &image::RgbaImage,
+    ) -> util::SimpleResult {
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(
+            block_buffer
+                .view(0, 0, block.width(), block.height())
+                .to_image(),
+        )
+        .write_to(&mut png, image::ImageOutputFormat::Png)?;
+
+        for value in &[block.min.x, block.min.y, block.max.x, block.max.y] {
+            self.0.write_all(&value.to_le_bytes())?;
+        }
+        self.0.write_all(&(png.len() as u32).to_le_bytes())?;
+        self.0.write_all(&png)?;
+        self.0.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Writer that sends every block to a sink, in addition to (or instead of) another writer.
+pub struct SinkWriter<'a, S: BlockSink> {
+    sink: lock::Mutex<S>,
+    inner: Option<Box<dyn image_buffer::ImageBufferWriter + 'a>>,
+}
+
+impl<'a, S: BlockSink> SinkWriter<'a, S> {
+    pub fn new(
+        sink: S,
+        inner: Option<Box<dyn image_buffer::ImageBufferWriter + 'a>>,
+    ) -> SinkWriter<'a, S> {
+        SinkWriter {
+            sink: lock::Mutex::new(sink),
+            inner,
+        }
+    }
+
+    /// Consumes the writer and returns the sink.
+    pub fn into_sink(self) -> S {
+        self.sink.into_inner()
+    }
+}
+
+impl<'a, S: BlockSink> image_buffer::ImageBufferWriter for SinkWriter<'a, S> {
+    fn write(&self, block: ScreenBlock, block_buffer: &image::RgbaImage) -> util::SimpleResult {
+        if let Some(inner) = &self.inner {
+            inner.write(block, block_buffer)?;
+        }
+        self.sink.lock().send_block(block, block_buffer)
+    }
+
+    fn finished(&self) -> util::SimpleResult {
+        match &self.inner {
+            Some(inner) => inner.finished(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads blocks serialized by PngStreamSink.
+pub struct PngStreamReader<R: Read>(pub R);
+
+impl<R: Read> PngStreamReader<R> {
+    /// Reads a single block, returns None at the end of the stream.
+    pub fn read_block(&mut self) -> util::SimpleResult<Option<(ScreenBlock, image::RgbaImage)>> {
+        let mut header = [0u32; 5];
+        for (i, value) in header.iter_mut().enumerate() {
+            let mut bytes = [0u8; 4];
+            match self.0.read_exact(&mut bytes) {
+                Err(e) if i == 0 && e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None);
+                }
+                result => result?,
+            }
+            *value = u32::from_le_bytes(bytes);
+        }
+
+        let block = ScreenBlock::new(
+            ScreenPoint::new(header[0], header[1]),
+            ScreenPoint::new(header[2], header[3]),
+        );
+        let mut png = vec![0u8; header[4] as usize];
+        self.0.read_exact(&mut png)?;
+
+        match image::load_from_memory_with_format(&png, image::ImageFormat::Png)? {
+            image::DynamicImage::ImageRgba8(block_buffer)
+                if block_buffer.dimensions() == (block.width(), block.height()) =>
+            {
+                Ok(Some((block, block_buffer)))
+            }
+            _ => Err("Unexpected block image format".into()),
+        }
+    }
+
+    /// Reads all remaining blocks and assembles them into an image of the given size.
+    pub fn read_image(&mut self, size: ScreenSize) -> util::SimpleResult<image::RgbaImage> {
+        use image::GenericImage;
+
+        let mut img = image::RgbaImage::new(size.width, size.height);
+        while let Some((block, block_buffer)) = self.read_block()? {
+            img.copy_from(&block_buffer, block.min.x, block.min.y)?;
+        }
+        Ok(img)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image_buffer::ImageBufferWriter;
+    use crate::screen_block::ScreenBlockExt;
+    use assert2::assert;
+
+    /// Streams blocks into a vector, reads them back and checks that the image is identical.
+    #[test]
+    fn round_trip() {
+        let size = ScreenSize::new(70, 45);
+        let img = image::RgbaImage::from_fn(size.width, size.height, |x, y| {
+            image::Rgba([x as u8, y as u8, (x * y) as u8, 255 - (x + y) as u8])
+        });
+
+        let writer = SinkWriter::new(PngStreamSink(Vec::new()), None);
+        for block in ScreenBlock::from_size(size).spiral_chunks(16) {
+            // Block buffers are allowed to be larger than the block
+            let mut block_buffer = image::RgbaImage::new(16, 16);
+            for point in block.internal_points() {
+                let offset = point - block.min;
+                block_buffer.put_pixel(offset.x, offset.y, *img.get_pixel(point.x, point.y));
+            }
+            writer.write(block, &block_buffer).unwrap();
+        }
+
+        let stream = writer.into_sink().0;
+        let read_back = PngStreamReader(stream.as_slice()).read_image(size).unwrap();
+
+        assert!(read_back.as_raw() == img.as_raw());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::util;
+
+use std::io::Write;
+
+/// Minimal interval between redraws of the progress bar.
+const UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Number of characters between the brackets of the progress bar.
+const BAR_WIDTH: usize = 40;
+
+/// Text progress bar for renders without a window (e.g. when no display is available).
+/// Uses the same inputs as `ImageWindow::set_progress_source`: a counter of processed items
+/// and the total number of items.
+/// The bar is redrawn in place on a single line, so the writer should be a terminal.
+pub struct ConsoleProgress<W: Write> {
+    writer: W,
+    counter: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    total: usize,
+    /// Progress currently shown and when it was drawn.
+    shown: Option<(u32, std::time::Instant)>,
+}
+
+impl ConsoleProgress<std::io::Stderr> {
+    /// Creates a progress bar printed to stderr.
+    pub fn new(
+        counter: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        total: usize,
+    ) -> ConsoleProgress<std::io::Stderr> {
+        ConsoleProgress::with_writer(std::io::stderr(), counter, total)
+    }
+}
+
+impl<W: Write> ConsoleProgress<W> {
+    pub fn with_writer(
+        writer: W,
+        counter: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        total: usize,
+    ) -> ConsoleProgress<W> {
+        ConsoleProgress {
+            writer,
+            counter,
+            total,
+            shown: None,
+        }
+    }
+
+    /// Redraws the bar if the progress changed, at most a few times per second (except for
+    /// reaching 100 %, which is always shown).
+    /// Meant to be called repeatedly, e.g. from a background function of parallel_for_each
+    /// with `BackgroundMode::PollUntilDone`.
+    pub fn update(&mut self) -> util::SimpleResult {
+        self.update_at(std::time::Instant::now())
+    }
+
+    /// Draws the current progress and ends the line.
+    pub fn finish(&mut self) -> util::SimpleResult {
+        let percent = self.percent();
+        self.draw(percent, std::time::Instant::now())?;
+        writeln!(self.writer)?;
+        Ok(())
+    }
+
+    fn update_at(&mut self, now: std::time::Instant) -> util::SimpleResult {
+        let percent = self.percent();
+        match self.shown {
+            Some((shown, _)) if shown == percent => Ok(()),
+            Some((_, drawn)) if percent != 100 && now.duration_since(drawn) < UPDATE_INTERVAL => {
+                Ok(())
+            }
+            _ => self.draw(percent, now),
+        }
+    }
+
+    fn percent(&self) -> u32 {
+        progress_percent(
+            self.counter.load(std::sync::atomic::Ordering::Relaxed),
+            self.total,
+        )
+    }
+
+    fn draw(&mut self, percent: u32, now: std::time::Instant) -> util::SimpleResult {
+        write!(self.writer, "\r{}", progress_bar(percent))?;
+        self.writer.flush()?;
+        self.shown = Some((percent, now));
+        Ok(())
+    }
+}
+
+/// Returns progress in whole percent, rounded down so that 100 % means done.
+pub fn progress_percent(processed: usize, total: usize) -> u32 {
+    if total == 0 {
+        100
+    } else {
+        (processed.min(total) as u64 * 100 / total as u64) as u32
+    }
+}
+
+/// Formats the progress bar line, without the leading carriage return.
+fn progress_bar(percent: u32) -> String {
+    let filled = BAR_WIDTH * percent as usize / 100;
+    format!(
+        "[{}{}] {:3}%",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        percent
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    /// Returns the bar that is currently visible (after the last carriage return).
+    fn visible_bar(output: &[u8]) -> &str {
+        let output = std::str::from_utf8(output).unwrap();
+        output.rsplit('\r').next().unwrap()
+    }
+
+    #[test]
+    fn bar_reflects_progress() {
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let set = |value| counter.store(value, std::sync::atomic::Ordering::Relaxed);
+        let mut progress = ConsoleProgress::with_writer(Vec::new(), counter.clone(), 200);
+        let start = std::time::Instant::now();
+
+        progress.update_at(start).unwrap();
+        assert!(visible_bar(&progress.writer) == format!("[{}]   0%", " ".repeat(40)));
+
+        // Throttled
+        set(100);
+        progress.update_at(start + UPDATE_INTERVAL / 2).unwrap();
+        assert!(visible_bar(&progress.writer).ends_with("  0%"));
+
+        progress.update_at(start + UPDATE_INTERVAL).unwrap();
+        assert!(
+            visible_bar(&progress.writer) == format!("[{}{}]  50%", "#".repeat(20), " ".repeat(20))
+        );
+
+        // Done is shown immediately
+        set(200);
+        progress.update_at(start + UPDATE_INTERVAL).unwrap();
+        assert!(visible_bar(&progress.writer) == format!("[{}] 100%", "#".repeat(40)));
+
+        progress.finish().unwrap();
+        let output = progress.writer;
+        assert!(output.ends_with(b"100%\n"));
+        assert!(output.iter().filter(|&&c| c == b'\r').count() == 4);
+    }
+}
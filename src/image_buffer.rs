@@ -11,10 +11,21 @@ pub trait ImageBuffer {
 
     /// Saves the content of the buffer to a file
     fn save(&self, path: &std::path::Path) -> util::SimpleResult;
+
+    /// True if the buffer doesn't show anything while it is written (its `run` returns
+    /// immediately), so the progress has to be reported some other way.
+    fn is_headless(&self) -> bool {
+        false
+    }
 }
 
 pub trait ImageBufferWriter: Sync + Send {
     fn write(&self, block: ScreenBlock, block_buffer: &image::RgbaImage) -> util::SimpleResult;
+
+    /// Notifies the buffer that all blocks were written.
+    fn finished(&self) -> util::SimpleResult {
+        Ok(())
+    }
 }
 
 /// This is an implementation of the unit tests that is shared for all impls of
@@ -60,6 +71,7 @@ pub mod test {
                 for block in blocks {
                     writer.write(block, &create_test_pattern(block)).unwrap();
                 }
+                writer.finished().unwrap();
             });
             buffer.run().unwrap();
         })
@@ -1,22 +1,22 @@
 use crate::geometry::*;
 use crate::image_buffer;
+use crate::lock;
 use crate::util;
 
 use image;
-use parking_lot;
 
 use image::GenericImage;
 
 /// ImageBuffer that can only save its content to file.
 pub struct ImageFileBuffer {
-    img: parking_lot::Mutex<image::RgbaImage>,
+    img: lock::Mutex<image::RgbaImage>,
 }
 
 impl ImageFileBuffer {
     /// Creates new image file buffer
     pub fn new(width: u32, height: u32) -> ImageFileBuffer {
         ImageFileBuffer {
-            img: parking_lot::Mutex::new(image::RgbaImage::new(width, height)),
+            img: lock::Mutex::new(image::RgbaImage::new(width, height)),
         }
     }
 }
@@ -35,9 +35,13 @@ impl image_buffer::ImageBuffer for ImageFileBuffer {
         self.img.lock().save(path)?;
         Ok(())
     }
+
+    fn is_headless(&self) -> bool {
+        true
+    }
 }
 
-pub struct Writer<'a>(&'a parking_lot::Mutex<image::RgbaImage>);
+pub struct Writer<'a>(&'a lock::Mutex<image::RgbaImage>);
 
 impl<'a> image_buffer::ImageBufferWriter for Writer<'a> {
     fn write(&self, block: ScreenBlock, block_buffer: &image::RgbaImage) -> util::SimpleResult {
@@ -55,6 +59,7 @@ impl<'a> image_buffer::ImageBufferWriter for Writer<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use assert2::assert;
     use proptest_attr_macro::proptest;
 
     #[test]
@@ -64,6 +69,7 @@ mod test {
         const CHUNK_SIZE: u32 = 51;
 
         let mut buffer = ImageFileBuffer::new(WIDTH, HEIGHT);
+        assert!(image_buffer::ImageBuffer::is_headless(&buffer));
         image_buffer::test::test_image_buffer(WIDTH, HEIGHT, CHUNK_SIZE, &mut buffer);
     }
 }
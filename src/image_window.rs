@@ -1,219 +1,5675 @@
+use crate::console_progress::progress_percent;
 use crate::geometry::*;
 use crate::image_buffer;
+use crate::lock;
+use crate::parallel_for_each;
 use crate::util;
 
 use image;
-use parking_lot;
 use sdl2;
 
 use image::GenericImage;
 use image::GenericImageView;
 
+use std::cmp;
+
 const SDL_PIXEL_FORMAT: sdl2::pixels::PixelFormatEnum = sdl2::pixels::PixelFormatEnum::ABGR8888;
 type PixelType = image::Rgba<u8>;
 
-pub struct ImageWindow {
-    title: String,
-    size: ScreenSize,
+/// Image with 16 bits per channel, accepted by `WriterU16`.
+pub type Rgba16Image = image::ImageBuffer<image::Rgba<u16>, Vec<u16>>;
 
-    context: sdl2::Sdl,
-    event: sdl2::EventSubsystem,
+/// Image with linear 32 bit float channels, accepted by `WriterF32`.
+pub type RgbaF32Image = image::ImageBuffer<image::Rgba<f32>, Vec<f32>>;
+
+/// Custom drawing callback, see `ImageWindow::set_post_draw`.
+pub type PostDrawHook = Box<dyn FnMut(&mut sdl2::render::Canvas<sdl2::video::Window>) + 'static>;
+
+/// Returns the number of samples used for a block, see `ImageWindow::set_sample_overlay`.
+pub type SampleCountFn = Box<dyn Fn(&ScreenBlock) -> u32 + 'static>;
 
-    img: parking_lot::Mutex<image::RgbaImage>,
+/// Callback for events that the window doesn't handle, see `ImageWindow::set_on_unhandled`.
+pub type UnhandledEventHook = Box<dyn FnMut(&sdl2::event::Event) + 'static>;
+
+/// Callback for every block received by the window, see `ImageWindow::set_on_block_update`.
+pub type BlockUpdateHook = Box<dyn FnMut(&ScreenBlock) + 'static>;
+
+/// Callback for left clicks on the image, see `ImageWindow::set_on_click`.
+pub type ClickHook = Box<dyn FnMut(ScreenPoint) + 'static>;
+
+/// Callback for background work while there are no events, see `ImageWindow::set_idle_callback`.
+pub type IdleCallback = Box<dyn FnMut() -> parallel_for_each::Continue + 'static>;
+
+/// Where the public coordinate API of the window (`window_to_image`, `image_to_window`) puts
+/// the image origin. The image buffer itself always has the origin in the top left corner.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CoordinateOrigin {
+    TopLeft,
+    /// Y axis points up, (0, 0) is the bottom left pixel.
+    BottomLeft,
 }
 
-impl ImageWindow {
-    /// Creates a SDL window.
-    /// There can be only one!
-    pub fn new(title: &str, width: u32, height: u32) -> util::SimpleResult<ImageWindow> {
-        let context = sdl2::init()?;
-        let event = context.event()?;
+/// Corner of the window where the thumbnail is drawn.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ThumbnailCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
 
-        event.register_custom_event::<ScreenBlock>()?;
+/// Placement of a small overview of the whole image drawn over the main view.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ThumbnailConfig {
+    pub corner: ThumbnailCorner,
+    /// Size of the thumbnail in logical pixels.
+    pub size: ScreenSize,
+}
 
-        Ok(ImageWindow {
-            title: String::from(title),
-            size: ScreenSize::new(width, height),
+/// Position and size of the window on the screen, in screen coordinates.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
 
-            context,
-            event,
+/// Custom SDL event sent when all blocks of the render were written.
+#[derive(Copy, Clone, Debug)]
+pub struct RenderFinished;
 
-            img: parking_lot::Mutex::new(image::ImageBuffer::<PixelType, _>::new(width, height)),
-        })
-    }
+/// Custom SDL event that closes the window, see `ImageWindow::make_closer`.
+#[derive(Copy, Clone, Debug)]
+pub struct CloseRequest;
+
+/// Custom SDL event that makes the window upload the whole image again, see
+/// `ImageWindow::invalidate`.
+#[derive(Copy, Clone, Debug)]
+struct InvalidateRequest;
+
+/// How many most recent frames are used for frame timing statistics.
+const FRAME_TIMES_CAPACITY: usize = 256;
+
+/// Alpha of the sample count overlay.
+const SAMPLE_OVERLAY_ALPHA: u8 = 96;
+
+/// How long it takes for a newly arrived block to fade in, with reveal animation enabled.
+const REVEAL_DURATION: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How often is the window redrawn while some blocks are fading in.
+const REVEAL_FRAME_INTERVAL: std::time::Duration = std::time::Duration::from_millis(16);
+
+/// Number of dots of the startup spinner, time of one turn and how often it is redrawn.
+const SPINNER_DOTS: u32 = 8;
+const SPINNER_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+const SPINNER_FRAME_INTERVAL: std::time::Duration = std::time::Duration::from_millis(33);
+
+/// How often is the benchmark overlay redrawn while shown, and over how long are the received
+/// blocks counted for its blocks per second.
+const BENCHMARK_FRAME_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+const BLOCK_RATE_WINDOW: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Size of a pixel of the benchmark overlay font and margin around its text, in logical pixels
+/// at display scale 1.
+const BENCHMARK_FONT_SCALE: u32 = 2;
+const BENCHMARK_MARGIN: u32 = 4;
+
+/// Glyphs of the benchmark overlay font, 3x5 pixels, one row per byte with the leftmost pixel
+/// in bit 2. Characters that are not listed are drawn as spaces.
+const BENCHMARK_FONT: &[(char, [u8; 5])] = &[
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+];
+
+/// Distance of the thumbnail from the edges of the window, in logical pixels at display scale 1.
+const THUMBNAIL_MARGIN: u32 = 8;
+
+/// Range and step of exposure adjustment from the keyboard, in stops.
+const EXPOSURE_RANGE: (f32, f32) = (-10.0, 10.0);
+const EXPOSURE_STEP: f32 = 0.5;
+
+/// Range and step of gamma adjustment from the keyboard.
+const GAMMA_RANGE: (f32, f32) = (0.2, 5.0);
+const GAMMA_STEP: f32 = 0.1;
+
+/// Range and step of brightness and contrast adjustment from the keyboard.
+const BRIGHTNESS_RANGE: (f32, f32) = (-1.0, 1.0);
+const BRIGHTNESS_STEP: f32 = 0.05;
+const CONTRAST_RANGE: (f32, f32) = (0.0, 4.0);
+const CONTRAST_STEP: f32 = 0.1;
+
+/// Zoom range of the view, relative to the image fitted to the window.
+const MIN_ZOOM: f64 = 1.0;
+const MAX_ZOOM: f64 = 32.0;
+
+/// Zoom factor of a single mouse wheel step.
+const ZOOM_STEP: f64 = 1.25;
+
+/// Color of the line separating the compared images.
+const WIPE_LINE_COLOR: sdl2::pixels::Color = sdl2::pixels::Color::RGB(255, 255, 255);
+
+/// Color and half length of the crosses drawn by `ImageWindow::set_markers`, the length is in
+/// logical pixels at display scale 1.
+const MARKER_COLOR: sdl2::pixels::Color = sdl2::pixels::Color::RGB(255, 64, 64);
+const MARKER_RADIUS: u32 = 4;
+
+/// How often the thread started by `ImageWindow::dump_on_signal` checks for signals.
+const SIGNAL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Exit code of the process after the image was dumped on a signal (128 + SIGINT).
+const SIGNAL_EXIT_CODE: i32 = 130;
+
+/// Minimal interval between title updates showing progress.
+const TITLE_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Minimal interval between frames stored in the frame history while rendering.
+/// The image when the render finishes is always stored.
+const HISTORY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Display DPI that corresponds to display scale 1.
+const REFERENCE_DPI: f32 = 96.0;
+
+/// SDL hint with the filter used for scaling textures.
+const SCALE_QUALITY_HINT: &str = "SDL_RENDER_SCALE_QUALITY";
+
+/// Threshold map for ordered dithering when converting 16 bit blocks for display.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Colors and cell size of the checkerboard drawn behind the (possibly transparent) image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Background {
+    pub base_color: sdl2::pixels::Color,
+    pub cell_color: sdl2::pixels::Color,
+    pub cell_size: u32,
 }
 
-impl image_buffer::ImageBuffer for ImageWindow {
-    /// Runs SDL event loop and handles the window.
-    /// Only exits when the window is closed.
-    fn run(&self) -> util::SimpleResult {
-        let video = self.context.video()?;
-        let mut canvas = video
-            .window(&self.title, self.size.width, self.size.height)
-            .position_centered()
-            .resizable()
-            .build()?
-            .into_canvas()
-            .build()?;
-        canvas.set_logical_size(self.size.width, self.size.height)?;
+/// Named backgrounds, useful for debugging alpha of the rendered image.
+/// Can be cycled through with the B key while the window is running.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackgroundPreset {
+    DarkChecker,
+    LightChecker,
+    /// Solid magenta, makes zero-alpha holes stand out.
+    Magenta,
+    Black,
+}
 
-        let texture_creator = canvas.texture_creator();
-        let mut texture = texture_creator.create_texture_streaming(
-            SDL_PIXEL_FORMAT,
-            self.size.width,
-            self.size.height,
-        )?;
-        texture.set_blend_mode(sdl2::render::BlendMode::Blend);
+impl BackgroundPreset {
+    pub fn background(self) -> Background {
+        use sdl2::pixels::Color;
+        match self {
+            BackgroundPreset::DarkChecker => Background {
+                base_color: Color::RGB(50, 50, 50),
+                cell_color: Color::RGB(200, 200, 200),
+                cell_size: 20,
+            },
+            BackgroundPreset::LightChecker => Background {
+                base_color: Color::RGB(255, 255, 255),
+                cell_color: Color::RGB(204, 204, 204),
+                cell_size: 8,
+            },
+            BackgroundPreset::Magenta => Background {
+                base_color: Color::RGB(255, 0, 255),
+                cell_color: Color::RGB(255, 0, 255),
+                cell_size: 20,
+            },
+            BackgroundPreset::Black => Background {
+                base_color: Color::RGB(0, 0, 0),
+                cell_color: Color::RGB(0, 0, 0),
+                cell_size: 20,
+            },
+        }
+    }
+
+    /// Returns the preset that follows this one when cycling.
+    pub fn next(self) -> BackgroundPreset {
+        match self {
+            BackgroundPreset::DarkChecker => BackgroundPreset::LightChecker,
+            BackgroundPreset::LightChecker => BackgroundPreset::Magenta,
+            BackgroundPreset::Magenta => BackgroundPreset::Black,
+            BackgroundPreset::Black => BackgroundPreset::DarkChecker,
+        }
+    }
+}
 
-        update_texture(&self.img.lock(), &mut texture, self.size.into())?; // Copy the empty output to texture
+/// Timing statistics of recent frames (texture update and redraw).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub mean: std::time::Duration,
+    pub p95: std::time::Duration,
+    pub max: std::time::Duration,
+    /// Total number of frames recorded, including those that already fell out of the statistics.
+    pub count: usize,
+}
 
-        let mut events = self.context.event_pump()?;
+/// Transform applied to the image when it is uploaded for display.
+/// Each color channel is scaled by 2^exposure (clamped to 1), then adjusted as
+/// `(value - 0.5) * contrast + 0.5 + brightness` (clamped to 0..1) and finally raised
+/// to 1/gamma, alpha is unchanged.
+/// The image buffer itself (and what gets saved) is not affected.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DisplayTransform {
+    pub exposure: f32,
+    pub gamma: f32,
+    pub brightness: f32,
+    pub contrast: f32,
+}
 
-        for event in events.wait_iter() {
-            use sdl2::event::Event;
-            use sdl2::event::WindowEvent;
-            use sdl2::keyboard::Keycode;
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Q),
-                    ..
-                } => break,
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        DisplayTransform {
+            exposure: 0.0,
+            gamma: 1.0,
+            brightness: 0.0,
+            contrast: 1.0,
+        }
+    }
+}
 
-                Event::Window {
-                    win_event: WindowEvent::Exposed,
-                    ..
-                } => redraw(&mut canvas, &texture)?,
+impl DisplayTransform {
+    fn is_identity(&self) -> bool {
+        *self == DisplayTransform::default()
+    }
 
-                _ => {
-                    if let Some(rendered) = event.as_user_event_type::<ScreenBlock>() {
-                        update_texture(&self.img.lock(), &mut texture, rendered)?;
-                        redraw(&mut canvas, &texture)?;
-                    }
-                }
+    /// Returns the transformed value of every possible channel value.
+    fn lookup_table(&self) -> [u8; 256] {
+        let scale = self.exposure.exp2();
+        let mut table = [0u8; 256];
+        for (i, value) in table.iter_mut().enumerate() {
+            let linear = (i as f32 / 255.0 * scale).min(1.0);
+            let adjusted = ((linear - 0.5) * self.contrast + 0.5 + self.brightness).clamp(0.0, 1.0);
+            *value = (adjusted.powf(1.0 / self.gamma) * 255.0).round() as u8;
+        }
+        table
+    }
+
+    /// Returns the transform adjusted by a key (+ and - for exposure, [ and ] for gamma,
+    /// , and . for brightness, ; and ' for contrast), clamped to reasonable ranges.
+    fn adjusted(self, key: sdl2::keyboard::Keycode) -> DisplayTransform {
+        use sdl2::keyboard::Keycode;
+        let clamp = |value: f32, (min, max): (f32, f32)| value.clamp(min, max);
+        let mut adjusted = self;
+        match key {
+            Keycode::Plus | Keycode::KpPlus | Keycode::Equals => {
+                adjusted.exposure = clamp(self.exposure + EXPOSURE_STEP, EXPOSURE_RANGE)
             }
+            Keycode::Minus | Keycode::KpMinus => {
+                adjusted.exposure = clamp(self.exposure - EXPOSURE_STEP, EXPOSURE_RANGE)
+            }
+            Keycode::RightBracket => adjusted.gamma = clamp(self.gamma + GAMMA_STEP, GAMMA_RANGE),
+            Keycode::LeftBracket => adjusted.gamma = clamp(self.gamma - GAMMA_STEP, GAMMA_RANGE),
+            Keycode::Period => {
+                adjusted.brightness = clamp(self.brightness + BRIGHTNESS_STEP, BRIGHTNESS_RANGE)
+            }
+            Keycode::Comma => {
+                adjusted.brightness = clamp(self.brightness - BRIGHTNESS_STEP, BRIGHTNESS_RANGE)
+            }
+            Keycode::Quote => {
+                adjusted.contrast = clamp(self.contrast + CONTRAST_STEP, CONTRAST_RANGE)
+            }
+            Keycode::Semicolon => {
+                adjusted.contrast = clamp(self.contrast - CONTRAST_STEP, CONTRAST_RANGE)
+            }
+            _ => {}
         }
-        Ok(())
+        adjusted
     }
+}
 
-    /// Creates a writer function that can write data into the window from different thread.
-    fn make_writer<'a>(&'a self) -> Box<dyn image_buffer::ImageBufferWriter + 'a> {
-        Box::new(Writer {
-            event_sender: self.event.event_sender(),
-            img: &self.img,
-        })
+/// Zoom and pan of the image in the window.
+/// Maps display coordinates (the image fitted to the logical window size) to logical window
+/// coordinates as `window = offset + scale * display`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct View {
+    scale: f64,
+    offset_x: f64,
+    offset_y: f64,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View {
+            scale: 1.0,
+            offset_x: 0.0,
+            offset_y: 0.0,
+        }
+    }
+}
+
+impl View {
+    /// Returns the display pixel under a window position.
+    fn window_to_display(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            ((x as f64 - self.offset_x) / self.scale).floor() as i32,
+            ((y as f64 - self.offset_y) / self.scale).floor() as i32,
+        )
     }
 
-    fn save(&self, path: &std::path::Path) -> util::SimpleResult {
-        self.img.lock().save(path)?;
-        Ok(())
+    /// Returns the window position of the center of a display pixel.
+    fn display_to_window(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            (self.offset_x + self.scale * (x as f64 + 0.5)).floor() as i32,
+            (self.offset_y + self.scale * (y as f64 + 0.5)).floor() as i32,
+        )
+    }
+
+    /// Returns the window rectangle covering a display rectangle.
+    fn rect(&self, rect: sdl2::rect::Rect) -> sdl2::rect::Rect {
+        let x0 = (self.offset_x + self.scale * rect.x() as f64).floor();
+        let y0 = (self.offset_y + self.scale * rect.y() as f64).floor();
+        let x1 = (self.offset_x + self.scale * (rect.x() as f64 + rect.width() as f64)).ceil();
+        let y1 = (self.offset_y + self.scale * (rect.y() as f64 + rect.height() as f64)).ceil();
+        sdl2::rect::Rect::new(x0 as i32, y0 as i32, (x1 - x0) as u32, (y1 - y0) as u32)
+    }
+
+    /// Zooms by the given factor, keeping the point under the window position in place.
+    fn zoomed(self, factor: f64, x: i32, y: i32, display_size: ScreenSize) -> View {
+        let scale = (self.scale * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        let ratio = scale / self.scale;
+        View {
+            scale,
+            offset_x: x as f64 - (x as f64 - self.offset_x) * ratio,
+            offset_y: y as f64 - (y as f64 - self.offset_y) * ratio,
+        }
+        .clamped(display_size)
+    }
+
+    /// Moves the image by the given amount of window pixels.
+    fn panned(self, dx: i32, dy: i32, display_size: ScreenSize) -> View {
+        View {
+            offset_x: self.offset_x + dx as f64,
+            offset_y: self.offset_y + dy as f64,
+            ..self
+        }
+        .clamped(display_size)
+    }
+
+    /// Limits the offset so that the image always covers the whole window.
+    fn clamped(self, display_size: ScreenSize) -> View {
+        let clamp = |offset: f64, size: u32| offset.max(size as f64 * (1.0 - self.scale)).min(0.0);
+        View {
+            offset_x: clamp(self.offset_x, display_size.width),
+            offset_y: clamp(self.offset_y, display_size.height),
+            ..self
+        }
     }
 }
 
-pub struct Writer<'a> {
-    event_sender: sdl2::event::EventSender,
-    img: &'a parking_lot::Mutex<image::RgbaImage>,
+/// When does the window present updated frames, see `ImageWindow::set_present_mode`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PresentMode {
+    /// Every event is drawn as soon as it is handled.
+    #[default]
+    Immediate,
+    /// Presents are synchronized with the display refresh, and all events that are queued when
+    /// a frame starts are handled together, with the changed blocks uploaded as a single region.
+    /// The user only sees complete frames, at most one per refresh.
+    FrameComplete,
 }
 
-impl<'a> image_buffer::ImageBufferWriter for Writer<'a> {
-    fn write(&self, block: ScreenBlock, block_buffer: &image::RgbaImage) -> util::SimpleResult {
-        debug_assert!(block.width() <= block_buffer.width());
-        debug_assert!(block.height() <= block_buffer.height());
+/// Texture filtering, see `ImageWindow::set_auto_scale_quality`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ScaleQuality {
+    Nearest,
+    Linear,
+}
 
-        self.img
-            .lock()
-            .copy_from(block_buffer, block.min.x, block.min.y)?;
-        self.event_sender.push_custom_event(block)?;
+impl ScaleQuality {
+    /// Nearest neighbor at and above the threshold zoom, linear below.
+    fn for_zoom(zoom: f64, threshold: f32) -> ScaleQuality {
+        if zoom >= threshold as f64 {
+            ScaleQuality::Nearest
+        } else {
+            ScaleQuality::Linear
+        }
+    }
 
-        Ok(())
+    /// Value of the `SCALE_QUALITY_HINT` hint.
+    fn hint_value(self) -> &'static str {
+        match self {
+            ScaleQuality::Nearest => "nearest",
+            ScaleQuality::Linear => "linear",
+        }
     }
 }
 
-/// Copies a block from the image to the texture (to the gpu).
-fn update_texture(
-    img: &image::RgbaImage,
-    texture: &mut sdl2::render::Texture,
-    block: ScreenBlock,
-) -> util::SimpleResult {
-    let rect = sdl2::rect::Rect::new(
-        block.min.x as i32,
-        block.min.y as i32,
-        block.width(),
-        block.height(),
-    );
+/// Bounded ring of recently presented frames, see `ImageWindow::set_history`.
+struct FrameHistory {
+    capacity: usize,
+    /// Oldest frame first.
+    frames: std::collections::VecDeque<image::RgbaImage>,
+    /// Index of the frame that is shown instead of the live image.
+    selected: Option<usize>,
+    last_push: Option<std::time::Instant>,
+}
 
-    texture.with_lock(
-        Some(rect),
-        |texture_buffer: &mut [u8], pitch: usize| -> util::SimpleResult {
-            // Obtain view to the part of the texture that we are updating.
-            let mut texture_samples = image::flat::FlatSamples {
-                samples: texture_buffer,
-                layout: image::flat::SampleLayout {
-                    channels: 4,       // There is no place to get this value programatically
-                    channel_stride: 1, // There is no place to get this value programatically
-                    width: block.width(),
-                    width_stride: SDL_PIXEL_FORMAT.byte_size_per_pixel(),
-                    height: block.height(),
-                    height_stride: pitch,
-                },
-                color_hint: None,
-            };
-            let mut texture_view = texture_samples.as_view_mut::<PixelType>().unwrap();
-            texture_view.copy_from(
-                &img.view(block.min.x, block.min.y, block.width(), block.height()),
-                0,
-                0,
-            )?;
-            Ok(())
-        },
-    )??;
+impl FrameHistory {
+    fn new(capacity: usize) -> FrameHistory {
+        FrameHistory {
+            capacity,
+            frames: std::collections::VecDeque::with_capacity(capacity),
+            selected: None,
+            last_push: None,
+        }
+    }
 
-    Ok(())
+    /// Returns true if the history should store a frame presented at `now`: it isn't disabled
+    /// and `HISTORY_INTERVAL` has passed since the last stored frame.
+    fn due(&self, now: std::time::Instant) -> bool {
+        self.capacity > 0
+            && self
+                .last_push
+                .is_none_or(|last_push| now - last_push >= HISTORY_INTERVAL)
+    }
+
+    /// Stores a presented frame, unless it is the same as the newest one.
+    fn push(&mut self, frame: &image::RgbaImage, now: std::time::Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.last_push = Some(now);
+        if self.frames.back() == Some(frame) {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame.clone());
+    }
+
+    /// Selects the previous frame (the newest one when showing the live image), returns
+    /// whether the selection changed.
+    fn step_back(&mut self) -> bool {
+        let selected = match self.selected {
+            None if self.frames.is_empty() => return false,
+            None => self.frames.len() - 1,
+            Some(0) => return false,
+            Some(index) => index - 1,
+        };
+        self.selected = Some(selected);
+        true
+    }
+
+    /// Selects the next frame, or the live image after the newest frame, returns whether
+    /// the selection changed.
+    fn step_forward(&mut self) -> bool {
+        self.selected = match self.selected {
+            None => return false,
+            Some(index) if index + 1 < self.frames.len() => Some(index + 1),
+            Some(_) => None,
+        };
+        true
+    }
+
+    fn selected_frame(&self) -> Option<&image::RgbaImage> {
+        self.selected.map(|index| &self.frames[index])
+    }
 }
 
-/// Completely redraws the canvas, puts a checkerboard behind and draws the texture on top.
-fn redraw(
-    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
-    texture: &sdl2::render::Texture,
-) -> util::SimpleResult {
-    draw_checkerboard(canvas)?;
-    canvas.copy(texture, None, None)?;
-    canvas.present();
+/// Result of `ImageWindow::pump`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PumpStatus {
+    Open,
+    /// The user closed the window (or it was closed by `WindowCloser`).
+    Closed,
+}
 
-    Ok(())
+/// Handles an event that isn't one of the window's own events: written blocks go to `on_block`,
+/// everything else is passed to the unhandled event hook.
+fn dispatch_other_event(
+    event: &sdl2::event::Event,
+    on_block: impl FnOnce(ScreenBlock) -> EventResponse,
+    on_unhandled: &mut Option<UnhandledEventHook>,
+) -> EventResponse {
+    match event.as_user_event_type::<ScreenBlock>() {
+        Some(rendered) => on_block(rendered),
+        None => {
+            if let Some(on_unhandled) = on_unhandled.as_mut() {
+                on_unhandled(event);
+            }
+            EventResponse::Nothing
+        }
+    }
 }
 
-/// Clears the canvas with a checkerboard pattern.
-fn draw_checkerboard(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>) -> util::SimpleResult {
-    canvas.set_draw_color(sdl2::pixels::Color::RGB(50, 50, 50));
-    canvas.clear();
-    canvas.set_draw_color(sdl2::pixels::Color::RGB(200, 200, 200));
+/// What has to be done after an event was handled by `ImageWindow::handle_event`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum EventResponse {
+    Nothing,
+    Redraw,
+    /// The block has to be uploaded to the texture before redrawing.
+    UpdateBlock(ScreenBlock),
+    /// The whole image has to be uploaded to the texture before redrawing.
+    UpdateAll,
+    /// The textures were lost (e.g. GPU driver reset) and have to be created again.
+    RecreateTextures,
+    Close,
+}
 
-    let (w, h) = canvas.logical_size();
-    let checkerboard_size = 20;
+impl EventResponse {
+    /// Combines responses to several events handled within one frame into a response that does
+    /// all the work of both.
+    fn merge(self, other: EventResponse) -> EventResponse {
+        use EventResponse::*;
+        match (self, other) {
+            (Close, _) | (_, Close) => Close,
+            (RecreateTextures, _) | (_, RecreateTextures) => RecreateTextures,
+            (UpdateAll, _) | (_, UpdateAll) => UpdateAll,
+            (UpdateBlock(a), UpdateBlock(b)) => UpdateBlock(a.union(&b)),
+            (UpdateBlock(block), _) | (_, UpdateBlock(block)) => UpdateBlock(block),
+            (Redraw, _) | (_, Redraw) => Redraw,
+            (Nothing, Nothing) => Nothing,
+        }
+    }
+}
 
-    for y in 0..(h / checkerboard_size) {
-        for x in ((y % 2)..(w / checkerboard_size)).step_by(2) {
-            let rect = sdl2::rect::Rect::new(
-                (x * checkerboard_size) as i32,
-                (y * checkerboard_size) as i32,
-                checkerboard_size,
-                checkerboard_size,
-            );
-            canvas.fill_rect(Some(rect))?;
+/// State of an open window that is kept between events, except for the textures.
+struct WindowState {
+    canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    fullscreen: bool,
+    transform: DisplayTransform,
+    background: BackgroundPreset,
+    received_blocks: Vec<ScreenBlock>,
+    show_sample_overlay: bool,
+    cursor: (i32, i32),
+    /// Position of the comparison wipe, in logical window coordinates.
+    wipe_x: i32,
+    /// Scale quality the textures were created with, if chosen automatically.
+    scale_quality: Option<ScaleQuality>,
+    /// Progress shown in the title and when the title was last updated because of it.
+    title_progress: Option<u32>,
+    title_updated: std::time::Instant,
+    /// The window was opened hidden and nothing was presented yet.
+    hidden: bool,
+    /// Display that the window was on when the display scale was last updated.
+    display_index: i32,
+    /// Time of the last handled event or idle callback.
+    idle_since: std::time::Instant,
+    /// When the startup spinner started, None once it is not shown anymore.
+    spinner_since: Option<std::time::Instant>,
+    show_benchmark_overlay: bool,
+    block_rate: BlockRate,
+    /// When the window was last redrawn for the spinner or the benchmark overlay.
+    animation_frame: std::time::Instant,
+}
+
+/// Arrival times of the blocks received during the last `BLOCK_RATE_WINDOW`.
+struct BlockRate {
+    arrivals: std::collections::VecDeque<std::time::Instant>,
+}
+
+impl BlockRate {
+    fn new() -> BlockRate {
+        BlockRate {
+            arrivals: std::collections::VecDeque::new(),
         }
     }
 
-    Ok(())
+    fn record(&mut self, now: std::time::Instant) {
+        self.expire(now);
+        self.arrivals.push_back(now);
+    }
+
+    fn per_second(&mut self, now: std::time::Instant) -> f64 {
+        self.expire(now);
+        self.arrivals.len() as f64 / BLOCK_RATE_WINDOW.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: std::time::Instant) {
+        while let Some(&arrival) = self.arrivals.front() {
+            if now.duration_since(arrival) < BLOCK_RATE_WINDOW {
+                break;
+            }
+            self.arrivals.pop_front();
+        }
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// Fixed size ring buffer of frame durations.
+struct FrameTimes {
+    durations: Vec<std::time::Duration>,
+    count: usize,
+}
+
+impl FrameTimes {
+    fn new() -> FrameTimes {
+        FrameTimes {
+            durations: Vec::with_capacity(FRAME_TIMES_CAPACITY),
+            count: 0,
+        }
+    }
+
+    fn record(&mut self, duration: std::time::Duration) {
+        if self.durations.len() < FRAME_TIMES_CAPACITY {
+            self.durations.push(duration);
+        } else {
+            self.durations[self.count % FRAME_TIMES_CAPACITY] = duration;
+        }
+        self.count += 1;
+    }
+
+    /// Duration of the most recently recorded frame.
+    fn last(&self) -> Option<std::time::Duration> {
+        match self.count {
+            0 => None,
+            count => Some(self.durations[(count - 1) % FRAME_TIMES_CAPACITY]),
+        }
+    }
+
+    fn stats(&self) -> FrameStats {
+        if self.durations.is_empty() {
+            return FrameStats::default();
+        }
+
+        let mut sorted = self.durations.clone();
+        sorted.sort();
+        let p95_index = (sorted.len() * 95).div_ceil(100) - 1;
+
+        FrameStats {
+            mean: sorted.iter().sum::<std::time::Duration>() / sorted.len() as u32,
+            p95: sorted[p95_index],
+            max: *sorted.last().unwrap(),
+            count: self.count,
+        }
+    }
+}
+
+/// Window that shows the image while it is being rendered.
+///
+/// Lifecycle:
+/// - While rendering, writers send the blocks to the window and `run` shows them.
+/// - When all blocks were written, `ImageBufferWriter::finished` sends the `RenderFinished`
+///   event. The window then shows 100 % progress and "(finished)" in the title and enables
+///   saving with Ctrl+S (see `set_save_path`, a failed save is reported on stderr and the
+///   window stays open). Everything else stays interactive, e.g.
+///   resizing, zooming and the overlays.
+/// - `run` returns only when the user closes the window (or presses Escape / Q), never just
+///   because the render finished.
+///
+/// ```ignore
+/// let mut window = ImageWindow::new("minipath", 800, 600)?;
+/// window.set_save_path(Some("render.png".into()));
+/// let writer = window.make_writer();
+/// parallel_for_each::parallel_for_each(
+///     blocks,
+///     |_worker_id| -> Result<_, util::NoError> { Ok(()) },
+///     |_state, block| writer.write(block, &render_block(block)),
+///     || -> util::SimpleResult<_> {
+///         window.run()?; // Keeps showing the final image until the window is closed
+///         Ok(parallel_for_each::Continue::Stop)
+///     },
+///     || {
+///         // Only when the render completed, not after an error or an early close
+///         if all_blocks_written() {
+///             let _ = writer.finished();
+///         }
+///     },
+///     parallel_for_each::WorkerCount::Auto,
+/// )?;
+/// ```
+pub struct ImageWindow {
+    title: String,
+    size: ScreenSize,
+    display_size: ScreenSize,
+    background: BackgroundPreset,
+    frame_times: lock::Mutex<FrameTimes>,
+    print_frame_stats: bool,
+    fullscreen: bool,
+    fullscreen_key: Option<sdl2::keyboard::Keycode>,
+    kiosk: bool,
+    opaque: bool,
+    premultiplied_alpha: bool,
+    color_key: Option<image::Rgb<u8>>,
+    comparison: Option<image::RgbaImage>,
+    icc_profile: Option<Vec<u8>>,
+    geometry: lock::Mutex<Option<WindowGeometry>>,
+    auto_fit: bool,
+    display_scale: lock::Mutex<f32>,
+    history: lock::Mutex<FrameHistory>,
+    thumbnail: Option<ThumbnailConfig>,
+    coordinate_origin: CoordinateOrigin,
+    reveal_animation: bool,
+    present_mode: PresentMode,
+    auto_scale_quality: Option<f32>,
+    start_hidden: bool,
+    startup_spinner: bool,
+    benchmark_overlay: bool,
+    progress_in_title: bool,
+    progress: Option<(std::sync::Arc<std::sync::atomic::AtomicUsize>, usize)>,
+    sample_overlay: Option<SampleCountFn>,
+    display_transform: DisplayTransform,
+    view: lock::Mutex<View>,
+    pumped: lock::Mutex<Option<PumpedWindow>>,
+    post_draw: lock::Mutex<Option<PostDrawHook>>,
+    on_unhandled: lock::Mutex<Option<UnhandledEventHook>>,
+    on_block_update: lock::Mutex<Option<BlockUpdateHook>>,
+    on_click: lock::Mutex<Option<ClickHook>>,
+    markers: Vec<ScreenPoint>,
+    idle_callback: lock::Mutex<Option<IdleCallback>>,
+    idle_interval: std::time::Duration,
+    render_finished: std::sync::atomic::AtomicBool,
+    save_path: Option<std::path::PathBuf>,
+    writer_settings: WriterSettings,
+
+    context: sdl2::Sdl,
+    event: sdl2::EventSubsystem,
+
+    img: std::sync::Arc<lock::Mutex<image::RgbaImage>>,
+    img16: Option<lock::Mutex<Rgba16Image>>,
+    accumulation: Option<lock::Mutex<Accumulation>>,
+}
+
+impl ImageWindow {
+    /// Creates a SDL window.
+    /// There can be only one!
+    pub fn new(title: &str, width: u32, height: u32) -> util::SimpleResult<ImageWindow> {
+        ImageWindow::with_display_size(title, width, height, width, height)
+    }
+
+    /// Creates a SDL window with the buffer initialized to a given image (e.g. a background
+    /// plate, or a previous render to continue), instead of a transparent one.
+    /// The buffer has the size of the image, blocks are written over it as usual.
+    pub fn with_image(title: &str, img: image::RgbaImage) -> util::SimpleResult<ImageWindow> {
+        let window = ImageWindow::new(title, img.width(), img.height())?;
+        // The window isn't open yet, its textures start from the current image
+        *window.img.lock() = img;
+        Ok(window)
+    }
+
+    /// Creates a SDL window that shows the image buffer scaled to a different size.
+    /// Blocks are still written in buffer coordinates, the display is downsampled using a box
+    /// filter (e.g. for antialiased preview of an image rendered at higher resolution).
+    pub fn with_display_size(
+        title: &str,
+        buffer_width: u32,
+        buffer_height: u32,
+        display_width: u32,
+        display_height: u32,
+    ) -> util::SimpleResult<ImageWindow> {
+        let context = sdl2::init()?;
+        let event = context.event()?;
+
+        event.register_custom_event::<ScreenBlock>()?;
+        event.register_custom_event::<RenderFinished>()?;
+        event.register_custom_event::<CloseRequest>()?;
+        event.register_custom_event::<InvalidateRequest>()?;
+
+        Ok(ImageWindow {
+            title: String::from(title),
+            size: ScreenSize::new(buffer_width, buffer_height),
+            display_size: ScreenSize::new(display_width, display_height),
+            background: BackgroundPreset::DarkChecker,
+            frame_times: lock::Mutex::new(FrameTimes::new()),
+            print_frame_stats: false,
+            fullscreen: false,
+            fullscreen_key: Some(sdl2::keyboard::Keycode::F11),
+            kiosk: false,
+            opaque: false,
+            premultiplied_alpha: false,
+            color_key: None,
+            comparison: None,
+            icc_profile: None,
+            geometry: lock::Mutex::new(None),
+            auto_fit: false,
+            display_scale: lock::Mutex::new(1.0),
+            history: lock::Mutex::new(FrameHistory::new(0)),
+            thumbnail: None,
+            coordinate_origin: CoordinateOrigin::TopLeft,
+            reveal_animation: false,
+            present_mode: PresentMode::default(),
+            auto_scale_quality: None,
+            start_hidden: false,
+            startup_spinner: false,
+            benchmark_overlay: false,
+            progress_in_title: false,
+            progress: None,
+            sample_overlay: None,
+            display_transform: DisplayTransform::default(),
+            view: lock::Mutex::new(View::default()),
+            pumped: lock::Mutex::new(None),
+            post_draw: lock::Mutex::new(None),
+            on_unhandled: lock::Mutex::new(None),
+            on_block_update: lock::Mutex::new(None),
+            on_click: lock::Mutex::new(None),
+            markers: Vec::new(),
+            idle_callback: lock::Mutex::new(None),
+            idle_interval: std::time::Duration::from_secs(1),
+            render_finished: std::sync::atomic::AtomicBool::new(false),
+            save_path: None,
+            writer_settings: WriterSettings::default(),
+
+            context,
+            event,
+
+            img: std::sync::Arc::new(lock::Mutex::new(image::ImageBuffer::<PixelType, _>::new(
+                buffer_width,
+                buffer_height,
+            ))),
+            img16: None,
+            accumulation: None,
+        })
+    }
+
+    /// Selects the background drawn behind transparent parts of the image.
+    pub fn set_background_preset(&mut self, preset: BackgroundPreset) {
+        self.background = preset;
+    }
+
+    /// Sets the file that Ctrl+S saves the image to (see `ImageBuffer::save`).
+    /// Saving is only enabled once the render is finished, so that a partial image doesn't
+    /// get saved by accident.
+    pub fn set_save_path(&mut self, save_path: Option<std::path::PathBuf>) {
+        self.save_path = save_path;
+    }
+
+    /// Returns true if the window has received the `RenderFinished` event.
+    pub fn render_finished(&self) -> bool {
+        self.render_finished
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the scale of the display the window is on, relative to 96 DPI (e.g. 1.5 for a
+    /// 144 DPI monitor), updated when the window moves to a different display.
+    /// Returns 1 before the window is opened and on platforms where SDL can't query the
+    /// display DPI (e.g. Wayland); Windows, macOS and X11 report it.
+    /// The benchmark overlay, the markers and the thumbnail margin are scaled by it.
+    pub fn display_scale(&self) -> f32 {
+        *self.display_scale.lock()
+    }
+
+    /// Keeps up to `frames` recently presented frames, which can be browsed with the left and
+    /// right arrow keys for debugging temporal artifacts. The left arrow pauses the window on
+    /// the last stored frame and steps back, the right arrow steps forward and returns to
+    /// the live image after the newest frame. Blocks received while browsing are shown after
+    /// returning.
+    /// A frame is stored at most every `HISTORY_INTERVAL` while rendering, and once more when
+    /// the render finishes.
+    /// Every frame is a full copy of the image, so the history takes up to `frames` times
+    /// the image size of memory (frames that didn't change the image are not stored again).
+    /// Zero disables the history and drops the stored frames.
+    pub fn set_history(&mut self, frames: usize) {
+        self.history = lock::Mutex::new(FrameHistory::new(frames));
+    }
+
+    /// Returns timing statistics of the recently drawn frames.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_times.lock().stats()
+    }
+
+    /// Enables printing of the frame statistics to stderr when the window is closed.
+    pub fn set_print_frame_stats(&mut self, print: bool) {
+        self.print_frame_stats = print;
+    }
+
+    /// Sets whether the window is shown as borderless fullscreen when `run` starts.
+    /// The image keeps its logical size when switching, SDL scales it to fit the new output size
+    /// (keeping the aspect ratio and letterboxing the rest), so the scaling quality hint applies
+    /// to it as well.
+    pub fn set_fullscreen(&mut self, fullscreen: bool) {
+        self.fullscreen = fullscreen;
+    }
+
+    /// Sets the key that toggles fullscreen while the window is running (F11 by default).
+    pub fn set_fullscreen_key(&mut self, key: Option<sdl2::keyboard::Keycode>) {
+        self.fullscreen_key = key;
+    }
+
+    /// Enables kiosk mode: the window is borderless fullscreen, without mouse cursor, and
+    /// Escape / Q don't close it.
+    /// The window then only closes on a quit event from the system (e.g. SIGINT / SIGTERM) or
+    /// when a close request is sent through `make_closer`.
+    pub fn set_kiosk(&mut self, kiosk: bool) {
+        self.kiosk = kiosk;
+    }
+
+    /// Returns the geometry of the window when `run` last returned, or the geometry set by
+    /// `set_geometry` if the window wasn't shown since then.
+    /// Returns None if neither happened. Geometry in fullscreen mode is not recorded.
+    pub fn geometry(&self) -> Option<WindowGeometry> {
+        *self.geometry.lock()
+    }
+
+    /// Sets position and size of the window for the next call to `run` (e.g. restored from
+    /// a previous session).
+    /// The image keeps its display size as the logical size, so a window with different
+    /// aspect ratio is letterboxed rather than distorting the image.
+    pub fn set_geometry(&mut self, geometry: WindowGeometry) {
+        self.geometry = lock::Mutex::new(Some(geometry));
+    }
+
+    /// Makes a newly opened window fit the usable bounds of the display, instead of opening
+    /// at the display size of the image even if it's larger than the screen.
+    /// The window size is the display size divided by the smallest integer that makes it fit,
+    /// the logical size stays the same, so the image is only scaled on the screen.
+    /// The bounds are those of the display the window is centered on (the first one), on
+    /// multi-monitor setups the window is not refitted when moved to a different display.
+    /// Geometry set by `set_geometry` takes precedence.
+    pub fn set_auto_fit(&mut self, auto_fit: bool) {
+        self.auto_fit = auto_fit;
+    }
+
+    /// Enables drawing of a thumbnail of the whole image in a corner of the window.
+    /// The thumbnail is downsampled from the image buffer (nearest neighbor) on every redraw.
+    pub fn set_thumbnail(&mut self, thumbnail: Option<ThumbnailConfig>) {
+        self.thumbnail = thumbnail;
+    }
+
+    /// Sets the origin used by `window_to_image`, `image_to_window`, `set_on_click` and
+    /// `set_markers`.
+    pub fn set_coordinate_origin(&mut self, origin: CoordinateOrigin) {
+        self.coordinate_origin = origin;
+    }
+
+    /// Maps a point in logical window coordinates (e.g. from a mouse event) to the image
+    /// pixel under it. Returns None if the point is outside of the image.
+    pub fn window_to_image(&self, x: i32, y: i32) -> Option<ScreenPoint> {
+        let (x, y) = self.view.lock().window_to_display(x, y);
+        window_to_image(x, y, self.display_size, self.size, self.coordinate_origin)
+    }
+
+    /// Maps an image pixel to the logical window coordinates of its center, e.g. for placing
+    /// markers from the post draw hook.
+    pub fn image_to_window(&self, point: ScreenPoint) -> (i32, i32) {
+        let (x, y) = image_to_window(point, self.display_size, self.size, self.coordinate_origin);
+        self.view.lock().display_to_window(x, y)
+    }
+
+    /// Processes all currently queued events without blocking, for embedding the window into
+    /// an external main loop instead of calling `run`. Call it on every tick of the loop.
+    /// The window is opened by the first call, and is closed when `PumpStatus::Closed` is
+    /// returned (a later call opens it again).
+    /// All queued changes are drawn at once, without the reveal animation.
+    pub fn pump(&self) -> anyhow::Result<PumpStatus> {
+        self.pump_events().map_err(|e| anyhow::anyhow!(e))
+    }
+
+    fn pump_events(&self) -> util::SimpleResult<PumpStatus> {
+        let mut pumped = self.pumped.lock();
+        let (mut window, mut needs_redraw) = match pumped.take() {
+            Some(window) => (window, false),
+            None => {
+                let window = PumpedWindow::open(self)?;
+                let needs_redraw = !window.state.hidden;
+                (window, needs_redraw)
+            }
+        };
+
+        let mut closed = false;
+        {
+            let PumpedWindow {
+                textures,
+                texture_creator,
+                state,
+            } = &mut window;
+            let mut on_unhandled = self.on_unhandled.lock();
+            let mut events = self.context.event_pump()?;
+            let mut had_events = false;
+            for event in events.poll_iter() {
+                had_events = true;
+                state.idle_since = std::time::Instant::now();
+                match self.handle_event(state, &event, &mut on_unhandled)? {
+                    EventResponse::Nothing => {}
+                    EventResponse::Close => {
+                        closed = true;
+                        break;
+                    }
+                    EventResponse::Redraw => needs_redraw = true,
+                    EventResponse::UpdateAll => {
+                        self.upload_shown_image(&mut textures.texture, state)?;
+                        if let (Some(img), Some(comparison)) =
+                            (&self.comparison, &mut textures.comparison)
+                        {
+                            update_texture(
+                                img,
+                                comparison,
+                                self.size.into(),
+                                self.upload_settings(state),
+                            )?;
+                        }
+                        needs_redraw = true;
+                    }
+                    EventResponse::RecreateTextures => {
+                        // SAFETY: See `PumpedWindow`
+                        *textures = unsafe {
+                            PumpedTextures::new(self.create_textures(texture_creator, state)?)
+                        };
+                        needs_redraw = true;
+                    }
+                    EventResponse::UpdateBlock(block) => {
+                        update_texture(
+                            &self.img.lock(),
+                            &mut textures.texture,
+                            block,
+                            self.upload_settings(state),
+                        )?;
+                        needs_redraw = true;
+                    }
+                }
+            }
+            if !had_events {
+                let mut idle_callback = self.idle_callback.lock();
+                if let parallel_for_each::Continue::Stop = self.run_idle(state, &mut idle_callback)
+                {
+                    closed = true;
+                }
+            }
+        }
+
+        if closed {
+            let PumpedWindow {
+                textures,
+                texture_creator,
+                state,
+            } = window;
+            drop(textures);
+            drop(texture_creator);
+            self.close_window(state);
+            return Ok(PumpStatus::Closed);
+        }
+        self.update_progress_title(&mut window.state)?;
+        if self.animation_due(&window.state) {
+            window.state.animation_frame = std::time::Instant::now();
+            needs_redraw = true;
+        }
+
+        if needs_redraw {
+            let frame_start = std::time::Instant::now();
+            let PumpedWindow {
+                textures, state, ..
+            } = &mut window;
+            self.draw(
+                state,
+                &textures.texture,
+                textures.comparison.as_ref(),
+                &mut textures.thumbnail,
+                &mut self.post_draw.lock(),
+            )?;
+            self.frame_times.lock().record(frame_start.elapsed());
+        }
+
+        *pumped = Some(window);
+        Ok(PumpStatus::Open)
+    }
+
+    /// Creates the SDL window, shared by `run` and `pump`.
+    fn open_window(&self) -> util::SimpleResult<WindowState> {
+        let video = self.context.video()?;
+        let geometry = self.geometry();
+        let mut window_builder = match geometry {
+            Some(geometry) => video.window(&self.title, geometry.width, geometry.height),
+            None => {
+                let window_size = if self.auto_fit {
+                    let bounds = video.display_usable_bounds(0)?;
+                    auto_fit_size(
+                        self.display_size,
+                        ScreenSize::new(bounds.width(), bounds.height()),
+                    )
+                } else {
+                    self.display_size
+                };
+                video.window(&self.title, window_size.width, window_size.height)
+            }
+        };
+        match geometry {
+            Some(geometry) => window_builder.position(geometry.x, geometry.y),
+            None => window_builder.position_centered(),
+        };
+        if self.start_hidden {
+            window_builder.hidden();
+        }
+        let mut canvas_builder = window_builder.resizable().build()?.into_canvas();
+        if self.present_mode == PresentMode::FrameComplete {
+            canvas_builder = canvas_builder.present_vsync();
+        }
+        let mut canvas = canvas_builder.build()?;
+        canvas.set_logical_size(self.display_size.width, self.display_size.height)?;
+        let display_index = canvas.window().display_index()?;
+        self.update_display_scale(display_index);
+        let fullscreen = self.fullscreen || self.kiosk;
+        set_window_fullscreen(&mut canvas, fullscreen)?;
+        if self.kiosk {
+            self.context.mouse().show_cursor(false);
+        }
+
+        let mut state = WindowState {
+            canvas,
+            fullscreen,
+            transform: self.display_transform,
+            background: self.background,
+            received_blocks: Vec::new(),
+            show_sample_overlay: self.sample_overlay.is_some(),
+            cursor: (0, 0),
+            wipe_x: (self.display_size.width / 2) as i32,
+            scale_quality: self.scale_quality(),
+            title_progress: None,
+            title_updated: std::time::Instant::now(),
+            hidden: self.start_hidden,
+            display_index,
+            idle_since: std::time::Instant::now(),
+            spinner_since: if self.startup_spinner {
+                Some(std::time::Instant::now())
+            } else {
+                None
+            },
+            show_benchmark_overlay: self.benchmark_overlay,
+            block_rate: BlockRate::new(),
+            animation_frame: std::time::Instant::now(),
+        };
+        self.update_title(&mut state)?;
+        Ok(state)
+    }
+
+    /// Sets the window title according to the current state.
+    fn update_title(&self, state: &mut WindowState) -> util::SimpleResult {
+        state.title_progress = self.progress_percent();
+        state.title_updated = std::time::Instant::now();
+        state.canvas.window_mut().set_title(&window_title(
+            &self.title,
+            self.render_finished(),
+            state.transform,
+            state.title_progress,
+        ))?;
+        Ok(())
+    }
+
+    /// Updates the title if the shown progress changed, at most once per
+    /// `TITLE_UPDATE_INTERVAL` (except for reaching 100 %).
+    fn update_progress_title(&self, state: &mut WindowState) -> util::SimpleResult {
+        let progress = self.progress_percent();
+        if progress == state.title_progress
+            || (progress != Some(100) && state.title_updated.elapsed() < TITLE_UPDATE_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.update_title(state)
+    }
+
+    /// Returns the progress in percent, if it is shown in the title.
+    /// A finished render is at 100 %, even if the counter doesn't count every block.
+    fn progress_percent(&self) -> Option<u32> {
+        match &self.progress {
+            Some(_) if self.progress_in_title && self.render_finished() => Some(100),
+            Some((counter, total)) if self.progress_in_title => Some(progress_percent(
+                counter.load(std::sync::atomic::Ordering::Relaxed),
+                *total,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Creates the image, thumbnail and comparison textures, with the current images uploaded.
+    fn create_textures<'t>(
+        &self,
+        texture_creator: &'t sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+        state: &WindowState,
+    ) -> util::SimpleResult<(
+        sdl2::render::Texture<'t>,
+        Option<Thumbnail<'t>>,
+        Option<sdl2::render::Texture<'t>>,
+    )> {
+        // The hint is only read when a texture is created. It is global for the whole process,
+        // so the previous value is restored once the textures are created.
+        let _restore_hint = state.scale_quality.map(|scale_quality| {
+            let previous = sdl2::hint::get(SCALE_QUALITY_HINT);
+            sdl2::hint::set(SCALE_QUALITY_HINT, scale_quality.hint_value());
+            scopeguard::guard(previous, |previous| {
+                // Nearest is the SDL default when the hint is not set
+                let previous = previous
+                    .as_deref()
+                    .unwrap_or(ScaleQuality::Nearest.hint_value());
+                sdl2::hint::set(SCALE_QUALITY_HINT, previous);
+            })
+        });
+        let create_image_texture = || -> util::SimpleResult<_> {
+            let mut texture = texture_creator.create_texture_streaming(
+                SDL_PIXEL_FORMAT,
+                self.display_size.width,
+                self.display_size.height,
+            )?;
+            texture.set_blend_mode(if self.opaque {
+                sdl2::render::BlendMode::None
+            } else {
+                sdl2::render::BlendMode::Blend
+            });
+            Ok(texture)
+        };
+
+        let mut texture = create_image_texture()?;
+        self.upload_shown_image(&mut texture, state)?;
+
+        let comparison = match &self.comparison {
+            Some(img) => {
+                let mut texture = create_image_texture()?;
+                update_texture(
+                    img,
+                    &mut texture,
+                    self.size.into(),
+                    self.upload_settings(state),
+                )?;
+                Some(texture)
+            }
+            None => None,
+        };
+
+        let thumbnail = match self.thumbnail {
+            Some(config) => Some(Thumbnail {
+                config,
+                texture: texture_creator.create_texture_streaming(
+                    SDL_PIXEL_FORMAT,
+                    config.size.width,
+                    config.size.height,
+                )?,
+            }),
+            None => None,
+        };
+
+        Ok((texture, thumbnail, comparison))
+    }
+
+    /// Replaces the textures with new ones after the renderer lost them.
+    fn recreate_texture<'t>(
+        &self,
+        texture_creator: &'t sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+        state: &WindowState,
+        texture: &mut sdl2::render::Texture<'t>,
+        thumbnail: &mut Option<Thumbnail<'t>>,
+        comparison: &mut Option<sdl2::render::Texture<'t>>,
+    ) -> util::SimpleResult {
+        let (new_texture, new_thumbnail, new_comparison) =
+            self.create_textures(texture_creator, state)?;
+        *texture = new_texture;
+        *thumbnail = new_thumbnail;
+        *comparison = new_comparison;
+        Ok(())
+    }
+
+    /// Returns the scale quality for the current zoom with automatic scale quality enabled.
+    fn scale_quality(&self) -> Option<ScaleQuality> {
+        self.auto_scale_quality
+            .map(|threshold| ScaleQuality::for_zoom(self.view.lock().scale, threshold))
+    }
+
+    /// Textures have to be recreated if the zoom changes the scale quality.
+    fn zoom_changed(&self, state: &mut WindowState) -> EventResponse {
+        let scale_quality = self.scale_quality();
+        if scale_quality == state.scale_quality {
+            EventResponse::Redraw
+        } else {
+            state.scale_quality = scale_quality;
+            EventResponse::RecreateTextures
+        }
+    }
+
+    /// Uploads the whole shown image into the texture: the live image, or a frame from
+    /// the history while it is browsed.
+    fn upload_shown_image(
+        &self,
+        texture: &mut sdl2::render::Texture,
+        state: &WindowState,
+    ) -> util::SimpleResult {
+        let history = self.history.lock();
+        match history.selected_frame() {
+            Some(frame) => update_texture(
+                frame,
+                texture,
+                self.size.into(),
+                self.upload_settings(state),
+            ),
+            None => update_texture(
+                &self.img.lock(),
+                texture,
+                self.size.into(),
+                self.upload_settings(state),
+            ),
+        }
+    }
+
+    fn browsing_history(&self) -> bool {
+        self.history.lock().selected_frame().is_some()
+    }
+
+    /// Moves in the history, the whole image has to be uploaded if the shown frame changed.
+    fn navigate_history(&self, step: fn(&mut FrameHistory) -> bool) -> EventResponse {
+        if step(&mut self.history.lock()) {
+            EventResponse::UpdateAll
+        } else {
+            EventResponse::Nothing
+        }
+    }
+
+    /// Queries the scale of the display with the given index, keeps 1 if SDL can't tell.
+    fn update_display_scale(&self, display_index: i32) {
+        let dpi = self
+            .context
+            .video()
+            .and_then(|video| video.display_dpi(display_index));
+        *self.display_scale.lock() = match dpi {
+            Ok((diagonal_dpi, _, _)) => display_scale(diagonal_dpi),
+            Err(_) => 1.0,
+        };
+    }
+
+    /// SDL doesn't send an event when the window changes displays, so this is checked after
+    /// every move. The image keeps its logical size, only the overlays drawn in window
+    /// coordinates (benchmark text, markers, thumbnail margin) are resized for the new display.
+    fn window_moved(&self, state: &mut WindowState) -> util::SimpleResult<EventResponse> {
+        let display_index = state.canvas.window().display_index()?;
+        if display_index == state.display_index {
+            return Ok(EventResponse::Nothing);
+        }
+        state.display_index = display_index;
+        self.update_display_scale(display_index);
+        Ok(EventResponse::Redraw)
+    }
+
+    /// Updates the window state according to an event and returns what has to be redrawn.
+    fn handle_event(
+        &self,
+        state: &mut WindowState,
+        event: &sdl2::event::Event,
+        on_unhandled: &mut Option<UnhandledEventHook>,
+    ) -> util::SimpleResult<EventResponse> {
+        use sdl2::event::Event;
+        use sdl2::event::WindowEvent;
+        use sdl2::keyboard::Keycode;
+        if let Event::MouseMotion { x, y, .. } = *event {
+            state.cursor = (x, y);
+        }
+        Ok(match *event {
+            Event::Quit { .. } => EventResponse::Close,
+            Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Q),
+                ..
+            } if !self.kiosk => EventResponse::Close,
+            _ if event.as_user_event_type::<CloseRequest>().is_some() => EventResponse::Close,
+            _ if event.as_user_event_type::<InvalidateRequest>().is_some() => {
+                self.update_title(state)?;
+                EventResponse::UpdateAll
+            }
+
+            Event::KeyDown {
+                keycode: Some(key), ..
+            } if Some(key) == self.fullscreen_key => {
+                state.fullscreen = !state.fullscreen;
+                set_window_fullscreen(&mut state.canvas, state.fullscreen)?;
+                EventResponse::Redraw
+            }
+
+            Event::KeyDown {
+                keycode: Some(Keycode::S),
+                keymod,
+                ..
+            } if keymod
+                .intersects(sdl2::keyboard::Mod::LCTRLMOD | sdl2::keyboard::Mod::RCTRLMOD) =>
+            {
+                if let (Some(path), true) = (&self.save_path, self.render_finished()) {
+                    // A failed save must not close the window, the user can fix the cause and
+                    // try again
+                    if let Err(e) = image_buffer::ImageBuffer::save(self, path) {
+                        eprintln!("Failed to save image to {}: {}", path.display(), e);
+                    }
+                }
+                EventResponse::Nothing
+            }
+
+            Event::KeyDown {
+                keycode: Some(Keycode::S),
+                ..
+            } if self.sample_overlay.is_some() => {
+                state.show_sample_overlay = !state.show_sample_overlay;
+                EventResponse::Redraw
+            }
+
+            Event::KeyDown {
+                keycode:
+                    Some(
+                        key @ (Keycode::Plus
+                        | Keycode::KpPlus
+                        | Keycode::Equals
+                        | Keycode::Minus
+                        | Keycode::KpMinus
+                        | Keycode::LeftBracket
+                        | Keycode::RightBracket
+                        | Keycode::Period
+                        | Keycode::Comma
+                        | Keycode::Quote
+                        | Keycode::Semicolon),
+                    ),
+                ..
+            } => {
+                state.transform = state.transform.adjusted(key);
+                self.update_title(state)?;
+                EventResponse::UpdateAll
+            }
+
+            Event::KeyDown {
+                keycode: Some(Keycode::B),
+                ..
+            } => {
+                state.background = state.background.next();
+                EventResponse::Redraw
+            }
+
+            Event::KeyDown {
+                keycode: Some(Keycode::F3),
+                ..
+            } => {
+                state.show_benchmark_overlay = !state.show_benchmark_overlay;
+                EventResponse::Redraw
+            }
+
+            Event::KeyDown {
+                keycode: Some(Keycode::Left),
+                ..
+            } => self.navigate_history(FrameHistory::step_back),
+            Event::KeyDown {
+                keycode: Some(Keycode::Right),
+                ..
+            } => self.navigate_history(FrameHistory::step_forward),
+
+            Event::KeyDown {
+                keycode: Some(Keycode::Home),
+                ..
+            } => {
+                self.reset_view();
+                self.zoom_changed(state)
+            }
+
+            Event::MouseWheel { y: steps, .. } if steps != 0 => {
+                {
+                    let mut view = self.view.lock();
+                    *view = view.zoomed(
+                        ZOOM_STEP.powi(steps),
+                        state.cursor.0,
+                        state.cursor.1,
+                        self.display_size,
+                    );
+                }
+                self.zoom_changed(state)
+            }
+
+            Event::MouseButtonDown {
+                mouse_btn: sdl2::mouse::MouseButton::Left,
+                x,
+                ..
+            } if self.comparison.is_some() => {
+                state.wipe_x = x.clamp(0, self.display_size.width as i32);
+                EventResponse::Redraw
+            }
+
+            Event::MouseButtonDown {
+                mouse_btn: sdl2::mouse::MouseButton::Left,
+                x,
+                y,
+                ..
+            } if self.on_click.lock().is_some() => {
+                if let Some(point) = self.window_to_image(x, y) {
+                    if let Some(on_click) = self.on_click.lock().as_mut() {
+                        on_click(point);
+                    }
+                }
+                EventResponse::Nothing
+            }
+
+            Event::MouseMotion { x, mousestate, .. }
+                if self.comparison.is_some() && mousestate.left() =>
+            {
+                state.wipe_x = x.clamp(0, self.display_size.width as i32);
+                EventResponse::Redraw
+            }
+
+            Event::MouseMotion {
+                xrel,
+                yrel,
+                mousestate,
+                ..
+            } if mousestate.middle() => {
+                let mut view = self.view.lock();
+                *view = view.panned(xrel, yrel, self.display_size);
+                EventResponse::Redraw
+            }
+
+            Event::Window {
+                win_event: WindowEvent::Moved(..),
+                ..
+            } => self.window_moved(state)?,
+
+            // Presenting would show a window that is waiting for its first content
+            Event::Window {
+                win_event: WindowEvent::Exposed,
+                ..
+            } if state.hidden => EventResponse::Nothing,
+            Event::Window {
+                win_event: WindowEvent::Exposed,
+                ..
+            } => EventResponse::Redraw,
+
+            Event::RenderTargetsReset { .. } | Event::RenderDeviceReset { .. } => {
+                EventResponse::RecreateTextures
+            }
+
+            _ if event.as_user_event_type::<RenderFinished>().is_some() => {
+                self.render_finished
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                self.update_title(state)?;
+                let mut history = self.history.lock();
+                if history.selected_frame().is_none() {
+                    history.push(&self.img.lock(), std::time::Instant::now());
+                }
+                drop(history);
+                if state.hidden || state.spinner_since.take().is_some() {
+                    // The render didn't produce any block, show the window anyway
+                    EventResponse::Redraw
+                } else {
+                    EventResponse::Nothing
+                }
+            }
+
+            _ => dispatch_other_event(
+                event,
+                |rendered| {
+                    state.spinner_since = None;
+                    state.block_rate.record(std::time::Instant::now());
+                    if let Some(on_block_update) = self.on_block_update.lock().as_mut() {
+                        on_block_update(&rendered);
+                    }
+                    if self.sample_overlay.is_some() {
+                        state.received_blocks.push(rendered);
+                    }
+                    if self.browsing_history() {
+                        // Shown when returning to the live image
+                        EventResponse::Nothing
+                    } else {
+                        EventResponse::UpdateBlock(rendered)
+                    }
+                },
+                on_unhandled,
+            ),
+        })
+    }
+
+    /// Draws the window contents with the current state.
+    fn draw(
+        &self,
+        state: &mut WindowState,
+        texture: &sdl2::render::Texture,
+        comparison: Option<&sdl2::render::Texture>,
+        thumbnail: &mut Option<Thumbnail>,
+        post_draw: &mut Option<PostDrawHook>,
+    ) -> util::SimpleResult {
+        let background = if self.opaque {
+            None
+        } else {
+            Some(state.background.background())
+        };
+        let mut overlay = match &self.sample_overlay {
+            Some(sample_count) if state.show_sample_overlay => sample_overlay_rects(
+                &state.received_blocks,
+                sample_count,
+                self.size,
+                self.display_size,
+            ),
+            _ => Vec::new(),
+        };
+        if let Some(spinner_since) = state.spinner_since {
+            overlay.extend(spinner_rects(self.display_size, spinner_since.elapsed()));
+        }
+        let display_scale = self.display_scale();
+        let mut hud = if state.show_benchmark_overlay {
+            let frame_times = self.frame_times.lock();
+            let text = benchmark_text(
+                frame_times.last().unwrap_or_default(),
+                frame_times.stats().mean,
+                state.block_rate.per_second(std::time::Instant::now()),
+            );
+            text_rects(&text, display_scale)
+        } else {
+            Vec::new()
+        };
+        for marker in &self.markers {
+            hud.extend(marker_rects(self.image_to_window(*marker), display_scale));
+        }
+        let wipe_x = state.wipe_x;
+        let frame = Frame {
+            texture,
+            wipe: comparison.map(|comparison| (comparison, wipe_x)),
+            view: *self.view.lock(),
+            background: background.as_ref(),
+            overlay: &overlay,
+            hud: &hud,
+            display_scale,
+            img: &self.img,
+        };
+        redraw(&mut state.canvas, &frame, thumbnail, post_draw)?;
+        let now = std::time::Instant::now();
+        let mut history = self.history.lock();
+        if history.selected_frame().is_none() && history.due(now) {
+            history.push(&self.img.lock(), now);
+        }
+        drop(history);
+        if state.hidden {
+            state.canvas.window_mut().show();
+            state.hidden = false;
+        }
+        Ok(())
+    }
+
+    /// Returns how the image is converted for the textures with the current window state.
+    fn upload_settings(&self, state: &WindowState) -> UploadSettings {
+        UploadSettings {
+            transform: state.transform,
+            premultiplied: self.premultiplied_alpha,
+            color_key: self.color_key,
+        }
+    }
+
+    /// Remembers the window geometry and destroys the window.
+    fn close_window(&self, state: WindowState) {
+        if !state.fullscreen {
+            let (x, y) = state.canvas.window().position();
+            let (width, height) = state.canvas.window().size();
+            *self.geometry.lock() = Some(WindowGeometry {
+                x,
+                y,
+                width,
+                height,
+            });
+        }
+
+        if self.print_frame_stats {
+            eprintln!("Frame stats: {:?}", self.frame_stats());
+        }
+    }
+
+    /// Resets zoom and pan, so that the whole image is visible.
+    /// While the window is running, the image can be zoomed with the mouse wheel, panned by
+    /// dragging with the middle button and reset with the Home key.
+    pub fn reset_view(&self) {
+        *self.view.lock() = View::default();
+    }
+
+    /// Enables fading in of newly arrived blocks instead of showing them at once.
+    /// While some blocks are fading, the window is redrawn periodically instead of only
+    /// on events.
+    pub fn set_reveal_animation(&mut self, reveal_animation: bool) {
+        self.reveal_animation = reveal_animation;
+    }
+
+    /// Shows the progress set by `set_progress_source` in the window title, as a percentage.
+    /// The title is updated when the window handles events (e.g. written blocks), at most a few
+    /// times per second.
+    pub fn set_progress_in_title(&mut self, progress_in_title: bool) {
+        self.progress_in_title = progress_in_title;
+    }
+
+    /// Sets the counter of processed items (e.g. `parallel_for_each::Settings::progress_counter`)
+    /// and the total number of items, for `set_progress_in_title`.
+    /// Without a display, `console_progress::ConsoleProgress` shows the same progress on the
+    /// console.
+    pub fn set_progress_source(
+        &mut self,
+        counter: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        total: usize,
+    ) {
+        self.progress = Some((counter, total));
+    }
+
+    /// Switches the scaling filter automatically with zoom: nearest neighbor from the given zoom
+    /// level up (to see individual pixels), linear below it (to avoid aliasing).
+    /// The zoom is relative to the image fitted to the window. None keeps the scale quality hint
+    /// as set by the application.
+    /// SDL only reads the filter when a texture is created, so the textures are recreated and
+    /// the whole image uploaded again when the filter changes. The SDL hint is changed only
+    /// while the textures are created, other textures in the process are not affected.
+    pub fn set_auto_scale_quality(&mut self, threshold: Option<f32>) {
+        self.auto_scale_quality = threshold;
+    }
+
+    /// Opens the window hidden and shows it only after the first frame with content (a block or
+    /// the end of the render) is presented, so that an empty window doesn't flash on screen.
+    /// Takes effect when the window is opened.
+    pub fn set_start_hidden(&mut self, start_hidden: bool) {
+        self.start_hidden = start_hidden;
+    }
+
+    /// Shows an animated spinner in the middle of the window until the first block arrives
+    /// (or the render finishes without any). Takes effect when the window is opened.
+    pub fn set_startup_spinner(&mut self, startup_spinner: bool) {
+        self.startup_spinner = startup_spinner;
+    }
+
+    /// Returns true if the startup spinner is shown and should be animated.
+    fn spinner_animating(&self, state: &WindowState) -> bool {
+        state.spinner_since.is_some() && !state.hidden
+    }
+
+    /// Shows the benchmark overlay in the top left corner when the window opens: duration of
+    /// the last frame, mean frame duration (both in ms) and the number of blocks received
+    /// during the last second. It is only drawn by the window, the render is not affected.
+    /// The overlay can be toggled with F3 while the window is running.
+    pub fn set_benchmark_overlay(&mut self, show: bool) {
+        self.benchmark_overlay = show;
+    }
+
+    /// Returns how often the window has to be redrawn for the startup spinner and the benchmark
+    /// overlay, None if neither is shown.
+    fn animation_interval(&self, state: &WindowState) -> Option<std::time::Duration> {
+        let spinner = if self.spinner_animating(state) {
+            Some(SPINNER_FRAME_INTERVAL)
+        } else {
+            None
+        };
+        let benchmark = if state.show_benchmark_overlay && !state.hidden {
+            Some(BENCHMARK_FRAME_INTERVAL)
+        } else {
+            None
+        };
+        spinner.into_iter().chain(benchmark).min()
+    }
+
+    /// Returns true if the window should be redrawn for the spinner or the benchmark overlay.
+    fn animation_due(&self, state: &WindowState) -> bool {
+        self.animation_interval(state)
+            .is_some_and(|interval| state.animation_frame.elapsed() >= interval)
+    }
+
+    /// Selects when updated frames are presented, `PresentMode::FrameComplete` avoids tearing
+    /// and limits redraws to the display refresh rate when blocks arrive quickly.
+    /// Takes effect when the window is opened.
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) {
+        self.present_mode = present_mode;
+    }
+
+    /// Enables a debugging overlay that tints every received block by the number of samples it
+    /// got, from blue (fewest) to red (most), relative to the other blocks.
+    /// The overlay can be toggled with the S key while the window is running.
+    pub fn set_sample_overlay(&mut self, sample_count: Option<SampleCountFn>) {
+        self.sample_overlay = sample_count;
+    }
+
+    /// Sets the exposure and gamma used for displaying the image.
+    /// While the window is running, they can be adjusted with + / - (exposure) and [ / ] (gamma),
+    /// the current values are shown in the title.
+    pub fn set_display_transform(&mut self, transform: DisplayTransform) {
+        self.display_transform = transform;
+    }
+
+    /// Sets the brightness offset of the display transform, 0 keeps the image as it is.
+    /// While the window is running, it can be adjusted with , and . keys.
+    pub fn set_brightness(&mut self, brightness: f32) {
+        self.display_transform.brightness = brightness;
+    }
+
+    /// Sets the contrast of the display transform (scaling around mid gray), 1 keeps the image
+    /// as it is. While the window is running, it can be adjusted with ; and ' keys.
+    pub fn set_contrast(&mut self, contrast: f32) {
+        self.display_transform.contrast = contrast;
+    }
+
+    /// Sets a color that is shown as fully transparent, for images from pipelines that mark
+    /// transparency with a magic color (e.g. magenta). Only the display is affected, the image
+    /// buffer and saved files keep the key color.
+    pub fn set_color_key(&mut self, color_key: Option<image::Rgb<u8>>) {
+        self.color_key = color_key;
+    }
+
+    /// Declares that the written image uses premultiplied alpha.
+    /// SDL2 doesn't expose custom blend modes, so the image is converted to straight alpha when
+    /// it is uploaded to the texture and blended normally. The image buffer (and what gets
+    /// saved) stays premultiplied.
+    pub fn set_premultiplied_alpha(&mut self, premultiplied: bool) {
+        self.premultiplied_alpha = premultiplied;
+    }
+
+    /// Hints that the rendered image is fully opaque.
+    /// The image is then drawn without blending and without the checkerboard behind it, which
+    /// makes redraws cheaper. Transparent parts of the image are shown over black.
+    pub fn set_opaque(&mut self, opaque: bool) {
+        self.opaque = opaque;
+    }
+
+    /// Creates a handle that can close the running window from a different thread.
+    pub fn make_closer(&self) -> WindowCloser {
+        WindowCloser {
+            event_sender: self.event.event_sender(),
+        }
+    }
+
+    /// Opens a window, renders the blocks into it in parallel and shows the progress until the
+    /// window is closed. Returns the window, e.g. for saving the image.
+    /// Must be called from the main thread, see `render_blocks`.
+    pub fn preview_render<It, F>(
+        title: &str,
+        width: u32,
+        height: u32,
+        blocks: It,
+        render_block: F,
+        worker_count: parallel_for_each::WorkerCount,
+    ) -> util::SimpleResult<ImageWindow>
+    where
+        It: Iterator<Item = ScreenBlock> + Send,
+        F: Fn(ScreenBlock) -> image::RgbaImage + Sync,
+    {
+        let window = ImageWindow::new(title, width, height)?;
+        window.render_blocks(blocks, render_block, worker_count)?;
+        Ok(window)
+    }
+
+    /// Renders the blocks in worker threads and writes them into the window, while running
+    /// the event loop on the calling thread (SDL only handles events on the main thread).
+    /// `render_block` returns the image of a single block.
+    /// Returns when the window is closed, remaining blocks are not rendered then.
+    pub fn render_blocks<It, F>(
+        &self,
+        blocks: It,
+        render_block: F,
+        worker_count: parallel_for_each::WorkerCount,
+    ) -> util::SimpleResult
+    where
+        It: Iterator<Item = ScreenBlock> + Send,
+        F: Fn(ScreenBlock) -> image::RgbaImage + Sync,
+    {
+        use image_buffer::{ImageBuffer, ImageBufferWriter};
+
+        let writer = self.make_writer_with_settings(WriterSettings::default());
+        // The render is finished only if the iterator ran out and every block got written
+        let taken = std::sync::atomic::AtomicUsize::new(0);
+        let written = std::sync::atomic::AtomicUsize::new(0);
+        let exhausted = std::sync::atomic::AtomicBool::new(false);
+        let blocks = blocks
+            .inspect(|_| {
+                taken.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            })
+            .chain(std::iter::from_fn(|| {
+                exhausted.store(true, std::sync::atomic::Ordering::Relaxed);
+                None
+            }));
+        parallel_for_each::parallel_for_each(
+            blocks,
+            |_worker_id| -> Result<_, util::NoError> { Ok(()) },
+            |_state, block| -> util::SimpleResult {
+                writer.write(block, &render_block(block))?;
+                written.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Ok(())
+            },
+            || -> util::SimpleResult<_> {
+                self.run()?;
+                Ok(parallel_for_each::Continue::Stop)
+            },
+            || {
+                if exhausted.load(std::sync::atomic::Ordering::Relaxed)
+                    && written.load(std::sync::atomic::Ordering::Relaxed)
+                        == taken.load(std::sync::atomic::Ordering::Relaxed)
+                {
+                    // The image is complete even if the notification fails
+                    let _ = writer.finished();
+                }
+            },
+            worker_count,
+        )?;
+        Ok(())
+    }
+
+    /// Sets a callback that is called every time the window is redrawn, after the image is drawn
+    /// and before the frame is presented, for drawing custom overlays.
+    /// The canvas uses logical coordinates, same as the display size of the image, regardless
+    /// of the actual window size.
+    ///
+    /// ```ignore
+    /// let mut frame = 0u32;
+    /// window.set_post_draw(Some(Box::new(move |canvas| {
+    ///     // Frame counter as a row of small bars, wrapping every 32 frames
+    ///     frame += 1;
+    ///     canvas.set_draw_color(sdl2::pixels::Color::RGB(255, 0, 0));
+    ///     for i in 0..(frame % 32) {
+    ///         let _ = canvas.fill_rect(sdl2::rect::Rect::new(4 + 6 * i as i32, 4, 4, 8));
+    ///     }
+    /// })));
+    /// ```
+    pub fn set_post_draw(&mut self, hook: Option<PostDrawHook>) {
+        self.post_draw = lock::Mutex::new(hook);
+    }
+
+    /// Sets a callback that receives all events that the window itself doesn't consume
+    /// (e.g. controller, drag and drop or custom user events), so that they are not lost when the
+    /// window runs the event loop of a larger application.
+    pub fn set_on_unhandled(&mut self, hook: Option<UnhandledEventHook>) {
+        self.on_unhandled = lock::Mutex::new(hook);
+    }
+
+    /// Sets a callback that gets every block update in the order the window receives them,
+    /// for tracing misplaced or missing blocks. This shows what actually arrived through
+    /// the event queue, not what the writers sent.
+    pub fn set_on_block_update(&mut self, hook: Option<BlockUpdateHook>) {
+        self.on_block_update = lock::Mutex::new(hook);
+    }
+
+    /// Sets a callback for left clicks on the image, called with the clicked pixel in the
+    /// coordinates set by `set_coordinate_origin`. Clicks outside of the image are ignored.
+    /// While a comparison image is shown, left clicks move the wipe line instead.
+    pub fn set_on_click(&mut self, hook: Option<ClickHook>) {
+        self.on_click = lock::Mutex::new(hook);
+    }
+
+    /// Marks image pixels (in the coordinates set by `set_coordinate_origin`) with small crosses
+    /// drawn over the image. The crosses keep their size when zooming.
+    pub fn set_markers(&mut self, markers: Vec<ScreenPoint>) {
+        self.markers = markers;
+    }
+
+    /// Sets a callback that runs when the window had no events for the given interval
+    /// (and then again after each further interval), for periodic work like autosaves.
+    /// Unlike the post draw hook it runs even when nothing is redrawn.
+    /// Returning `Continue::Stop` closes the window.
+    pub fn set_idle_callback(
+        &mut self,
+        callback: Option<IdleCallback>,
+        interval: std::time::Duration,
+    ) {
+        self.idle_callback = lock::Mutex::new(callback);
+        self.idle_interval = interval;
+    }
+
+    /// Returns how long to wait for events before the idle callback is due, None if there is
+    /// no idle callback.
+    fn idle_timeout(
+        &self,
+        state: &WindowState,
+        idle_callback: &Option<IdleCallback>,
+    ) -> Option<std::time::Duration> {
+        idle_callback.as_ref()?;
+        Some(
+            self.idle_interval
+                .checked_sub(state.idle_since.elapsed())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Runs the idle callback if there were no events for the idle interval.
+    fn run_idle(
+        &self,
+        state: &mut WindowState,
+        idle_callback: &mut Option<IdleCallback>,
+    ) -> parallel_for_each::Continue {
+        match idle_callback {
+            Some(callback) if state.idle_since.elapsed() >= self.idle_interval => {
+                state.idle_since = std::time::Instant::now();
+                callback()
+            }
+            _ => parallel_for_each::Continue::Continue,
+        }
+    }
+
+    /// Replaces the whole image, bypassing blocks and writers.
+    /// Useful for showing an already finished image. The image must have the same size as the
+    /// buffer. The texture is updated and redrawn by `run` (on its next event, or when it starts).
+    pub fn set_image(&mut self, img: image::RgbaImage) -> util::SimpleResult {
+        replace_image(&self.img, self.img16.as_ref(), self.size, img)?;
+        if self.accumulation.is_some() {
+            self.set_accumulate(true);
+        }
+        self.event
+            .push_custom_event(ScreenBlock::from_size(self.size))?;
+
+        Ok(())
+    }
+
+    /// Uploads the whole image buffer to the window again and redraws it, with the current
+    /// display settings (`set_display_transform`, `set_brightness`, `set_contrast`), e.g. after
+    /// changing them while the window is pumped. This replaces the adjustments made from the
+    /// keyboard. Takes effect on the next call of `pump` (or event handled by `run`).
+    pub fn invalidate(&mut self) -> util::SimpleResult {
+        if let Some(window) = self.pumped.get_mut() {
+            window.state.transform = self.display_transform;
+        }
+        self.event.push_custom_event(InvalidateRequest)?;
+        Ok(())
+    }
+
+    /// Sets a second image to compare with the buffer, or removes it.
+    /// The window then shows the buffer left of a vertical wipe line and the comparison image
+    /// right of it, the line is moved by dragging with the left mouse button.
+    /// The image must have the same size as the buffer. It is picked up when the window opens.
+    pub fn set_comparison_image(&mut self, img: Option<image::RgbaImage>) -> util::SimpleResult {
+        if let Some(img) = &img {
+            if img.dimensions() != (self.size.width, self.size.height) {
+                return Err(format!(
+                    "Comparison image size {}x{} doesn't match the window buffer size {}x{}",
+                    img.width(),
+                    img.height(),
+                    self.size.width,
+                    self.size.height
+                )
+                .into());
+            }
+        }
+        self.comparison = img;
+        Ok(())
+    }
+
+    /// Returns a copy of the currently displayed image.
+    pub fn snapshot(&self) -> image::RgbaImage {
+        self.img.lock().clone()
+    }
+
+    /// Returns a copy of a single block of the currently displayed image, e.g. for verifying
+    /// rendered blocks in tests without copying the whole image.
+    /// Fails if the block is empty or doesn't fit into the image.
+    pub fn read_block(&self, block: ScreenBlock) -> util::SimpleResult<image::RgbaImage> {
+        check_block_fits("Block", block, self.size)?;
+        Ok(self
+            .img
+            .lock()
+            .view(block.min.x, block.min.y, block.width(), block.height())
+            .to_image())
+    }
+
+    /// Saves a debugging overview of the image, where every block of a grid of
+    /// `block_size` × `block_size` blocks is shrunk into a `tile` × `tile` tile of a sheet.
+    /// Blocks at the right and bottom edges that are smaller are stretched over the full tile.
+    pub fn export_block_sheet<P: AsRef<std::path::Path>>(
+        &self,
+        block_size: u32,
+        tile: u32,
+        path: P,
+    ) -> util::SimpleResult {
+        if block_size == 0 || tile == 0 {
+            return Err("Block size and tile size must not be zero".into());
+        }
+        block_sheet(&self.img.lock(), block_size, tile)?.save(path)?;
+        Ok(())
+    }
+
+    /// Enables or disables keeping a full precision copy of blocks written through
+    /// `make_writer_u16`, for use by `save_16bit`.
+    pub fn set_keep_16bit(&mut self, keep: bool) {
+        self.img16 = if keep {
+            Some(lock::Mutex::new(Rgba16Image::new(
+                self.size.width,
+                self.size.height,
+            )))
+        } else {
+            None
+        };
+    }
+
+    /// Enables or disables the float accumulation buffer, which averages all samples written
+    /// through `make_writer_f32` at full precision, for use by `save_exr`.
+    /// Enabling it again starts a new accumulation.
+    pub fn set_accumulate(&mut self, accumulate: bool) {
+        self.accumulation = if accumulate {
+            Some(lock::Mutex::new(Accumulation::new(self.size)))
+        } else {
+            None
+        };
+    }
+
+    /// Sets the settings of writers created by `make_writer`, `make_writer_with_viewport` and
+    /// `make_writer_u16`, e.g. `BlendMode::Over` to composite the rendered blocks over the
+    /// current image.
+    pub fn set_writer_settings(&mut self, settings: WriterSettings) {
+        self.writer_settings = settings;
+    }
+
+    /// Creates a writer with its own settings.
+    /// Any number of writers can be used at the same time, they all write to the same image.
+    pub fn make_writer_with_settings<'a>(&'a self, settings: WriterSettings) -> Writer<'a> {
+        Writer {
+            event_sender: self.event.event_sender(),
+            img: &self.img,
+            coalescer: settings
+                .coalesce_interval
+                .map(|interval| lock::Mutex::new(Coalescer::new(interval))),
+            blend_mode: settings.blend_mode,
+            out_of_bounds: settings.out_of_bounds,
+            viewport: ScreenBlock::from_size(self.size),
+        }
+    }
+
+    /// Creates a writer that writes into a sub-rectangle of the image, e.g. one cell of an atlas
+    /// shared by several renderers. Blocks written to it are relative to the viewport origin.
+    /// Fails if the viewport doesn't fit into the image.
+    pub fn make_writer_with_viewport<'a>(
+        &'a self,
+        viewport: ScreenBlock,
+    ) -> util::SimpleResult<Writer<'a>> {
+        check_block_fits("Viewport", viewport, self.size)?;
+
+        let mut writer = self.make_writer_with_settings(self.writer_settings);
+        writer.viewport = viewport;
+        Ok(writer)
+    }
+
+    /// Creates a writer that accepts 16 bit per channel blocks, using the settings set by
+    /// `set_writer_settings`.
+    /// The blocks are converted to 8 bits for display, optionally with ordered dithering.
+    pub fn make_writer_u16<'a>(&'a self, dither: bool) -> WriterU16<'a> {
+        WriterU16 {
+            writer: self.make_writer_with_settings(self.writer_settings),
+            img16: self.img16.as_ref(),
+            dither,
+        }
+    }
+
+    /// Creates a writer that accepts linear float blocks.
+    /// With accumulation enabled by `set_accumulate`, every written block is added to the
+    /// accumulation buffer and the window shows the average of all samples of each pixel.
+    /// Otherwise each block replaces the previous contents.
+    pub fn make_writer_f32<'a>(&'a self) -> WriterF32<'a> {
+        WriterF32 {
+            event_sender: self.event.event_sender(),
+            img: &self.img,
+            accumulation: self.accumulation.as_ref(),
+        }
+    }
+
+    /// Sets the ICC color profile embedded into PNG files written by `save`. Without a profile
+    /// the files are tagged as sRGB.
+    pub fn set_icc_profile(&mut self, icc_profile: Option<Vec<u8>>) {
+        self.icc_profile = icc_profile;
+    }
+
+    /// Saves the full precision copy of the image as a 16 bit PNG.
+    /// Only works if enabled by `set_keep_16bit` before writing.
+    pub fn save_16bit(&self, path: &std::path::Path) -> util::SimpleResult {
+        match &self.img16 {
+            Some(img16) => {
+                img16.lock().save(path)?;
+                Ok(())
+            }
+            None => Err("16 bit buffer is not enabled".into()),
+        }
+    }
+
+    /// Installs a SIGINT and SIGTERM handler that writes the current image to a binary PPM
+    /// file and exits the process, so that a partial render survives Ctrl-C.
+    /// The handler itself only sets a flag, the image is written from a dedicated thread that
+    /// checks it periodically. The file is written to a temporary file next to `path` and
+    /// renamed, so an existing dump is never left half written.
+    ///
+    /// Limitations: the alpha channel is dropped (PPM is RGB only), the process exits with
+    /// code 130 regardless of which signal arrived, signals arriving while a worker holds the
+    /// image lock for a long time delay the exit, and SIGKILL (or a crash) can't be handled.
+    /// The handlers are removed when the returned guard is dropped.
+    #[cfg(feature = "signal")]
+    pub fn dump_on_signal<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> util::SimpleResult<SignalDump> {
+        let flag = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let mut signal_ids = Vec::new();
+        for signal in &[signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            signal_ids.push(signal_hook::flag::register(*signal, flag.clone())?);
+        }
+        let mut dump = SignalDump::start(self.img.clone(), path.as_ref().to_owned(), flag, true);
+        dump.signal_ids = signal_ids;
+        Ok(dump)
+    }
+
+    /// Saves the image as OpenEXR with linear 32 bit float RGBA channels.
+    /// Uses the accumulation buffer if enabled by `set_accumulate`, so values outside of the
+    /// 0-1 range are preserved. Without it uses the full precision copy if enabled by
+    /// `set_keep_16bit`, and the 8 bit display image otherwise.
+    #[cfg(feature = "exr")]
+    pub fn save_exr<P: AsRef<std::path::Path>>(&self, path: P) -> util::SimpleResult {
+        let path = path.as_ref();
+        if let Some(accumulation) = &self.accumulation {
+            let accumulation = accumulation.lock();
+            let img = self.img.lock();
+            return write_exr(path, self.size, |x, y| accumulation.pixel(&img, x, y));
+        }
+        match &self.img16 {
+            Some(img16) => {
+                let img16 = img16.lock();
+                write_exr(path, self.size, |x, y| {
+                    let pixel = img16.get_pixel(x, y);
+                    [0, 1, 2, 3].map(|i| pixel[i] as f32 / u16::MAX as f32)
+                })
+            }
+            None => {
+                let img = self.img.lock();
+                write_exr(path, self.size, |x, y| {
+                    let pixel = img.get_pixel(x, y);
+                    [0, 1, 2, 3].map(|i| pixel[i] as f32 / u8::MAX as f32)
+                })
+            }
+        }
+    }
+}
+
+/// Writes an 8 bit RGBA PNG file with a color profile chunk: iCCP with the given ICC profile,
+/// or sRGB (with perceptual rendering intent) if there is none.
+fn write_png(
+    path: &std::path::Path,
+    img: &image::RgbaImage,
+    icc_profile: Option<&[u8]>,
+) -> util::SimpleResult {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, img.width(), img.height());
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    // Color space chunks must come before the image data
+    match icc_profile {
+        Some(icc_profile) => writer.write_chunk(*b"iCCP", &iccp_chunk_data(icc_profile))?,
+        None => writer.write_chunk(*b"sRGB", &[0])?,
+    }
+    writer.write_image_data(img.as_raw())?;
+    Ok(())
+}
+
+/// Contents of a PNG iCCP chunk: profile name, compression method (zlib) and the compressed
+/// profile.
+fn iccp_chunk_data(icc_profile: &[u8]) -> Vec<u8> {
+    let mut data = b"ICC Profile\0\0".to_vec();
+    data.extend(deflate::deflate_bytes_zlib(icc_profile));
+    data
+}
+
+/// Writes an OpenEXR file with f32 RGBA pixels given by a function.
+#[cfg(feature = "exr")]
+fn write_exr(
+    path: &std::path::Path,
+    size: ScreenSize,
+    pixel: impl Fn(u32, u32) -> [f32; 4] + Sync,
+) -> util::SimpleResult {
+    exr::prelude::write_rgba_file(path, size.width as usize, size.height as usize, |x, y| {
+        let [r, g, b, a] = pixel(x as u32, y as u32);
+        (r, g, b, a)
+    })?;
+    Ok(())
+}
+
+impl image_buffer::ImageBuffer for ImageWindow {
+    /// Runs SDL event loop and handles the window.
+    /// Only exits when the window is closed.
+    fn run(&self) -> util::SimpleResult {
+        let mut state = self.open_window()?;
+        let texture_creator = state.canvas.texture_creator();
+        let (mut texture, mut thumbnail, mut comparison) =
+            self.create_textures(&texture_creator, &state)?;
+
+        let mut post_draw = self.post_draw.lock();
+        let mut on_unhandled = self.on_unhandled.lock();
+        let mut idle_callback = self.idle_callback.lock();
+        let mut events = self.context.event_pump()?;
+        let mut reveals: Vec<(ScreenBlock, std::time::Instant)> = Vec::new();
+        let mut last_reveal_frame = std::time::Instant::now();
+
+        loop {
+            if !reveals.is_empty() && last_reveal_frame.elapsed() >= REVEAL_FRAME_INTERVAL {
+                let frame_start = std::time::Instant::now();
+                {
+                    let img = self.img.lock();
+                    for (block, arrival) in &reveals {
+                        let opacity = reveal_opacity(frame_start - *arrival);
+                        update_texture_faded(
+                            &img,
+                            &mut texture,
+                            *block,
+                            opacity,
+                            self.upload_settings(&state),
+                        )?;
+                    }
+                }
+                reveals.retain(|(_, arrival)| frame_start - *arrival < REVEAL_DURATION);
+                self.draw(
+                    &mut state,
+                    &texture,
+                    comparison.as_ref(),
+                    &mut thumbnail,
+                    &mut post_draw,
+                )?;
+                self.frame_times.lock().record(frame_start.elapsed());
+                last_reveal_frame = frame_start;
+            }
+            if self.animation_due(&state) {
+                let frame_start = std::time::Instant::now();
+                self.draw(
+                    &mut state,
+                    &texture,
+                    comparison.as_ref(),
+                    &mut thumbnail,
+                    &mut post_draw,
+                )?;
+                self.frame_times.lock().record(frame_start.elapsed());
+                state.animation_frame = frame_start;
+            }
+
+            let reveal_timeout = if reveals.is_empty() {
+                None
+            } else {
+                Some(REVEAL_FRAME_INTERVAL)
+            };
+            let timeout = [
+                reveal_timeout,
+                self.animation_interval(&state),
+                self.idle_timeout(&state, &idle_callback),
+            ]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+            let event = match timeout {
+                None => events.wait_event(),
+                Some(timeout) => {
+                    // Rounded up, so that the idle callback isn't polled before it is due
+                    let timeout_ms = timeout.as_micros().div_ceil(1000);
+                    match events.wait_event_timeout(timeout_ms as u32) {
+                        Some(event) => event,
+                        None => match self.run_idle(&mut state, &mut idle_callback) {
+                            parallel_for_each::Continue::Continue => continue,
+                            parallel_for_each::Continue::Stop => break,
+                        },
+                    }
+                }
+            };
+            state.idle_since = std::time::Instant::now();
+
+            let frame_start = std::time::Instant::now();
+            let mut response = self.handle_event(&mut state, &event, &mut on_unhandled)?;
+            if self.present_mode == PresentMode::FrameComplete {
+                // Everything that has arrived until now goes into a single frame
+                for event in events.poll_iter() {
+                    response =
+                        response.merge(self.handle_event(&mut state, &event, &mut on_unhandled)?);
+                    if response == EventResponse::Close {
+                        break;
+                    }
+                }
+            }
+            self.update_progress_title(&mut state)?;
+            match response {
+                EventResponse::Nothing => continue,
+                EventResponse::Close => break,
+                EventResponse::Redraw => {}
+                EventResponse::UpdateAll => {
+                    self.upload_shown_image(&mut texture, &state)?;
+                    if self.browsing_history() {
+                        // Fading in the live blocks would overwrite the historical frame
+                        reveals.clear();
+                    }
+                    if let (Some(img), Some(comparison)) = (&self.comparison, &mut comparison) {
+                        update_texture(
+                            img,
+                            comparison,
+                            self.size.into(),
+                            self.upload_settings(&state),
+                        )?;
+                    }
+                }
+                EventResponse::RecreateTextures => {
+                    self.recreate_texture(
+                        &texture_creator,
+                        &state,
+                        &mut texture,
+                        &mut thumbnail,
+                        &mut comparison,
+                    )?;
+                    // The new texture already contains the whole image
+                    reveals.clear();
+                }
+                EventResponse::UpdateBlock(block) => {
+                    if self.reveal_animation {
+                        update_texture_faded(
+                            &self.img.lock(),
+                            &mut texture,
+                            block,
+                            0.0,
+                            self.upload_settings(&state),
+                        )?;
+                        reveals.push((block, frame_start));
+                    } else {
+                        update_texture(
+                            &self.img.lock(),
+                            &mut texture,
+                            block,
+                            self.upload_settings(&state),
+                        )?;
+                    }
+                }
+            }
+            self.draw(
+                &mut state,
+                &texture,
+                comparison.as_ref(),
+                &mut thumbnail,
+                &mut post_draw,
+            )?;
+            self.frame_times.lock().record(frame_start.elapsed());
+        }
+
+        self.close_window(state);
+        Ok(())
+    }
+
+    /// Creates a writer function that can write data into the window from different thread.
+    /// If the 16 bit copy is enabled by `set_keep_16bit`, the writer also stores the blocks
+    /// there, so that they are included in `save_16bit`.
+    fn make_writer<'a>(&'a self) -> Box<dyn image_buffer::ImageBufferWriter + 'a> {
+        if self.img16.is_some() {
+            Box::new(self.make_writer_u16(false))
+        } else {
+            Box::new(self.make_writer_with_settings(self.writer_settings))
+        }
+    }
+
+    /// PNG files are tagged with the color profile set by `set_icc_profile` (sRGB by default).
+    fn save(&self, path: &std::path::Path) -> util::SimpleResult {
+        let is_png = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+        if is_png {
+            write_png(path, &self.img.lock(), self.icc_profile.as_deref())
+        } else {
+            self.img.lock().save(path)?;
+            Ok(())
+        }
+    }
+}
+
+/// Handle for closing the window, created by `ImageWindow::make_closer`.
+pub struct WindowCloser {
+    event_sender: sdl2::event::EventSender,
+}
+
+impl WindowCloser {
+    /// Asks the window to close, `run` returns after processing the already queued events.
+    pub fn close(&self) -> util::SimpleResult {
+        self.event_sender.push_custom_event(CloseRequest)?;
+        Ok(())
+    }
+}
+
+/// Guard of the signal handler installed by `ImageWindow::dump_on_signal`.
+pub struct SignalDump {
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    thread: Option<std::thread::JoinHandle<util::SimpleResult>>,
+    #[cfg(feature = "signal")]
+    signal_ids: Vec<signal_hook::SigId>,
+}
+
+impl SignalDump {
+    /// Starts the thread that writes the image once `triggered` is set, then exits the process
+    /// if `exit` is true.
+    fn start(
+        img: std::sync::Arc<lock::Mutex<image::RgbaImage>>,
+        path: std::path::PathBuf,
+        triggered: std::sync::Arc<std::sync::atomic::AtomicBool>,
+        exit: bool,
+    ) -> SignalDump {
+        use std::sync::atomic::Ordering;
+
+        let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || -> util::SimpleResult {
+                while !triggered.load(Ordering::SeqCst) {
+                    if stop.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                    std::thread::sleep(SIGNAL_POLL_INTERVAL);
+                }
+                let result = write_ppm_atomically(&img.lock(), &path);
+                if exit {
+                    if let Err(e) = &result {
+                        eprintln!("Failed to save image to {}: {}", path.display(), e);
+                    }
+                    std::process::exit(SIGNAL_EXIT_CODE);
+                }
+                result
+            })
+        };
+
+        SignalDump {
+            stop,
+            thread: Some(thread),
+            #[cfg(feature = "signal")]
+            signal_ids: Vec::new(),
+        }
+    }
+
+    /// Stops the thread, returns the result of writing the image if it was triggered.
+    fn stop(&mut self) -> util::SimpleResult {
+        self.stop.store(true, std::sync::atomic::Ordering::SeqCst);
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err("Signal dump thread panicked".into())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SignalDump {
+    fn drop(&mut self) {
+        #[cfg(feature = "signal")]
+        for id in self.signal_ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+        // There is nowhere to report a failed write from here
+        let _ = self.stop();
+    }
+}
+
+/// Writes the image as a binary PPM (without alpha) into a temporary file and renames it
+/// to `path`.
+fn write_ppm_atomically(img: &image::RgbaImage, path: &std::path::Path) -> util::SimpleResult {
+    use std::io::Write;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = std::path::PathBuf::from(tmp_path);
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    write!(file, "P6\n{} {}\n255\n", img.width(), img.height())?;
+    for pixel in img.pixels() {
+        file.write_all(&pixel.0[..3])?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// How a writer combines written blocks with the image already in the window.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Pixels of the block replace the image.
+    #[default]
+    Replace,
+    /// Pixels of the block are alpha composited over the image, in linear space.
+    Over,
+}
+
+/// What a writer does with blocks that don't fit into its viewport (e.g. stale blocks written
+/// after a resize).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutOfBoundsPolicy {
+    /// The write fails and nothing is written.
+    #[default]
+    Error,
+    /// Only the part of the block inside the viewport is written.
+    Clip,
+    /// The whole block is silently dropped.
+    Ignore,
+}
+
+/// Settings of a single writer, see `ImageWindow::make_writer_with_settings`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WriterSettings {
+    /// If set, the window is notified about written blocks at most once per interval, with
+    /// a single block covering all blocks written since the last notification.
+    /// None notifies about every block immediately.
+    pub coalesce_interval: Option<std::time::Duration>,
+    pub blend_mode: BlendMode,
+    pub out_of_bounds: OutOfBoundsPolicy,
+}
+
+pub struct Writer<'a> {
+    event_sender: sdl2::event::EventSender,
+    img: &'a lock::Mutex<image::RgbaImage>,
+    coalescer: Option<lock::Mutex<Coalescer>>,
+    blend_mode: BlendMode,
+    out_of_bounds: OutOfBoundsPolicy,
+    /// Part of the image that the written blocks are relative to.
+    viewport: ScreenBlock,
+}
+
+impl<'a> Writer<'a> {
+    /// Notifies the window about blocks that are waiting for the coalesce interval.
+    pub fn flush(&self) -> util::SimpleResult {
+        if let Some(block) = self.coalescer.as_ref().and_then(|c| c.lock().flush()) {
+            self.event_sender.push_custom_event(block)?;
+        }
+        Ok(())
+    }
+
+    /// Places a written block into the image according to the viewport and the
+    /// out-of-bounds policy, see `place_block`.
+    fn place(&self, block: ScreenBlock) -> util::SimpleResult<Option<(ScreenBlock, ScreenBlock)>> {
+        place_block(self.viewport, block, self.out_of_bounds)
+    }
+
+    /// Notifies the window about a block written to the image, possibly coalesced with other
+    /// blocks.
+    fn notify(&self, block: ScreenBlock) -> util::SimpleResult {
+        let notify = match &self.coalescer {
+            Some(coalescer) => coalescer.lock().add(block, std::time::Instant::now()),
+            None => Some(block),
+        };
+        if let Some(block) = notify {
+            self.event_sender.push_custom_event(block)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> image_buffer::ImageBufferWriter for Writer<'a> {
+    fn write(&self, block: ScreenBlock, block_buffer: &image::RgbaImage) -> util::SimpleResult {
+        debug_assert!(block.width() <= block_buffer.width());
+        debug_assert!(block.height() <= block_buffer.height());
+
+        let (placed, source) = match self.place(block)? {
+            Some(placement) => placement,
+            None => return Ok(()),
+        };
+        let clipped_buffer;
+        let block_buffer = if source == ScreenBlock::from_size(block.size()) {
+            block_buffer
+        } else {
+            clipped_buffer = block_buffer
+                .view(source.min.x, source.min.y, source.width(), source.height())
+                .to_image();
+            &clipped_buffer
+        };
+        let block = placed;
+        match self.blend_mode {
+            BlendMode::Replace => copy_block(&mut self.img.lock(), block, block_buffer)?,
+            BlendMode::Over => blend_block(&mut self.img.lock(), block, block_buffer)?,
+        }
+        self.notify(block)
+    }
+
+    fn finished(&self) -> util::SimpleResult {
+        self.flush()?;
+        self.event_sender.push_custom_event(RenderFinished)?;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Writer<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Merges blocks written in quick succession into a single block to notify the window about.
+struct Coalescer {
+    interval: std::time::Duration,
+    pending: Option<ScreenBlock>,
+    last_notify: Option<std::time::Instant>,
+}
+
+impl Coalescer {
+    fn new(interval: std::time::Duration) -> Coalescer {
+        Coalescer {
+            interval,
+            pending: None,
+            last_notify: None,
+        }
+    }
+
+    /// Adds a block, returns the block to notify about if the interval has elapsed.
+    fn add(&mut self, block: ScreenBlock, now: std::time::Instant) -> Option<ScreenBlock> {
+        self.pending = Some(match self.pending {
+            Some(pending) => pending.union(&block),
+            None => block,
+        });
+        match self.last_notify {
+            Some(last_notify) if now - last_notify < self.interval => None,
+            _ => {
+                self.last_notify = Some(now);
+                self.pending.take()
+            }
+        }
+    }
+
+    /// Returns the pending block, if any.
+    fn flush(&mut self) -> Option<ScreenBlock> {
+        self.pending.take()
+    }
+}
+
+/// Copies block buffer into the image at the position of the block.
+/// Blocks that span whole rows of the image are copied in one go, instead of pixel by pixel.
+fn copy_block(
+    img: &mut image::RgbaImage,
+    block: ScreenBlock,
+    block_buffer: &image::RgbaImage,
+) -> util::SimpleResult {
+    let full_rows = block.min.x == 0
+        && block.width() == img.width()
+        && block_buffer.dimensions() == (block.width(), block.height())
+        && block.max.y <= img.height();
+
+    if full_rows {
+        let row_bytes = img.width() as usize * std::mem::size_of::<PixelType>();
+        let start = block.min.y as usize * row_bytes;
+        let end = block.max.y as usize * row_bytes;
+        let raw: &mut [u8] = img;
+        raw[start..end].copy_from_slice(block_buffer);
+    } else {
+        img.copy_from(block_buffer, block.min.x, block.min.y)?;
+    }
+
+    Ok(())
+}
+
+/// Fails if the block (or viewport, as named by `what`) is empty or doesn't fit into an image
+/// of the given size.
+fn check_block_fits(what: &str, block: ScreenBlock, size: ScreenSize) -> util::SimpleResult {
+    if block.is_empty_or_negative() || !ScreenBlock::from_size(size).contains_box(&block) {
+        return Err(format!("{} {:?} doesn't fit into the image", what, block).into());
+    }
+    Ok(())
+}
+
+/// Translates a block relative to the viewport to image coordinates.
+/// Fails if the block doesn't fit into the viewport.
+fn viewport_to_image(viewport: ScreenBlock, block: ScreenBlock) -> util::SimpleResult<ScreenBlock> {
+    let translated = block.translate(viewport.min.to_vector());
+    if viewport.contains_box(&translated) {
+        Ok(translated)
+    } else {
+        Err(format!("Block {:?} doesn't fit into viewport {:?}", block, viewport).into())
+    }
+}
+
+/// Translates a block relative to the viewport to image coordinates, handling blocks that don't
+/// fit according to the policy.
+/// Returns the block in image coordinates and the part of the block buffer to write into it,
+/// or None if nothing should be written.
+fn place_block(
+    viewport: ScreenBlock,
+    block: ScreenBlock,
+    policy: OutOfBoundsPolicy,
+) -> util::SimpleResult<Option<(ScreenBlock, ScreenBlock)>> {
+    let source = ScreenBlock::from_size(block.size());
+    match (viewport_to_image(viewport, block), policy) {
+        (Ok(translated), _) => Ok(Some((translated, source))),
+        (Err(e), OutOfBoundsPolicy::Error) => Err(e),
+        (Err(_), OutOfBoundsPolicy::Ignore) => Ok(None),
+        (Err(_), OutOfBoundsPolicy::Clip) => {
+            let translated = block.translate(viewport.min.to_vector());
+            if !translated.intersects(&viewport) {
+                return Ok(None);
+            }
+            let clipped = translated.intersection(&viewport);
+            let source = ScreenBlock::new(
+                (clipped.min - translated.min).to_point(),
+                (clipped.max - translated.min).to_point(),
+            );
+            Ok(Some((clipped, source)))
+        }
+    }
+}
+
+/// Composites the block buffer over the image at the position of the block.
+/// Colors are blended in linear space, with straight (not premultiplied) alpha.
+/// Fails if the block doesn't fit into the image or the block buffer.
+/// Works with both 8 and 16 bit images.
+fn blend_block<P: image::Primitive + Into<f32> + 'static>(
+    img: &mut image::ImageBuffer<image::Rgba<P>, Vec<P>>,
+    block: ScreenBlock,
+    block_buffer: &image::ImageBuffer<image::Rgba<P>, Vec<P>>,
+) -> util::SimpleResult {
+    check_block_fits("Block", block, ScreenSize::new(img.width(), img.height()))?;
+    if block.width() > block_buffer.width() || block.height() > block_buffer.height() {
+        return Err(format!("Block buffer is smaller than block {:?}", block).into());
+    }
+
+    let max: f32 = P::max_value().into();
+    let from_unit = |c: f32| P::from((c.clamp(0.0, 1.0) * max).round()).unwrap();
+    let to_linear = |c: P| {
+        let c = c.into() / max;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let to_srgb = |l: f32| {
+        let c = if l <= 0.003_130_8 {
+            l * 12.92
+        } else {
+            1.055 * l.powf(1.0 / 2.4) - 0.055
+        };
+        from_unit(c)
+    };
+
+    for y in 0..block.height() {
+        for x in 0..block.width() {
+            let src = *block_buffer.get_pixel(x, y);
+            let dst = img.get_pixel_mut(block.min.x + x, block.min.y + y);
+            let src_alpha = src[3].into() / max;
+            let dst_alpha = dst[3].into() / max * (1.0 - src_alpha);
+            let alpha = src_alpha + dst_alpha;
+            if alpha <= 0.0 {
+                *dst = image::Rgba([P::zero(); 4]);
+                continue;
+            }
+            for i in 0..3 {
+                let linear = to_linear(src[i]) * src_alpha + to_linear(dst[i]) * dst_alpha;
+                dst[i] = to_srgb(linear / alpha);
+            }
+            dst[3] = from_unit(alpha);
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles the writer settings (coalescing, blend mode, out-of-bounds policy and viewport)
+/// the same way as `Writer`, which it uses for placing the blocks and notifying the window.
+pub struct WriterU16<'a> {
+    writer: Writer<'a>,
+    img16: Option<&'a lock::Mutex<Rgba16Image>>,
+    dither: bool,
+}
+
+impl<'a> WriterU16<'a> {
+    pub fn write_u16(&self, block: ScreenBlock, block_buffer: &Rgba16Image) -> util::SimpleResult {
+        debug_assert!(block.width() <= block_buffer.width());
+        debug_assert!(block.height() <= block_buffer.height());
+
+        let (placed, source) = match self.writer.place(block)? {
+            Some(placement) => placement,
+            None => return Ok(()),
+        };
+        let clipped_buffer;
+        let block_buffer = if source == ScreenBlock::from_size(block.size()) {
+            block_buffer
+        } else {
+            clipped_buffer = block_buffer
+                .view(source.min.x, source.min.y, source.width(), source.height())
+                .to_image();
+            &clipped_buffer
+        };
+        write_u16_block(
+            self.writer.img,
+            self.img16,
+            placed,
+            block_buffer,
+            self.dither,
+            self.writer.blend_mode,
+        )?;
+        self.writer.notify(placed)
+    }
+
+    /// Notifies the window about blocks that are waiting for the coalesce interval.
+    pub fn flush(&self) -> util::SimpleResult {
+        self.writer.flush()
+    }
+}
+
+/// Accepts 8 bit blocks too, so that the writer can be used wherever an `ImageBufferWriter` is
+/// expected. The blocks are widened exactly, so they also end up in the full precision image.
+impl<'a> image_buffer::ImageBufferWriter for WriterU16<'a> {
+    fn write(&self, block: ScreenBlock, block_buffer: &image::RgbaImage) -> util::SimpleResult {
+        self.write_u16(block, &widen_block(block_buffer))
+    }
+
+    fn finished(&self) -> util::SimpleResult {
+        self.writer.finished()
+    }
+}
+
+/// Replaces the whole image buffer (and the 16 bit buffer, if there is one) with `new`,
+/// which must have the buffer size.
+fn replace_image(
+    img: &lock::Mutex<image::RgbaImage>,
+    img16: Option<&lock::Mutex<Rgba16Image>>,
+    size: ScreenSize,
+    new: image::RgbaImage,
+) -> util::SimpleResult {
+    if new.dimensions() != (size.width, size.height) {
+        return Err(format!(
+            "Image size {}x{} doesn't match the window buffer size {}x{}",
+            new.width(),
+            new.height(),
+            size.width,
+            size.height
+        )
+        .into());
+    }
+
+    if let Some(img16) = img16 {
+        *img16.lock() = widen_block(&new);
+    }
+    *img.lock() = new;
+    Ok(())
+}
+
+/// Converts an 8 bit block buffer to 16 bits, mapping 255 to 65535.
+fn widen_block(block_buffer: &image::RgbaImage) -> Rgba16Image {
+    Rgba16Image::from_fn(block_buffer.width(), block_buffer.height(), |x, y| {
+        let pixel = block_buffer.get_pixel(x, y);
+        let widen = |c: u8| c as u16 * 257;
+        image::Rgba([
+            widen(pixel[0]),
+            widen(pixel[1]),
+            widen(pixel[2]),
+            widen(pixel[3]),
+        ])
+    })
+}
+
+pub struct WriterF32<'a> {
+    event_sender: sdl2::event::EventSender,
+    img: &'a lock::Mutex<image::RgbaImage>,
+    accumulation: Option<&'a lock::Mutex<Accumulation>>,
+}
+
+impl<'a> WriterF32<'a> {
+    pub fn write_f32(&self, block: ScreenBlock, block_buffer: &RgbaF32Image) -> util::SimpleResult {
+        let display = match self.accumulation {
+            Some(accumulation) => accumulation.lock().add(block, block_buffer)?,
+            None => {
+                if block.width() > block_buffer.width() || block.height() > block_buffer.height() {
+                    return Err(format!("Block buffer is smaller than block {:?}", block).into());
+                }
+                image::RgbaImage::from_fn(block.width(), block.height(), |x, y| {
+                    narrow_pixel(block_buffer.get_pixel(x, y).0)
+                })
+            }
+        };
+        self.img
+            .lock()
+            .copy_from(&display, block.min.x, block.min.y)?;
+        self.event_sender.push_custom_event(block)?;
+
+        Ok(())
+    }
+}
+
+/// Accepts 8 bit blocks too, so that the writer can be used wherever an `ImageBufferWriter` is
+/// expected. The blocks are converted to floats in the 0-1 range and written as samples.
+impl<'a> image_buffer::ImageBufferWriter for WriterF32<'a> {
+    fn write(&self, block: ScreenBlock, block_buffer: &image::RgbaImage) -> util::SimpleResult {
+        let block_buffer =
+            RgbaF32Image::from_fn(block_buffer.width(), block_buffer.height(), |x, y| {
+                image::Rgba(
+                    block_buffer
+                        .get_pixel(x, y)
+                        .0
+                        .map(|c| c as f32 / u8::MAX as f32),
+                )
+            });
+        self.write_f32(block, &block_buffer)
+    }
+
+    fn finished(&self) -> util::SimpleResult {
+        self.event_sender.push_custom_event(RenderFinished)?;
+        Ok(())
+    }
+}
+
+/// Running sums of the linear float samples written to each pixel, and their counts.
+struct Accumulation {
+    sum: RgbaF32Image,
+    counts: Vec<u32>,
+}
+
+impl Accumulation {
+    fn new(size: ScreenSize) -> Accumulation {
+        Accumulation {
+            sum: RgbaF32Image::new(size.width, size.height),
+            counts: vec![0; (size.width * size.height) as usize],
+        }
+    }
+
+    /// Adds a block of samples and returns the new averages of the block converted to 8 bits.
+    fn add(
+        &mut self,
+        block: ScreenBlock,
+        block_buffer: &RgbaF32Image,
+    ) -> util::SimpleResult<image::RgbaImage> {
+        check_block_fits(
+            "Block",
+            block,
+            ScreenSize::new(self.sum.width(), self.sum.height()),
+        )?;
+        if block.width() > block_buffer.width() || block.height() > block_buffer.height() {
+            return Err(format!("Block buffer is smaller than block {:?}", block).into());
+        }
+
+        for y in 0..block.height() {
+            for x in 0..block.width() {
+                let (image_x, image_y) = (block.min.x + x, block.min.y + y);
+                let sum = self.sum.get_pixel_mut(image_x, image_y);
+                for (sum, sample) in sum.0.iter_mut().zip(block_buffer.get_pixel(x, y).0.iter()) {
+                    *sum += sample;
+                }
+                self.counts[(image_y * self.sum.width() + image_x) as usize] += 1;
+            }
+        }
+
+        Ok(image::RgbaImage::from_fn(
+            block.width(),
+            block.height(),
+            |x, y| {
+                let (image_x, image_y) = (block.min.x + x, block.min.y + y);
+                narrow_pixel(self.average(image_x, image_y).unwrap())
+            },
+        ))
+    }
+
+    /// Average of the samples of a pixel, None if it didn't get any.
+    fn average(&self, x: u32, y: u32) -> Option<[f32; 4]> {
+        let count = self.counts[(y * self.sum.width() + x) as usize];
+        if count == 0 {
+            None
+        } else {
+            Some(self.sum.get_pixel(x, y).0.map(|c| c / count as f32))
+        }
+    }
+
+    /// Average of the samples of a pixel, or the 8 bit display image value converted to float
+    /// if it didn't get any samples (e.g. it was written by an 8 bit writer).
+    #[cfg_attr(not(feature = "exr"), allow(dead_code))]
+    fn pixel(&self, img: &image::RgbaImage, x: u32, y: u32) -> [f32; 4] {
+        self.average(x, y)
+            .unwrap_or_else(|| img.get_pixel(x, y).0.map(|c| c as f32 / u8::MAX as f32))
+    }
+}
+
+/// Converts a linear float pixel to 8 bits for display, clamping to the 0-1 range.
+fn narrow_pixel(pixel: [f32; 4]) -> image::Rgba<u8> {
+    image::Rgba(pixel.map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8))
+}
+
+/// Stores a 16 bit block to the 8 bit display image and to the full precision image, if any.
+fn write_u16_block(
+    img: &lock::Mutex<image::RgbaImage>,
+    img16: Option<&lock::Mutex<Rgba16Image>>,
+    block: ScreenBlock,
+    block_buffer: &Rgba16Image,
+    dither: bool,
+    blend_mode: BlendMode,
+) -> util::SimpleResult {
+    debug_assert!(block.width() <= block_buffer.width());
+    debug_assert!(block.height() <= block_buffer.height());
+
+    let display = downconvert_block(block, block_buffer, dither);
+    match blend_mode {
+        BlendMode::Replace => {
+            img.lock().copy_from(&display, block.min.x, block.min.y)?;
+            if let Some(img16) = img16 {
+                img16.lock().copy_from(
+                    &block_buffer.view(0, 0, block.width(), block.height()),
+                    block.min.x,
+                    block.min.y,
+                )?;
+            }
+        }
+        BlendMode::Over => {
+            blend_block(&mut img.lock(), block, &display)?;
+            if let Some(img16) = img16 {
+                blend_block(&mut img16.lock(), block, block_buffer)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts the part of a 16 bit block buffer covered by block to 8 bits.
+/// Dithering pattern is aligned to the image coordinates, so that it doesn't change between blocks.
+fn downconvert_block(
+    block: ScreenBlock,
+    block_buffer: &Rgba16Image,
+    dither: bool,
+) -> image::RgbaImage {
+    image::RgbaImage::from_fn(block.width(), block.height(), |x, y| {
+        let threshold = if dither {
+            let cell =
+                BAYER_4X4[((block.min.y + y) % 4) as usize][((block.min.x + x) % 4) as usize];
+            (cell as f64 + 0.5) / 16.0 - 0.5
+        } else {
+            0.0
+        };
+        let pixel = block_buffer.get_pixel(x, y);
+        let convert = |c: u16| {
+            (c as f64 * 255.0 / 65535.0 + threshold)
+                .round()
+                .clamp(0.0, 255.0) as u8
+        };
+        image::Rgba([
+            convert(pixel[0]),
+            convert(pixel[1]),
+            convert(pixel[2]),
+            convert(pixel[3]),
+        ])
+    })
+}
+
+/// Copies a block from the image to the texture (to the gpu).
+/// If the texture has different size than the image, the block is resampled.
+/// The display transform is applied to the uploaded block, premultiplied images are converted
+/// to straight alpha first.
+fn update_texture(
+    img: &image::RgbaImage,
+    texture: &mut sdl2::render::Texture,
+    block: ScreenBlock,
+    settings: UploadSettings,
+) -> util::SimpleResult {
+    let query = texture.query();
+    let texture_size = ScreenSize::new(query.width, query.height);
+    let (texture_block, mut source) = if texture_size == ScreenSize::from(img.dimensions()) {
+        let view = img.view(block.min.x, block.min.y, block.width(), block.height());
+        if settings.is_identity() {
+            return upload_block(texture, block, &view);
+        }
+        let mut source = view.to_image();
+        apply_color_key(&mut source, settings.color_key);
+        (block, source)
+    } else {
+        downsample_block(img, block, texture_size, settings.color_key)
+    };
+    if settings.premultiplied {
+        unpremultiply(&mut source);
+    }
+    apply_display_transform(&mut source, settings.transform);
+    upload_block(texture, texture_block, &source)
+}
+
+/// Converts premultiplied alpha to straight alpha.
+/// Fully transparent pixels become transparent black.
+fn unpremultiply(img: &mut image::RgbaImage) {
+    for pixel in img.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for channel in &mut pixel.0[..3] {
+            *channel = match alpha {
+                0 => 0,
+                _ => ((*channel as u32 * 255 + alpha / 2) / alpha).min(255) as u8,
+            };
+        }
+    }
+}
+
+/// How the image is converted when uploading it to a texture.
+#[derive(Copy, Clone, Debug, Default)]
+struct UploadSettings {
+    transform: DisplayTransform,
+    /// See `ImageWindow::set_premultiplied_alpha`.
+    premultiplied: bool,
+    /// See `ImageWindow::set_color_key`.
+    color_key: Option<image::Rgb<u8>>,
+}
+
+impl UploadSettings {
+    /// Returns true if the image can be uploaded unchanged.
+    fn is_identity(&self) -> bool {
+        self.transform.is_identity() && !self.premultiplied && self.color_key.is_none()
+    }
+}
+
+/// Makes all pixels with the key color fully transparent.
+fn apply_color_key(img: &mut image::RgbaImage, color_key: Option<image::Rgb<u8>>) {
+    if let Some(color_key) = color_key {
+        for pixel in img.pixels_mut() {
+            *pixel = color_keyed(*pixel, color_key);
+        }
+    }
+}
+
+/// Returns transparent black if the pixel has the key color, the pixel otherwise.
+fn color_keyed(pixel: image::Rgba<u8>, color_key: image::Rgb<u8>) -> image::Rgba<u8> {
+    if pixel.0[..3] == color_key.0 {
+        image::Rgba([0, 0, 0, 0])
+    } else {
+        pixel
+    }
+}
+
+/// Applies the display transform to color channels of all pixels.
+fn apply_display_transform(img: &mut image::RgbaImage, transform: DisplayTransform) {
+    if transform.is_identity() {
+        return;
+    }
+    let table = transform.lookup_table();
+    for pixel in img.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = table[*channel as usize];
+        }
+    }
+}
+
+/// Returns the window title, with the render state (finished or progress) and non-default
+/// display transform.
+fn window_title(
+    title: &str,
+    finished: bool,
+    transform: DisplayTransform,
+    progress: Option<u32>,
+) -> String {
+    let mut result = String::from(title);
+    if let Some(progress) = progress {
+        result.push_str(&format!(" {}%", progress));
+    }
+    if finished {
+        result.push_str(" (finished)");
+    }
+    if !transform.is_identity() {
+        result.push_str(&format!(
+            " [exposure {:+.1}, gamma {:.1}]",
+            transform.exposure, transform.gamma
+        ));
+        if transform.brightness != 0.0 || transform.contrast != 1.0 {
+            result.insert_str(
+                result.len() - 1,
+                &format!(
+                    ", brightness {:+.2}, contrast {:.1}",
+                    transform.brightness, transform.contrast
+                ),
+            );
+        }
+    }
+    result
+}
+
+/// Same as update_texture, but the alpha of the uploaded block is multiplied by opacity.
+fn update_texture_faded(
+    img: &image::RgbaImage,
+    texture: &mut sdl2::render::Texture,
+    block: ScreenBlock,
+    opacity: f32,
+    settings: UploadSettings,
+) -> util::SimpleResult {
+    if opacity >= 1.0 {
+        return update_texture(img, texture, block, settings);
+    }
+
+    let query = texture.query();
+    let texture_size = ScreenSize::new(query.width, query.height);
+    let (texture_block, mut source) = if texture_size == ScreenSize::from(img.dimensions()) {
+        let mut source = img
+            .view(block.min.x, block.min.y, block.width(), block.height())
+            .to_image();
+        apply_color_key(&mut source, settings.color_key);
+        (block, source)
+    } else {
+        downsample_block(img, block, texture_size, settings.color_key)
+    };
+    if settings.premultiplied {
+        unpremultiply(&mut source);
+    }
+    apply_display_transform(&mut source, settings.transform);
+    fade_alpha(&mut source, opacity);
+    upload_block(texture, texture_block, &source)
+}
+
+/// Returns opacity of a block that arrived the given time ago, with reveal animation.
+fn reveal_opacity(elapsed: std::time::Duration) -> f32 {
+    (elapsed.as_secs_f32() / REVEAL_DURATION.as_secs_f32()).min(1.0)
+}
+
+/// Multiplies alpha of all pixels by opacity.
+fn fade_alpha(img: &mut image::RgbaImage, opacity: f32) {
+    for pixel in img.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+    }
+}
+
+/// Copies the whole source image to the texture at the position of the block.
+fn upload_block<V: GenericImageView<Pixel = PixelType>>(
+    texture: &mut sdl2::render::Texture,
+    block: ScreenBlock,
+    source: &V,
+) -> util::SimpleResult {
+    let rect = sdl2::rect::Rect::new(
+        block.min.x as i32,
+        block.min.y as i32,
+        block.width(),
+        block.height(),
+    );
+
+    texture.with_lock(
+        Some(rect),
+        |texture_buffer: &mut [u8], pitch: usize| -> util::SimpleResult {
+            // Obtain view to the part of the texture that we are updating.
+            let mut texture_samples = image::flat::FlatSamples {
+                samples: texture_buffer,
+                layout: image::flat::SampleLayout {
+                    channels: 4,       // There is no place to get this value programatically
+                    channel_stride: 1, // There is no place to get this value programatically
+                    width: block.width(),
+                    width_stride: SDL_PIXEL_FORMAT.byte_size_per_pixel(),
+                    height: block.height(),
+                    height_stride: pitch,
+                },
+                color_hint: None,
+            };
+            let mut texture_view = texture_samples.as_view_mut::<PixelType>().unwrap();
+            texture_view.copy_from(source, 0, 0)?;
+            Ok(())
+        },
+    )??;
+
+    Ok(())
+}
+
+/// Maps logical window coordinates to image coordinates with the given origin.
+fn window_to_image(
+    x: i32,
+    y: i32,
+    display_size: ScreenSize,
+    image_size: ScreenSize,
+    origin: CoordinateOrigin,
+) -> Option<ScreenPoint> {
+    if x < 0 || y < 0 || x as u32 >= display_size.width || y as u32 >= display_size.height {
+        return None;
+    }
+
+    let image_x = (x as u64 * image_size.width as u64 / display_size.width as u64) as u32;
+    let image_y = (y as u64 * image_size.height as u64 / display_size.height as u64) as u32;
+    let image_y = match origin {
+        CoordinateOrigin::TopLeft => image_y,
+        CoordinateOrigin::BottomLeft => image_size.height - 1 - image_y,
+    };
+
+    Some(ScreenPoint::new(image_x, image_y))
+}
+
+/// Maps image coordinates with the given origin to logical window coordinates of the pixel
+/// center.
+fn image_to_window(
+    point: ScreenPoint,
+    display_size: ScreenSize,
+    image_size: ScreenSize,
+    origin: CoordinateOrigin,
+) -> (i32, i32) {
+    let image_y = match origin {
+        CoordinateOrigin::TopLeft => point.y,
+        CoordinateOrigin::BottomLeft => image_size.height - 1 - point.y,
+    };
+
+    let x = (2 * point.x as u64 + 1) * display_size.width as u64 / (2 * image_size.width as u64);
+    let y = (2 * image_y as u64 + 1) * display_size.height as u64 / (2 * image_size.height as u64);
+
+    (x as i32, y as i32)
+}
+
+/// Computes the dots of the startup spinner (in display coordinates, like the sample count
+/// overlay), a ring of dots around the center with a bright leading dot and fading tail.
+fn spinner_rects(
+    display_size: ScreenSize,
+    elapsed: std::time::Duration,
+) -> Vec<(sdl2::rect::Rect, sdl2::pixels::Color)> {
+    let radius = (cmp::min(display_size.width, display_size.height) / 12).max(4) as f64;
+    let dot_size = (radius / 3.0).ceil().max(2.0) as u32;
+    let turn = elapsed.as_secs_f64() / SPINNER_PERIOD.as_secs_f64();
+    let leading_dot = (turn.fract() * SPINNER_DOTS as f64) as u32;
+    (0..SPINNER_DOTS)
+        .map(|i| {
+            let angle = std::f64::consts::PI * 2.0 * i as f64 / SPINNER_DOTS as f64;
+            let x = display_size.width as f64 / 2.0 + radius * angle.sin();
+            let y = display_size.height as f64 / 2.0 - radius * angle.cos();
+            let rect = sdl2::rect::Rect::from_center((x as i32, y as i32), dot_size, dot_size);
+            let age = (leading_dot + SPINNER_DOTS - i) % SPINNER_DOTS;
+            let alpha = 255 - age * 255 / SPINNER_DOTS;
+            (rect, sdl2::pixels::Color::RGBA(255, 255, 255, alpha as u8))
+        })
+        .collect()
+}
+
+/// Computes the cross marking a pixel whose center is at the given logical window coordinates.
+fn marker_rects(
+    center: (i32, i32),
+    display_scale: f32,
+) -> Vec<(sdl2::rect::Rect, sdl2::pixels::Color)> {
+    let length = 2 * scale_metric(MARKER_RADIUS, display_scale) + 1;
+    vec![
+        (
+            sdl2::rect::Rect::from_center(center, length, 1),
+            MARKER_COLOR,
+        ),
+        (
+            sdl2::rect::Rect::from_center(center, 1, length),
+            MARKER_COLOR,
+        ),
+    ]
+}
+
+/// Formats the text of the benchmark overlay.
+fn benchmark_text(
+    last_frame: std::time::Duration,
+    mean_frame: std::time::Duration,
+    blocks_per_second: f64,
+) -> String {
+    format!(
+        "{:.1}MS {:.1}AVG {:.0}B/S",
+        last_frame.as_secs_f64() * 1000.0,
+        mean_frame.as_secs_f64() * 1000.0,
+        blocks_per_second
+    )
+}
+
+/// Computes the rectangles of the benchmark overlay (in logical window coordinates, not
+/// affected by zoom), a dark box in the top left corner with the text drawn in
+/// `BENCHMARK_FONT`, sized for the given display scale.
+fn text_rects(text: &str, display_scale: f32) -> Vec<(sdl2::rect::Rect, sdl2::pixels::Color)> {
+    let scale = scale_metric(BENCHMARK_FONT_SCALE, display_scale);
+    let margin = scale_metric(BENCHMARK_MARGIN, display_scale);
+    let advance = 4 * scale;
+    let width = text.chars().count() as u32 * advance + 2 * margin - scale;
+    let height = 5 * scale + 2 * margin;
+    let mut rects = vec![(
+        sdl2::rect::Rect::new(0, 0, width, height),
+        sdl2::pixels::Color::RGBA(0, 0, 0, 160),
+    )];
+    for (i, c) in text.chars().enumerate() {
+        let glyph = match BENCHMARK_FONT
+            .iter()
+            .find(|(glyph_char, _)| *glyph_char == c)
+        {
+            Some((_, glyph)) => glyph,
+            None => continue,
+        };
+        let left = margin + i as u32 * advance;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    let rect = sdl2::rect::Rect::new(
+                        (left + column * scale) as i32,
+                        (margin + row as u32 * scale) as i32,
+                        scale,
+                        scale,
+                    );
+                    rects.push((rect, sdl2::pixels::Color::RGB(255, 255, 255)));
+                }
+            }
+        }
+    }
+    rects
+}
+
+/// Computes the rectangles (in logical window coordinates) and colors of the sample count
+/// overlay for the given blocks.
+fn sample_overlay_rects(
+    blocks: &[ScreenBlock],
+    sample_count: &SampleCountFn,
+    image_size: ScreenSize,
+    display_size: ScreenSize,
+) -> Vec<(sdl2::rect::Rect, sdl2::pixels::Color)> {
+    let counts: Vec<u32> = blocks.iter().map(|block| sample_count(block)).collect();
+    let max_count = counts.iter().copied().max().unwrap_or(0);
+
+    let scale = |value: u32, display: u32, image: u32, round_up: bool| {
+        let scaled = value as u64 * display as u64;
+        let image = image as u64;
+        (if round_up {
+            scaled.div_ceil(image)
+        } else {
+            scaled / image
+        }) as u32
+    };
+
+    blocks
+        .iter()
+        .zip(counts)
+        .map(|(block, count)| {
+            let x0 = scale(block.min.x, display_size.width, image_size.width, false);
+            let y0 = scale(block.min.y, display_size.height, image_size.height, false);
+            let x1 = scale(block.max.x, display_size.width, image_size.width, true);
+            let y1 = scale(block.max.y, display_size.height, image_size.height, true);
+            let rect = sdl2::rect::Rect::new(x0 as i32, y0 as i32, x1 - x0, y1 - y0);
+            (rect, heat_color(count, max_count))
+        })
+        .collect()
+}
+
+/// Maps a sample count to a translucent color between blue (zero) and red (max_count).
+fn heat_color(count: u32, max_count: u32) -> sdl2::pixels::Color {
+    let t = if max_count == 0 {
+        0.0
+    } else {
+        count as f64 / max_count as f64
+    };
+    let red = (255.0 * t).round() as u8;
+    sdl2::pixels::Color::RGBA(red, 0, 255 - red, SAMPLE_OVERLAY_ALPHA)
+}
+
+/// Splits the window at the comparison wipe, returns the parts showing the image and the
+/// comparison image. Empty parts are None.
+fn wipe_clip_rects(
+    wipe_x: i32,
+    size: ScreenSize,
+) -> (Option<sdl2::rect::Rect>, Option<sdl2::rect::Rect>) {
+    let wipe_x = wipe_x.clamp(0, size.width as i32) as u32;
+    let left = if wipe_x > 0 {
+        Some(sdl2::rect::Rect::new(0, 0, wipe_x, size.height))
+    } else {
+        None
+    };
+    let right = if wipe_x < size.width {
+        Some(sdl2::rect::Rect::new(
+            wipe_x as i32,
+            0,
+            size.width - wipe_x,
+            size.height,
+        ))
+    } else {
+        None
+    };
+    (left, right)
+}
+
+/// Texture with the thumbnail and its placement.
+struct Thumbnail<'a> {
+    config: ThumbnailConfig,
+    texture: sdl2::render::Texture<'a>,
+}
+
+/// Window opened by `ImageWindow::pump`, kept between the calls together with its textures.
+///
+/// The textures borrow the texture creator stored next to them, so their lifetime is erased to
+/// `'static`. This is sound because a `Texture` only holds a raw pointer and a lifetime marker,
+/// not a reference into the creator, so moving the struct doesn't invalidate it, and the
+/// renderer is kept alive by the texture creator and the canvas until the textures are
+/// dropped. Fields are dropped in declaration order, so the textures go first.
+struct PumpedWindow {
+    textures: PumpedTextures,
+    texture_creator: sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+    state: WindowState,
+}
+
+impl PumpedWindow {
+    fn open(window: &ImageWindow) -> util::SimpleResult<PumpedWindow> {
+        let state = window.open_window()?;
+        let texture_creator = state.canvas.texture_creator();
+        // SAFETY: See above, the textures are stored before the texture creator
+        let textures =
+            unsafe { PumpedTextures::new(window.create_textures(&texture_creator, &state)?) };
+        Ok(PumpedWindow {
+            textures,
+            texture_creator,
+            state,
+        })
+    }
+}
+
+/// Image, thumbnail and comparison textures of a `PumpedWindow`.
+struct PumpedTextures {
+    texture: sdl2::render::Texture<'static>,
+    thumbnail: Option<Thumbnail<'static>>,
+    comparison: Option<sdl2::render::Texture<'static>>,
+}
+
+impl PumpedTextures {
+    /// Erases the lifetime of textures created by `ImageWindow::create_textures`.
+    ///
+    /// # Safety
+    /// The textures must be dropped before the texture creator they were created by.
+    unsafe fn new(
+        (texture, thumbnail, comparison): (
+            sdl2::render::Texture<'_>,
+            Option<Thumbnail<'_>>,
+            Option<sdl2::render::Texture<'_>>,
+        ),
+    ) -> PumpedTextures {
+        use sdl2::render::Texture;
+        PumpedTextures {
+            texture: std::mem::transmute::<Texture<'_>, Texture<'static>>(texture),
+            thumbnail: std::mem::transmute::<Option<Thumbnail<'_>>, Option<Thumbnail<'static>>>(
+                thumbnail,
+            ),
+            comparison: std::mem::transmute::<Option<Texture<'_>>, Option<Texture<'static>>>(
+                comparison,
+            ),
+        }
+    }
+}
+
+/// Downsamples the whole image to the given size, using the nearest pixel.
+fn thumbnail_image(img: &image::RgbaImage, size: ScreenSize) -> image::RgbaImage {
+    image::RgbaImage::from_fn(size.width, size.height, |x, y| {
+        let source_x = (x as u64 * img.width() as u64 / size.width as u64) as u32;
+        let source_y = (y as u64 * img.height() as u64 / size.height as u64) as u32;
+        *img.get_pixel(source_x, source_y)
+    })
+}
+
+/// Shrinks the display size by the smallest integer factor that makes it fit into the bounds.
+fn auto_fit_size(display_size: ScreenSize, bounds: ScreenSize) -> ScreenSize {
+    let factor = |size: u32, bound: u32| size.div_ceil(bound.max(1));
+    let factor = factor(display_size.width, bounds.width)
+        .max(factor(display_size.height, bounds.height))
+        .max(1);
+    ScreenSize::new(
+        (display_size.width / factor).max(1),
+        (display_size.height / factor).max(1),
+    )
+}
+
+/// Scales a size in logical pixels at display scale 1 to the given display scale, at least 1.
+fn scale_metric(value: u32, display_scale: f32) -> u32 {
+    ((value as f32 * display_scale).round() as u32).max(1)
+}
+
+/// Converts display DPI to display scale, unknown DPI (zero or invalid) gives 1.
+fn display_scale(dpi: f32) -> f32 {
+    if dpi.is_finite() && dpi > 0.0 {
+        dpi / REFERENCE_DPI
+    } else {
+        1.0
+    }
+}
+
+/// Shrinks every block of a grid with the given block size into a tile of a new image, with
+/// the same layout as the grid.
+fn block_sheet(
+    img: &image::RgbaImage,
+    block_size: u32,
+    tile: u32,
+) -> util::SimpleResult<image::RgbaImage> {
+    let columns = img.width().div_ceil(block_size);
+    let rows = img.height().div_ceil(block_size);
+    let mut sheet = image::RgbaImage::new(columns * tile, rows * tile);
+    for row in 0..rows {
+        for column in 0..columns {
+            let x = column * block_size;
+            let y = row * block_size;
+            let block = img
+                .view(
+                    x,
+                    y,
+                    cmp::min(block_size, img.width() - x),
+                    cmp::min(block_size, img.height() - y),
+                )
+                .to_image();
+            let thumbnail = thumbnail_image(&block, ScreenSize::new(tile, tile));
+            sheet.copy_from(&thumbnail, column * tile, row * tile)?;
+        }
+    }
+    Ok(sheet)
+}
+
+/// Returns the rectangle in logical coordinates where the thumbnail should be drawn, with the
+/// margin sized for the given display scale.
+fn thumbnail_rect(
+    config: &ThumbnailConfig,
+    canvas_size: ScreenSize,
+    display_scale: f32,
+) -> sdl2::rect::Rect {
+    let margin = scale_metric(THUMBNAIL_MARGIN, display_scale);
+    let left = margin as i32;
+    let top = margin as i32;
+    let right = canvas_size.width as i32 - (margin + config.size.width) as i32;
+    let bottom = canvas_size.height as i32 - (margin + config.size.height) as i32;
+    let (x, y) = match config.corner {
+        ThumbnailCorner::TopLeft => (left, top),
+        ThumbnailCorner::TopRight => (right, top),
+        ThumbnailCorner::BottomLeft => (left, bottom),
+        ThumbnailCorner::BottomRight => (right, bottom),
+    };
+    sdl2::rect::Rect::new(x, y, config.size.width, config.size.height)
+}
+
+/// Maps a block of the image to the smallest block of display pixels that it affects and
+/// computes values of these display pixels by averaging the image pixels they cover.
+fn downsample_block(
+    img: &image::RgbaImage,
+    block: ScreenBlock,
+    display_size: ScreenSize,
+    color_key: Option<image::Rgb<u8>>,
+) -> (ScreenBlock, image::RgbaImage) {
+    let scale_down =
+        |value: u32, from: u32, to: u32| (value as u64 * to as u64 / from as u64) as u32;
+    let scale_up =
+        |value: u32, from: u32, to: u32| (value as u64 * to as u64).div_ceil(from as u64) as u32;
+
+    let (width, height) = img.dimensions();
+    let display_block = ScreenBlock::new(
+        ScreenPoint::new(
+            scale_down(block.min.x, width, display_size.width),
+            scale_down(block.min.y, height, display_size.height),
+        ),
+        ScreenPoint::new(
+            scale_up(block.max.x, width, display_size.width),
+            scale_up(block.max.y, height, display_size.height),
+        ),
+    );
+
+    let downsampled =
+        image::RgbaImage::from_fn(display_block.width(), display_block.height(), |x, y| {
+            let x = x + display_block.min.x;
+            let y = y + display_block.min.y;
+            let x0 = scale_down(x, display_size.width, width);
+            let x1 = cmp::max(x0 + 1, scale_up(x + 1, display_size.width, width));
+            let y0 = scale_down(y, display_size.height, height);
+            let y1 = cmp::max(y0 + 1, scale_up(y + 1, display_size.height, height));
+
+            let mut sum = [0u32; 4];
+            for source_y in y0..y1 {
+                for source_x in x0..x1 {
+                    let mut pixel = *img.get_pixel(source_x, source_y);
+                    if let Some(color_key) = color_key {
+                        pixel = color_keyed(pixel, color_key);
+                    }
+                    for (s, c) in sum.iter_mut().zip(pixel.0.iter()) {
+                        *s += *c as u32;
+                    }
+                }
+            }
+            let count = (x1 - x0) * (y1 - y0);
+            let average = |s: u32| ((s + count / 2) / count) as u8;
+            image::Rgba([
+                average(sum[0]),
+                average(sum[1]),
+                average(sum[2]),
+                average(sum[3]),
+            ])
+        });
+
+    (display_block, downsampled)
+}
+
+/// Switches the window between borderless fullscreen and windowed mode.
+fn set_window_fullscreen(
+    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+    fullscreen: bool,
+) -> util::SimpleResult {
+    use sdl2::video::FullscreenType;
+    canvas.window_mut().set_fullscreen(if fullscreen {
+        FullscreenType::Desktop
+    } else {
+        FullscreenType::Off
+    })?;
+    Ok(())
+}
+
+/// Everything that is drawn in a single redraw of the window, except the thumbnail, see `redraw`.
+struct Frame<'a> {
+    texture: &'a sdl2::render::Texture<'a>,
+    /// Comparison texture shown right of the wipe line x coordinate.
+    wipe: Option<(&'a sdl2::render::Texture<'a>, i32)>,
+    view: View,
+    /// Checkerboard behind a transparent image, None for opaque images.
+    background: Option<&'a Background>,
+    /// Rectangles in image coordinates, drawn over the image.
+    overlay: &'a [(sdl2::rect::Rect, sdl2::pixels::Color)],
+    /// Rectangles in window coordinates, drawn on top of everything else.
+    hud: &'a [(sdl2::rect::Rect, sdl2::pixels::Color)],
+    /// Scale of the hud and the thumbnail margin, see `ImageWindow::display_scale`.
+    display_scale: f32,
+    img: &'a lock::Mutex<image::RgbaImage>,
+}
+
+/// Completely redraws the canvas, puts a checkerboard behind, draws the texture on top and then
+/// calls the post draw hook.
+fn redraw(
+    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+    frame: &Frame,
+    thumbnail: &mut Option<Thumbnail>,
+    post_draw: &mut Option<PostDrawHook>,
+) -> util::SimpleResult {
+    let Frame {
+        texture,
+        wipe,
+        view,
+        background,
+        overlay,
+        hud,
+        display_scale,
+        img,
+    } = *frame;
+    draw_background(canvas, background)?;
+    let (width, height) = canvas.logical_size();
+    let image_rect = view.rect(sdl2::rect::Rect::new(0, 0, width, height));
+    match wipe {
+        None => canvas.copy(texture, None, Some(image_rect))?,
+        Some((comparison, wipe_x)) => {
+            let (left, right) = wipe_clip_rects(wipe_x, ScreenSize::new(width, height));
+            for (texture, clip) in &[(texture, left), (comparison, right)] {
+                if let Some(clip) = clip {
+                    canvas.set_clip_rect(*clip);
+                    canvas.copy(texture, None, Some(image_rect))?;
+                }
+            }
+            canvas.set_clip_rect(None);
+            canvas.set_draw_color(WIPE_LINE_COLOR);
+            canvas.draw_line((wipe_x, 0), (wipe_x, height as i32 - 1))?;
+        }
+    }
+    if !overlay.is_empty() {
+        canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
+        for (rect, color) in overlay {
+            canvas.set_draw_color(*color);
+            canvas.fill_rect(view.rect(*rect))?;
+        }
+        canvas.set_blend_mode(sdl2::render::BlendMode::None);
+    }
+    if let Some(thumbnail) = thumbnail {
+        let thumbnail_img = thumbnail_image(&img.lock(), thumbnail.config.size);
+        upload_block(
+            &mut thumbnail.texture,
+            ScreenBlock::from_size(thumbnail.config.size),
+            &thumbnail_img,
+        )?;
+        let rect = thumbnail_rect(
+            &thumbnail.config,
+            ScreenSize::new(width, height),
+            display_scale,
+        );
+        canvas.copy(&thumbnail.texture, None, Some(rect))?;
+    }
+    if !hud.is_empty() {
+        canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
+        for (rect, color) in hud {
+            canvas.set_draw_color(*color);
+            canvas.fill_rect(*rect)?;
+        }
+        canvas.set_blend_mode(sdl2::render::BlendMode::None);
+    }
+    if let Some(post_draw) = post_draw {
+        post_draw(canvas);
+    }
+    canvas.present();
+
+    Ok(())
+}
+
+/// Clears the canvas with the checkerboard background, or with black if there is no background
+/// (the image is opaque).
+fn draw_background<T: sdl2::render::RenderTarget>(
+    canvas: &mut sdl2::render::Canvas<T>,
+    background: Option<&Background>,
+) -> util::SimpleResult {
+    match background {
+        Some(background) => draw_checkerboard(canvas, background),
+        None => {
+            canvas.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
+            canvas.clear();
+            Ok(())
+        }
+    }
+}
+
+/// Clears the canvas with a checkerboard pattern.
+fn draw_checkerboard<T: sdl2::render::RenderTarget>(
+    canvas: &mut sdl2::render::Canvas<T>,
+    background: &Background,
+) -> util::SimpleResult {
+    canvas.set_draw_color(background.base_color);
+    canvas.clear();
+    if background.cell_color == background.base_color {
+        return Ok(()); // Solid background, no need to draw the cells
+    }
+    canvas.set_draw_color(background.cell_color);
+
+    let (w, h) = canvas.logical_size();
+    let checkerboard_size = background.cell_size;
+
+    for y in 0..(h / checkerboard_size) {
+        for x in ((y % 2)..(w / checkerboard_size)).step_by(2) {
+            let rect = sdl2::rect::Rect::new(
+                (x * checkerboard_size) as i32,
+                (y * checkerboard_size) as i32,
+                checkerboard_size,
+                checkerboard_size,
+            );
+            canvas.fill_rect(Some(rect))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use proptest_attr_macro::proptest;
+
+    #[test]
+    fn window_to_image_bottom_left() {
+        let display_size = ScreenSize::new(400, 300);
+        let image_size = ScreenSize::new(800, 600);
+        let map =
+            |x, y| window_to_image(x, y, display_size, image_size, CoordinateOrigin::BottomLeft);
+
+        assert!(map(0, 299) == Some(ScreenPoint::new(0, 1)));
+        assert!(map(0, 0) == Some(ScreenPoint::new(0, 599)));
+        assert!(map(399, 150) == Some(ScreenPoint::new(798, 299)));
+        assert!(map(-1, 0).is_none());
+        assert!(map(0, 300).is_none());
+    }
+
+    /// Checks that a marker placed at an image pixel maps back to the same pixel, for both
+    /// origins.
+    #[proptest]
+    fn image_to_window_round_trip(x: u16, y: u16, bottom_left: bool) {
+        let display_size = ScreenSize::new(640, 480);
+        let image_size = ScreenSize::new(320, 240);
+        let origin = if bottom_left {
+            CoordinateOrigin::BottomLeft
+        } else {
+            CoordinateOrigin::TopLeft
+        };
+        let point = ScreenPoint::new(x as u32 % image_size.width, y as u32 % image_size.height);
+
+        let (window_x, window_y) = image_to_window(point, display_size, image_size, origin);
+
+        assert!(
+            window_to_image(window_x, window_y, display_size, image_size, origin) == Some(point)
+        );
+    }
+
+    #[test]
+    fn image_to_window_bottom_left_marker() {
+        let size = ScreenSize::new(100, 100);
+        let marker = image_to_window(
+            ScreenPoint::new(0, 0),
+            size,
+            size,
+            CoordinateOrigin::BottomLeft,
+        );
+        assert!(marker == (0, 99));
+        let rects = marker_rects(marker, 1.0);
+        assert!(rects.len() == 2);
+        for (rect, _color) in rects {
+            assert!(rect.contains_point(marker));
+            assert!(rect.width().max(rect.height()) == 2 * MARKER_RADIUS + 1);
+        }
+    }
+
+    /// Checks that blocks with more samples get hotter (more red, less blue) colors and that
+    /// the rectangles are scaled to the display.
+    #[test]
+    fn sample_overlay_heat() {
+        let blocks = [
+            ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(10, 10)),
+            ScreenBlock::new(ScreenPoint::new(10, 0), ScreenPoint::new(20, 10)),
+            ScreenBlock::new(ScreenPoint::new(0, 10), ScreenPoint::new(10, 20)),
+        ];
+        let sample_count: SampleCountFn = Box::new(|block| 16 + block.min.x * 10 + block.min.y);
+
+        let overlay = sample_overlay_rects(
+            &blocks,
+            &sample_count,
+            ScreenSize::new(20, 20),
+            ScreenSize::new(40, 40),
+        );
+
+        let colors: Vec<_> = overlay.iter().map(|(_, color)| *color).collect();
+        // Counts are 16, 116 and 26
+        assert!(colors[1].r > colors[2].r);
+        assert!(colors[2].r > colors[0].r);
+        assert!(colors[1].b < colors[2].b);
+        assert!(colors[2].b < colors[0].b);
+        assert!(colors[1].r == 255);
+
+        assert!(overlay[1].0 == sdl2::rect::Rect::new(20, 0, 20, 20));
+    }
+
+    /// Checks that a block that just arrived is transparent, partially transparent during the
+    /// animation and fully opaque once the reveal duration has passed.
+    #[test]
+    fn reveal_fade_in() {
+        let block = image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 200]));
+        let faded = |elapsed| {
+            let mut faded = block.clone();
+            fade_alpha(&mut faded, reveal_opacity(elapsed));
+            faded
+        };
+
+        assert!(faded(std::time::Duration::from_millis(0))
+            .pixels()
+            .all(|p| p[3] == 0));
+        assert!(faded(REVEAL_DURATION / 2).pixels().all(|p| p[3] == 100));
+        assert!(faded(REVEAL_DURATION).as_raw() == block.as_raw());
+        assert!(faded(REVEAL_DURATION * 3).as_raw() == block.as_raw());
+    }
+
+    /// Checks that the accumulation buffer averages passes at full precision, shows the clamped
+    /// average, and falls back to the display image for pixels without samples.
+    #[test]
+    fn accumulation_averages_passes() {
+        let mut accumulation = Accumulation::new(ScreenSize::new(4, 3));
+        let block = ScreenBlock::new(ScreenPoint::new(1, 1), ScreenPoint::new(3, 2));
+        let pass =
+            |value: f32| RgbaF32Image::from_pixel(2, 1, image::Rgba([value, 0.25, 0.0, 1.0]));
+
+        let shown = accumulation.add(block, &pass(1.0)).unwrap();
+        assert!(shown.get_pixel(0, 0) == &image::Rgba([255, 64, 0, 255]));
+        let shown = accumulation.add(block, &pass(4.0)).unwrap();
+        assert!(shown.dimensions() == (2, 1));
+        assert!(shown.get_pixel(1, 0) == &image::Rgba([255, 64, 0, 255]));
+
+        assert!(accumulation.average(2, 1) == Some([2.5, 0.25, 0.0, 1.0]));
+        assert!(accumulation.average(0, 1).is_none());
+
+        let img = image::RgbaImage::from_pixel(4, 3, image::Rgba([255, 0, 51, 255]));
+        assert!(accumulation.pixel(&img, 1, 1) == [2.5, 0.25, 0.0, 1.0]);
+        assert!(accumulation.pixel(&img, 3, 2) == [1.0, 0.0, 0.2, 1.0]);
+
+        let outside = ScreenBlock::new(ScreenPoint::new(3, 2), ScreenPoint::new(5, 3));
+        assert!(accumulation.add(outside, &pass(1.0)).is_err());
+        let too_large = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(3, 1));
+        assert!(accumulation.add(too_large, &pass(1.0)).is_err());
+    }
+
+    /// Writes values outside of the 0-1 range and checks that they are preserved.
+    #[cfg(feature = "exr")]
+    #[test]
+    fn exr_round_trip() {
+        let size = ScreenSize::new(13, 7);
+        let value = |x: u32, y: u32| {
+            [
+                x as f32 * 0.5,
+                y as f32 * 1.25,
+                -(x as f32) / 3.0,
+                0.5 + (x + y) as f32 * 0.01,
+            ]
+        };
+        let file = tempfile::Builder::new()
+            .suffix(".exr")
+            .tempfile()
+            .unwrap()
+            .into_temp_path();
+
+        write_exr(&file, size, value).unwrap();
+
+        let read_back = exr::prelude::read_first_rgba_layer_from_file(
+            &file,
+            |resolution, _| vec![[0f32; 4]; resolution.width() * resolution.height()],
+            move |pixels, position, (r, g, b, a): (f32, f32, f32, f32)| {
+                pixels[position.y() * size.width as usize + position.x()] = [r, g, b, a];
+            },
+        )
+        .unwrap();
+        let pixels = read_back.layer_data.channel_data.pixels;
+
+        assert!(pixels.len() == (size.width * size.height) as usize);
+        for (i, pixel) in pixels.iter().enumerate() {
+            let expected = value(i as u32 % size.width, i as u32 / size.width);
+            for c in 0..4 {
+                assert!((pixel[c] - expected[c]).abs() < 1e-6);
+            }
+        }
+    }
+
+    /// Accumulates two passes of float blocks with values above 1 and checks that the saved
+    /// OpenEXR file contains their averages.
+    #[cfg(feature = "exr")]
+    #[test]
+    #[ignore]
+    fn test_accumulated_exr() {
+        let mut window = ImageWindow::new("ImageWindow accumulated exr test", 6, 4).unwrap();
+        window.set_accumulate(true);
+        let block = ScreenBlock::new(ScreenPoint::new(1, 1), ScreenPoint::new(5, 3));
+        let pass =
+            |value: f32| RgbaF32Image::from_pixel(4, 2, image::Rgba([value, 0.25, 3.0, 1.0]));
+        let writer = window.make_writer_f32();
+        writer.write_f32(block, &pass(2.0)).unwrap();
+        writer.write_f32(block, &pass(5.0)).unwrap();
+
+        let file = tempfile::Builder::new()
+            .suffix(".exr")
+            .tempfile()
+            .unwrap()
+            .into_temp_path();
+        window.save_exr(&file).unwrap();
+
+        let read_back = exr::prelude::read_first_rgba_layer_from_file(
+            &file,
+            |resolution, _| vec![[0f32; 4]; resolution.width() * resolution.height()],
+            |pixels, position, (r, g, b, a): (f32, f32, f32, f32)| {
+                pixels[position.y() * 6 + position.x()] = [r, g, b, a];
+            },
+        )
+        .unwrap();
+        let pixels = read_back.layer_data.channel_data.pixels;
+
+        let expected = [3.5, 0.25, 3.0, 1.0];
+        for y in 1..3 {
+            for x in 1..5 {
+                let pixel = pixels[y * 6 + x];
+                for c in 0..4 {
+                    assert!((pixel[c] - expected[c]).abs() < 1e-6);
+                }
+            }
+        }
+        assert!(pixels[0] == [0.0; 4]);
+        assert!(window.snapshot().get_pixel(1, 1) == &image::Rgba([255, 64, 255, 255]));
+    }
+
+    /// Checks that every quadrant of the image ends up in the corresponding quadrant of the
+    /// thumbnail.
+    #[test]
+    fn thumbnail_quadrants() {
+        let colors = [
+            image::Rgba([255, 0, 0, 255]),
+            image::Rgba([0, 255, 0, 255]),
+            image::Rgba([0, 0, 255, 255]),
+            image::Rgba([255, 255, 255, 128]),
+        ];
+        let img =
+            image::RgbaImage::from_fn(640, 480, |x, y| colors[(x / 320 + 2 * (y / 240)) as usize]);
+
+        let thumbnail = thumbnail_image(&img, ScreenSize::new(64, 48));
+
+        for (x, y, pixel) in thumbnail.enumerate_pixels() {
+            assert!(*pixel == colors[(x / 32 + 2 * (y / 24)) as usize]);
+        }
+    }
+
+    /// Pushes more distinct frames than the history holds, checks that browsing returns
+    /// the newest ones in order and then the live image again.
+    #[test]
+    fn frame_history_navigation() {
+        let frame = |value: u8| image::RgbaImage::from_pixel(4, 3, image::Rgba([value; 4]));
+        let mut history = FrameHistory::new(3);
+        assert!(!history.step_back());
+        let now = std::time::Instant::now();
+        for value in 1..=5 {
+            history.push(&frame(value), now);
+            history.push(&frame(value), now);
+        }
+        assert!(history.frames.len() == 3);
+
+        let mut shown = Vec::new();
+        while history.step_back() {
+            shown.push(history.selected_frame().unwrap().get_pixel(0, 0)[0]);
+        }
+        assert!(shown == vec![5, 4, 3]);
+        assert!(history.selected_frame() == Some(&frame(3)));
+
+        let mut shown_forward = Vec::new();
+        while history.step_forward() {
+            shown_forward.push(
+                history
+                    .selected_frame()
+                    .map(|frame| frame.get_pixel(0, 0)[0]),
+            );
+        }
+        assert!(shown_forward == vec![Some(4), Some(5), None]);
+    }
+
+    /// Checks that frames are due only after the interval since the last stored one, and
+    /// never with the history disabled.
+    #[test]
+    fn frame_history_interval() {
+        let start = std::time::Instant::now();
+        let mut history = FrameHistory::new(3);
+        assert!(history.due(start));
+        history.push(&image::RgbaImage::new(4, 3), start);
+        assert!(!history.due(start + HISTORY_INTERVAL / 2));
+        assert!(history.due(start + HISTORY_INTERVAL));
+
+        let disabled = FrameHistory::new(0);
+        assert!(!disabled.due(start));
+    }
+
+    #[test]
+    fn display_scale_from_dpi() {
+        assert!(display_scale(96.0) == 1.0);
+        assert!(display_scale(144.0) == 1.5);
+        assert!(display_scale(192.0) == 2.0);
+        assert!(display_scale(0.0) == 1.0);
+        assert!(display_scale(f32::NAN) == 1.0);
+    }
+
+    /// Checks the sheet size for a grid with partial blocks and that the tiles come from the
+    /// right blocks.
+    #[test]
+    fn block_sheet_tiles() {
+        let color =
+            |column: u32, row: u32| image::Rgba([column as u8 * 100, row as u8 * 100, 50, 255]);
+        let img = image::RgbaImage::from_fn(25, 15, |x, y| color(x / 10, y / 10));
+
+        let sheet = block_sheet(&img, 10, 4).unwrap();
+
+        assert!(sheet.dimensions() == (12, 8));
+        assert!(*sheet.get_pixel(0, 0) == color(0, 0));
+        assert!(*sheet.get_pixel(9, 6) == color(2, 1));
+        assert!(*sheet.get_pixel(11, 3) == color(2, 0));
+    }
+
+    #[test]
+    fn thumbnail_rect_corners() {
+        let canvas_size = ScreenSize::new(800, 600);
+        let rect = |corner, display_scale| {
+            thumbnail_rect(
+                &ThumbnailConfig {
+                    corner,
+                    size: ScreenSize::new(100, 50),
+                },
+                canvas_size,
+                display_scale,
+            )
+        };
+
+        assert!(rect(ThumbnailCorner::TopLeft, 1.0) == sdl2::rect::Rect::new(8, 8, 100, 50));
+        assert!(rect(ThumbnailCorner::TopRight, 1.0) == sdl2::rect::Rect::new(692, 8, 100, 50));
+        assert!(rect(ThumbnailCorner::BottomLeft, 1.0) == sdl2::rect::Rect::new(8, 542, 100, 50));
+        assert!(
+            rect(ThumbnailCorner::BottomRight, 1.0) == sdl2::rect::Rect::new(692, 542, 100, 50)
+        );
+        assert!(
+            rect(ThumbnailCorner::BottomRight, 2.0) == sdl2::rect::Rect::new(684, 534, 100, 50)
+        );
+    }
+
+    #[test]
+    fn metrics_follow_display_scale() {
+        assert!(scale_metric(4, 1.0) == 4);
+        assert!(scale_metric(4, 1.5) == 6);
+        assert!(scale_metric(4, 0.1) == 1);
+
+        let box_size = |display_scale| {
+            let rect = text_rects("1", display_scale)[0].0;
+            (rect.width(), rect.height())
+        };
+        assert!(box_size(1.0) == (14, 18));
+        assert!(box_size(2.0) == (28, 36));
+
+        let length = |display_scale| {
+            let rects = marker_rects((10, 10), display_scale);
+            rects[0].0.width()
+        };
+        assert!(length(1.0) == 2 * MARKER_RADIUS + 1);
+        assert!(length(2.0) == 4 * MARKER_RADIUS + 1);
+    }
+
+    /// Checks that cycling through background presets visits all of them and wraps around.
+    #[test]
+    fn background_preset_cycle() {
+        let start = BackgroundPreset::DarkChecker;
+        let mut preset = start.next();
+        let mut count = 1;
+        while preset != start {
+            assert!(preset.background().cell_size > 0);
+            preset = preset.next();
+            count += 1;
+        }
+        assert!(count == 4);
+    }
+
+    /// Writes a 16 bit gradient and checks both the display and the full precision values.
+    #[test]
+    fn write_u16_gradient() {
+        for &dither in &[false, true] {
+            let img = lock::Mutex::new(image::RgbaImage::new(300, 2));
+            let img16 = lock::Mutex::new(Rgba16Image::new(300, 2));
+            let block = ScreenBlock::new(ScreenPoint::new(10, 1), ScreenPoint::new(266, 2));
+            let block_buffer = Rgba16Image::from_fn(256, 1, |x, _y| {
+                let value = x as u16 * 257;
+                image::Rgba([value, value, 65535 - value, 65535])
+            });
+
+            write_u16_block(
+                &img,
+                Some(&img16),
+                block,
+                &block_buffer,
+                dither,
+                BlendMode::Replace,
+            )
+            .unwrap();
+
+            let img = img.lock();
+            let img16 = img16.lock();
+            for x in 0..256u32 {
+                let value = x as u8;
+                assert!(img.get_pixel(x + 10, 1) == &image::Rgba([value, value, 255 - value, 255]));
+                assert!(img16.get_pixel(x + 10, 1) == block_buffer.get_pixel(x, 0));
+            }
+            assert!(img.get_pixel(9, 1) == &image::Rgba([0, 0, 0, 0]));
+            assert!(img16.get_pixel(9, 1) == &image::Rgba([0, 0, 0, 0]));
+        }
+    }
+
+    /// Blends a half transparent 16 bit block over an opaque one and checks that the display
+    /// and the full precision images agree.
+    #[test]
+    fn write_u16_blend_over() {
+        let img = lock::Mutex::new(image::RgbaImage::from_pixel(
+            4,
+            1,
+            image::Rgba([200, 100, 0, 255]),
+        ));
+        let img16 = lock::Mutex::new(widen_block(&img.lock()));
+        let block = ScreenBlock::new(ScreenPoint::new(1, 0), ScreenPoint::new(3, 1));
+        let block_buffer = Rgba16Image::from_pixel(2, 1, image::Rgba([0, 0, 65535, 32896]));
+
+        write_u16_block(
+            &img,
+            Some(&img16),
+            block,
+            &block_buffer,
+            false,
+            BlendMode::Over,
+        )
+        .unwrap();
+
+        let img = img.lock();
+        let img16 = img16.lock();
+        assert!(img.get_pixel(0, 0) == &image::Rgba([200, 100, 0, 255]));
+        assert!(img.get_pixel(1, 0) == &image::Rgba([146, 71, 188, 255]));
+        assert!(img.get_pixel(2, 0) == img.get_pixel(1, 0));
+        for x in 0..4 {
+            let pixel = img.get_pixel(x, 0);
+            let pixel16 = img16.get_pixel(x, 0);
+            for i in 0..4 {
+                assert!((pixel16[i] as i32 - pixel[i] as i32 * 257).abs() <= 257);
+            }
+        }
+    }
+
+    /// Checks that 8 bit blocks widened for the 16 bit writer display unchanged.
+    #[proptest]
+    fn widened_block_round_trips(pixels: Vec<[u8; 4]>, dither: bool) {
+        let width = pixels.len().max(1) as u32;
+        let block_buffer = image::RgbaImage::from_fn(width, 1, |x, _y| {
+            image::Rgba(pixels.get(x as usize).copied().unwrap_or_default())
+        });
+        let img = lock::Mutex::new(image::RgbaImage::new(width, 1));
+        let img16 = lock::Mutex::new(Rgba16Image::new(width, 1));
+        let block = ScreenBlock::from_size(ScreenSize::new(width, 1));
+
+        write_u16_block(
+            &img,
+            Some(&img16),
+            block,
+            &widen_block(&block_buffer),
+            dither,
+            BlendMode::Replace,
+        )
+        .unwrap();
+
+        assert!(*img.lock() == block_buffer);
+        assert!(img16.lock().get_pixel(0, 0)[3] == block_buffer.get_pixel(0, 0)[3] as u16 * 257);
+    }
+
+    /// Checks that frame statistics count all recorded frames, but only keep the recent ones.
+    #[test]
+    fn frame_stats() {
+        let mut frame_times = FrameTimes::new();
+        assert!(frame_times.stats() == FrameStats::default());
+
+        for i in 0..(FRAME_TIMES_CAPACITY + 100) {
+            let ms = if i < 100 { 1000 } else { (i % 100) as u64 + 1 };
+            frame_times.record(std::time::Duration::from_millis(ms));
+        }
+
+        let stats = frame_times.stats();
+        assert!(stats.count == FRAME_TIMES_CAPACITY + 100);
+        assert!(stats.max == std::time::Duration::from_millis(100));
+        assert!(stats.p95 >= std::time::Duration::from_millis(90));
+        assert!(stats.p95 <= stats.max);
+        assert!(stats.mean < stats.p95);
+    }
+
+    /// Downsamples a 2x checker pattern and checks that display pixels are the averages.
+    #[test]
+    fn downsample_checker() {
+        let img = image::RgbaImage::from_fn(8, 6, |x, y| {
+            if (x + y) % 2 == 0 {
+                image::Rgba([255, 255, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 255])
+            }
+        });
+        let block = ScreenBlock::new(ScreenPoint::new(2, 2), ScreenPoint::new(6, 6));
+
+        let (display_block, downsampled) =
+            downsample_block(&img, block, ScreenSize::new(4, 3), None);
+
+        assert!(display_block == ScreenBlock::new(ScreenPoint::new(1, 1), ScreenPoint::new(3, 3)));
+        assert!(downsampled
+            .pixels()
+            .all(|pixel| pixel == &image::Rgba([128, 128, 128, 255])));
+    }
+
+    fn make_strip(width: u32, y: u32, height: u32) -> (ScreenBlock, image::RgbaImage) {
+        let block = ScreenBlock::new(ScreenPoint::new(0, y), ScreenPoint::new(width, y + height));
+        let buffer = image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([x as u8, y as u8, (x + y) as u8, 255 - x as u8])
+        });
+        (block, buffer)
+    }
+
+    /// Checks that the full row fast path gives the same result as copy_from.
+    #[test]
+    fn copy_block_full_rows() {
+        let mut fast = image::RgbaImage::new(37, 20);
+        let mut generic = image::RgbaImage::new(37, 20);
+
+        for &(y, height) in &[(0, 3), (3, 10), (13, 7)] {
+            let (block, buffer) = make_strip(37, y, height);
+            copy_block(&mut fast, block, &buffer).unwrap();
+            generic
+                .copy_from(&buffer, block.min.x, block.min.y)
+                .unwrap();
+        }
+
+        assert!(fast.as_raw() == generic.as_raw());
+    }
+
+    #[bench]
+    fn bench_copy_block_full_rows(b: &mut ::test::Bencher) {
+        let mut img = image::RgbaImage::new(1920, 1080);
+        let (block, buffer) = make_strip(1920, 500, 64);
+        b.iter(|| copy_block(&mut img, block, &buffer).unwrap());
+    }
+
+    #[bench]
+    fn bench_copy_from_full_rows(b: &mut ::test::Bencher) {
+        let mut img = image::RgbaImage::new(1920, 1080);
+        let (block, buffer) = make_strip(1920, 500, 64);
+        b.iter(|| img.copy_from(&buffer, block.min.x, block.min.y).unwrap());
+    }
+
+    /// Draws the background and an opaque image on a software canvas, the way redraw does.
+    fn bench_redraw(b: &mut ::test::Bencher, opaque: bool) {
+        let surface =
+            sdl2::surface::Surface::new(800, 600, sdl2::pixels::PixelFormatEnum::ARGB8888).unwrap();
+        let mut canvas = surface.into_canvas().unwrap();
+        let texture_creator = canvas.texture_creator();
+        let mut texture = texture_creator
+            .create_texture_streaming(SDL_PIXEL_FORMAT, 800, 600)
+            .unwrap();
+        texture.set_blend_mode(if opaque {
+            sdl2::render::BlendMode::None
+        } else {
+            sdl2::render::BlendMode::Blend
+        });
+        let img = image::RgbaImage::from_pixel(800, 600, image::Rgba([10, 20, 30, 255]));
+        update_texture(
+            &img,
+            &mut texture,
+            ScreenBlock::from_size(ScreenSize::new(800, 600)),
+            UploadSettings::default(),
+        )
+        .unwrap();
+        let background = if opaque {
+            None
+        } else {
+            Some(BackgroundPreset::DarkChecker.background())
+        };
+
+        b.iter(|| {
+            draw_background(&mut canvas, background.as_ref()).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        });
+    }
+
+    #[bench]
+    #[ignore]
+    fn bench_redraw_blended(b: &mut ::test::Bencher) {
+        bench_redraw(b, false);
+    }
+
+    #[bench]
+    #[ignore]
+    fn bench_redraw_opaque(b: &mut ::test::Bencher) {
+        bench_redraw(b, true);
+    }
+
+    /// Checks that events the window doesn't handle itself reach the passthrough hook, and that
+    /// they are not mistaken for written blocks.
+    #[test]
+    fn unhandled_event_passthrough() {
+        let received = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let received_clone = received.clone();
+        let mut on_unhandled: Option<UnhandledEventHook> = Some(Box::new(move |event| {
+            if let sdl2::event::Event::DropFile { filename, .. } = event {
+                received_clone.borrow_mut().push(filename.clone());
+            }
+        }));
+
+        let event = sdl2::event::Event::DropFile {
+            timestamp: 0,
+            window_id: 1,
+            filename: "scene.obj".into(),
+        };
+        let response =
+            dispatch_other_event(&event, |_block| panic!("Not a block"), &mut on_unhandled);
+
+        assert!(response == EventResponse::Nothing);
+        assert!(*received.borrow() == ["scene.obj"]);
+
+        // Without a hook the event is just dropped
+        let response = dispatch_other_event(&event, |_block| panic!("Not a block"), &mut None);
+        assert!(response == EventResponse::Nothing);
+    }
+
+    /// Checks that the block update hook gets the written blocks in order.
+    #[test]
+    #[ignore]
+    fn test_block_update_hook() {
+        use crate::screen_block::ScreenBlockExt;
+        use image_buffer::ImageBuffer;
+
+        let mut window = ImageWindow::new("ImageWindow block update test", 40, 30).unwrap();
+        let received = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let received_clone = received.clone();
+        window.set_on_block_update(Some(Box::new(move |block| {
+            received_clone.borrow_mut().push(*block);
+        })));
+
+        let blocks: Vec<_> = ScreenBlock::from_size(window.size)
+            .spiral_chunks(10)
+            .collect();
+        {
+            let writer = window.make_writer();
+            for block in &blocks {
+                let block_buffer = image::RgbaImage::new(block.width(), block.height());
+                writer.write(*block, &block_buffer).unwrap();
+            }
+        }
+        window.make_closer().close().unwrap();
+        window.run().unwrap();
+
+        assert!(*received.borrow() == blocks);
+    }
+
+    /// Checks that the initial image is shown before any block is written.
+    #[test]
+    #[ignore]
+    fn test_with_image() {
+        let img = image::RgbaImage::from_fn(30, 20, |x, y| {
+            image::Rgba([x as u8 * 8, y as u8 * 12, 64, 255])
+        });
+        let window = ImageWindow::with_image("ImageWindow with_image test", img.clone()).unwrap();
+        assert!(window.size == ScreenSize::new(30, 20));
+
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(window.frame_stats().count == 1);
+        assert!(window.snapshot() == img);
+
+        window.make_closer().close().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Closed);
+    }
+
+    /// Checks that the display scale is known once the window is open.
+    #[test]
+    #[ignore]
+    fn test_display_scale() {
+        let window = ImageWindow::new("ImageWindow display scale test", 20, 10).unwrap();
+        assert!(window.display_scale() == 1.0);
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(window.display_scale() > 0.0);
+
+        window.make_closer().close().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Closed);
+    }
+
+    /// Checks that a written block is read back unchanged, and blocks outside are rejected.
+    #[test]
+    #[ignore]
+    fn test_read_block() {
+        use image_buffer::ImageBuffer;
+
+        let window = ImageWindow::new("ImageWindow read_block test", 30, 20).unwrap();
+        let block = ScreenBlock::new(ScreenPoint::new(7, 3), ScreenPoint::new(19, 11));
+        let block_buffer = image::RgbaImage::from_fn(block.width(), block.height(), |x, y| {
+            image::Rgba([x as u8 * 20, y as u8 * 30, (x + y) as u8, 200])
+        });
+        window.make_writer().write(block, &block_buffer).unwrap();
+
+        assert!(window.read_block(block).unwrap().as_raw() == block_buffer.as_raw());
+        let outside = ScreenBlock::new(ScreenPoint::new(25, 15), ScreenPoint::new(31, 20));
+        assert!(window.read_block(outside).is_err());
+    }
+
+    /// Checks that a replaced image ends up in both buffers, and wrong sizes are rejected.
+    #[test]
+    fn replace_image_checks_size() {
+        let size = ScreenSize::new(30, 20);
+        let buffer = lock::Mutex::new(image::RgbaImage::new(30, 20));
+        let buffer16 = lock::Mutex::new(Rgba16Image::new(30, 20));
+        let img = image::RgbaImage::from_fn(30, 20, |x, y| {
+            image::Rgba([x as u8, y as u8, (x + y) as u8, 255])
+        });
+
+        replace_image(&buffer, Some(&buffer16), size, img.clone()).unwrap();
+        assert!(*buffer.lock() == img);
+        assert!(
+            buffer16.lock().get_pixel(7, 3) == &image::Rgba([7 * 257, 3 * 257, 10 * 257, 65535])
+        );
+
+        assert!(replace_image(
+            &buffer,
+            Some(&buffer16),
+            size,
+            image::RgbaImage::new(20, 30)
+        )
+        .is_err());
+        assert!(*buffer.lock() == img);
+
+        replace_image(&buffer, None, size, image::RgbaImage::new(30, 20)).unwrap();
+        assert!(*buffer.lock() == image::RgbaImage::new(30, 20));
+    }
+
+    /// Checks that raising exposure by one stop doubles the displayed color values (clamped),
+    /// and leaves alpha alone.
+    #[test]
+    fn exposure_changes_displayed_values() {
+        let mut img = image::RgbaImage::from_pixel(2, 1, image::Rgba([0, 50, 100, 128]));
+        img.put_pixel(1, 0, image::Rgba([200, 255, 127, 255]));
+
+        let mut same = img.clone();
+        apply_display_transform(&mut same, DisplayTransform::default());
+        assert!(same == img);
+
+        let transform = DisplayTransform::default().adjusted(sdl2::keyboard::Keycode::Plus);
+        let transform = transform.adjusted(sdl2::keyboard::Keycode::Plus);
+        assert!(transform.exposure == 1.0);
+        apply_display_transform(&mut img, transform);
+        assert!(img.get_pixel(0, 0) == &image::Rgba([0, 100, 200, 128]));
+        assert!(img.get_pixel(1, 0) == &image::Rgba([255, 255, 254, 255]));
+    }
+
+    /// Checks that gamma brightens midtones and that adjustments are clamped.
+    #[test]
+    fn gamma_adjustment() {
+        let transform = DisplayTransform {
+            gamma: 2.0,
+            ..DisplayTransform::default()
+        };
+        let table = transform.lookup_table();
+        assert!(table[0] == 0);
+        assert!(table[64] == 128);
+        assert!(table[255] == 255);
+
+        let mut transform = DisplayTransform::default();
+        for _ in 0..100 {
+            transform = transform
+                .adjusted(sdl2::keyboard::Keycode::LeftBracket)
+                .adjusted(sdl2::keyboard::Keycode::Minus);
+        }
+        assert!(transform.gamma == GAMMA_RANGE.0);
+        assert!(transform.exposure == EXPOSURE_RANGE.0);
+    }
+
+    #[test]
+    fn title_shows_transform() {
+        assert!(window_title("a", false, DisplayTransform::default(), None) == "a");
+        let transform = DisplayTransform {
+            exposure: 1.5,
+            gamma: 2.2,
+            ..DisplayTransform::default()
+        };
+        assert!(
+            window_title("a", true, transform, Some(100))
+                == "a 100% (finished) [exposure +1.5, gamma 2.2]"
+        );
+        let transform = DisplayTransform {
+            brightness: -0.1,
+            contrast: 1.5,
+            ..DisplayTransform::default()
+        };
+        assert!(
+            window_title("a", false, transform, None)
+                == "a [exposure +0.0, gamma 1.0, brightness -0.10, contrast 1.5]"
+        );
+    }
+
+    /// Checks brightness and contrast on a mid gray pixel, alone and combined with the other
+    /// parts of the transform.
+    #[test]
+    fn brightness_contrast_mid_gray() {
+        let gray = |transform: DisplayTransform| {
+            let mut img = image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 128, 200]));
+            apply_display_transform(&mut img, transform);
+            let pixel = *img.get_pixel(0, 0);
+            assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2] && pixel[3] == 200);
+            pixel[0]
+        };
+        let transform = |brightness, contrast| DisplayTransform {
+            brightness,
+            contrast,
+            ..DisplayTransform::default()
+        };
+
+        assert!(gray(transform(0.0, 1.0)) == 128);
+        // Contrast scales around 0.5, so mid gray barely moves
+        assert!(gray(transform(0.0, 3.0)) == 129);
+        assert!(gray(transform(0.0, 0.0)) == 128);
+        assert!(gray(transform(0.25, 1.0)) == 192);
+        assert!(gray(transform(-0.25, 2.0)) == 65);
+        assert!(gray(transform(1.0, 1.0)) == 255);
+        assert!(gray(transform(-1.0, 1.0)) == 0);
+        // Applied after exposure: 128 is pushed to white first
+        let exposed = DisplayTransform {
+            exposure: 1.0,
+            ..transform(-0.5, 1.0)
+        };
+        assert!(gray(exposed) == 128);
+
+        let mut adjusted = DisplayTransform::default();
+        for _ in 0..100 {
+            adjusted = adjusted
+                .adjusted(sdl2::keyboard::Keycode::Period)
+                .adjusted(sdl2::keyboard::Keycode::Semicolon);
+        }
+        assert!(adjusted.brightness == BRIGHTNESS_RANGE.1);
+        assert!(adjusted.contrast == CONTRAST_RANGE.0);
+    }
+
+    /// Checks the progress shown in the title.
+    #[test]
+    fn title_shows_progress() {
+        let title = |processed, total| {
+            window_title(
+                "a",
+                false,
+                DisplayTransform::default(),
+                Some(progress_percent(processed, total)),
+            )
+        };
+        assert!(title(0, 200) == "a 0%");
+        assert!(title(1, 200) == "a 0%");
+        assert!(title(84, 200) == "a 42%");
+        assert!(title(199, 200) == "a 99%");
+        assert!(title(200, 200) == "a 100%");
+        assert!(title(300, 200) == "a 100%");
+        assert!(title(0, 0) == "a 100%");
+        assert!(
+            window_title("a", true, DisplayTransform::default(), Some(100)) == "a 100% (finished)"
+        );
+        assert!(window_title("a", true, DisplayTransform::default(), None) == "a (finished)");
+    }
+
+    /// Checks that a half transparent block is composited over an opaque one in linear space,
+    /// and that blending over transparent pixels keeps the block color.
+    #[test]
+    fn blend_over() {
+        let mut img = image::RgbaImage::from_pixel(4, 2, image::Rgba([200, 100, 0, 255]));
+        for x in 2..4 {
+            img.put_pixel(x, 0, image::Rgba([0, 0, 0, 0]));
+            img.put_pixel(x, 1, image::Rgba([0, 0, 0, 0]));
+        }
+        let over = ScreenBlock::new(ScreenPoint::new(1, 0), ScreenPoint::new(3, 1));
+        let mut block_buffer = image::RgbaImage::from_pixel(3, 3, image::Rgba([0, 0, 255, 128]));
+        block_buffer.put_pixel(1, 0, image::Rgba([255, 0, 0, 128]));
+
+        blend_block(&mut img, over, &block_buffer).unwrap();
+
+        assert!(img.get_pixel(0, 0) == &image::Rgba([200, 100, 0, 255]));
+        assert!(img.get_pixel(1, 0) == &image::Rgba([146, 71, 188, 255]));
+        assert!(img.get_pixel(2, 0) == &image::Rgba([255, 0, 0, 128]));
+        assert!(img.get_pixel(3, 0) == &image::Rgba([0, 0, 0, 0]));
+        assert!(img.get_pixel(1, 1) == &image::Rgba([200, 100, 0, 255]));
+
+        let blended = img.clone();
+        let outside = ScreenBlock::new(ScreenPoint::new(3, 0), ScreenPoint::new(5, 1));
+        assert!(blend_block(&mut img, outside, &block_buffer).is_err());
+        let too_large = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(4, 1));
+        assert!(blend_block(&mut img, too_large, &block_buffer).is_err());
+        assert!(img == blended);
+    }
+
+    /// Checks that blocks must be non empty and inside the image, including at the edges.
+    #[test]
+    fn block_fits_check() {
+        let size = ScreenSize::new(30, 20);
+        let block =
+            |x0, y0, x1, y1| ScreenBlock::new(ScreenPoint::new(x0, y0), ScreenPoint::new(x1, y1));
+
+        assert!(check_block_fits("Block", block(0, 0, 30, 20), size).is_ok());
+        assert!(check_block_fits("Block", block(25, 15, 30, 20), size).is_ok());
+        assert!(check_block_fits("Block", block(25, 15, 31, 20), size).is_err());
+        assert!(check_block_fits("Block", block(25, 15, 30, 21), size).is_err());
+        assert!(check_block_fits("Block", block(5, 5, 5, 10), size).is_err());
+        assert!(check_block_fits("Block", block(6, 5, 5, 10), size).is_err());
+
+        let message = check_block_fits("Viewport", block(0, 0, 40, 10), size)
+            .unwrap_err()
+            .to_string();
+        assert!(message.starts_with("Viewport "));
+    }
+
+    /// Checks that blocks are translated by the viewport origin and must fit into the viewport.
+    #[test]
+    fn viewport_translation() {
+        let block =
+            |x0, y0, x1, y1| ScreenBlock::new(ScreenPoint::new(x0, y0), ScreenPoint::new(x1, y1));
+        let viewport = block(10, 20, 30, 25);
+
+        assert!(viewport_to_image(viewport, block(0, 0, 20, 5)).unwrap() == viewport);
+        assert!(viewport_to_image(viewport, block(5, 1, 7, 3)).unwrap() == block(15, 21, 17, 23));
+        assert!(viewport_to_image(viewport, block(15, 0, 21, 5)).is_err());
+        assert!(viewport_to_image(viewport, block(0, 4, 1, 6)).is_err());
+    }
+
+    /// Places a block that is partially outside of the viewport with each policy.
+    #[test]
+    fn out_of_bounds_policies() {
+        let block =
+            |x0, y0, x1, y1| ScreenBlock::new(ScreenPoint::new(x0, y0), ScreenPoint::new(x1, y1));
+        let viewport = block(10, 20, 30, 25);
+        let partial = block(15, 3, 25, 8);
+        let outside = block(25, 0, 30, 5);
+
+        assert!(place_block(viewport, partial, OutOfBoundsPolicy::Error).is_err());
+        assert!(place_block(viewport, partial, OutOfBoundsPolicy::Ignore)
+            .unwrap()
+            .is_none());
+        assert!(
+            place_block(viewport, partial, OutOfBoundsPolicy::Clip).unwrap()
+                == Some((block(25, 23, 30, 25), block(0, 0, 5, 2)))
+        );
+        assert!(place_block(viewport, outside, OutOfBoundsPolicy::Clip)
+            .unwrap()
+            .is_none());
+
+        let inside = block(5, 1, 7, 3);
+        for policy in &[
+            OutOfBoundsPolicy::Error,
+            OutOfBoundsPolicy::Clip,
+            OutOfBoundsPolicy::Ignore,
+        ] {
+            assert!(
+                place_block(viewport, inside, *policy).unwrap()
+                    == Some((block(15, 21, 17, 23), block(0, 0, 2, 2)))
+            );
+        }
+    }
+
+    /// Checks that a clipped block writes only its overlapping part, with the right pixels.
+    #[test]
+    #[ignore]
+    fn test_clipped_write() {
+        use image_buffer::ImageBufferWriter;
+
+        let window = ImageWindow::new("ImageWindow clipped write test", 20, 10).unwrap();
+        let writer = window.make_writer_with_settings(WriterSettings {
+            out_of_bounds: OutOfBoundsPolicy::Clip,
+            ..Default::default()
+        });
+        let block_buffer = image::RgbaImage::from_fn(8, 6, |x, y| {
+            image::Rgba([x as u8 * 30, y as u8 * 40, 0, 255])
+        });
+        let block = ScreenBlock::new(ScreenPoint::new(16, 7), ScreenPoint::new(24, 13));
+        writer.write(block, &block_buffer).unwrap();
+
+        let img = window.snapshot();
+        assert!(img.get_pixel(16, 7) == block_buffer.get_pixel(0, 0));
+        assert!(img.get_pixel(19, 9) == block_buffer.get_pixel(3, 2));
+        assert!(img.get_pixel(15, 7) == &image::Rgba([0, 0, 0, 0]));
+    }
+
+    /// Writes an opaque block and then a half transparent one over it through a writer with
+    /// `BlendMode::Over`.
+    #[test]
+    #[ignore]
+    fn test_blend_over_writer() {
+        use image_buffer::ImageBufferWriter;
+
+        let window = ImageWindow::new("ImageWindow blend over test", 20, 10).unwrap();
+        let writer = window.make_writer_with_settings(WriterSettings {
+            blend_mode: BlendMode::Over,
+            ..Default::default()
+        });
+        let base = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(4, 2));
+        writer
+            .write(
+                base,
+                &image::RgbaImage::from_pixel(4, 2, image::Rgba([200, 100, 0, 255])),
+            )
+            .unwrap();
+        let over = ScreenBlock::new(ScreenPoint::new(2, 0), ScreenPoint::new(6, 2));
+        writer
+            .write(
+                over,
+                &image::RgbaImage::from_pixel(4, 2, image::Rgba([0, 0, 255, 128])),
+            )
+            .unwrap();
+
+        let img = window.snapshot();
+        assert!(img.get_pixel(1, 1) == &image::Rgba([200, 100, 0, 255]));
+        assert!(img.get_pixel(3, 1) == &image::Rgba([146, 71, 188, 255]));
+        assert!(img.get_pixel(5, 1) == &image::Rgba([0, 0, 255, 128]));
+        assert!(img.get_pixel(7, 1) == &image::Rgba([0, 0, 0, 0]));
+    }
+
+    /// Checks that two writers with different viewports place their blocks correctly.
+    #[test]
+    #[ignore]
+    fn test_viewport_writers() {
+        use image_buffer::ImageBufferWriter;
+
+        let window = ImageWindow::new("ImageWindow viewport test", 20, 10).unwrap();
+        let left = window
+            .make_writer_with_viewport(ScreenBlock::new(
+                ScreenPoint::new(0, 0),
+                ScreenPoint::new(10, 10),
+            ))
+            .unwrap();
+        let right = window
+            .make_writer_with_viewport(ScreenBlock::new(
+                ScreenPoint::new(10, 0),
+                ScreenPoint::new(20, 10),
+            ))
+            .unwrap();
+        assert!(window
+            .make_writer_with_viewport(ScreenBlock::new(
+                ScreenPoint::new(15, 0),
+                ScreenPoint::new(25, 10),
+            ))
+            .is_err());
+
+        let corner = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(2, 2));
+        let red = image::Rgba([255, 0, 0, 255]);
+        let blue = image::Rgba([0, 0, 255, 255]);
+        left.write(corner, &image::RgbaImage::from_pixel(2, 2, red))
+            .unwrap();
+        right
+            .write(corner, &image::RgbaImage::from_pixel(2, 2, blue))
+            .unwrap();
+
+        let snapshot = window.snapshot();
+        assert!(snapshot.get_pixel(1, 1) == &red);
+        assert!(snapshot.get_pixel(11, 1) == &blue);
+        assert!(snapshot.get_pixel(5, 5) == &image::Rgba([0, 0, 0, 0]));
+    }
+
+    /// Checks that image pixels map to window positions and back through a zoomed and panned
+    /// view.
+    #[test]
+    fn view_round_trip() {
+        let display_size = ScreenSize::new(400, 300);
+        let image_size = ScreenSize::new(200, 150);
+        let origin = CoordinateOrigin::TopLeft;
+
+        for &(zoom, cursor) in &[(1.0, (0, 0)), (2.0, (100, 50)), (6.5, (399, 299))] {
+            let view = View::default().zoomed(zoom, cursor.0, cursor.1, display_size);
+            assert!(view.scale == zoom);
+            for &(x, y) in &[(0, 0), (123, 45), (199, 149), (100, 75)] {
+                let point = ScreenPoint::new(x, y);
+                let (display_x, display_y) =
+                    image_to_window(point, display_size, image_size, origin);
+                let (window_x, window_y) = view.display_to_window(display_x, display_y);
+                let (display_x, display_y) = view.window_to_display(window_x, window_y);
+                assert!(
+                    window_to_image(display_x, display_y, display_size, image_size, origin)
+                        == Some(point)
+                );
+            }
+        }
+    }
+
+    /// Checks that zooming keeps the point under the cursor in place, and that zoom and pan are
+    /// clamped so that the image covers the window.
+    #[test]
+    fn view_zoom_and_pan() {
+        let display_size = ScreenSize::new(400, 300);
+
+        let view = View::default().zoomed(2.0, 100, 50, display_size);
+        assert!(view.window_to_display(100, 50) == (100, 50));
+        assert!(view.window_to_display(0, 0) == (50, 25));
+        assert!(
+            view.rect(sdl2::rect::Rect::new(0, 0, 400, 300))
+                == sdl2::rect::Rect::new(-100, -50, 800, 600)
+        );
+
+        let panned = view.panned(1000, -1000, display_size);
+        assert!(panned.offset_x == 0.0);
+        assert!(panned.offset_y == -300.0);
+
+        assert!(View::default().zoomed(0.5, 10, 10, display_size) == View::default());
+        assert!(view.zoomed(1000.0, 0, 0, display_size).scale == MAX_ZOOM);
+    }
+
+    /// Checks that a premultiplied semi-transparent edge, converted to straight alpha and blended
+    /// normally, looks the same as when composited as premultiplied.
+    #[test]
+    fn unpremultiplied_edge_composites_correctly() {
+        let premultiplied = [
+            image::Rgba([64, 0, 32, 128]),
+            image::Rgba([200, 100, 50, 255]),
+            image::Rgba([10, 5, 0, 20]),
+            image::Rgba([0, 0, 0, 0]),
+        ];
+        let mut img = image::RgbaImage::from_fn(4, 1, |x, _| premultiplied[x as usize]);
+        unpremultiply(&mut img);
+
+        assert!(img.get_pixel(0, 0) == &image::Rgba([128, 0, 64, 128]));
+        assert!(img.get_pixel(1, 0) == &premultiplied[1]);
+        assert!(img.get_pixel(3, 0) == &image::Rgba([0, 0, 0, 0]));
+
+        for background in &[0u8, 100, 255] {
+            for (straight, premultiplied) in img.pixels().zip(&premultiplied) {
+                let alpha = straight[3] as f64 / 255.0;
+                for i in 0..3 {
+                    let blended = straight[i] as f64 * alpha + *background as f64 * (1.0 - alpha);
+                    let expected = premultiplied[i] as f64 + *background as f64 * (1.0 - alpha);
+                    assert!((blended - expected).abs() <= 1.0);
+                }
+            }
+        }
+    }
+
+    /// Checks that pixels left of the wipe are taken from the image and pixels right of it
+    /// from the comparison image.
+    #[proptest]
+    fn wipe_split(wipe_x: i8, x: u8) {
+        let wipe_x = wipe_x as i32;
+        let size = ScreenSize::new(100, 30);
+        let x = x as u32 % size.width;
+        let point = sdl2::rect::Point::new(x as i32, 10);
+        let (left, right) = wipe_clip_rects(wipe_x, size);
+        let in_left = left.is_some_and(|rect| rect.contains_point(point));
+        let in_right = right.is_some_and(|rect| rect.contains_point(point));
+        assert!(in_left == ((x as i32) < wipe_x));
+        assert!(in_right != in_left);
+    }
+
+    /// Checks the blocks per second of the benchmark overlay for blocks arriving at a known
+    /// rate.
+    #[test]
+    fn benchmark_block_rate() {
+        let start = std::time::Instant::now();
+        let mut block_rate = BlockRate::new();
+        assert!(block_rate.per_second(start) == 0.0);
+
+        // 40 blocks per second for 3 seconds
+        let interval = std::time::Duration::from_millis(25);
+        for i in 0..120 {
+            block_rate.record(start + interval * i);
+        }
+        let now = start + interval * 119;
+        assert!(block_rate.per_second(now) == 40.0);
+
+        // Nothing arrived during the last second
+        assert!(block_rate.per_second(now + BLOCK_RATE_WINDOW) == 0.0);
+    }
+
+    #[test]
+    fn benchmark_overlay_text() {
+        let text = benchmark_text(
+            std::time::Duration::from_micros(16_700),
+            std::time::Duration::from_millis(12),
+            40.0,
+        );
+        assert!(text == "16.7MS 12.0AVG 40B/S");
+        assert!(text
+            .chars()
+            .all(|c| c == ' ' || BENCHMARK_FONT.iter().any(|(glyph, _)| *glyph == c)));
+
+        // Box and the eight pixels of "1"
+        let rects = text_rects("1", 1.0);
+        assert!(rects.len() == 1 + 8);
+        assert!(rects[1..]
+            .iter()
+            .all(|(rect, _)| rects[0].0.contains_rect(*rect)));
+    }
+
+    /// Checks that the auto fitted window never exceeds the display bounds and only shrinks
+    /// images that don't fit.
+    #[proptest]
+    fn auto_fit_within_bounds(width: u16, height: u16, bound_width: u16, bound_height: u16) {
+        let display_size = ScreenSize::new(width as u32 + 1, height as u32 + 1);
+        let bounds = ScreenSize::new(bound_width as u32 + 1, bound_height as u32 + 1);
+        let fitted = auto_fit_size(display_size, bounds);
+
+        assert!(fitted.width >= 1 && fitted.height >= 1);
+        assert!(fitted.width <= bounds.width && fitted.height <= bounds.height);
+        if display_size.width <= bounds.width && display_size.height <= bounds.height {
+            assert!(fitted == display_size);
+        }
+    }
+
+    #[test]
+    fn auto_fit_integer_factor() {
+        let bounds = ScreenSize::new(1920, 1040);
+        assert!(auto_fit_size(ScreenSize::new(4000, 3000), bounds) == ScreenSize::new(1333, 1000));
+        assert!(auto_fit_size(ScreenSize::new(1920, 1080), bounds) == ScreenSize::new(960, 540));
+        assert!(auto_fit_size(ScreenSize::new(800, 600), bounds) == ScreenSize::new(800, 600));
+    }
+
+    /// Checks that the scale quality flips at the threshold zoom.
+    #[test]
+    fn scale_quality_threshold() {
+        assert!(ScaleQuality::for_zoom(1.0, 2.0) == ScaleQuality::Linear);
+        assert!(ScaleQuality::for_zoom(1.99, 2.0) == ScaleQuality::Linear);
+        assert!(ScaleQuality::for_zoom(2.0, 2.0) == ScaleQuality::Nearest);
+        assert!(ScaleQuality::for_zoom(8.0, 2.0) == ScaleQuality::Nearest);
+
+        let mut view = View::default();
+        let display_size = ScreenSize::new(400, 300);
+        let mut qualities = Vec::new();
+        for _ in 0..5 {
+            qualities.push(ScaleQuality::for_zoom(view.scale, 2.0));
+            view = view.zoomed(ZOOM_STEP, 200, 150, display_size);
+        }
+        // 1.25^3 = 1.95, 1.25^4 = 2.44
+        assert!(
+            qualities
+                == vec![
+                    ScaleQuality::Linear,
+                    ScaleQuality::Linear,
+                    ScaleQuality::Linear,
+                    ScaleQuality::Linear,
+                    ScaleQuality::Nearest
+                ]
+        );
+    }
+
+    /// Checks that responses to events within a frame are merged into one.
+    #[test]
+    fn event_responses_merge() {
+        use EventResponse::*;
+        let a = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(4, 4));
+        let b = ScreenBlock::new(ScreenPoint::new(10, 2), ScreenPoint::new(12, 8));
+        let ab = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(12, 8));
+
+        assert!(Nothing.merge(Nothing) == Nothing);
+        assert!(Nothing.merge(Redraw) == Redraw);
+        assert!(Redraw.merge(UpdateBlock(a)) == UpdateBlock(a));
+        assert!(UpdateBlock(a).merge(Nothing) == UpdateBlock(a));
+        assert!(UpdateBlock(a).merge(UpdateBlock(b)) == UpdateBlock(ab));
+        assert!(UpdateBlock(a).merge(UpdateAll) == UpdateAll);
+        assert!(UpdateAll.merge(RecreateTextures) == RecreateTextures);
+        assert!(RecreateTextures.merge(Close) == Close);
+        assert!(Close.merge(UpdateBlock(b)) == Close);
+    }
+
+    /// Checks that setting the flag of the signal dump writes the current image as PPM.
+    #[test]
+    fn signal_dump_writes_image() {
+        let img = image::RgbaImage::from_fn(7, 5, |x, y| image::Rgba([x as u8, y as u8, 9, 128]));
+        let img = std::sync::Arc::new(lock::Mutex::new(img));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.ppm");
+        let triggered = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let mut dump = SignalDump::start(img.clone(), path.clone(), triggered.clone(), false);
+        std::thread::sleep(SIGNAL_POLL_INTERVAL);
+        assert!(!path.exists());
+        triggered.store(true, std::sync::atomic::Ordering::SeqCst);
+        while dump
+            .thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+        {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        dump.stop().unwrap();
+
+        let written = image::open(&path).unwrap();
+        let written = written.as_rgb8().unwrap();
+        assert!(written.dimensions() == (7, 5));
+        assert!(written.get_pixel(3, 2) == &image::Rgb([3, 2, 9]));
+    }
+
+    /// Checks that a signal dump that wasn't triggered doesn't write anything.
+    #[test]
+    fn signal_dump_not_triggered() {
+        let img = std::sync::Arc::new(lock::Mutex::new(image::RgbaImage::new(3, 3)));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.ppm");
+        let triggered = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        drop(SignalDump::start(img, path.clone(), triggered, false));
+        assert!(!path.exists());
+    }
+
+    /// Checks that pixels with the key color composite as transparent over the checkerboard,
+    /// both at full resolution and downsampled.
+    #[test]
+    fn color_key_is_transparent() {
+        let key = image::Rgb([255, 0, 255]);
+        let magenta = image::Rgba([255, 0, 255, 255]);
+        let gray = image::Rgba([100, 100, 100, 255]);
+        let mut img = image::RgbaImage::from_fn(4, 2, |x, _| if x < 2 { magenta } else { gray });
+
+        let (_, downsampled) = downsample_block(
+            &img,
+            ScreenBlock::from_size(ScreenSize::new(4, 2)),
+            ScreenSize::new(2, 1),
+            Some(key),
+        );
+        assert!(downsampled.get_pixel(0, 0) == &image::Rgba([0, 0, 0, 0]));
+        assert!(downsampled.get_pixel(1, 0) == &gray);
+
+        apply_color_key(&mut img, Some(key));
+        for checker in &[40u8, 200] {
+            for (x, pixel) in img.enumerate_pixels().map(|(x, _, pixel)| (x, pixel)) {
+                let alpha = pixel[3] as u32;
+                let composited = (pixel[0] as u32 * alpha + *checker as u32 * (255 - alpha)) / 255;
+                let expected = if x < 2 { *checker as u32 } else { 100 };
+                assert!(composited == expected);
+            }
+        }
+    }
+
+    /// Checks that blocks within the interval are merged and released after it elapses.
+    #[test]
+    fn coalescer_merges_blocks() {
+        let block =
+            |x0, y0, x1, y1| ScreenBlock::new(ScreenPoint::new(x0, y0), ScreenPoint::new(x1, y1));
+        let start = std::time::Instant::now();
+        let ms = std::time::Duration::from_millis;
+        let mut coalescer = Coalescer::new(ms(10));
+
+        assert!(coalescer.add(block(0, 0, 4, 4), start) == Some(block(0, 0, 4, 4)));
+        assert!(coalescer.add(block(4, 0, 8, 4), start + ms(2)).is_none());
+        assert!(coalescer.add(block(0, 4, 4, 8), start + ms(5)).is_none());
+        assert!(coalescer.add(block(8, 8, 9, 9), start + ms(11)) == Some(block(0, 0, 9, 9)));
+        assert!(coalescer.flush().is_none());
+
+        assert!(coalescer.add(block(1, 1, 2, 2), start + ms(12)).is_none());
+        assert!(coalescer.flush() == Some(block(1, 1, 2, 2)));
+        assert!(coalescer.flush().is_none());
+    }
+
+    /// Checks that a block written before pumping is shown, and that pump reports closing.
+    #[test]
+    #[ignore]
+    fn test_pump() {
+        use image_buffer::ImageBuffer;
+
+        let window = ImageWindow::new("ImageWindow pump test", 20, 10).unwrap();
+        let block = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(5, 5));
+        let red = image::Rgba([255, 0, 0, 255]);
+        window
+            .make_writer()
+            .write(block, &image::RgbaImage::from_pixel(5, 5, red))
+            .unwrap();
+
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(window.snapshot().get_pixel(2, 2) == &red);
+        assert!(window.frame_stats().count == 1);
+
+        window.make_closer().close().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Closed);
+    }
+
+    /// Checks that the idle callback runs in pumped mode when there are no events, and that
+    /// stopping from it closes the window.
+    #[test]
+    #[ignore]
+    fn test_idle_callback() {
+        let interval = std::time::Duration::from_millis(20);
+        let mut window = ImageWindow::new("ImageWindow idle test", 20, 10).unwrap();
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let calls_clone = calls.clone();
+        window.set_idle_callback(
+            Some(Box::new(move || {
+                calls_clone.set(calls_clone.get() + 1);
+                if calls_clone.get() < 2 {
+                    parallel_for_each::Continue::Continue
+                } else {
+                    parallel_for_each::Continue::Stop
+                }
+            })),
+            interval,
+        );
+
+        // Let the events from opening the window settle
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        while window.pump().unwrap() == PumpStatus::Open && calls.get() == 0 {
+            std::thread::sleep(interval / 4);
+        }
+        assert!(calls.get() == 1);
+
+        std::thread::sleep(interval);
+        let mut status = window.pump().unwrap();
+        while status == PumpStatus::Open {
+            std::thread::sleep(interval / 4);
+            status = window.pump().unwrap();
+        }
+        assert!(calls.get() == 2);
+    }
+
+    /// Checks that changing gamma and invalidating updates what is drawn in the window.
+    #[test]
+    #[ignore]
+    fn test_invalidate() {
+        let mut window = ImageWindow::new("ImageWindow invalidate test", 4, 4).unwrap();
+        window
+            .set_image(image::RgbaImage::from_pixel(
+                4,
+                4,
+                image::Rgba([64, 64, 64, 255]),
+            ))
+            .unwrap();
+        let center_pixel = |window: &ImageWindow| {
+            let pumped = window.pumped.lock();
+            let canvas = &pumped.as_ref().unwrap().state.canvas;
+            let pixels = canvas
+                .read_pixels(sdl2::rect::Rect::new(2, 2, 1, 1), SDL_PIXEL_FORMAT)
+                .unwrap();
+            pixels[0]
+        };
+
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(center_pixel(&window) == 64);
+
+        window.set_display_transform(DisplayTransform {
+            gamma: 2.0,
+            ..DisplayTransform::default()
+        });
+        window.invalidate().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(center_pixel(&window) == 128);
+
+        window.make_closer().close().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Closed);
+    }
+
+    /// Returns the type and data of all chunks of a PNG file.
+    fn png_chunks(bytes: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut rest = &bytes[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let chunk_type = [rest[4], rest[5], rest[6], rest[7]];
+            chunks.push((chunk_type, rest[8..8 + length].to_vec()));
+            rest = &rest[12 + length..];
+        }
+        chunks
+    }
+
+    /// Checks that saved PNGs are tagged as sRGB by default, or carry the ICC profile if one
+    /// is set, before the image data. The image itself must be unchanged.
+    #[test]
+    fn png_color_profile_chunks() {
+        let img = image::RgbaImage::from_fn(5, 3, |x, y| {
+            image::Rgba([x as u8 * 50, y as u8 * 80, 7, 255 - x as u8])
+        });
+        let file = tempfile::Builder::new()
+            .suffix(".png")
+            .tempfile()
+            .unwrap()
+            .into_temp_path();
+        let chunk_types = |chunks: &[([u8; 4], Vec<u8>)]| -> Vec<[u8; 4]> {
+            chunks.iter().map(|(chunk_type, _)| *chunk_type).collect()
+        };
+
+        write_png(&file, &img, None).unwrap();
+        let chunks = png_chunks(&std::fs::read(&file).unwrap());
+        assert!(chunk_types(&chunks) == vec![*b"IHDR", *b"sRGB", *b"IDAT", *b"IEND"]);
+        assert!(chunks[1].1 == vec![0]);
+        assert!(image::open(&file).unwrap().as_rgba8() == Some(&img));
+
+        let profile: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        write_png(&file, &img, Some(&profile)).unwrap();
+        let chunks = png_chunks(&std::fs::read(&file).unwrap());
+        assert!(chunk_types(&chunks) == vec![*b"IHDR", *b"iCCP", *b"IDAT", *b"IEND"]);
+        assert!(chunks[1].1 == iccp_chunk_data(&profile));
+        assert!(chunks[1].1.starts_with(b"ICC Profile\0\0"));
+        assert!(image::open(&file).unwrap().as_rgba8() == Some(&img));
+    }
+
+    /// Checks that the spinner dots are around the center and that the brightest dot moves
+    /// clockwise with time.
+    #[test]
+    fn spinner_animation() {
+        let size = ScreenSize::new(240, 120);
+        let brightest = |elapsed| {
+            let rects = spinner_rects(size, elapsed);
+            assert!(rects.len() == SPINNER_DOTS as usize);
+            for (rect, _) in &rects {
+                let distance =
+                    ((rect.center().x() - 120).pow(2) + (rect.center().y() - 60).pow(2)) as f64;
+                assert!((distance.sqrt() - 10.0).abs() <= 1.5);
+            }
+            let (rect, color) = *rects.iter().max_by_key(|(_, color)| color.a).unwrap();
+            assert!(color.a == 255);
+            rect.center()
+        };
+
+        assert!(brightest(std::time::Duration::from_secs(0)) == sdl2::rect::Point::new(120, 50));
+        assert!(
+            brightest(SPINNER_PERIOD / 4 + std::time::Duration::from_millis(1))
+                == sdl2::rect::Point::new(130, 60)
+        );
+        assert!(brightest(SPINNER_PERIOD * 3) == sdl2::rect::Point::new(120, 50));
+    }
+
+    /// Checks that the startup spinner is shown after opening and cleared by the first block.
+    #[test]
+    #[ignore]
+    fn test_startup_spinner() {
+        use image_buffer::ImageBuffer;
+
+        let mut window = ImageWindow::new("ImageWindow spinner test", 20, 10).unwrap();
+        window.set_startup_spinner(true);
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(window
+            .pumped
+            .lock()
+            .as_ref()
+            .unwrap()
+            .state
+            .spinner_since
+            .is_some());
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(window
+            .pumped
+            .lock()
+            .as_ref()
+            .unwrap()
+            .state
+            .spinner_since
+            .is_some());
+
+        let block = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(5, 5));
+        window
+            .make_writer()
+            .write(block, &image::RgbaImage::new(5, 5))
+            .unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(window
+            .pumped
+            .lock()
+            .as_ref()
+            .unwrap()
+            .state
+            .spinner_since
+            .is_none());
+
+        window.make_closer().close().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Closed);
+    }
+
+    /// Checks that a window opened hidden is shown only once the first block is presented.
+    #[test]
+    #[ignore]
+    fn test_start_hidden() {
+        use image_buffer::ImageBuffer;
+
+        let is_hidden = |window: &ImageWindow| {
+            let flags = window
+                .pumped
+                .lock()
+                .as_ref()
+                .unwrap()
+                .state
+                .canvas
+                .window()
+                .window_flags();
+            flags & sdl2::sys::SDL_WindowFlags::SDL_WINDOW_HIDDEN as u32 != 0
+        };
+
+        let mut window = ImageWindow::new("ImageWindow start hidden test", 20, 10).unwrap();
+        window.set_start_hidden(true);
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(is_hidden(&window));
+        assert!(window.frame_stats().count == 0);
+
+        window
+            .context
+            .event()
+            .unwrap()
+            .push_event(sdl2::event::Event::Window {
+                timestamp: 0,
+                window_id: 0,
+                win_event: sdl2::event::WindowEvent::Exposed,
+            })
+            .unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(is_hidden(&window));
+        assert!(window.frame_stats().count == 0);
+
+        let block = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(5, 5));
+        window
+            .make_writer()
+            .write(
+                block,
+                &image::RgbaImage::from_pixel(5, 5, image::Rgba([255, 0, 0, 255])),
+            )
+            .unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(!is_hidden(&window));
+        assert!(window.frame_stats().count == 1);
+
+        window.make_closer().close().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Closed);
+    }
+
+    /// Checks that the window survives a renderer reset and keeps the image.
+    #[test]
+    #[ignore]
+    fn test_render_device_reset() {
+        use image_buffer::ImageBuffer;
+
+        let window = ImageWindow::new("ImageWindow device reset test", 20, 10).unwrap();
+        let block = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(5, 5));
+        let red = image::Rgba([255, 0, 0, 255]);
+        window
+            .make_writer()
+            .write(block, &image::RgbaImage::from_pixel(5, 5, red))
+            .unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+
+        window
+            .context
+            .event()
+            .unwrap()
+            .push_event(sdl2::event::Event::RenderDeviceReset { timestamp: 0 })
+            .unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Open);
+        assert!(window.snapshot().get_pixel(2, 2) == &red);
+        assert!(window.frame_stats().count == 2);
+
+        window.make_closer().close().unwrap();
+        assert!(window.pump().unwrap() == PumpStatus::Closed);
+    }
+
+    /// Renders a gradient through render_blocks and checks the resulting image.
+    /// The window is closed by the worker rendering the last block.
+    #[test]
+    #[ignore]
+    fn test_render_blocks() {
+        use crate::screen_block::ScreenBlockExt;
+
+        let size = ScreenSize::new(64, 48);
+        let gradient = |x: u32, y: u32| image::Rgba([(x * 4) as u8, (y * 5) as u8, 128, 255]);
+        let window =
+            ImageWindow::new("ImageWindow render_blocks test", size.width, size.height).unwrap();
+        let closer = lock::Mutex::new(window.make_closer());
+        let block_count = ScreenBlock::from_size(size).spiral_chunks(16).count();
+        let rendered = std::sync::atomic::AtomicUsize::new(0);
+
+        window
+            .render_blocks(
+                ScreenBlock::from_size(size).spiral_chunks(16),
+                |block| {
+                    let img = image::RgbaImage::from_fn(block.width(), block.height(), |x, y| {
+                        gradient(x + block.min.x, y + block.min.y)
+                    });
+                    if rendered.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+                        == block_count
+                    {
+                        closer.lock().close().unwrap();
+                    }
+                    img
+                },
+                parallel_for_each::WorkerCount::Auto,
+            )
+            .unwrap();
+
+        assert!(window.snapshot() == image::RgbaImage::from_fn(size.width, size.height, gradient));
+    }
+
+    /// Writes many small blocks quickly with `PresentMode::FrameComplete`, checks that the
+    /// final image is complete and that there were at most as many frames as display refreshes.
+    #[test]
+    #[ignore]
+    fn test_frame_complete_stress() {
+        use crate::screen_block::ScreenBlockExt;
+        use image_buffer::ImageBuffer;
+
+        let size = ScreenSize::new(200, 150);
+        let pattern = |x: u32, y: u32| image::Rgba([x as u8, y as u8, (x ^ y) as u8, 255]);
+        let mut window = ImageWindow::new("ImageWindow frame complete test", 200, 150).unwrap();
+        window.set_present_mode(PresentMode::FrameComplete);
+        let refresh_rate = match window.context.video().unwrap().current_display_mode(0) {
+            Ok(mode) if mode.refresh_rate > 0 => mode.refresh_rate as f64,
+            _ => 60.0,
+        };
+
+        let writer = window.make_writer();
+        let closer = window.make_closer();
+        let start = std::time::Instant::now();
+        crossbeam_utils::thread::scope(|scope| {
+            scope.spawn(|_| {
+                for block in ScreenBlock::from_size(size).spiral_chunks(4) {
+                    let block_buffer = image::RgbaImage::from_fn(4, 4, |x, y| {
+                        pattern(x + block.min.x, y + block.min.y)
+                    });
+                    writer.write(block, &block_buffer).unwrap();
+                }
+                writer.finished().unwrap();
+                closer.close().unwrap();
+            });
+            window.run().unwrap();
+        })
+        .unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        assert!(window.snapshot() == image::RgbaImage::from_fn(200, 150, pattern));
+        assert!(window.frame_stats().count as f64 <= (elapsed * refresh_rate).ceil() + 1.0);
+    }
+
+    /// Checks that two writers with different settings both write into the image.
+    #[test]
+    #[ignore]
+    fn test_multiple_writers() {
+        use image_buffer::ImageBufferWriter;
+
+        let window = ImageWindow::new("ImageWindow multiple writers test", 20, 10).unwrap();
+        let immediate = window.make_writer_with_settings(WriterSettings::default());
+        let coalescing = window.make_writer_with_settings(WriterSettings {
+            coalesce_interval: Some(std::time::Duration::from_secs(1)),
+            ..Default::default()
+        });
+
+        let left = ScreenBlock::new(ScreenPoint::new(0, 0), ScreenPoint::new(10, 10));
+        let right = ScreenBlock::new(ScreenPoint::new(10, 0), ScreenPoint::new(20, 10));
+        let red = image::Rgba([255, 0, 0, 255]);
+        let blue = image::Rgba([0, 0, 255, 255]);
+        immediate
+            .write(left, &image::RgbaImage::from_pixel(10, 10, red))
+            .unwrap();
+        coalescing
+            .write(right, &image::RgbaImage::from_pixel(10, 10, blue))
+            .unwrap();
+        coalescing.finished().unwrap();
+
+        let snapshot = window.snapshot();
+        assert!(snapshot.get_pixel(5, 5) == &red);
+        assert!(snapshot.get_pixel(15, 5) == &blue);
+    }
 
     #[test]
     #[ignore]
@@ -0,0 +1,206 @@
+/// Locks used by the rest of the crate: parking_lot's with the `parking_lot` feature (enabled by
+/// default), otherwise std wrapped to the same interface. Tests built with `--cfg loom` always use
+/// the wrapper, around loom's locks.
+/// Only the parts of the parking_lot interface used in the crate are provided.
+/// The test suite should pass with both, e.g. `cargo test` and
+/// `cargo test --no-default-features --features gui`.
+#[cfg(all(feature = "parking_lot", not(all(test, loom))))]
+pub use parking_lot::{Condvar, Mutex, MutexGuard};
+#[cfg(not(all(feature = "parking_lot", not(all(test, loom)))))]
+pub use std_lock::{Condvar, Mutex, MutexGuard};
+
+/// std::sync locks with the parking_lot interface: no poisoning (panics are handled by the
+/// users of the locks) and guards that can be unlocked temporarily.
+/// Always compiled, so that its tests run with parking_lot enabled too.
+#[cfg_attr(all(feature = "parking_lot", not(all(test, loom))), allow(dead_code))]
+mod std_lock {
+    #[cfg(all(test, loom))]
+    use loom::sync as imp;
+    #[cfg(not(all(test, loom)))]
+    use std::sync as imp;
+
+    use std::sync::{PoisonError, TryLockError};
+
+    #[derive(Debug, Default)]
+    pub struct Mutex<T>(imp::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub fn new(value: T) -> Mutex<T> {
+            Mutex(imp::Mutex::new(value))
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            MutexGuard {
+                mutex: &self.0,
+                guard: Some(self.0.lock().unwrap_or_else(PoisonError::into_inner)),
+            }
+        }
+
+        pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+            let guard = match self.0.try_lock() {
+                Ok(guard) => guard,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => return None,
+            };
+            Some(MutexGuard {
+                mutex: &self.0,
+                guard: Some(guard),
+            })
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    /// Guard of Mutex, the inner guard is only None while unlocked.
+    pub struct MutexGuard<'a, T> {
+        mutex: &'a imp::Mutex<T>,
+        guard: Option<imp::MutexGuard<'a, T>>,
+    }
+
+    impl<'a, T> MutexGuard<'a, T> {
+        /// Unlocks the mutex for the duration of `f`, relocks it afterwards (even when `f`
+        /// panics).
+        pub fn unlocked<R>(this: &mut Self, f: impl FnOnce() -> R) -> R {
+            this.guard = None;
+            let mutex = this.mutex;
+            let _relock = scopeguard::guard(&mut this.guard, |guard| {
+                *guard = Some(mutex.lock().unwrap_or_else(PoisonError::into_inner))
+            });
+            f()
+        }
+    }
+
+    impl<'a, T> std::ops::Deref for MutexGuard<'a, T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            self.guard.as_ref().unwrap()
+        }
+    }
+
+    impl<'a, T> std::ops::DerefMut for MutexGuard<'a, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            self.guard.as_mut().unwrap()
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub struct Condvar(imp::Condvar);
+
+    impl Condvar {
+        pub fn new() -> Condvar {
+            Condvar(imp::Condvar::new())
+        }
+
+        pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+            let inner = guard.guard.take().unwrap();
+            guard.guard = Some(self.0.wait(inner).unwrap_or_else(PoisonError::into_inner));
+        }
+
+        pub fn wait_for<T>(
+            &self,
+            guard: &mut MutexGuard<'_, T>,
+            timeout: std::time::Duration,
+        ) -> imp::WaitTimeoutResult {
+            let inner = guard.guard.take().unwrap();
+            let (inner, result) = self
+                .0
+                .wait_timeout(inner, timeout)
+                .unwrap_or_else(PoisonError::into_inner);
+            guard.guard = Some(inner);
+            result
+        }
+
+        pub fn notify_one(&self) {
+            self.0.notify_one()
+        }
+
+        pub fn notify_all(&self) {
+            self.0.notify_all()
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    /// Checks that the std mutex can be locked by others while unlocked, and is locked
+    /// again afterwards, even after a panic.
+    #[test]
+    fn std_mutex_unlocked() {
+        let mutex = std_lock::Mutex::new(0);
+        let mut guard = mutex.lock();
+        *guard += 1;
+        std_lock::MutexGuard::unlocked(&mut guard, || {
+            crossbeam_utils::thread::scope(|scope| {
+                scope.spawn(|_| *mutex.lock() += 10);
+            })
+            .unwrap()
+        });
+        assert!(*guard == 11);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            std_lock::MutexGuard::unlocked(&mut guard, || panic!("Panic while unlocked"))
+        }));
+        assert!(result.is_err());
+        *guard += 100;
+        assert!(*guard == 111);
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert!(*mutex.lock() == 111);
+    }
+
+    /// Checks that a panic while locked doesn't poison the std mutex.
+    #[test]
+    fn std_mutex_ignores_poison() {
+        let mutex = std_lock::Mutex::new(1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = mutex.lock();
+            panic!("Panic while locked");
+        }));
+        assert!(result.is_err());
+        *mutex.lock() += 1;
+        assert!(mutex.into_inner() == 2);
+    }
+
+    /// Checks that waiting on the std condvar releases the lock and times out.
+    #[test]
+    fn std_condvar_wait() {
+        let state = (std_lock::Mutex::new(false), std_lock::Condvar::new());
+        let mut done = state.0.lock();
+        assert!(state
+            .1
+            .wait_for(&mut done, std::time::Duration::from_millis(1))
+            .timed_out());
+
+        crossbeam_utils::thread::scope(|scope| {
+            scope.spawn(|_| {
+                *state.0.lock() = true;
+                state.1.notify_all();
+            });
+            while !*done {
+                state.1.wait(&mut done);
+            }
+        })
+        .unwrap();
+        assert!(*done);
+    }
+
+    /// The interface in use compiles with both backends.
+    #[test]
+    fn selected_backend() {
+        let mutex = Mutex::new(vec![1]);
+        let cond = Condvar::new();
+        let mut guard = mutex.lock();
+        MutexGuard::unlocked(&mut guard, || mutex.lock().push(2));
+        cond.notify_one();
+        assert!(*guard == [1, 2]);
+    }
+}
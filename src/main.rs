@@ -1,11 +1,18 @@
 #![feature(specialization)]
+#![cfg_attr(test, feature(test))]
 
+#[cfg(test)]
+extern crate test;
+
+mod block_stream;
 mod camera;
+mod console_progress;
 mod geometry;
 mod image_buffer;
 mod image_file_buffer;
 #[cfg(feature = "gui")]
 mod image_window;
+mod lock;
 mod parallel_for_each;
 mod renderer;
 mod scene;
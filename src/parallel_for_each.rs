@@ -1,9 +1,21 @@
-use crossbeam_utils;
 use num_cpus;
-use parking_lot;
 use scopeguard;
 
 use std::num::NonZeroUsize;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::lock;
+use crate::util;
+
+/// How often does the run started by `spawn` check for stop requests.
+const SPAWN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// `Batching::Auto` grows the batch while processing it takes less than this, which is long
+/// enough to make the locking overhead negligible.
+const AUTO_BATCH_DURATION: Duration = Duration::from_micros(20);
 
 #[must_use]
 #[derive(Copy, Clone, Debug)]
@@ -18,6 +30,498 @@ pub enum WorkerCount {
     Manual(NonZeroUsize),
 }
 
+impl WorkerCount {
+    /// Returns the number of worker threads, calling `detector` to get the count for `Auto`.
+    /// At least one worker is always used.
+    pub fn resolve_with(self, detector: impl FnOnce() -> usize) -> usize {
+        match self {
+            WorkerCount::Auto => detector().max(1),
+            WorkerCount::Manual(num) => num.get(),
+        }
+    }
+}
+
+/// Returns the number of worker threads that will be used for a worker count setting.
+/// `Auto` uses one worker per logical CPU.
+pub fn resolve_worker_count(worker_count: WorkerCount) -> usize {
+    worker_count.resolve_with(detect_worker_count)
+}
+
+#[cfg(not(test))]
+fn detect_worker_count() -> usize {
+    num_cpus::get()
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Worker count used for `Auto` in tests instead of the number of CPUs.
+    static AUTO_WORKER_COUNT_OVERRIDE: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+#[cfg(test)]
+fn detect_worker_count() -> usize {
+    AUTO_WORKER_COUNT_OVERRIDE
+        .with(|value| value.get())
+        .unwrap_or_else(num_cpus::get)
+}
+
+/// Source of the current time for the lock metrics.
+type LockClockFn = dyn Fn() -> std::time::Instant + Send + Sync;
+
+#[cfg(not(test))]
+fn lock_metrics_clock() -> Arc<LockClockFn> {
+    Arc::new(std::time::Instant::now)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Clock used for the lock metrics of runs started on this thread in tests.
+    static LOCK_METRICS_CLOCK_OVERRIDE: std::cell::RefCell<Option<Arc<LockClockFn>>> =
+        std::cell::RefCell::new(None);
+}
+
+#[cfg(test)]
+fn lock_metrics_clock() -> Arc<LockClockFn> {
+    LOCK_METRICS_CLOCK_OVERRIDE
+        .with(|clock| clock.borrow().clone())
+        .unwrap_or_else(|| Arc::new(std::time::Instant::now))
+}
+
+/// How is the background function called.
+#[derive(Copy, Clone, Debug)]
+pub enum BackgroundMode {
+    /// Background function is called once, if it returns `Continue::Stop` the workers are stopped.
+    /// Then the workers are joined.
+    GateThenJoin,
+    /// Background function is called repeatedly with the given interval, until all workers have
+    /// finished. The last call always happens after the workers are finished.
+    /// Returning `Continue::Stop` stops the workers, but the polling still continues until they
+    /// actually finish.
+    PollUntilDone(Duration),
+    /// Background function is called repeatedly with the given interval until it returns
+    /// `Continue::Stop`, regardless of whether the workers have finished already.
+    PollUntilStop(Duration),
+}
+
+/// What happens when a worker thread panics.
+#[derive(Copy, Clone, Debug)]
+pub enum PanicPolicy {
+    /// The panic is resumed in the calling thread.
+    Propagate,
+    /// The panic is converted to `ParallelForEachError::WorkerPanicked`.
+    CaptureAsError,
+}
+
+/// What happens when a worker function returns an error.
+#[derive(Copy, Clone, Debug)]
+pub enum ErrorPolicy {
+    /// The run is stopped on the first error, which is returned as `WorkerTaskError`.
+    FailFast,
+    /// The workers keep going until the given number of errors, then the run is stopped and the
+    /// collected errors are returned as `WorkerTaskErrors`.
+    /// Errors from items that were already in progress when the limit was reached are dropped.
+    StopAfter(NonZeroUsize),
+    /// Same as `StopAfter`, but each collected error is returned together with the index of the
+    /// item that failed (its position in the iterator), as `IndexedWorkerTaskErrors`.
+    Collect(NonZeroUsize),
+}
+
+impl ErrorPolicy {
+    /// Number of worker errors after which the run stops.
+    fn stopping_error_count(self) -> usize {
+        match self {
+            ErrorPolicy::FailFast => 1,
+            ErrorPolicy::StopAfter(limit) | ErrorPolicy::Collect(limit) => limit.get(),
+        }
+    }
+}
+
+/// Which error is returned when several workers fail.
+#[derive(Copy, Clone, Debug)]
+pub enum ErrorSelection {
+    /// First error in the order in which the workers are joined (by worker id).
+    /// Which of nearly simultaneous errors gets returned depends on the timing.
+    First,
+    /// Error of the item that came first from the iterator, for reproducible results.
+    /// Errors not related to an item (init errors, captured panics) are only returned if no
+    /// item failed.
+    LowestIndex,
+}
+
+/// Which thread calls the finished callback.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FinishedCallbackThread {
+    /// The last worker to finish calls it as soon as it is done, while the other workers may
+    /// still be exiting and the background function may still be running.
+    LastWorker,
+    /// The calling thread calls it after all workers are joined, unless a worker panic is
+    /// propagated.
+    Caller,
+}
+
+/// Order in which queued items are processed by parallel_for_each_recursive.
+/// Only the traversal order changes, every item is still processed exactly once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueueOrder {
+    /// Items are processed in the order they were queued (breadth-first for trees).
+    Fifo,
+    /// The most recently queued item is processed first (depth-first for trees), which keeps
+    /// the queue small for deep trees. Initial items are processed starting from the last one.
+    Lifo,
+}
+
+/// How many items a worker takes from the iterator at once.
+/// Taking more items per lock of the iterator helps with very cheap worker functions, at the
+/// cost of coarser load balancing at the end of the run.
+/// Stopping the run (stop from the background function, errors, panics) is checked between the
+/// items of a batch, so the rest of the batch is dropped without calling the worker function on
+/// it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Batching {
+    Single,
+    Fixed(NonZeroUsize),
+    /// Every worker starts with single items and doubles its batch size (up to `max`) while
+    /// a batch takes less than `AUTO_BATCH_DURATION` to process. Halves it again if a batch
+    /// takes much longer.
+    Auto {
+        max: NonZeroUsize,
+    },
+}
+
+/// Scheduling priority of the worker threads, relative to the rest of the process.
+/// Only affects the worker threads, not the calling thread that runs the background function.
+/// Applied by each worker when it starts with the `thread-priority` feature, through the
+/// thread-priority crate (Linux, macOS, Windows and other unixes), as the lowest or highest
+/// priority the system allows for the thread. Without the feature, or if the system refuses the
+/// change (raising the priority usually needs a permission), the workers keep the default
+/// priority.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ThreadPriority {
+    Normal,
+    Low,
+    High,
+}
+
+impl ThreadPriority {
+    /// Applies the priority to the current thread, returns false if it couldn't be changed.
+    #[cfg(feature = "thread-priority")]
+    fn apply(self) -> bool {
+        let priority = match self {
+            ThreadPriority::Normal => return true,
+            ThreadPriority::Low => thread_priority::ThreadPriority::Min,
+            ThreadPriority::High => thread_priority::ThreadPriority::Max,
+        };
+        thread_priority::set_current_thread_priority(priority).is_ok()
+    }
+
+    #[cfg(not(feature = "thread-priority"))]
+    fn apply(self) -> bool {
+        self == ThreadPriority::Normal
+    }
+}
+
+/// Current batch size of a single worker.
+struct BatchSize {
+    size: usize,
+    batching: Batching,
+}
+
+impl BatchSize {
+    fn new(batching: Batching) -> BatchSize {
+        let size = match batching {
+            Batching::Fixed(size) => size.get(),
+            Batching::Single | Batching::Auto { .. } => 1,
+        };
+        BatchSize { size, batching }
+    }
+
+    fn get(&self) -> usize {
+        self.size
+    }
+
+    /// Only the auto batch size needs measuring the batches.
+    fn is_adaptive(&self) -> bool {
+        matches!(self.batching, Batching::Auto { .. })
+    }
+
+    /// Adjusts the auto batch size after processing a batch of `count` items.
+    fn record(&mut self, count: usize, duration: Duration) {
+        let max = match self.batching {
+            Batching::Auto { max } => max.get(),
+            _ => return,
+        };
+        if count < self.size {
+            return; // Incomplete batch at the end of the iterator, nothing to learn
+        }
+        if duration < AUTO_BATCH_DURATION {
+            self.size = (self.size * 2).min(max);
+        } else if duration > AUTO_BATCH_DURATION * 8 {
+            self.size = (self.size / 2).max(1);
+        }
+    }
+}
+
+/// Panic hook that is installed in the worker threads when panics are captured as errors.
+pub enum PanicHook {
+    /// Keep whatever panic hook is globally installed.
+    Default,
+    /// Don't report the captured panics at all.
+    Silent,
+    Custom(Box<dyn Fn(&std::panic::PanicHookInfo) + Sync + Send>),
+}
+
+impl std::fmt::Debug for PanicHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Default => write!(f, "Default"),
+            Self::Silent => write!(f, "Silent"),
+            Self::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// Less commonly used parameters of parallel_for_each.
+#[derive(Debug)]
+pub struct Settings {
+    pub worker_count: WorkerCount,
+    pub background_mode: BackgroundMode,
+    pub panic_policy: PanicPolicy,
+    /// Only used with `PanicPolicy::CaptureAsError`.
+    pub panic_hook: PanicHook,
+    /// Worker threads are named `<thread_name>-<worker id>`.
+    pub thread_name: Option<String>,
+    /// Stack size of the worker threads in bytes, uses the std default if not set.
+    pub stack_size: Option<usize>,
+    /// Priority of the worker threads, see `ThreadPriority` for where it can be changed.
+    pub priority: ThreadPriority,
+    /// Counter increased for every successfully processed item, can be read from the background
+    /// function to show progress.
+    pub progress_counter: Option<Arc<AtomicUsize>>,
+    /// Workers count the processed items locally and only add them to the progress counter
+    /// after this many items (and when they finish), to avoid contention on the counter.
+    /// The counter may therefore lag behind by up to `worker_count * progress_flush_interval`
+    /// items during the run, but it is exact once the finished callback is called.
+    pub progress_flush_interval: NonZeroUsize,
+    /// Measure how long the workers wait for the lock on the shared iterator, see `RunReport`.
+    pub collect_lock_metrics: bool,
+    /// Current activity of each worker is published here, see `WorkerStates`.
+    pub worker_states: Option<Arc<WorkerStates>>,
+    pub error_policy: ErrorPolicy,
+    pub error_selection: ErrorSelection,
+    /// Safety cap on the number of items taken from the iterator, the run stops cleanly once
+    /// it is reached (see `RunReport::item_cap_reached`). Guards against accidentally endless
+    /// runs with infinite iterators.
+    pub max_items: Option<usize>,
+    pub finished_callback_thread: FinishedCallbackThread,
+    /// Only used by parallel_for_each_recursive.
+    pub queue_order: QueueOrder,
+    pub batching: Batching,
+    /// Number of processed items that are excluded from `RunReport::items_processed` and
+    /// `RunReport::elapsed`, to leave out cache-cold effects at the start of benchmarks.
+    /// The items are processed normally.
+    pub warmup_items: usize,
+    /// If a worker spends longer than this on a single item, a watchdog thread stops the run
+    /// and it fails with `ParallelForEachError::Timeout`, unless another worker failed or
+    /// panicked (that error is returned instead).
+    /// This doesn't make the run return promptly: threads can't be killed and the workers
+    /// borrow the closures of the caller, so the run still waits for the stuck worker to finish
+    /// its item, and the finished callback runs only after that. No new items are started by
+    /// any worker in the meantime.
+    pub per_item_hard_timeout: Option<Duration>,
+    /// Runs with fewer items than this are processed on the calling thread as a single worker,
+    /// like with the `sequential` feature, because spawning the workers would take longer than
+    /// the work itself. Only applies to iterators with a known upper bound of their length
+    /// (`Iterator::size_hint`, e.g. any `ExactSizeIterator`), other iterators always use the
+    /// worker threads.
+    /// Only applies with `BackgroundMode::GateThenJoin`, where the background function is then
+    /// called after the items. The polling modes always use worker threads, so that a background
+    /// function waiting for the workers to make progress behaves the same for any input length.
+    pub parallel_threshold: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            worker_count: WorkerCount::Auto,
+            background_mode: BackgroundMode::GateThenJoin,
+            panic_policy: PanicPolicy::Propagate,
+            panic_hook: PanicHook::Default,
+            thread_name: None,
+            stack_size: None,
+            priority: ThreadPriority::Normal,
+            progress_counter: None,
+            progress_flush_interval: NonZeroUsize::new(64).unwrap(),
+            collect_lock_metrics: false,
+            worker_states: None,
+            error_policy: ErrorPolicy::FailFast,
+            error_selection: ErrorSelection::First,
+            max_items: None,
+            finished_callback_thread: FinishedCallbackThread::LastWorker,
+            queue_order: QueueOrder::Fifo,
+            batching: Batching::Single,
+            warmup_items: 0,
+            per_item_hard_timeout: None,
+            parallel_threshold: 0,
+        }
+    }
+}
+
+/// What a worker is doing at the moment.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WorkerState {
+    /// Started, running the init function.
+    Init,
+    /// Waiting for the lock on the iterator, or getting the next item.
+    WaitingForItem,
+    /// Running the worker function.
+    Working,
+    Done,
+}
+
+impl WorkerState {
+    fn from_u8(value: u8) -> WorkerState {
+        match value {
+            0 => WorkerState::Init,
+            1 => WorkerState::WaitingForItem,
+            2 => WorkerState::Working,
+            _ => WorkerState::Done,
+        }
+    }
+}
+
+/// Live states of the workers of a run, e.g. for drawing a utilization meter.
+/// Can be polled from any thread while the run is in progress; it is reset with the worker
+/// count of the run when the run starts.
+#[derive(Debug, Default)]
+pub struct WorkerStates {
+    states: lock::Mutex<Arc<[std::sync::atomic::AtomicU8]>>,
+}
+
+impl WorkerStates {
+    pub fn new() -> WorkerStates {
+        Default::default()
+    }
+
+    /// Returns a snapshot of states of all workers, indexed by worker id.
+    pub fn get(&self) -> Vec<WorkerState> {
+        self.states
+            .lock()
+            .iter()
+            .map(|state| WorkerState::from_u8(state.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Replaces the states with a new set of workers in Init state.
+    fn reset(&self, worker_count: usize) -> Arc<[std::sync::atomic::AtomicU8]> {
+        let states: Arc<[_]> = (0..worker_count)
+            .map(|_| std::sync::atomic::AtomicU8::new(WorkerState::Init as u8))
+            .collect();
+        *self.states.lock() = states.clone();
+        states
+    }
+}
+
+/// Partial progress of the items that are being processed, for runs with long items.
+/// Reported by the workers of parallel_for_each_with_item_progress through
+/// `ProgressReporter`, can be polled from any thread while the run is in progress.
+/// Fractional progress of the whole run is the value of `Settings::progress_counter` plus
+/// `in_progress()`.
+#[derive(Debug, Default)]
+pub struct ItemProgress {
+    /// Fraction of the current item of each worker, as f32 bits.
+    fractions: lock::Mutex<Arc<[std::sync::atomic::AtomicU32]>>,
+}
+
+impl ItemProgress {
+    pub fn new() -> ItemProgress {
+        Default::default()
+    }
+
+    /// Returns the sum of completed fractions of the items that are being processed.
+    pub fn in_progress(&self) -> f64 {
+        self.fractions
+            .lock()
+            .iter()
+            .map(|fraction| f32::from_bits(fraction.load(Ordering::Relaxed)) as f64)
+            .sum()
+    }
+
+    /// Replaces the fractions with a new set of workers with no progress.
+    fn reset(&self, worker_count: usize) -> Arc<[std::sync::atomic::AtomicU32]> {
+        let fractions: Arc<[_]> = (0..worker_count)
+            .map(|_| std::sync::atomic::AtomicU32::new(0f32.to_bits()))
+            .collect();
+        *self.fractions.lock() = fractions.clone();
+        fractions
+    }
+}
+
+/// Handle for reporting progress within a single item, see
+/// parallel_for_each_with_item_progress.
+pub struct ProgressReporter<'a> {
+    fraction: &'a std::sync::atomic::AtomicU32,
+}
+
+impl<'a> ProgressReporter<'a> {
+    /// Sets the completed fraction of the current item, clamped to 0-1.
+    pub fn report(&self, fraction: f32) {
+        let fraction = fraction.clamp(0.0, 1.0);
+        self.fraction.store(fraction.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Live view of a run for the background function of parallel_for_each_with_run_context.
+#[derive(Debug)]
+pub struct RunContext {
+    items_processed: AtomicUsize,
+    workers_active: AtomicUsize,
+    start: std::time::Instant,
+}
+
+impl RunContext {
+    /// Number of items successfully processed so far.
+    pub fn items_processed(&self) -> usize {
+        self.items_processed.load(Ordering::Relaxed)
+    }
+
+    /// Number of workers that have been initialized and haven't stopped yet.
+    pub fn workers_active(&self) -> usize {
+        self.workers_active.load(Ordering::Relaxed)
+    }
+
+    /// Time since the run started.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Statistics about a finished run of parallel_for_each_with_report.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RunReport {
+    /// Time spent by all workers waiting for the lock on the shared iterator.
+    /// Only measured with `Settings::collect_lock_metrics`, zero otherwise.
+    pub total_lock_wait: Duration,
+    /// Longest single wait for the lock.
+    /// Only measured with `Settings::collect_lock_metrics`, zero otherwise.
+    pub max_lock_wait: Duration,
+    /// The run was stopped because `Settings::max_items` items were taken from the iterator
+    /// and it had more. A run whose iterator ends exactly at the cap is completed instead.
+    pub item_cap_reached: bool,
+    /// Number of worker threads used, see `resolve_worker_count`.
+    pub worker_count: usize,
+    /// All items were handed out (the iterator was exhausted, or every worker got None from the
+    /// scheduler), so all items were processed. False if the run was stopped early (by the
+    /// background function or the item cap); a partial result shouldn't be treated as final
+    /// then.
+    pub completed: bool,
+    /// Number of successfully processed items, without `Settings::warmup_items`.
+    pub items_processed: usize,
+    /// Time from the end of the warmup (start of the run if there is none) until all workers
+    /// finished. Zero if the warmup didn't finish.
+    pub elapsed: Duration,
+}
+
 #[derive(Debug)]
 pub enum ParallelForEachError<Ei, Ew, Eb>
 where
@@ -25,9 +529,43 @@ where
     Ew: ErrorSource,
     Eb: ErrorSource,
 {
-    InitTaskError { source: Ei },
-    WorkerTaskError { source: Ew },
-    BackgroundTaskError { source: Eb },
+    InitTaskError {
+        source: Ei,
+    },
+    WorkerTaskError {
+        source: Ew,
+    },
+    /// Errors collected with `ErrorPolicy::StopAfter`, in the order in which they happened.
+    WorkerTaskErrors {
+        sources: Vec<Ew>,
+    },
+    /// Errors collected with `ErrorPolicy::Collect`, in the order in which they happened, each
+    /// with the index of the item (position in the iterator) that failed.
+    IndexedWorkerTaskErrors {
+        errors: Vec<(usize, Ew)>,
+    },
+    BackgroundTaskError {
+        source: Eb,
+    },
+    WorkerPanicked {
+        worker_id: usize,
+        message: String,
+    },
+    /// A worker thread could not be started. No items are processed in this case.
+    SpawnError {
+        source: std::io::Error,
+    },
+    /// Number of per-worker functions doesn't match the manually set worker count.
+    WorkerCountMismatch {
+        worker_count: usize,
+        worker_fun_count: usize,
+    },
+    /// A worker spent longer than `Settings::per_item_hard_timeout` on a single item.
+    /// Returned only after that worker finished the item.
+    Timeout {
+        worker_id: usize,
+        item_index: usize,
+    },
 }
 
 impl<Ei, Ew, Eb> std::fmt::Display for ParallelForEachError<Ei, Ew, Eb>
@@ -40,7 +578,85 @@ where
         match self {
             Self::InitTaskError { .. } => write!(f, "Init task failed"),
             Self::WorkerTaskError { .. } => write!(f, "Worker task failed"),
+            Self::WorkerTaskErrors { sources } => {
+                write!(f, "{} worker tasks failed", sources.len())
+            }
+            Self::IndexedWorkerTaskErrors { errors } => {
+                write!(f, "{} worker tasks failed", errors.len())
+            }
             Self::BackgroundTaskError { .. } => write!(f, "Background task failed"),
+            Self::WorkerPanicked { worker_id, message } => {
+                write!(f, "Worker {} panicked: {}", worker_id, message)
+            }
+            Self::SpawnError { .. } => write!(f, "Spawning worker thread failed"),
+            Self::WorkerCountMismatch {
+                worker_count,
+                worker_fun_count,
+            } => write!(
+                f,
+                "Got {} worker functions for {} workers",
+                worker_fun_count, worker_count
+            ),
+            Self::Timeout {
+                worker_id,
+                item_index,
+            } => write!(
+                f,
+                "Worker {} timed out processing item {}",
+                worker_id, item_index
+            ),
+        }
+    }
+}
+
+impl<Ei, Ew, Eb> ParallelForEachError<Ei, Ew, Eb>
+where
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    /// Returns the error of the init function, if that is what failed.
+    pub fn as_init_error(&self) -> Option<&Ei> {
+        match self {
+            Self::InitTaskError { source } => Some(source),
+            _ => None,
+        }
+    }
+
+    /// Returns the error of the worker function, if that is what failed.
+    /// With `ErrorPolicy::StopAfter` or `ErrorPolicy::Collect` this is the first of the
+    /// collected errors.
+    pub fn as_worker_error(&self) -> Option<&Ew> {
+        match self {
+            Self::IndexedWorkerTaskErrors { errors } => errors.first().map(|(_, source)| source),
+            _ => self.worker_errors().first(),
+        }
+    }
+
+    /// Returns all errors of the worker function, empty if something else failed.
+    /// Errors collected with `ErrorPolicy::Collect` are returned by `indexed_worker_errors`.
+    pub fn worker_errors(&self) -> &[Ew] {
+        match self {
+            Self::WorkerTaskError { source } => std::slice::from_ref(source),
+            Self::WorkerTaskErrors { sources } => sources,
+            _ => &[],
+        }
+    }
+
+    /// Returns the errors collected with `ErrorPolicy::Collect` together with the indices of
+    /// the items that failed, empty if something else failed.
+    pub fn indexed_worker_errors(&self) -> &[(usize, Ew)] {
+        match self {
+            Self::IndexedWorkerTaskErrors { errors } => errors,
+            _ => &[],
+        }
+    }
+
+    /// Returns the error of the background function, if that is what failed.
+    pub fn as_background_error(&self) -> Option<&Eb> {
+        match self {
+            Self::BackgroundTaskError { source } => Some(source),
+            _ => None,
         }
     }
 }
@@ -55,7 +671,17 @@ where
         match self {
             Self::InitTaskError { source } => source.source(),
             Self::WorkerTaskError { source } => source.source(),
+            Self::WorkerTaskErrors { sources } => {
+                sources.first().and_then(|source| source.source())
+            }
+            Self::IndexedWorkerTaskErrors { errors } => {
+                errors.first().and_then(|(_, source)| source.source())
+            }
             Self::BackgroundTaskError { source } => source.source(),
+            Self::WorkerPanicked { .. } => None,
+            Self::SpawnError { source } => Some(source),
+            Self::WorkerCountMismatch { .. } => None,
+            Self::Timeout { .. } => None,
         }
     }
 }
@@ -63,6 +689,22 @@ where
 /// Runs a worker function for each item of an iterator in multiple threads.
 /// Allows a per-thread initialization function and a background function that runs in the main thread
 /// while the workers are processing.
+/// The background function is called only once, see `BackgroundMode` for the alternatives.
+///
+/// # Thread safety
+///
+/// - The iterator must be `Send`, because it is shared between the workers behind a mutex.
+///   The items don't need to be `Send`, each item is created by `next()` in the worker thread that
+///   processes it. This also means that a `!Send` item (e.g. `Rc` based) can be built from a `Send`
+///   seed with `iterator.map(...)`, as long as the map closure itself is `Send`.
+/// - The init, worker and finished functions are called from all worker threads at once, so they
+///   must be `Sync` (and `Send`, to be usable by `spawn`).
+/// - `State` is created and dropped in the worker thread, so it doesn't need to be `Send`.
+/// - Errors are passed back to the calling thread and must be `Send + Sync` (see `ErrorSource`).
+/// - The background function only runs in the calling thread and has no extra bounds.
+///
+/// The errors for an iterator that is not `Send` and for a worker function that captures a
+/// non-`Sync` value are shown in `tests/ui`.
 pub fn parallel_for_each<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
     iterator: It,
     init_fun: Fi,
@@ -76,492 +718,6178 @@ where
     Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
     Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
     Fb: FnOnce() -> Result<Continue, Eb>,
-    Ff: Fn() -> () + Sync + Send,
+    Ff: Fn() + Sync + Send,
     Ei: ErrorSource,
     Ew: ErrorSource,
     Eb: ErrorSource,
 {
-    struct State<T> {
-        iterator: Option<T>,
-        threads_running: usize,
-    }
-
-    impl<T: Iterator> State<T> {
-        /// Behaves like iterator next
-        fn next(&mut self) -> Option<<T as Iterator>::Item> {
-            let iterator = self.iterator.as_mut()?;
-            let item = iterator.next();
-
-            if item.is_none() {
-                self.stop();
-            }
-
-            item
-        }
-
-        fn stop(&mut self) {
-            self.iterator = None
-        }
-    }
-
-    let worker_count = match worker_count {
-        WorkerCount::Auto => num_cpus::get(),
-        WorkerCount::Manual(num) => num.get(),
-    };
-
-    let state = parking_lot::Mutex::new(State {
-        iterator: Some(iterator),
-        threads_running: worker_count,
-    });
-
-    // References that can safely be moved into the thread
-    let state = &state;
-    let init_fun = &init_fun;
-    let worker_fun = &worker_fun;
-    let finished_callback = &finished_callback;
-
-    crossbeam_utils::thread::scope(|scope| -> Result<(), ParallelForEachError<Ei, Ew, Eb>> {
-        let handles = (0..worker_count).map(|worker_id| {
-            scope.spawn(move |_| -> Result<(), ParallelForEachError<Ei, Ew, Eb>> {
-                let mut state = scopeguard::guard(state.lock(), |mut state| {
-                    state.stop(); // Stop all threads if we're running out from the loop (even when panicking)
-                    state.threads_running -= 1;
-                    if state.threads_running == 0 {
-                        parking_lot::lock_api::MutexGuard::unlocked(&mut state, || finished_callback());
-                    }
-                });
-                let mut thread_state = parking_lot::lock_api::MutexGuard::unlocked(&mut state, || init_fun(worker_id))
-                    .map_err(|source| ParallelForEachError::InitTaskError{source})?;
-
-                #[allow(clippy::while_let_loop)]
-                loop {
-                    let item = match (*state).next() {
-                        Some(item) => item,
-                        None => break,
-                    };
-                    parking_lot::lock_api::MutexGuard::unlocked(&mut state, || worker_fun(&mut thread_state, item))
-                        .map_err(|source| ParallelForEachError::WorkerTaskError{source})?
-                };
-
-                Ok(())
-            })
-        }).collect::<Vec<_>>();
-
-        scopeguard::defer_on_unwind! {
-            state.lock().stop()
-        }
-
-        let background_result = background_fun()
-            .map_err(|source| ParallelForEachError::BackgroundTaskError{source});
-
-        match background_result {
-            Ok(Continue::Continue) => {},
-            _ => (*state.lock()).stop(),
-        };
-
-        let _ = background_result?;
-
-        for handle in handles {
-            match handle.join() {
-                Ok(Ok(())) => {},
-                Ok(Err(e)) => return Err(e),
-                Err(p) => std::panic::resume_unwind(p),
-            }
-        }
-
-        Ok(())
-    })
-    .unwrap() // We have already propagated panics
-    ?;
-
-    Ok(())
+    // The default BackgroundMode::GateThenJoin calls the background function exactly once
+    let mut background_fun = Some(background_fun);
+    parallel_for_each_with_settings(
+        iterator,
+        init_fun,
+        worker_fun,
+        || {
+            (background_fun
+                .take()
+                .expect("Background function called twice"))()
+        },
+        finished_callback,
+        Settings {
+            worker_count,
+            ..Default::default()
+        },
+    )
 }
 
-/// Trait for values that can be used as source error.
-pub trait ErrorSource: Sync + Send + std::fmt::Debug {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>;
-}
+/// Error of parallel_for_each_uniform, when the init, worker and background functions all
+/// fail with the same error type.
+pub type ParallelForEachErrorUniform<E> = ParallelForEachError<E, E, E>;
 
-impl<T> ErrorSource for T
+/// Same as parallel_for_each, but the init, worker and background functions share a single
+/// error type (e.g. `anyhow::Error`), so it only has to be named once.
+pub fn parallel_for_each_uniform<It, Fi, Fw, Fb, Ff, E, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    worker_count: WorkerCount,
+) -> Result<(), ParallelForEachErrorUniform<E>>
 where
-    T: std::error::Error + std::fmt::Debug + Send + Sync + 'static,
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, E> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), E> + Sync + Send,
+    Fb: FnOnce() -> Result<Continue, E>,
+    Ff: Fn() + Sync + Send,
+    E: ErrorSource,
 {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(self)
-    }
-}
-
-impl ErrorSource for dyn std::error::Error + Send + Sync + 'static {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(self)
-    }
+    parallel_for_each(
+        iterator,
+        init_fun,
+        worker_fun,
+        background_fun,
+        finished_callback,
+        worker_count,
+    )
 }
 
-impl<T> ErrorSource for T
+/// Same as parallel_for_each, but takes all of the less common parameters in a settings struct.
+///
+/// If any of the worker threads fails to spawn, the already spawned workers are stopped before
+/// they get any item, the finished callback is called and `SpawnError` is returned.
+///
+/// On every exit path (items exhausted, stop from the background function, init, worker or
+/// background error, worker panic) the finished callback is called exactly once and it has
+/// returned before this function returns or propagates the panic. This relies on the thread
+/// scope joining all workers, including when returning early because of a background error.
+///
+/// A panic from the finished callback is caught and resumed after all workers are joined.
+/// It takes precedence over any error from the init, worker or background functions (these are
+/// lost).
+/// With `FinishedCallbackThread::Caller` the callback is not called if a worker panic is
+/// propagated (`PanicPolicy::Propagate`): the calling thread is already unwinding then, and
+/// a panic from the callback would be a double panic. The last worker still calls it when
+/// unwinding from its own panic, so that a background function waiting for it can finish.
+///
+/// With `FinishedCallbackThread::LastWorker` the callback runs after every worker has stopped
+/// taking items and no internal lock is held, so it may freely access state shared with the
+/// workers or the background function, or even start another parallel_for_each. It must not
+/// wait for this call to return, though, that would deadlock.
+pub fn parallel_for_each_with_settings<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
 where
-    T: std::fmt::Debug + Sync + Send,
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
 {
-    default fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
-    }
+    parallel_for_each_with_report(
+        iterator,
+        init_fun,
+        worker_fun,
+        background_fun,
+        finished_callback,
+        settings,
+    )?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use assert2::assert;
-    use panic_control;
-    use proptest::prelude::*;
-    use proptest_attr_macro::proptest;
-    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-    use std::time::{Duration, Instant};
+/// Same as parallel_for_each_with_settings, but each worker runs its own worker function,
+/// worker with id `k` uses `worker_funs[k]`. All workers share the same iterator, so each item
+/// is still processed by exactly one of the functions.
+/// With `WorkerCount::Auto` the worker count is the number of functions, with
+/// `WorkerCount::Manual` the counts must match, otherwise `WorkerCountMismatch` is returned.
+/// Panics if `worker_funs` is empty.
+pub fn parallel_for_each_per_worker<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_funs: Vec<Fw>,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    assert!(
+        !worker_funs.is_empty(),
+        "At least one worker function is needed"
+    );
 
-    const TIMEOUT: Duration = Duration::from_secs(2);
+    let worker_count = match settings.worker_count {
+        WorkerCount::Auto => NonZeroUsize::new(worker_funs.len()).unwrap(),
+        WorkerCount::Manual(num) if num.get() == worker_funs.len() => num,
+        WorkerCount::Manual(num) => {
+            return Err(ParallelForEachError::WorkerCountMismatch {
+                worker_count: num.get(),
+                worker_fun_count: worker_funs.len(),
+            })
+        }
+    };
 
-    struct IterationCheckHelper {
-        finished: AtomicBool,
-        latest_end_time: Instant,
-    }
+    parallel_for_each_with_settings(
+        iterator,
+        |worker_id| Ok((worker_id, init_fun(worker_id)?)),
+        |(worker_id, state), item| worker_funs[*worker_id](state, item),
+        background_fun,
+        finished_callback,
+        Settings {
+            worker_count: WorkerCount::Manual(worker_count),
+            ..settings
+        },
+    )
+}
 
-    impl IterationCheckHelper {
-        fn new() -> IterationCheckHelper {
-            IterationCheckHelper {
-                finished: AtomicBool::new(false),
-                latest_end_time: Instant::now() + TIMEOUT,
+/// Same as parallel_for_each_with_settings, but the init and worker functions also get a
+/// reference to a shared read-only context (e.g. a scene), next to the per-worker mutable state.
+/// The context only has to outlive the call, all workers are joined before this returns, and
+/// it must be `Sync` because all workers read it at the same time.
+pub fn parallel_for_each_with_shared<Ctx, It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    shared: &Ctx,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    Ctx: Sync + ?Sized,
+    It: Iterator + Send,
+    Fi: Fn(&Ctx, usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&Ctx, &mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    parallel_for_each_with_settings(
+        iterator,
+        |worker_id| init_fun(shared, worker_id),
+        |state, item| worker_fun(shared, state, item),
+        background_fun,
+        finished_callback,
+        settings,
+    )
+}
+
+/// Same as parallel_for_each_with_settings, but the finished callback is `FnOnce`, so it can
+/// consume values moved into it (e.g. send a completion message through a channel).
+/// It is called exactly once, by the last worker to finish.
+pub fn parallel_for_each_with_finished_once<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: FnOnce() -> () + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let finished_callback = lock::Mutex::new(Some(finished_callback));
+
+    parallel_for_each_with_settings(
+        iterator,
+        init_fun,
+        worker_fun,
+        background_fun,
+        || {
+            if let Some(finished_callback) = finished_callback.lock().take() {
+                finished_callback()
             }
+        },
+        settings,
+    )
+}
+
+/// Same as parallel_for_each_with_settings, with hooks called by each worker when it starts and
+/// when it stops, e.g. for registering the thread with a profiler.
+/// `on_worker_start` is called before `init_fun`. `on_worker_stop` is called after the worker is
+/// done with items, for any reason (end of items, stop, error or panic), with the worker state,
+/// or None if the init function failed. Both are called exactly once per worker, without any
+/// internal lock held. The stop hook is called before the finished callback, so it can be
+/// used e.g. for counting active workers in a UI.
+#[allow(clippy::too_many_arguments)]
+pub fn parallel_for_each_with_worker_hooks<It, Fi, Fw, Fb, Ff, Fs, Fe, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    on_worker_start: Fs,
+    on_worker_stop: Fe,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Fs: Fn(usize) + Sync + Send,
+    Fe: Fn(usize, Option<&State>) + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    /// Worker state that calls the stop hook when dropped.
+    struct HookedState<'a, State, Fe: Fn(usize, Option<&State>)> {
+        worker_id: usize,
+        state: State,
+        on_worker_stop: &'a Fe,
+    }
+
+    impl<'a, State, Fe: Fn(usize, Option<&State>)> Drop for HookedState<'a, State, Fe> {
+        fn drop(&mut self) {
+            (self.on_worker_stop)(self.worker_id, Some(&self.state));
         }
+    }
 
-        fn workers_running_check(&self) -> Result<(), String> {
-            if self.finished.load(Ordering::Relaxed) {
-                Err("Thread is running even though the end callback was encountered".into())
-            } else if Instant::now() > self.latest_end_time {
-                Err("Time limit exceeded".into())
-            } else {
-                Ok(())
+    let on_worker_stop = &on_worker_stop;
+
+    parallel_for_each_with_settings(
+        iterator,
+        |worker_id| {
+            on_worker_start(worker_id);
+            let init_failed = scopeguard::guard((), |_| on_worker_stop(worker_id, None));
+            let state = init_fun(worker_id)?;
+            scopeguard::ScopeGuard::into_inner(init_failed);
+            Ok(HookedState {
+                worker_id,
+                state,
+                on_worker_stop,
+            })
+        },
+        |hooked, item| worker_fun(&mut hooked.state, item),
+        background_fun,
+        finished_callback,
+        settings,
+    )
+}
+
+/// Set of completed item indices of a checkpointed run, see `parallel_for_each_checkpointed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointState {
+    completed: Vec<bool>,
+}
+
+impl CheckpointState {
+    /// Creates a state for `len` items with nothing completed.
+    pub fn new(len: usize) -> Self {
+        CheckpointState {
+            completed: vec![false; len],
+        }
+    }
+
+    /// Creates a state for `len` items with the given indices completed.
+    /// Panics if any index is out of range.
+    pub fn with_completed(len: usize, indices: impl IntoIterator<Item = usize>) -> Self {
+        let mut state = CheckpointState::new(len);
+        for index in indices {
+            state.completed[index] = true;
+        }
+        state
+    }
+
+    /// Total number of items of the run.
+    pub fn len(&self) -> usize {
+        self.completed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.completed.is_empty()
+    }
+
+    pub fn is_completed(&self, index: usize) -> bool {
+        self.completed[index]
+    }
+
+    pub fn completed_count(&self) -> usize {
+        self.completed.iter().filter(|&&done| done).count()
+    }
+
+    /// Returns the indices of completed items in increasing order.
+    pub fn completed_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.completed
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &done)| if done { Some(index) } else { None })
+    }
+}
+
+/// Same as parallel_for_each_with_settings, but tracks which items were completed so that an
+/// interrupted run can be resumed.
+/// After every `checkpoint_interval` completed items `checkpoint_fun` is called with indices
+/// of all completed items (in the order of the iterator), and once more when the run ends if
+/// any items were completed since the last call. Calls are serialized and the workers
+/// completing items wait for them, so the callback should be fast (e.g. write a small file).
+/// Items that are completed in `resume_from` (typically the last checkpoint of an earlier run)
+/// are skipped and count as completed in the checkpoints.
+/// Panics if the length of `resume_from` doesn't match the length of the iterator.
+#[allow(clippy::too_many_arguments)]
+pub fn parallel_for_each_checkpointed<It, Fi, Fw, Fb, Ff, Fc, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    checkpoint_interval: NonZeroUsize,
+    checkpoint_fun: Fc,
+    resume_from: Option<CheckpointState>,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: ExactSizeIterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Fc: Fn(&CheckpointState) + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let len = iterator.len();
+    let resume_from = resume_from.unwrap_or_else(|| CheckpointState::new(len));
+    assert!(
+        resume_from.len() == len,
+        "Checkpoint state doesn't match the number of items"
+    );
+
+    // Checkpoint state and number of items completed since the last checkpoint
+    let checkpoint = lock::Mutex::new((resume_from.clone(), 0usize));
+
+    let result = parallel_for_each_with_settings(
+        iterator
+            .enumerate()
+            .filter(|(index, _)| !resume_from.is_completed(*index)),
+        init_fun,
+        |state, (index, item)| {
+            worker_fun(state, item)?;
+            let mut checkpoint = checkpoint.lock();
+            checkpoint.0.completed[index] = true;
+            checkpoint.1 += 1;
+            if checkpoint.1 == checkpoint_interval.get() {
+                checkpoint.1 = 0;
+                checkpoint_fun(&checkpoint.0);
             }
+            Ok(())
+        },
+        background_fun,
+        finished_callback,
+        settings,
+    );
+
+    let checkpoint = checkpoint.into_inner();
+    if checkpoint.1 > 0 {
+        checkpoint_fun(&checkpoint.0);
+    }
+
+    result
+}
+
+/// Same as parallel_for_each_with_settings, but the worker function also gets a
+/// `ProgressReporter` for reporting progress within the item into `item_progress`, so that
+/// progress displays don't freeze during long items.
+/// The reported fraction is reset to zero after each item. Workers that don't report behave
+/// exactly as with parallel_for_each_with_settings.
+pub fn parallel_for_each_with_item_progress<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    item_progress: &ItemProgress,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item, &ProgressReporter) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let fractions = item_progress.reset(resolve_worker_count(settings.worker_count));
+    let fractions = &fractions;
+
+    parallel_for_each_with_settings(
+        iterator,
+        |worker_id| {
+            let reporter = ProgressReporter {
+                fraction: &fractions[worker_id],
+            };
+            Ok((reporter, init_fun(worker_id)?))
+        },
+        |(reporter, state), item| {
+            let _reset = scopeguard::guard((), |_| reporter.report(0.0));
+            worker_fun(state, item, reporter)
+        },
+        background_fun,
+        finished_callback,
+        settings,
+    )
+}
+
+/// Same as parallel_for_each_with_settings, but the background function gets a `RunContext`
+/// with the progress of the workers, e.g. for driving a progress bar from a polling background
+/// function (`BackgroundMode::PollUntilDone`).
+pub fn parallel_for_each_with_run_context<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    mut background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut(&RunContext) -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    /// Keeps the worker counted as active until the worker state is dropped.
+    struct ActiveWorker<'a>(&'a AtomicUsize);
+
+    impl<'a> Drop for ActiveWorker<'a> {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
         }
+    }
 
-        fn finished_callback(&self) {
-            self.finished.store(true, Ordering::Relaxed);
+    let context = RunContext {
+        items_processed: AtomicUsize::new(0),
+        workers_active: AtomicUsize::new(0),
+        start: std::time::Instant::now(),
+    };
+    let context = &context;
+
+    parallel_for_each_with_settings(
+        iterator,
+        |worker_id| {
+            let state = init_fun(worker_id)?;
+            context.workers_active.fetch_add(1, Ordering::Relaxed);
+            Ok((ActiveWorker(&context.workers_active), state))
+        },
+        |(_active, state), item| {
+            worker_fun(state, item)?;
+            context.items_processed.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        },
+        || background_fun(context),
+        finished_callback,
+        settings,
+    )
+}
+
+/// Semaphore limiting how many workers use a resource at the same time (e.g. a rate limited
+/// API), see parallel_for_each_with_limited_resource.
+#[derive(Debug)]
+pub struct LimitedResource {
+    /// Number of free slots and whether the resource was closed.
+    state: lock::Mutex<(usize, bool)>,
+    cond: lock::Condvar,
+}
+
+/// Slot of a `LimitedResource`, released when dropped.
+#[derive(Debug)]
+pub struct LimitedResourceGuard<'a> {
+    resource: &'a LimitedResource,
+}
+
+impl LimitedResource {
+    pub fn new(limit: NonZeroUsize) -> LimitedResource {
+        LimitedResource {
+            state: lock::Mutex::new((limit.get(), false)),
+            cond: lock::Condvar::new(),
         }
+    }
 
-        fn callback_called_check(&self) -> bool {
-            self.finished.load(Ordering::Relaxed)
+    /// Waits for a free slot. Returns None if the resource was closed (the run is stopping),
+    /// the item should then be abandoned.
+    pub fn acquire(&self) -> Option<LimitedResourceGuard<'_>> {
+        let mut state = self.state.lock();
+        loop {
+            match *state {
+                (_, true) => return None,
+                (0, false) => self.cond.wait(&mut state),
+                (ref mut available, false) => {
+                    *available -= 1;
+                    return Some(LimitedResourceGuard { resource: self });
+                }
+            }
         }
     }
 
-    impl Arbitrary for WorkerCount {
-        type Parameters = ();
-        type Strategy = proptest::strategy::BoxedStrategy<Self>;
-        fn arbitrary_with(_args: Self::Parameters) -> Self::Strategy {
-            prop_oneof![
-                (1..128usize).prop_map(|n| WorkerCount::Manual(NonZeroUsize::new(n).unwrap())),
-                Just(WorkerCount::Auto),
-            ]
-            .boxed()
+    /// Makes all waiting and future `acquire` calls return None.
+    pub fn close(&self) {
+        self.state.lock().1 = true;
+        self.cond.notify_all();
+    }
+}
+
+impl<'a> Drop for LimitedResourceGuard<'a> {
+    fn drop(&mut self) {
+        self.resource.state.lock().0 += 1;
+        self.resource.cond.notify_one();
+    }
+}
+
+/// Same as parallel_for_each_with_settings, but the worker function gets a `LimitedResource`
+/// that at most `limit` workers can hold at a time, for items that need a resource with
+/// limited concurrency.
+/// The resource is closed when the run is stopping (stop or error from the background
+/// function, init or worker error, worker panic), so that workers waiting for it can give up
+/// their items instead of waiting for the current holders.
+#[allow(clippy::too_many_arguments)]
+pub fn parallel_for_each_with_limited_resource<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    mut background_fun: Fb,
+    finished_callback: Ff,
+    limit: NonZeroUsize,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item, &LimitedResource) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let resource = LimitedResource::new(limit);
+    let resource = &resource;
+    let stopping_error_count = settings.error_policy.stopping_error_count();
+    let error_count = AtomicUsize::new(0);
+    let error_count = &error_count;
+
+    parallel_for_each_with_settings(
+        iterator,
+        |worker_id| {
+            let state = init_fun(worker_id);
+            if state.is_err() {
+                resource.close();
+            }
+            state
+        },
+        |state, item| {
+            scopeguard::defer_on_unwind! { resource.close(); }
+            let result = worker_fun(state, item, resource);
+            if result.is_err()
+                && error_count.fetch_add(1, Ordering::Relaxed) + 1 >= stopping_error_count
+            {
+                resource.close();
+            }
+            result
+        },
+        || {
+            let result = background_fun();
+            if !matches!(result, Ok(Continue::Continue)) {
+                resource.close();
+            }
+            result
+        },
+        finished_callback,
+        settings,
+    )
+}
+
+/// Same as parallel_for_each_with_settings, but returns statistics about the run.
+pub fn parallel_for_each_with_report<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<RunReport, ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    run_with_leftover(
+        iterator,
+        init_fun,
+        worker_fun,
+        background_fun,
+        finished_callback,
+        settings,
+        None,
+    )
+}
+
+/// Same as parallel_for_each_with_settings, but if the run stops before the iterator is
+/// exhausted (background function stop, `Settings::max_items`, errors), the iterator with the
+/// items that were not taken by any worker is returned alongside the result, so that they can
+/// be processed later.
+/// Items that were taken by a worker are not part of the leftovers, even if the worker failed
+/// on them or dropped them from its batch (see `Batching`).
+/// Returns None as the iterator if all items were taken.
+pub fn parallel_for_each_with_leftover<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> ResultWithLeftover<It, Ei, Ew, Eb>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let mut leftover = None;
+    let result = run_with_leftover(
+        iterator,
+        init_fun,
+        worker_fun,
+        background_fun,
+        finished_callback,
+        settings,
+        Some(&mut leftover),
+    );
+    (result.map(|_| ()), leftover)
+}
+
+/// Result of parallel_for_each_with_leftover: the result of the run and the iterator with the
+/// items that were not taken.
+pub type ResultWithLeftover<It, Ei, Ew, Eb> =
+    (Result<(), ParallelForEachError<Ei, Ew, Eb>>, Option<It>);
+
+/// Implementation of parallel_for_each_with_report, also stores the iterator into `leftover`
+/// if it was stopped before being exhausted.
+/// Runs the items in worker threads, or on the calling thread with the `sequential` feature
+/// (for targets without threads) or when there are fewer than `Settings::parallel_threshold`
+/// of them with `BackgroundMode::GateThenJoin`. See run_sequential for how the background
+/// function is called then.
+///
+/// The `sequential` feature only replaces the worker threads of the runs built on this and on
+/// parallel_for_each_with_scheduler. It still needs std, crossbeam-utils and scopeguard, and
+/// the timing of the runs (`std::time::Instant`, `std::thread::sleep` between polls) panics
+/// on targets without a clock, like wasm32-unknown-unknown. parallel_for_each_prefetched,
+/// parallel_map_reduce and the `spawn` functions still start threads.
+/// The test suite passes with `cargo test --features sequential`, the tests that need
+/// concurrent workers or a concurrent background function are ignored there.
+fn run_with_leftover<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+    leftover: Option<&mut Option<It>>,
+) -> Result<RunReport, ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let below_threshold =
+        matches!(iterator.size_hint().1, Some(len) if len < settings.parallel_threshold);
+    let sequential = cfg!(feature = "sequential")
+        || (below_threshold && matches!(settings.background_mode, BackgroundMode::GateThenJoin));
+    let scheduler = MutexScheduler::with_leftover(iterator, leftover.is_some());
+    let store_leftover = |scheduler: &mut MutexScheduler<It>| {
+        if let Some(leftover) = leftover {
+            *leftover = scheduler.take_leftover();
         }
+    };
+    if sequential {
+        run_sequential(
+            scheduler,
+            init_fun,
+            worker_fun,
+            background_fun,
+            finished_callback,
+            settings,
+            store_leftover,
+        )
+    } else {
+        run_threaded(
+            scheduler,
+            init_fun,
+            worker_fun,
+            background_fun,
+            finished_callback,
+            settings,
+            store_leftover,
+        )
+    }
+}
+
+/// Threaded backend of the runs, all of them hand out their items through a scheduler
+/// (`MutexScheduler` for iterators). The workers are scoped threads, see `scoped`.
+/// `on_exit` gets the scheduler when leaving, whatever the result (e.g. to take the leftover
+/// iterator).
+fn run_threaded<S, Fi, Fw, Fb, Ff, Fe, Ei, Ew, Eb, State>(
+    mut scheduler: S,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+    on_exit: Fe,
+) -> Result<RunReport, ParallelForEachError<Ei, Ew, Eb>>
+where
+    S: Scheduler + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, S::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Fe: FnOnce(&mut S),
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let worker_count = resolve_worker_count(settings.worker_count);
+    let background_mode = settings.background_mode;
+    let panic_policy = settings.panic_policy;
+    let thread_name = settings.thread_name;
+    let stack_size = settings.stack_size;
+    let priority = settings.priority;
+    let progress_counter = settings.progress_counter;
+    let progress_flush_interval = settings.progress_flush_interval.get();
+    let callback_on_last_worker =
+        settings.finished_callback_thread == FinishedCallbackThread::LastWorker;
+    let collect_lock_metrics = settings.collect_lock_metrics;
+    let batching = settings.batching;
+    let warmup_items = settings.warmup_items;
+    let per_item_hard_timeout = settings.per_item_hard_timeout;
+    let error_policy = settings.error_policy;
+    let error_selection = settings.error_selection;
+    let worker_states = settings
+        .worker_states
+        .map(|worker_states| worker_states.reset(worker_count));
+    let panic_hook = match (panic_policy, settings.panic_hook) {
+        (PanicPolicy::CaptureAsError, PanicHook::Default) | (PanicPolicy::Propagate, _) => None,
+        (PanicPolicy::CaptureAsError, hook) => {
+            install_dispatching_panic_hook();
+            Some(std::sync::Arc::new(hook))
+        }
+    };
+
+    scheduler.start(worker_count);
+    let state = lock::Mutex::new(RunState::new(scheduler, settings.max_items, worker_count));
+    // Set when the run is stopped before running out of items, checked without the lock
+    // after every item, so that workers don't continue with their batches.
+    let cancelled = sync::AtomicBool::new(false);
+    // Hands over the scheduler when leaving this function, whatever the result.
+    let state_ref = &state;
+    let _on_exit_guard = scopeguard::guard(on_exit, move |on_exit| {
+        on_exit(&mut state_ref.lock().scheduler)
+    });
+    // Index of the item on which each worker failed, for ErrorSelection::LowestIndex
+    let failed_items: Vec<_> = (0..worker_count)
+        .map(|_| AtomicUsize::new(usize::MAX))
+        .collect();
+    let report = lock::Mutex::new(RunReport::default());
+    let items_processed = AtomicUsize::new(0);
+    let warmup = Warmup::new(warmup_items);
+    let item_watch = per_item_hard_timeout.map(|_| ItemWatch::new(worker_count));
+    let timed_out = lock::Mutex::new(None);
+    let watchdog_done = (lock::Mutex::new(false), lock::Condvar::new());
+    let collected_errors = lock::Mutex::new(Vec::new());
+
+    // Stores the callback panic instead of unwinding from a worker, it is resumed when leaving
+    // this function
+    let finished_callback = CatchingCallback::new(finished_callback);
+    let finished_callback = &finished_callback;
+
+    // Called after all workers are joined, if not called by the last worker.
+    // Skipped when a propagated worker panic is unwinding, the callback can't panic safely then.
+    let _finished_on_caller = scopeguard::guard((), |_| {
+        if !callback_on_last_worker && !std::thread::panicking() {
+            finished_callback.call()
+        }
+    });
+
+    // References that can safely be moved into the thread
+    let state = &state;
+    let cancelled = &cancelled;
+    let items_processed = &items_processed;
+    let warmup = &warmup;
+    let item_watch = item_watch.as_ref();
+    let timed_out = &timed_out;
+    let watchdog_done = &watchdog_done;
+    let init_fun = &init_fun;
+    let worker_fun = &worker_fun;
+    let panic_hook = &panic_hook;
+    let collected_errors = &collected_errors;
+    let failed_items = &failed_items;
+    let progress_counter = progress_counter.as_deref();
+    let worker_states = worker_states.as_deref();
+    let lock_metrics_report = if collect_lock_metrics {
+        Some(&report)
+    } else {
+        None
+    };
+    let lock_metrics_clock = lock_metrics_clock();
+    let lock_metrics_clock: &LockClockFn = &*lock_metrics_clock;
+
+    let mut background_fun = background_fun;
+
+    scoped::scope(|scope| -> Result<(), ParallelForEachError<Ei, Ew, Eb>> {
+        let join_workers = |handles: Vec<WorkerHandle<Ei, Ew, Eb>>| {
+            let worker_results: Vec<_> = handles.into_iter().enumerate().map(|(worker_id, handle)| {
+                match handle.join() {
+                    Ok(worker_result) => worker_result,
+                    Err(p) => match panic_policy {
+                        PanicPolicy::Propagate => std::panic::resume_unwind(p),
+                        PanicPolicy::CaptureAsError => Err(ParallelForEachError::WorkerPanicked {
+                            worker_id,
+                            message: panic_message(&*p),
+                        }),
+                    },
+                }
+            }).collect();
+            // The stuck worker's result is meaningless, it was stopped by the watchdog.
+            // All workers are joined, so the watchdog can't find another one anymore.
+            let stuck_worker = timed_out.lock().map(|(worker_id, _)| worker_id);
+            let mut result = Ok(());
+            let mut result_index = usize::MAX;
+            for (worker_id, worker_result) in worker_results.into_iter().enumerate() {
+                if Some(worker_id) == stuck_worker {
+                    continue;
+                }
+                if worker_result.is_err() {
+                    let index = failed_items[worker_id].load(Ordering::Relaxed);
+                    let replace = match error_selection {
+                        ErrorSelection::First => result.is_ok(),
+                        ErrorSelection::LowestIndex => result.is_ok() || index < result_index,
+                    };
+                    if replace {
+                        result = worker_result;
+                        result_index = index;
+                    }
+                }
+            }
+            result
+        };
+
+        // Workers wait for this lock before taking the first item, so that nothing gets processed
+        // if some of them fail to spawn.
+        let mut spawn_state = state.lock();
+        let mut handles = Vec::with_capacity(worker_count);
+        for worker_id in 0..worker_count {
+            let name = thread_name.as_ref().map(|thread_name| format!("{}-{}", thread_name, worker_id));
+
+            let spawn_result = scoped::spawn(scope, name, stack_size, move || -> Result<(), ParallelForEachError<Ei, Ew, Eb>> {
+                // Best effort, the run works the same at any priority
+                let _ = priority.apply();
+                // Declared before the state guard, so that the hook is still active when
+                // the finished callback runs.
+                let _panic_hook_guard = panic_hook.as_ref().map(|hook| {
+                    WORKER_PANIC_HOOK.with(|current| *current.borrow_mut() = Some(hook.clone()));
+                    scopeguard::guard((), |_| {
+                        WORKER_PANIC_HOOK.with(|current| *current.borrow_mut() = None)
+                    })
+                });
+                let mut lock_wait = LockWait::new(lock_metrics_report, lock_metrics_clock);
+                let lock_start = lock_wait.start();
+                let ran_out_of_items = std::cell::Cell::new(false);
+                let mut state = scopeguard::guard(state.lock(), |mut state| {
+                    if !ran_out_of_items.get() {
+                        // Stop all threads if we're running out from the loop (even when panicking)
+                        state.cancel(cancelled);
+                    }
+                    if let Some(item_watch) = item_watch {
+                        // The item may have failed or panicked, it is not running anymore
+                        item_watch.item_finished(worker_id);
+                    }
+                    if state.leave() && callback_on_last_worker {
+                        lock::MutexGuard::unlocked(&mut state, || finished_callback.call());
+                    }
+                });
+                lock_wait.record_since(lock_start);
+                // Declared after the state guard, so that it gets flushed before the finished callback.
+                let mut progress = LocalProgress::new(progress_counter, progress_flush_interval);
+                let mut processed = LocalProgress::new(Some(items_processed), usize::MAX);
+                let activity = ActivityReporter(worker_states.map(|states| &states[worker_id]));
+                let mut unlocked_end = None;
+                let thread_state = lock::MutexGuard::unlocked(&mut *state, || {
+                    let result = init_fun(worker_id);
+                    activity.set(WorkerState::WaitingForItem);
+                    unlocked_end = lock_wait.start();
+                    result
+                });
+                lock_wait.record_since(unlocked_end.take());
+                let mut thread_state = thread_state
+                    .map_err(|source| ParallelForEachError::InitTaskError{source})?;
+
+                let mut batch_size = BatchSize::new(batching);
+                // Items taken from the iterator, but not processed yet (after an error)
+                let mut batch = std::collections::VecDeque::new();
+                let worker_result = loop {
+                    if batch.is_empty() {
+                        while batch.len() < batch_size.get() {
+                            match (*state).next(worker_id) {
+                                Some(item) => batch.push_back(item),
+                                None => break,
+                            }
+                        }
+                        if batch.is_empty() {
+                            ran_out_of_items.set(true);
+                            break Ok(());
+                        }
+                    }
+                    let result = lock::MutexGuard::unlocked(&mut *state, || {
+                        activity.set(WorkerState::Working);
+                        let batch_start = if batch_size.is_adaptive() {
+                            Some(std::time::Instant::now())
+                        } else {
+                            None
+                        };
+                        let count = batch.len();
+                        while let Some((index, item)) = batch.pop_front() {
+                            if let Some(item_watch) = item_watch {
+                                item_watch.item_started(worker_id, index);
+                            }
+                            if let Err(source) = worker_fun(&mut thread_state, item) {
+                                // The wait for the lock after an error counts too
+                                unlocked_end = lock_wait.start();
+                                return Err((index, source));
+                            }
+                            if let Some(item_watch) = item_watch {
+                                item_watch.item_finished(worker_id);
+                            }
+                            progress.increment();
+                            processed.increment();
+                            warmup.item_processed();
+                            if cancelled.load(sync::Ordering::Relaxed) {
+                                batch.clear();
+                            }
+                        }
+                        if let Some(batch_start) = batch_start {
+                            batch_size.record(count, batch_start.elapsed());
+                        }
+                        activity.set(WorkerState::WaitingForItem);
+                        unlocked_end = lock_wait.start();
+                        Ok(())
+                    });
+                    lock_wait.record_since(unlocked_end.take());
+                    match (result, error_policy) {
+                        (Ok(()), _) => {},
+                        (Err((index, source)), ErrorPolicy::FailFast) => {
+                            failed_items[worker_id].store(index, Ordering::Relaxed);
+                            break Err(ParallelForEachError::WorkerTaskError{source});
+                        },
+                        (
+                            Err((index, source)),
+                            ErrorPolicy::StopAfter(limit) | ErrorPolicy::Collect(limit),
+                        ) => {
+                            let mut collected_errors = collected_errors.lock();
+                            if collected_errors.len() < limit.get() {
+                                collected_errors.push((index, source));
+                            }
+                            if collected_errors.len() >= limit.get() {
+                                (*state).cancel(cancelled);
+                                batch.clear();
+                            }
+                        }
+                    }
+                };
+                // Dropped before the finished callback, without the lock, because dropping it
+                // runs user code (e.g. the stop hook of parallel_for_each_with_worker_hooks)
+                lock::MutexGuard::unlocked(&mut *state, move || drop(thread_state));
+
+                worker_result
+            });
+
+            match spawn_result {
+                Ok(handle) => handles.push(handle),
+                Err(source) => {
+                    spawn_state.stop();
+                    spawn_state.threads_running -= worker_count - handles.len();
+                    let finished = spawn_state.threads_running == 0 && callback_on_last_worker;
+                    drop(spawn_state);
+                    if finished {
+                        finished_callback.call();
+                    }
+
+                    let _ = join_workers(handles);
+                    return Err(ParallelForEachError::SpawnError { source });
+                }
+            }
+        }
+        drop(spawn_state);
+
+        if let (Some(timeout), Some(item_watch)) = (per_item_hard_timeout, item_watch) {
+            scoped::spawn(scope, None, None, move || {
+                let (done, cond) = watchdog_done;
+                let mut done = done.lock();
+                while !*done {
+                    if let Some(overdue) = item_watch.overdue(timeout) {
+                        *timed_out.lock() = Some(overdue);
+                        state.lock().cancel(cancelled);
+                        break;
+                    }
+                    cond.wait_for(&mut done, std::cmp::max(timeout / 4, Duration::from_millis(1)));
+                }
+            })
+            .expect("Failed to spawn the watchdog thread");
+        }
+        // The scope waits for the watchdog, so it has to be stopped whichever way we leave
+        let _stop_watchdog = scopeguard::guard((), |_| {
+            *watchdog_done.0.lock() = true;
+            watchdog_done.1.notify_all();
+        });
+
+        scopeguard::defer_on_unwind! {
+            state.lock().cancel(cancelled)
+        }
+
+        let background_result = loop {
+            let workers_done = match background_mode {
+                BackgroundMode::PollUntilDone(_) => state.lock().threads_running == 0,
+                _ => false,
+            };
+            let result = match background_fun() {
+                Ok(result) => result,
+                Err(source) => break Err(ParallelForEachError::BackgroundTaskError{source}),
+            };
+            match (background_mode, result) {
+                (BackgroundMode::GateThenJoin, _) => break Ok(result),
+                (BackgroundMode::PollUntilDone(_), _) if workers_done => break Ok(result),
+                (BackgroundMode::PollUntilDone(interval), Continue::Stop) => {
+                    (*state.lock()).cancel(cancelled);
+                    std::thread::sleep(interval);
+                }
+                (BackgroundMode::PollUntilStop(_), Continue::Stop) => break Ok(result),
+                (BackgroundMode::PollUntilDone(interval), Continue::Continue)
+                | (BackgroundMode::PollUntilStop(interval), Continue::Continue) => {
+                    std::thread::sleep(interval);
+                }
+            }
+        };
+
+        match background_result {
+            Ok(Continue::Continue) => {},
+            _ => (*state.lock()).cancel(cancelled),
+        };
+
+        if let Err(e) = background_result {
+            // Joined here instead of by the scope, so that a captured worker panic doesn't
+            // turn into a panic of the scope.
+            let _ = join_workers(handles);
+            return Err(e);
+        }
+
+        // Errors and panics of the other workers take precedence over the timeout
+        match (join_workers(handles), *timed_out.lock()) {
+            (Ok(()), Some((worker_id, item_index))) => Err(ParallelForEachError::Timeout {
+                worker_id,
+                item_index,
+            }),
+            (result, _) => result,
+        }
+    })
+    .unwrap_or_else(|p| std::panic::resume_unwind(p)) // Background or propagated worker panic
+    ?;
+
+    let collected_errors = std::mem::take(&mut *collected_errors.lock());
+    if !collected_errors.is_empty() {
+        return Err(collected_worker_errors(error_policy, collected_errors));
+    }
+
+    let mut report = report.into_inner();
+    report.item_cap_reached = state.lock().cap_reached;
+    report.completed = state.lock().completed();
+    report.worker_count = worker_count;
+    report.items_processed = items_processed
+        .load(Ordering::Relaxed)
+        .saturating_sub(warmup_items);
+    report.elapsed = warmup.elapsed();
+    Ok(report)
+}
+
+/// Join handle of a worker thread of run_threaded.
+type WorkerHandle<'scope, Ei, Ew, Eb> =
+    scoped::JoinHandle<'scope, Result<(), ParallelForEachError<Ei, Ew, Eb>>>;
+
+/// Sequential backend of the runs, without any threads: everything runs on the calling thread
+/// as a single worker with id 0 (the scheduler is started with a single slot).
+/// The background function can't run concurrently with the worker, so:
+/// - `BackgroundMode::GateThenJoin` calls it once after the worker finished all items (so a
+///   blocking background function, like the window loop of the renderer, shows the finished
+///   result, and its `Continue::Stop` has nothing left to stop).
+/// - The polling modes call it after the worker is initialized, between the items whenever the
+///   polling interval has passed, and after the worker finishes (`PollUntilStop` keeps polling
+///   until it returns `Continue::Stop`).
+///
+/// A background function that waits for the workers to make progress (e.g. on a barrier or
+/// a condition variable) never returns here, such functions only work with threads.
+/// Errors, panics, stopping, leftovers and the report behave as with a single worker thread.
+/// Settings that only concern threads (names, stack size, priority, batching, lock metrics,
+/// worker states, hard timeout) are ignored.
+fn run_sequential<S, Fi, Fw, Fb, Ff, Fe, Ei, Ew, Eb, State>(
+    mut scheduler: S,
+    init_fun: Fi,
+    worker_fun: Fw,
+    mut background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+    on_exit: Fe,
+) -> Result<RunReport, ParallelForEachError<Ei, Ew, Eb>>
+where
+    S: Scheduler + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, S::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Fe: FnOnce(&mut S),
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let poll_interval = match settings.background_mode {
+        BackgroundMode::GateThenJoin => None,
+        BackgroundMode::PollUntilDone(interval) | BackgroundMode::PollUntilStop(interval) => {
+            Some(interval)
+        }
+    };
+    let panic_policy = settings.panic_policy;
+    let error_policy = settings.error_policy;
+    let progress_counter = settings.progress_counter;
+    let panic_hook = match (panic_policy, settings.panic_hook) {
+        (PanicPolicy::CaptureAsError, PanicHook::Default) | (PanicPolicy::Propagate, _) => None,
+        (PanicPolicy::CaptureAsError, hook) => {
+            install_dispatching_panic_hook();
+            Some(std::sync::Arc::new(hook))
+        }
+    };
+
+    // Hands over the scheduler when leaving this function, whatever the result
+    scheduler.start(1);
+    let mut state = scopeguard::guard(
+        RunState::new(scheduler, settings.max_items, 1),
+        |mut state| on_exit(&mut state.scheduler),
+    );
+    let warmup = Warmup::new(settings.warmup_items);
+    let mut items_processed = 0usize;
+    let mut collected_errors = Vec::new();
+    let panicked = |message| ParallelForEachError::WorkerPanicked {
+        worker_id: 0,
+        message,
+    };
+
+    let mut background_error = None;
+    // Returns None if the background function failed
+    let mut poll_background = |state: &mut RunState<S>| match background_fun() {
+        Ok(Continue::Continue) => Some(Continue::Continue),
+        Ok(Continue::Stop) => {
+            state.stop();
+            Some(Continue::Stop)
+        }
+        Err(source) => {
+            background_error = Some(source);
+            state.stop();
+            None
+        }
+    };
+
+    // Like on the caller thread of run_threaded, not called while a propagated panic unwinds.
+    // With FinishedCallbackThread::LastWorker it is called then, a panic from it is stored and
+    // resumed when leaving this function like in run_threaded.
+    let skip_while_panicking = settings.finished_callback_thread == FinishedCallbackThread::Caller;
+    let finished_callback = CatchingCallback::new(finished_callback);
+    let finished = scopeguard::guard((), |_| {
+        if !(skip_while_panicking && std::thread::panicking()) {
+            finished_callback.call()
+        }
+    });
+    let thread_state = run_as_worker(panic_hook.as_ref(), panic_policy, || init_fun(0));
+    let mut background = match poll_interval {
+        Some(_) => poll_background(&mut state),
+        None => Some(Continue::Continue), // GateThenJoin is called after the worker
+    };
+    let mut last_poll = std::time::Instant::now();
+    let worker_result = match thread_state {
+        Err(message) => Err(panicked(message)),
+        Ok(Err(source)) => Err(ParallelForEachError::InitTaskError { source }),
+        Ok(Ok(mut thread_state)) => loop {
+            if let (Some(Continue::Continue), Some(interval)) = (background, poll_interval) {
+                if last_poll.elapsed() >= interval {
+                    background = poll_background(&mut state);
+                    last_poll = std::time::Instant::now();
+                }
+            }
+            let (index, item) = match state.next(0) {
+                Some(next) => next,
+                None => break Ok(()),
+            };
+            let result = run_as_worker(panic_hook.as_ref(), panic_policy, || {
+                worker_fun(&mut thread_state, item)
+            });
+            match (result, error_policy) {
+                (Ok(Ok(())), _) => {
+                    items_processed += 1;
+                    if let Some(progress_counter) = &progress_counter {
+                        progress_counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                    warmup.item_processed();
+                }
+                (Err(message), _) => {
+                    state.stop();
+                    break Err(panicked(message));
+                }
+                (Ok(Err(source)), ErrorPolicy::FailFast) => {
+                    state.stop();
+                    break Err(ParallelForEachError::WorkerTaskError { source });
+                }
+                (Ok(Err(source)), ErrorPolicy::StopAfter(limit) | ErrorPolicy::Collect(limit)) => {
+                    if collected_errors.len() < limit.get() {
+                        collected_errors.push((index, source));
+                    }
+                    if collected_errors.len() >= limit.get() {
+                        state.stop();
+                    }
+                }
+            }
+        },
+    };
+    drop(finished);
+
+    match (settings.background_mode, background) {
+        (BackgroundMode::GateThenJoin, _) => {
+            poll_background(&mut state);
+        }
+        // The last call always happens after the worker finished
+        (BackgroundMode::PollUntilDone(_), Some(_)) => {
+            poll_background(&mut state);
+        }
+        (BackgroundMode::PollUntilStop(interval), Some(Continue::Continue)) => loop {
+            std::thread::sleep(interval);
+            if let Some(Continue::Continue) = poll_background(&mut state) {
+                continue;
+            }
+            break;
+        },
+        _ => {}
+    }
+
+    if let Some(source) = background_error {
+        return Err(ParallelForEachError::BackgroundTaskError { source });
+    }
+    worker_result?;
+    if !collected_errors.is_empty() {
+        return Err(collected_worker_errors(error_policy, collected_errors));
+    }
+    Ok(RunReport {
+        item_cap_reached: state.cap_reached,
+        completed: state.completed(),
+        worker_count: 1,
+        items_processed: items_processed.saturating_sub(settings.warmup_items),
+        elapsed: warmup.elapsed(),
+        ..RunReport::default()
+    })
+}
+
+/// Builds the error for the worker errors collected with `error_policy`, which are paired with
+/// the indices of the failed items.
+fn collected_worker_errors<Ei, Ew, Eb>(
+    error_policy: ErrorPolicy,
+    errors: Vec<(usize, Ew)>,
+) -> ParallelForEachError<Ei, Ew, Eb>
+where
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    match error_policy {
+        ErrorPolicy::Collect(_) => ParallelForEachError::IndexedWorkerTaskErrors { errors },
+        _ => ParallelForEachError::WorkerTaskErrors {
+            sources: errors.into_iter().map(|(_, source)| source).collect(),
+        },
+    }
+}
+
+/// Runs a part of the sequential worker with the panic hook and panic policy of the run, like
+/// a worker thread would. Returns the panic message if it panicked and panics are captured.
+fn run_as_worker<R>(
+    panic_hook: Option<&std::sync::Arc<PanicHook>>,
+    panic_policy: PanicPolicy,
+    f: impl FnOnce() -> R,
+) -> Result<R, String> {
+    let _panic_hook_guard = panic_hook.map(|hook| {
+        WORKER_PANIC_HOOK.with(|current| *current.borrow_mut() = Some(hook.clone()));
+        scopeguard::guard((), |_| {
+            WORKER_PANIC_HOOK.with(|current| *current.borrow_mut() = None)
+        })
+    });
+    match panic_policy {
+        PanicPolicy::Propagate => Ok(f()),
+        PanicPolicy::CaptureAsError => std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+            .map_err(|payload| panic_message(&*payload)),
+    }
+}
+
+/// Finished callback that doesn't unwind into its caller, which may be a worker or a guard
+/// running while a worker panic propagates (a panic would abort the process then).
+/// A panic from the callback is stored and resumed when this is dropped at the end of the run,
+/// unless another panic is already propagating.
+struct CatchingCallback<Ff: Fn()> {
+    callback: Ff,
+    panic: lock::Mutex<Option<Box<dyn std::any::Any + Send>>>,
+}
+
+impl<Ff: Fn()> CatchingCallback<Ff> {
+    fn new(callback: Ff) -> CatchingCallback<Ff> {
+        CatchingCallback {
+            callback,
+            panic: lock::Mutex::new(None),
+        }
+    }
+
+    fn call(&self) {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(&self.callback));
+        if let Err(p) = result {
+            *self.panic.lock() = Some(p);
+        }
+    }
+}
+
+impl<Ff: Fn()> Drop for CatchingCallback<Ff> {
+    fn drop(&mut self) {
+        if let Some(p) = self.panic.lock().take() {
+            if !std::thread::panicking() {
+                std::panic::resume_unwind(p);
+            }
+        }
+    }
+}
+
+/// Shared state of a run, accessed by the workers under the state mutex.
+/// The scheduler is only asked for items under the mutex too.
+struct RunState<S> {
+    scheduler: S,
+    stopped: bool,
+    next_index: usize,
+    max_items: Option<usize>,
+    cap_reached: bool,
+    /// Worker slots that got None from the scheduler before the run was stopped.
+    out_of_items: Vec<bool>,
+    threads_running: usize,
+}
+
+impl<S: Scheduler> RunState<S> {
+    /// Scheduler has to be started already.
+    fn new(scheduler: S, max_items: Option<usize>, worker_count: usize) -> RunState<S> {
+        RunState {
+            scheduler,
+            stopped: false,
+            next_index: 0,
+            max_items,
+            cap_reached: false,
+            out_of_items: vec![false; worker_count],
+            threads_running: worker_count,
+        }
+    }
+
+    /// Behaves like scheduler next, but also returns index of the item
+    fn next(&mut self, worker_id: usize) -> Option<(usize, S::Item)> {
+        if self.stopped || self.out_of_items[worker_id] {
+            return None;
+        }
+        if Some(self.next_index) == self.max_items {
+            if self.scheduler.has_next(worker_id) {
+                self.cap_reached = true;
+                self.stop();
+            } else {
+                self.out_of_items[worker_id] = true;
+            }
+            return None;
+        }
+        match self.scheduler.next(worker_id) {
+            Some(item) => {
+                let index = self.next_index;
+                self.next_index += 1;
+                Some((index, item))
+            }
+            None => {
+                self.out_of_items[worker_id] = true;
+                None
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        if !self.stopped {
+            self.stopped = true;
+            self.scheduler.stop();
+        }
+    }
+
+    /// All worker slots ran out of items before the run was stopped.
+    fn completed(&self) -> bool {
+        self.out_of_items.iter().all(|&out_of_items| out_of_items)
+    }
+
+    /// Stops the run before running out of items, workers drop the rest of their batches.
+    fn cancel(&mut self, cancelled: &sync::AtomicBool) {
+        cancelled.store(true, sync::Ordering::Relaxed);
+        self.stop();
+    }
+
+    /// Called when a worker exits, returns true for the last one.
+    fn leave(&mut self) -> bool {
+        self.threads_running -= 1;
+        self.threads_running == 0
+    }
+}
+
+/// Atomics shared by the workers of a run, replaced by the model checked ones from loom in tests
+/// built with `--cfg loom` (like the locks in the lock module). Only the loom tests can run
+/// then, the worker threads themselves are not modeled.
+mod sync {
+    #[cfg(all(test, loom))]
+    pub(super) use loom::sync::atomic::{AtomicBool, Ordering};
+    #[cfg(not(all(test, loom)))]
+    pub(super) use std::sync::atomic::{AtomicBool, Ordering};
+}
+
+/// Scoped threads of the runs: `std::thread::scope`, or the scope of crossbeam-utils with the
+/// `crossbeam-scope` feature (the runs were originally built on it, it is kept to compare the
+/// two). The runs behave the same with either of them, the test suite passes with both
+/// `cargo test` and `cargo test --features crossbeam-scope`.
+#[cfg(not(feature = "crossbeam-scope"))]
+mod scoped {
+    pub(super) use std::thread::Scope;
+    pub(super) type JoinHandle<'scope, T> = std::thread::ScopedJoinHandle<'scope, T>;
+
+    /// Runs `f` with a scope for spawning threads and joins them all before returning.
+    /// Returns the panic of `f` as an error, like crossbeam.
+    pub(super) fn scope<'env, F, R>(f: F) -> std::thread::Result<R>
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| std::thread::scope(f)))
+    }
+
+    pub(super) fn spawn<'scope, 'env, F, T>(
+        scope: &'scope Scope<'scope, 'env>,
+        name: Option<String>,
+        stack_size: Option<usize>,
+        f: F,
+    ) -> std::io::Result<JoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'env,
+        T: Send + 'env,
+    {
+        let mut builder = std::thread::Builder::new();
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        if let Some(stack_size) = stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        builder.spawn_scoped(scope, f)
+    }
+}
+
+#[cfg(feature = "crossbeam-scope")]
+mod scoped {
+    pub(super) struct Scope<'scope, 'env>(&'scope crossbeam_utils::thread::Scope<'env>);
+    pub(super) type JoinHandle<'scope, T> = crossbeam_utils::thread::ScopedJoinHandle<'scope, T>;
+
+    pub(super) fn scope<'env, F, R>(f: F) -> std::thread::Result<R>
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        crossbeam_utils::thread::scope(|scope| f(&Scope(scope)))
+    }
+
+    pub(super) fn spawn<'scope, 'env, F, T>(
+        scope: &'scope Scope<'scope, 'env>,
+        name: Option<String>,
+        stack_size: Option<usize>,
+        f: F,
+    ) -> std::io::Result<JoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'env,
+        T: Send + 'env,
+    {
+        let mut builder = scope.0.builder();
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        if let Some(stack_size) = stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        builder.spawn(move |_| f())
+    }
+}
+
+/// Items currently processed by each worker and since when, for `Settings::per_item_hard_timeout`.
+struct ItemWatch {
+    start: std::time::Instant,
+    /// Microseconds from `start` to the start of the current item of each worker, u64::MAX
+    /// while the worker isn't processing any item.
+    item_started: Vec<std::sync::atomic::AtomicU64>,
+    item_index: Vec<AtomicUsize>,
+}
+
+impl ItemWatch {
+    const IDLE: u64 = u64::MAX;
+
+    fn new(worker_count: usize) -> ItemWatch {
+        ItemWatch {
+            start: std::time::Instant::now(),
+            item_started: (0..worker_count)
+                .map(|_| std::sync::atomic::AtomicU64::new(Self::IDLE))
+                .collect(),
+            item_index: (0..worker_count).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    fn item_started(&self, worker_id: usize, index: usize) {
+        self.item_index[worker_id].store(index, Ordering::Relaxed);
+        let started = self.start.elapsed().as_micros() as u64;
+        self.item_started[worker_id].store(started, Ordering::Release);
+    }
+
+    fn item_finished(&self, worker_id: usize) {
+        self.item_started[worker_id].store(Self::IDLE, Ordering::Release);
+    }
+
+    /// Returns the worker id and item index of an item that has been running for longer than
+    /// the timeout.
+    fn overdue(&self, timeout: Duration) -> Option<(usize, usize)> {
+        let now = self.start.elapsed().as_micros() as u64;
+        let timeout = timeout.as_micros() as u64;
+        let worker_id = self.item_started.iter().position(|started| {
+            let started = started.load(Ordering::Acquire);
+            started != Self::IDLE && now.saturating_sub(started) > timeout
+        })?;
+        let item_index = self.item_index[worker_id].load(Ordering::Relaxed);
+        Some((worker_id, item_index))
+    }
+}
+
+/// Finds when the first `Settings::warmup_items` items were processed.
+struct Warmup {
+    remaining: AtomicUsize,
+    end: lock::Mutex<Option<std::time::Instant>>,
+}
+
+impl Warmup {
+    fn new(items: usize) -> Warmup {
+        Warmup {
+            remaining: AtomicUsize::new(items),
+            end: lock::Mutex::new(if items == 0 {
+                Some(std::time::Instant::now())
+            } else {
+                None
+            }),
+        }
+    }
+
+    fn item_processed(&self) {
+        // Only a load once the warmup is over
+        if self.remaining.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let previous = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        if previous == Ok(1) {
+            *self.end.lock() = Some(std::time::Instant::now());
+        }
+    }
+
+    /// Time since the end of the warmup.
+    fn elapsed(&self) -> Duration {
+        self.end.lock().map(|end| end.elapsed()).unwrap_or_default()
+    }
+}
+
+/// Publishes the state of a single worker, sets it to Done when dropped.
+struct ActivityReporter<'a>(Option<&'a std::sync::atomic::AtomicU8>);
+
+impl<'a> ActivityReporter<'a> {
+    fn set(&self, state: WorkerState) {
+        if let Some(shared) = self.0 {
+            shared.store(state as u8, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<'a> Drop for ActivityReporter<'a> {
+    fn drop(&mut self) {
+        self.set(WorkerState::Done);
+    }
+}
+
+/// Per worker lock wait statistics, merged into the run report when the worker exits.
+struct LockWait<'a> {
+    report: Option<&'a lock::Mutex<RunReport>>,
+    clock: &'a LockClockFn,
+    total: Duration,
+    max: Duration,
+}
+
+impl<'a> LockWait<'a> {
+    fn new(report: Option<&'a lock::Mutex<RunReport>>, clock: &'a LockClockFn) -> Self {
+        LockWait {
+            report,
+            clock,
+            total: Duration::default(),
+            max: Duration::default(),
+        }
+    }
+
+    /// Returns the current time if the metrics are enabled.
+    fn start(&self) -> Option<std::time::Instant> {
+        self.report.map(|_| (self.clock)())
+    }
+
+    fn record_since(&mut self, start: Option<std::time::Instant>) {
+        if let Some(start) = start {
+            let wait = (self.clock)().saturating_duration_since(start);
+            self.total += wait;
+            self.max = self.max.max(wait);
+        }
+    }
+}
+
+impl<'a> Drop for LockWait<'a> {
+    fn drop(&mut self) {
+        if let Some(report) = self.report {
+            let mut report = report.lock();
+            report.total_lock_wait += self.total;
+            report.max_lock_wait = report.max_lock_wait.max(self.max);
+        }
+    }
+}
+
+/// Per worker count of processed items that is periodically added to the shared counter.
+struct LocalProgress<'a> {
+    shared: Option<&'a AtomicUsize>,
+    count: usize,
+    flush_interval: usize,
+}
+
+impl<'a> LocalProgress<'a> {
+    fn new(shared: Option<&'a AtomicUsize>, flush_interval: usize) -> Self {
+        LocalProgress {
+            shared,
+            count: 0,
+            flush_interval,
+        }
+    }
+
+    fn increment(&mut self) {
+        self.count += 1;
+        if self.count >= self.flush_interval {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(shared) = self.shared {
+            shared.fetch_add(self.count, Ordering::Relaxed);
+        }
+        self.count = 0;
+    }
+}
+
+impl<'a> Drop for LocalProgress<'a> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+thread_local! {
+    /// Panic hook of the current worker thread, used by the dispatching hook.
+    static WORKER_PANIC_HOOK: std::cell::RefCell<Option<std::sync::Arc<PanicHook>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Installs (once per process) a global panic hook that calls the hook of the current worker
+/// thread, or the previously installed hook if the current thread doesn't have any.
+fn install_dispatching_panic_hook() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let handled = WORKER_PANIC_HOOK
+                .try_with(|current| match current.borrow().as_deref() {
+                    None | Some(PanicHook::Default) => false,
+                    Some(PanicHook::Silent) => true,
+                    Some(PanicHook::Custom(hook)) => {
+                        hook(info);
+                        true
+                    }
+                })
+                .unwrap_or(false);
+            if !handled {
+                previous(info);
+            }
+        }));
+    });
+}
+
+/// Extracts the message from a panic payload.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<Any>".into()
+    }
+}
+
+/// Same as parallel_for_each_with_settings, but the worker function gets windows of
+/// `window_size` consecutive items instead of single items, e.g. an item with its neighbors.
+/// Windows start `step` items apart, so they overlap if `step < window_size` (items are cloned
+/// into every window that contains them) and items between them are skipped if
+/// `step > window_size`.
+/// Only full windows are processed: if the last items don't fill a whole window, they are
+/// skipped, and there are no windows at all if the iterator is shorter than `window_size`.
+#[allow(clippy::too_many_arguments)]
+pub fn parallel_windows<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    window_size: NonZeroUsize,
+    step: NonZeroUsize,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    It::Item: Clone + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, Vec<It::Item>) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    parallel_for_each_with_settings(
+        Windows {
+            iterator,
+            buffer: std::collections::VecDeque::with_capacity(window_size.get()),
+            window_size: window_size.get(),
+            step: step.get(),
+            to_skip: 0,
+        },
+        init_fun,
+        worker_fun,
+        background_fun,
+        finished_callback,
+        settings,
+    )
+}
+
+/// Iterator over full windows of items of another iterator, see parallel_windows.
+struct Windows<It: Iterator> {
+    iterator: It,
+    /// Items of the current window that were already taken from the iterator.
+    buffer: std::collections::VecDeque<It::Item>,
+    window_size: usize,
+    step: usize,
+    /// Number of items to drop before the next window starts.
+    to_skip: usize,
+}
+
+impl<It> Iterator for Windows<It>
+where
+    It: Iterator,
+    It::Item: Clone,
+{
+    type Item = Vec<It::Item>;
+
+    fn next(&mut self) -> Option<Vec<It::Item>> {
+        while self.to_skip > 0 {
+            if self.buffer.pop_front().is_none() {
+                self.iterator.next()?;
+            }
+            self.to_skip -= 1;
+        }
+        while self.buffer.len() < self.window_size {
+            self.buffer.push_back(self.iterator.next()?);
+        }
+        self.to_skip = self.step;
+        Some(self.buffer.iter().cloned().collect())
+    }
+}
+
+/// Distributes items between the workers of a run. Runs over an iterator use `MutexScheduler`,
+/// parallel_for_each_with_scheduler takes any scheduler.
+/// Each worker (`0..worker_count`) asks for items until it gets None, after which it isn't
+/// asked again. The run calls `next` under its own lock, together with the batching and
+/// `Settings::max_items` bookkeeping.
+pub trait Scheduler: Sync {
+    type Item;
+
+    /// Called once with the number of worker slots, before any items are requested.
+    fn start(&mut self, worker_count: usize);
+
+    /// Returns the next item for a worker slot, or None when the slot has nothing more to do
+    /// (also for slots that were not started).
+    fn next(&self, worker_id: usize) -> Option<Self::Item>;
+
+    /// Returns whether `next` would return another item for the worker slot. Called once the
+    /// run reached `Settings::max_items`, to tell a capped run from one that ran out of items
+    /// exactly at the cap. The run stops right after, so the default just takes the item and
+    /// drops it.
+    fn has_next(&self, worker_id: usize) -> bool {
+        self.next(worker_id).is_some()
+    }
+
+    /// Makes `next` return None for all slots from now on, called when the run is stopped
+    /// early (background function stop, errors, panics).
+    fn stop(&self);
+}
+
+/// Items of an iterator shared by all workers, each worker takes the next one. The default
+/// scheduler, used by parallel_for_each and the other runs over an iterator.
+pub struct MutexScheduler<It>(lock::Mutex<MutexSchedulerState<It>>);
+
+struct MutexSchedulerState<It> {
+    iterator: Option<It>,
+    /// Iterator that was stopped before being exhausted, if kept.
+    leftover: Option<It>,
+    keep_leftover: bool,
+}
+
+impl<It> MutexScheduler<It> {
+    pub fn new(iterator: It) -> MutexScheduler<It> {
+        MutexScheduler::with_leftover(iterator, false)
+    }
+
+    /// Scheduler that keeps the iterator when stopped, for `take_leftover`.
+    fn with_leftover(iterator: It, keep_leftover: bool) -> MutexScheduler<It> {
+        MutexScheduler(lock::Mutex::new(MutexSchedulerState {
+            iterator: Some(iterator),
+            leftover: None,
+            keep_leftover,
+        }))
+    }
+
+    /// Returns the iterator if the scheduler was stopped before it was exhausted.
+    fn take_leftover(&mut self) -> Option<It> {
+        self.0.get_mut().leftover.take()
+    }
+}
+
+impl<It: Iterator + Send> Scheduler for MutexScheduler<It> {
+    type Item = It::Item;
+
+    fn start(&mut self, _worker_count: usize) {}
+
+    fn next(&self, _worker_id: usize) -> Option<It::Item> {
+        let mut state = self.0.lock();
+        let item = state.iterator.as_mut()?.next();
+        if item.is_none() {
+            state.iterator = None;
+        }
+        item
+    }
+
+    fn has_next(&self, worker_id: usize) -> bool {
+        let state = self.0.lock();
+        if state.keep_leftover {
+            // Taking an item would lose it from the leftovers, so an iterator that wasn't
+            // exhausted yet counts as having more items
+            return state.iterator.is_some();
+        }
+        drop(state);
+        self.next(worker_id).is_some()
+    }
+
+    fn stop(&self) {
+        let mut state = self.0.lock();
+        let iterator = state.iterator.take();
+        if state.keep_leftover {
+            state.leftover = iterator;
+        }
+    }
+}
+
+/// Items of an iterator dealt to the worker slots in turns: item `i` always goes to slot
+/// `i % worker_count`, e.g. for interleaved scanlines with per-worker state.
+/// Items taken from the iterator for other slots are buffered until they ask for them, so a
+/// slow worker holds up its own items instead of getting fewer of them.
+pub struct RoundRobinScheduler<It: Iterator>(lock::Mutex<RoundRobinState<It>>);
+
+struct RoundRobinState<It: Iterator> {
+    iterator: Option<It>,
+    queues: Vec<std::collections::VecDeque<It::Item>>,
+    next_slot: usize,
+}
+
+impl<It: Iterator> RoundRobinScheduler<It> {
+    pub fn new(iterator: It) -> RoundRobinScheduler<It> {
+        RoundRobinScheduler(lock::Mutex::new(RoundRobinState {
+            iterator: Some(iterator),
+            queues: Vec::new(),
+            next_slot: 0,
+        }))
+    }
+}
+
+impl<It> Scheduler for RoundRobinScheduler<It>
+where
+    It: Iterator + Send,
+    It::Item: Send,
+{
+    type Item = It::Item;
+
+    fn start(&mut self, worker_count: usize) {
+        let state = self.0.get_mut();
+        state.queues = (0..worker_count)
+            .map(|_| std::collections::VecDeque::new())
+            .collect();
+        state.next_slot = 0;
+    }
+
+    fn next(&self, worker_id: usize) -> Option<It::Item> {
+        let mut state = self.0.lock();
+        let state = &mut *state;
+        if worker_id >= state.queues.len() {
+            return None; // Not started, or not a slot of this run
+        }
+        loop {
+            if let Some(item) = state.queues[worker_id].pop_front() {
+                return Some(item);
+            }
+            let item = state.iterator.as_mut()?.next();
+            let item = match item {
+                Some(item) => item,
+                None => {
+                    state.iterator = None;
+                    return None;
+                }
+            };
+            let slot = state.next_slot;
+            state.next_slot = (slot + 1) % state.queues.len();
+            if slot == worker_id {
+                return Some(item);
+            }
+            state.queues[slot].push_back(item);
+        }
+    }
+
+    fn stop(&self) {
+        let mut state = self.0.lock();
+        state.iterator = None;
+        state.queues.iter_mut().for_each(|queue| queue.clear());
+    }
+}
+
+/// Range of indices split into one contiguous part per worker slot up front, without any
+/// balancing between them. The cheapest distribution for items of uniform cost.
+pub struct StaticRangeScheduler {
+    range: std::ops::Range<usize>,
+    /// Next index and end of the part of each slot.
+    parts: Vec<(AtomicUsize, usize)>,
+    stopped: AtomicBool,
+}
+
+impl StaticRangeScheduler {
+    pub fn new(range: std::ops::Range<usize>) -> StaticRangeScheduler {
+        StaticRangeScheduler {
+            range,
+            parts: Vec::new(),
+            stopped: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Scheduler for StaticRangeScheduler {
+    type Item = usize;
+
+    fn start(&mut self, worker_count: usize) {
+        let len = self.range.end.saturating_sub(self.range.start);
+        let start = self.range.start;
+        let part_start =
+            |i: usize| start + len / worker_count * i + std::cmp::min(i, len % worker_count);
+        self.parts = (0..worker_count)
+            .map(|i| (AtomicUsize::new(part_start(i)), part_start(i + 1)))
+            .collect();
+    }
+
+    fn next(&self, worker_id: usize) -> Option<usize> {
+        if self.stopped.load(Ordering::Relaxed) {
+            return None;
+        }
+        let (next, end) = self.parts.get(worker_id)?;
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        if index < *end {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn has_next(&self, worker_id: usize) -> bool {
+        match self.parts.get(worker_id) {
+            Some((next, end)) => next.load(Ordering::Relaxed) < *end,
+            None => false,
+        }
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Same as parallel_for_each_with_settings, but the items are handed to the workers by a
+/// custom scheduler instead of being taken from a shared iterator (which is the same as
+/// using `MutexScheduler`).
+/// The scheduler decides which items each worker gets, everything else, including
+/// `Settings::batching` and `Settings::max_items`, works as with an iterator. The run is
+/// completed (see `RunReport::completed`) when every worker got None from the scheduler.
+/// `Settings::parallel_threshold` doesn't apply, schedulers don't know their item count.
+pub fn parallel_for_each_with_scheduler<S, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    scheduler: S,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    S: Scheduler + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, S::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let result = if cfg!(feature = "sequential") {
+        run_sequential(
+            scheduler,
+            init_fun,
+            worker_fun,
+            background_fun,
+            finished_callback,
+            settings,
+            |_| {},
+        )
+    } else {
+        run_threaded(
+            scheduler,
+            init_fun,
+            worker_fun,
+            background_fun,
+            finished_callback,
+            settings,
+            |_| {},
+        )
+    };
+    result.map(|_| ())
+}
+
+/// Same as parallel_for_each_with_settings, but the items are pulled from the iterator by a
+/// separate thread ahead of demand and buffered in a channel of the given depth.
+/// This helps when the iterator itself is slow (e.g. reads the items from disk), because the
+/// workers don't wait for it while holding the lock.
+pub fn parallel_for_each_prefetched<It, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    background_fun: Fb,
+    finished_callback: Ff,
+    prefetch_depth: NonZeroUsize,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    It: Iterator + Send,
+    It::Item: Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let (sender, receiver) = std::sync::mpsc::sync_channel(prefetch_depth.get());
+
+    let result = scoped::scope(|scope| {
+        scoped::spawn(scope, None, None, move || {
+            for item in iterator {
+                if sender.send(item).is_err() {
+                    break; // Receiver was dropped, the workers were stopped
+                }
+            }
+        })
+        .expect("Failed to spawn the prefetch thread");
+
+        parallel_for_each_with_settings(
+            receiver.into_iter(),
+            init_fun,
+            worker_fun,
+            background_fun,
+            finished_callback,
+            settings,
+        )
+    });
+
+    match result {
+        Ok(result) => result,
+        Err(p) => std::panic::resume_unwind(p),
+    }
+}
+
+/// Same as parallel_for_each_with_settings, but the worker function can add more items to be
+/// processed using the `Enqueue` handle it receives.
+/// The run finishes when there are no queued items and no worker is processing an item (which
+/// could enqueue more).
+/// Items are taken from the queue in `settings.queue_order`.
+///
+/// A worker that finds the queue empty while other items are in flight waits for them outside
+/// of the shared run state. Only the items themselves count for `settings.progress_counter`
+/// and `settings.max_items`, not these waits.
+/// The queue is closed when the run is stopping (stop or error from the background function,
+/// init or worker error, worker panic, `settings.max_items`), the queued items are dropped and
+/// waiting workers give up.
+pub fn parallel_for_each_recursive<Items, Fi, Fw, Fb, Ff, Ei, Ew, Eb, State>(
+    items: Items,
+    init_fun: Fi,
+    worker_fun: Fw,
+    mut background_fun: Fb,
+    finished_callback: Ff,
+    settings: Settings,
+) -> Result<(), ParallelForEachError<Ei, Ew, Eb>>
+where
+    Items: IntoIterator,
+    Items::Item: Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, Items::Item, &Enqueue<Items::Item>) -> Result<(), Ew> + Sync + Send,
+    Fb: FnMut() -> Result<Continue, Eb>,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    Eb: ErrorSource,
+{
+    let queue = Enqueue {
+        state: lock::Mutex::new(EnqueueState {
+            items: items.into_iter().collect(),
+            in_flight: 0,
+            remaining: settings.max_items,
+            closed: false,
+        }),
+        order: settings.queue_order,
+        cond: lock::Condvar::new(),
+    };
+    let queue = &queue;
+    let stopping_error_count = settings.error_policy.stopping_error_count();
+    let error_count = AtomicUsize::new(0);
+    let error_count = &error_count;
+    // Counted here instead of by the run, which sees the waits as items too
+    let progress_counter = settings.progress_counter.clone();
+    let progress_counter = progress_counter.as_deref();
+
+    parallel_for_each_with_settings(
+        std::iter::from_fn(move || queue.pop()),
+        |worker_id| {
+            let state = init_fun(worker_id);
+            if state.is_err() {
+                queue.close();
+            }
+            state
+        },
+        |state, slot| {
+            let item = match slot {
+                QueueSlot::Item(item) => item,
+                QueueSlot::Wait => match queue.wait_pop() {
+                    Some(item) => item,
+                    None => return Ok(()),
+                },
+            };
+            let _done_guard = scopeguard::guard((), |_| queue.done());
+            scopeguard::defer_on_unwind! { queue.close(); }
+            let result = worker_fun(state, item, queue);
+            match &result {
+                Ok(()) => {
+                    if let Some(progress_counter) = progress_counter {
+                        progress_counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Err(_) => {
+                    if error_count.fetch_add(1, Ordering::Relaxed) + 1 >= stopping_error_count {
+                        queue.close();
+                    }
+                }
+            }
+            result
+        },
+        || {
+            let result = background_fun();
+            if !matches!(result, Ok(Continue::Continue)) {
+                queue.close();
+            }
+            result
+        },
+        finished_callback,
+        Settings {
+            progress_counter: None,
+            max_items: None,
+            ..settings
+        },
+    )
+}
+
+/// Queue of items for parallel_for_each_recursive.
+pub struct Enqueue<T> {
+    state: lock::Mutex<EnqueueState<T>>,
+    cond: lock::Condvar,
+    order: QueueOrder,
+}
+
+/// Item taken from the queue by the run state iterator.
+enum QueueSlot<T> {
+    Item(T),
+    /// The queue was empty, but items in flight may still add more. The worker has to wait
+    /// for them, which must not happen while holding the run state.
+    Wait,
+}
+
+struct EnqueueState<T> {
+    items: std::collections::VecDeque<T>,
+    /// Number of items that were taken from the queue, but not processed yet.
+    in_flight: usize,
+    /// Number of items that can still be taken, for `Settings::max_items`.
+    remaining: Option<usize>,
+    /// The run is stopping, no more items are taken.
+    closed: bool,
+}
+
+impl<T> Enqueue<T> {
+    /// Adds an item to be processed before the run finishes.
+    /// The item is dropped if the run is already stopping.
+    pub fn push(&self, item: T) {
+        let mut state = self.state.lock();
+        if !state.closed {
+            state.items.push_back(item);
+            self.cond.notify_one();
+        }
+    }
+
+    /// Takes an item from the queue without waiting.
+    /// Returns None only when there is no more work.
+    fn pop(&self) -> Option<QueueSlot<T>> {
+        let mut state = self.state.lock();
+        match self.take(&mut state) {
+            Some(item) => Some(QueueSlot::Item(item)),
+            None if state.in_flight == 0 || state.closed => None,
+            None => Some(QueueSlot::Wait),
+        }
+    }
+
+    /// Takes an item from the queue, waiting for the in flight items if the queue is empty.
+    /// Returns None only when there is no more work.
+    fn wait_pop(&self) -> Option<T> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(item) = self.take(&mut state) {
+                return Some(item);
+            } else if state.in_flight == 0 || state.closed {
+                return None;
+            }
+            self.cond.wait(&mut state);
+        }
+    }
+
+    fn take(&self, state: &mut EnqueueState<T>) -> Option<T> {
+        if state.closed {
+            return None;
+        }
+        if state.remaining == Some(0) {
+            // The item cap was reached, the run stops cleanly
+            self.close_locked(state);
+            return None;
+        }
+        let item = match self.order {
+            QueueOrder::Fifo => state.items.pop_front(),
+            QueueOrder::Lifo => state.items.pop_back(),
+        }?;
+        state.in_flight += 1;
+        if let Some(remaining) = &mut state.remaining {
+            *remaining -= 1;
+        }
+        Some(item)
+    }
+
+    /// Drops the queued items and makes all waiting and future takes return None.
+    fn close(&self) {
+        let mut state = self.state.lock();
+        self.close_locked(&mut state);
+    }
+
+    fn close_locked(&self, state: &mut EnqueueState<T>) {
+        state.closed = true;
+        state.items.clear();
+        self.cond.notify_all();
+    }
+
+    /// Marks an item returned from `pop` as processed.
+    fn done(&self) {
+        let mut state = self.state.lock();
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            self.cond.notify_all();
+        }
+    }
+}
+
+/// Maps items of an iterator into per-worker accumulators in parallel, then combines the
+/// accumulators in parallel (pairwise, in a tree), reusing the same worker threads for both
+/// phases.
+/// Accumulators of workers with higher ids are always reduced into the ones with lower ids.
+pub fn parallel_map_reduce<It, Fi, Fm, Fr, Acc>(
+    iterator: It,
+    init_fun: Fi,
+    map_fun: Fm,
+    reduce_fun: Fr,
+    worker_count: WorkerCount,
+) -> Acc
+where
+    It: Iterator + Send,
+    Fi: Fn() -> Acc + Sync,
+    Fm: Fn(&mut Acc, It::Item) + Sync,
+    Fr: Fn(Acc, Acc) -> Acc + Sync,
+    Acc: Send,
+{
+    let worker_count = resolve_worker_count(worker_count);
+
+    let iterator = lock::Mutex::new(Some(iterator));
+    let accumulators: Vec<_> = (0..worker_count).map(|_| lock::Mutex::new(None)).collect();
+    let barrier = PoisonableBarrier::new(worker_count);
+
+    // References that can safely be moved into the thread
+    let iterator = &iterator;
+    let accumulators = &accumulators;
+    let barrier = &barrier;
+    let init_fun = &init_fun;
+    let map_fun = &map_fun;
+    let reduce_fun = &reduce_fun;
+
+    let panic = scoped::scope(|scope| {
+        let handles = (0..worker_count)
+            .map(|worker_id| {
+                scoped::spawn(scope, None, None, move || {
+                    // Other workers would process the items or wait for us at the barrier forever
+                    scopeguard::defer_on_unwind! {
+                        *iterator.lock() = None;
+                        barrier.poison();
+                    }
+
+                    let mut accumulator = init_fun();
+                    loop {
+                        let item = {
+                            let mut iterator = iterator.lock();
+                            let item = iterator.as_mut().and_then(|it| it.next());
+                            if item.is_none() {
+                                *iterator = None;
+                            }
+                            item
+                        };
+                        match item {
+                            Some(item) => map_fun(&mut accumulator, item),
+                            None => break,
+                        }
+                    }
+                    *accumulators[worker_id].lock() = Some(accumulator);
+
+                    let mut stride = 1;
+                    while stride < worker_count {
+                        barrier.wait();
+                        if worker_id % (2 * stride) == 0 && worker_id + stride < worker_count {
+                            let other = accumulators[worker_id + stride].lock().take().unwrap();
+                            let mut mine = accumulators[worker_id].lock();
+                            *mine = Some(reduce_fun(mine.take().unwrap(), other));
+                        }
+                        stride *= 2;
+                    }
+                })
+                .expect("Failed to spawn a worker thread")
+            })
+            .collect::<Vec<_>>();
+
+        // Panics caused by the poisoned barrier are not interesting, find the original one.
+        handles
+            .into_iter()
+            .filter_map(|handle| handle.join().err())
+            .fold(None, |first, p| match first {
+                Some(first) => Some(first),
+                None if p.is::<BarrierPoisoned>() => None,
+                None => Some(p),
+            })
+    })
+    .unwrap(); // We have already joined all the threads
+
+    if let Some(p) = panic {
+        std::panic::resume_unwind(p);
+    }
+
+    let result = accumulators[0].lock().take().unwrap();
+    result
+}
+
+/// Folds items into per-worker accumulators in parallel, like `parallel_map_reduce`, but the
+/// fold function can stop the whole run by returning `ControlFlow::Break` or an error.
+///
+/// Workers finish the item they are currently folding and then stop taking new items.
+/// Accumulators of all workers are then combined as usual, so after a break the result
+/// contains exactly the items that were folded before the workers stopped, which always
+/// includes the item that caused the break but otherwise depends on timing.
+/// Returns `Break` with the combined accumulator if any worker broke, `Continue` if all items
+/// were folded. If fold returned an error, the accumulators are dropped and the error of the
+/// worker with the lowest id is returned.
+pub fn parallel_try_fold<It, Fi, Ff, Fc, Acc, E>(
+    iterator: It,
+    init_fun: Fi,
+    fold_fun: Ff,
+    combine_fun: Fc,
+    worker_count: WorkerCount,
+) -> Result<ControlFlow<Acc, Acc>, E>
+where
+    It: Iterator + Send,
+    Fi: Fn() -> Acc + Sync,
+    Ff: Fn(&mut Acc, It::Item) -> Result<ControlFlow<()>, E> + Sync,
+    Fc: Fn(Acc, Acc) -> Acc + Sync,
+    Acc: Send,
+    E: Send,
+{
+    let stop = AtomicBool::new(false);
+    let stop = &stop;
+
+    // The status is Ok(true) if the worker broke
+    let (accumulator, status) = parallel_map_reduce(
+        iterator.take_while(|_| !stop.load(Ordering::Relaxed)),
+        || (init_fun(), Ok(false)),
+        |(accumulator, status), item| {
+            if !matches!(status, Ok(false)) {
+                return;
+            }
+            match fold_fun(accumulator, item) {
+                Ok(ControlFlow::Continue(())) => return,
+                Ok(ControlFlow::Break(())) => *status = Ok(true),
+                Err(e) => *status = Err(e),
+            }
+            stop.store(true, Ordering::Relaxed);
+        },
+        |(a, status_a), (b, status_b)| match (status_a, status_b) {
+            (Err(e), _) | (Ok(_), Err(e)) => (a, Err(e)),
+            (Ok(broke_a), Ok(broke_b)) => (combine_fun(a, b), Ok(broke_a || broke_b)),
+        },
+        worker_count,
+    );
+
+    Ok(if status? {
+        ControlFlow::Break(accumulator)
+    } else {
+        ControlFlow::Continue(accumulator)
+    })
+}
+
+/// Returns an item for which the predicate is true, stopping all workers as soon as one of
+/// them finds it. If several items match, any of them may be returned, depending on timing.
+pub fn parallel_find<It, Fp>(
+    iterator: It,
+    predicate: Fp,
+    worker_count: WorkerCount,
+) -> Option<It::Item>
+where
+    It: Iterator + Send,
+    It::Item: Send,
+    Fp: Fn(&It::Item) -> bool + Sync,
+{
+    let result = parallel_try_fold(
+        iterator,
+        || None,
+        |found, item| -> Result<_, std::convert::Infallible> {
+            Ok(if predicate(&item) {
+                *found = Some(item);
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            })
+        },
+        |a, b| a.or(b),
+        worker_count,
+    );
+    match result {
+        Ok(ControlFlow::Break(found)) | Ok(ControlFlow::Continue(found)) => found,
+        Err(e) => match e {},
+    }
+}
+
+/// Same as parallel_find, but returns the first matching item in the iteration order.
+/// Items are taken from the iterator in order, so all items before the first found match were
+/// already taken when the workers stop, and they are still checked before returning.
+pub fn parallel_find_first<It, Fp>(
+    iterator: It,
+    predicate: Fp,
+    worker_count: WorkerCount,
+) -> Option<It::Item>
+where
+    It: Iterator + Send,
+    It::Item: Send,
+    Fp: Fn(&It::Item) -> bool + Sync,
+{
+    let result = parallel_try_fold(
+        iterator.enumerate(),
+        || None,
+        |found, (index, item)| -> Result<_, std::convert::Infallible> {
+            Ok(if predicate(&item) {
+                *found = Some((index, item));
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            })
+        },
+        |a, b| match (a, b) {
+            (Some(a), Some(b)) => Some(if a.0 < b.0 { a } else { b }),
+            (a, b) => a.or(b),
+        },
+        worker_count,
+    );
+    match result {
+        Ok(ControlFlow::Break(found)) | Ok(ControlFlow::Continue(found)) => {
+            found.map(|(_index, item)| item)
+        }
+        Err(e) => match e {},
+    }
+}
+
+/// Extension methods for running the common cases of parallel_for_each directly on iterators,
+/// without per-worker state, background function or finished callback.
+pub trait ParallelIteratorExt: Iterator + Send + Sized {
+    /// Calls `f` for every item in parallel.
+    /// Panics if the worker threads can't be spawned.
+    fn par_for_each<F>(self, worker_count: WorkerCount, f: F)
+    where
+        F: Fn(Self::Item) + Sync + Send,
+    {
+        if let Err(e) = self.par_try_for_each(worker_count, |item| -> Result<(), ()> {
+            f(item);
+            Ok(())
+        }) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Calls `f` for every item in parallel, stops at the first error.
+    fn par_try_for_each<F, E>(
+        self,
+        worker_count: WorkerCount,
+        f: F,
+    ) -> Result<(), ParallelForEachError<(), E, ()>>
+    where
+        F: Fn(Self::Item) -> Result<(), E> + Sync + Send,
+        E: ErrorSource,
+    {
+        parallel_for_each(
+            self,
+            |_worker_id| Ok(()),
+            |_state, item| f(item),
+            || Ok(Continue::Continue),
+            || {},
+            worker_count,
+        )
+    }
+
+    /// Maps every item in parallel, returns the results in the order of the items.
+    fn par_map<F, T>(self, worker_count: WorkerCount, f: F) -> Vec<T>
+    where
+        F: Fn(Self::Item) -> T + Sync,
+        T: Send,
+    {
+        let mut indexed = parallel_map_reduce(
+            self.enumerate(),
+            Vec::new,
+            |acc, (index, item)| acc.push((index, f(item))),
+            |mut a, mut b| {
+                a.append(&mut b);
+                a
+            },
+            worker_count,
+        );
+        indexed.sort_unstable_by_key(|(index, _)| *index);
+        indexed.into_iter().map(|(_, value)| value).collect()
+    }
+}
+
+impl<I: Iterator + Send> ParallelIteratorExt for I {}
+
+/// Barrier that can be released by a panicking thread, making the waiting threads panic too.
+struct PoisonableBarrier {
+    state: lock::Mutex<PoisonableBarrierState>,
+    cond: lock::Condvar,
+    count: usize,
+}
+
+struct PoisonableBarrierState {
+    waiting: usize,
+    generation: usize,
+    poisoned: bool,
+}
+
+/// Panic payload of threads waiting on a poisoned barrier.
+struct BarrierPoisoned;
+
+impl PoisonableBarrier {
+    fn new(count: usize) -> Self {
+        PoisonableBarrier {
+            state: lock::Mutex::new(PoisonableBarrierState {
+                waiting: 0,
+                generation: 0,
+                poisoned: false,
+            }),
+            cond: lock::Condvar::new(),
+            count,
+        }
+    }
+
+    /// Blocks until all threads have called wait. Panics if the barrier is poisoned.
+    fn wait(&self) {
+        let mut state = self.state.lock();
+        let generation = state.generation;
+        state.waiting += 1;
+        if state.waiting == self.count {
+            state.waiting = 0;
+            state.generation += 1;
+            self.cond.notify_all();
+        } else {
+            while state.generation == generation && !state.poisoned {
+                self.cond.wait(&mut state);
+            }
+        }
+        if state.poisoned {
+            drop(state);
+            std::panic::resume_unwind(Box::new(BarrierPoisoned));
+        }
+    }
+
+    fn poison(&self) {
+        self.state.lock().poisoned = true;
+        self.cond.notify_all();
+    }
+}
+
+/// Starts parallel_for_each in a background thread and returns immediately.
+/// The run is controlled through the returned guard, see `RunGuard`.
+/// `settings.background_mode` is ignored, the background function is used for handling the
+/// stop requests.
+pub fn spawn<It, Fi, Fw, Ff, Ei, Ew, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    finished_callback: Ff,
+    settings: Settings,
+) -> RunGuard<Ei, Ew>
+where
+    It: Iterator + Send + 'static,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send + 'static,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send + 'static,
+    Ff: Fn() + Sync + Send + 'static,
+    Ei: ErrorSource + 'static,
+    Ew: ErrorSource + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_clone = stop.clone();
+
+    let handle = std::thread::spawn(move || {
+        run_until_stopped(
+            iterator,
+            init_fun,
+            worker_fun,
+            finished_callback,
+            settings,
+            &stop_clone,
+        )
+    });
+
+    RunGuard {
+        stop,
+        handle: Some(handle),
+    }
+}
+
+/// Same as `spawn`, but the run is started in the given std thread scope, so the functions and
+/// the iterator can borrow data from outside of the scope instead of requiring `'static`.
+pub fn spawn_scoped<'scope, 'env, It, Fi, Fw, Ff, Ei, Ew, State>(
+    scope: &'scope std::thread::Scope<'scope, 'env>,
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    finished_callback: Ff,
+    settings: Settings,
+) -> ScopedRunGuard<'scope, Ei, Ew>
+where
+    It: Iterator + Send + 'scope,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send + 'scope,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send + 'scope,
+    Ff: Fn() + Sync + Send + 'scope,
+    Ei: ErrorSource + 'scope,
+    Ew: ErrorSource + 'scope,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_clone = stop.clone();
+
+    let handle = scope.spawn(move || {
+        run_until_stopped(
+            iterator,
+            init_fun,
+            worker_fun,
+            finished_callback,
+            settings,
+            &stop_clone,
+        )
+    });
+
+    RunGuard {
+        stop,
+        handle: Some(handle),
+    }
+}
+
+/// Runs parallel_for_each_with_settings, polling the stop flag as the background function.
+fn run_until_stopped<It, Fi, Fw, Ff, Ei, Ew, State>(
+    iterator: It,
+    init_fun: Fi,
+    worker_fun: Fw,
+    finished_callback: Ff,
+    settings: Settings,
+    stop: &AtomicBool,
+) -> SpawnedResult<Ei, Ew>
+where
+    It: Iterator + Send,
+    Fi: Fn(usize) -> Result<State, Ei> + Sync + Send,
+    Fw: Fn(&mut State, It::Item) -> Result<(), Ew> + Sync + Send,
+    Ff: Fn() + Sync + Send,
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+{
+    parallel_for_each_with_settings(
+        iterator,
+        init_fun,
+        worker_fun,
+        || -> Result<_, util::NoError> {
+            if stop.load(Ordering::Relaxed) {
+                Ok(Continue::Stop)
+            } else {
+                Ok(Continue::Continue)
+            }
+        },
+        finished_callback,
+        Settings {
+            background_mode: BackgroundMode::PollUntilDone(SPAWN_POLL_INTERVAL),
+            ..settings
+        },
+    )
+}
+
+/// Result of a run started by `spawn` or `spawn_scoped`.
+pub type SpawnedResult<Ei, Ew> = Result<(), ParallelForEachError<Ei, Ew, util::NoError>>;
+
+/// Join handle of the thread that executes a spawned run, see `RunGuard`.
+pub trait RunHandle {
+    type Output;
+    fn join(self) -> std::thread::Result<Self::Output>;
+}
+
+impl<T> RunHandle for std::thread::JoinHandle<T> {
+    type Output = T;
+    fn join(self) -> std::thread::Result<T> {
+        std::thread::JoinHandle::join(self)
+    }
+}
+
+impl<'scope, T> RunHandle for std::thread::ScopedJoinHandle<'scope, T> {
+    type Output = T;
+    fn join(self) -> std::thread::Result<T> {
+        std::thread::ScopedJoinHandle::join(self)
+    }
+}
+
+/// Handle to a run started by `spawn` (or `spawn_scoped`, see `ScopedRunGuard`).
+/// Dropping the guard stops the run and blocks until the workers observe the stop and finish
+/// their current items. Errors of a run that is stopped by dropping the guard are ignored,
+/// use `join` to get them.
+#[must_use]
+pub struct RunGuard<Ei, Ew, H = std::thread::JoinHandle<SpawnedResult<Ei, Ew>>>
+where
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    H: RunHandle<Output = SpawnedResult<Ei, Ew>>,
+{
+    stop: Arc<AtomicBool>,
+    handle: Option<H>,
+}
+
+/// Handle to a run started by `spawn_scoped`.
+pub type ScopedRunGuard<'scope, Ei, Ew> =
+    RunGuard<Ei, Ew, std::thread::ScopedJoinHandle<'scope, SpawnedResult<Ei, Ew>>>;
+
+impl<Ei, Ew, H> RunGuard<Ei, Ew, H>
+where
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    H: RunHandle<Output = SpawnedResult<Ei, Ew>>,
+{
+    /// Asks the workers to stop, doesn't wait for them.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits until the run finishes (either by running out of items or after `stop`) and returns
+    /// its result. Panics from the run are propagated.
+    pub fn join(mut self) -> SpawnedResult<Ei, Ew> {
+        match self.handle.take().unwrap().join() {
+            Ok(result) => result,
+            Err(p) => std::panic::resume_unwind(p),
+        }
+    }
+}
+
+impl<Ei, Ew, H> Drop for RunGuard<Ei, Ew, H>
+where
+    Ei: ErrorSource,
+    Ew: ErrorSource,
+    H: RunHandle<Output = SpawnedResult<Ei, Ew>>,
+{
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop();
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Trait for values that can be used as source error.
+pub trait ErrorSource: Sync + Send + std::fmt::Debug {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>;
+}
+
+impl<T> ErrorSource for T
+where
+    T: std::error::Error + std::fmt::Debug + Send + Sync + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self)
+    }
+}
+
+impl ErrorSource for dyn std::error::Error + Send + Sync + 'static {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self)
+    }
+}
+
+impl<T> ErrorSource for T
+where
+    T: std::fmt::Debug + Sync + Send,
+{
+    default fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use panic_control;
+    use proptest::prelude::*;
+    use proptest_attr_macro::proptest;
+    use std::sync::atomic::AtomicU32;
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Makes `Auto` resolve to `count` workers on this thread until the guard is dropped.
+    fn override_auto_worker_count(count: usize) -> impl Drop {
+        AUTO_WORKER_COUNT_OVERRIDE.with(|value| value.set(Some(count)));
+        scopeguard::guard((), |_| {
+            AUTO_WORKER_COUNT_OVERRIDE.with(|value| value.set(None));
+        })
+    }
+
+    struct IterationCheckHelper {
+        finished: AtomicBool,
+        latest_end_time: Instant,
+    }
+
+    impl IterationCheckHelper {
+        fn new() -> IterationCheckHelper {
+            IterationCheckHelper {
+                finished: AtomicBool::new(false),
+                latest_end_time: Instant::now() + TIMEOUT,
+            }
+        }
+
+        fn workers_running_check(&self) -> Result<(), String> {
+            if self.finished.load(Ordering::Relaxed) {
+                Err("Thread is running even though the end callback was encountered".into())
+            } else if Instant::now() > self.latest_end_time {
+                Err("Time limit exceeded".into())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn finished_callback(&self) {
+            self.finished.store(true, Ordering::Relaxed);
+        }
+
+        fn callback_called_check(&self) -> bool {
+            self.finished.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Arbitrary for WorkerCount {
+        type Parameters = ();
+        type Strategy = proptest::strategy::BoxedStrategy<Self>;
+        fn arbitrary_with(_args: Self::Parameters) -> Self::Strategy {
+            prop_oneof![
+                (1..128usize).prop_map(|n| WorkerCount::Manual(NonZeroUsize::new(n).unwrap())),
+                Just(WorkerCount::Auto),
+            ]
+            .boxed()
+        }
+    }
+
+    // Checks that each worker has the same thread id as the state
+    #[proptest]
+    fn stable_thread_id(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        parallel_for_each(
+            0..n,
+            |_worker_id| -> Result<_, ()> { Ok(std::thread::current().id()) },
+            |state_thread_id, _i| -> Result<(), ()> {
+                assert!(&std::thread::current().id() == state_thread_id);
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            worker_count,
+        )
+        .unwrap();
+    }
+
+    /// Sums a range using pralellel_for_each, checks that sum is as expected
+    #[proptest]
+    fn sum(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let helper = IterationCheckHelper::new();
+        let sum = AtomicU32::new(0);
+
+        parallel_for_each(
+            0..n,
+            |_worker_id| helper.workers_running_check(),
+            |_state, i| -> Result<(), String> {
+                helper.workers_running_check()?;
+                sum.fetch_add(i, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || helper.finished_callback(),
+            worker_count,
+        )
+        .unwrap();
+
+        assert!(helper.callback_called_check());
+        assert!(sum.load(Ordering::Relaxed) == if n > 0 { n * (n - 1) / 2 } else { 0 });
+    }
+
+    /// Sums a range using the iterator extension methods, checks that the sums are as expected
+    #[proptest]
+    fn sum_ext(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let expected = if n > 0 { n * (n - 1) / 2 } else { 0 };
+
+        let sum = AtomicU32::new(0);
+        (0..n).par_for_each(worker_count, |i| {
+            sum.fetch_add(i, Ordering::Relaxed);
+        });
+        assert!(sum.load(Ordering::Relaxed) == expected);
+
+        let sum = AtomicU32::new(0);
+        (0..n)
+            .par_try_for_each(worker_count, |i| -> Result<(), String> {
+                sum.fetch_add(i, Ordering::Relaxed);
+                Ok(())
+            })
+            .unwrap();
+        assert!(sum.load(Ordering::Relaxed) == expected);
+
+        let mapped = (0..n).par_map(worker_count, |i| i * 2);
+        assert!(mapped == (0..n).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(mapped.iter().sum::<u32>() == 2 * expected);
+    }
+
+    /// Checks that par_try_for_each returns the worker error.
+    #[proptest]
+    fn try_for_each_ext_error(worker_count: WorkerCount) {
+        let result = (0..).par_try_for_each(worker_count, |i| {
+            if i == 10 {
+                Err("Worker".to_string())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.unwrap_err().as_worker_error() == Some(&"Worker".to_string()));
+    }
+
+    /// Sums a range using pralellel_for_each, keeping the partial sums in shared state, checks
+    /// that sum is as expected
+    #[proptest]
+    fn sum_in_state(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let sum = AtomicU32::new(0);
+
+        struct State<'a> {
+            local_sum: u32,
+            global_sum: &'a AtomicU32,
+        }
+
+        impl<'a> Drop for State<'a> {
+            fn drop(&mut self) {
+                self.global_sum.fetch_add(self.local_sum, Ordering::Relaxed);
+            }
+        }
+
+        parallel_for_each(
+            0..n,
+            |_worker_id| -> Result<_, ()> {
+                Ok(State {
+                    local_sum: 0,
+                    global_sum: &sum,
+                })
+            },
+            |state, i| -> Result<(), ()> {
+                state.local_sum += i;
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            worker_count,
+        )
+        .unwrap();
+
+        assert!(sum.load(Ordering::Relaxed) == if n > 0 { n * (n - 1) / 2 } else { 0 });
+    }
+
+    /// Checks that the jobs are actually running in different threads by
+    /// blocking as many threads as there are workers.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[proptest]
+    fn actual_threads(worker_count: WorkerCount) {
+        let _override = override_auto_worker_count(3);
+        let n = resolve_worker_count(worker_count);
+
+        let count_waiting = std::sync::Mutex::new(0usize);
+        let cond = std::sync::Condvar::new();
+
+        let end = Instant::now() + TIMEOUT;
+
+        parallel_for_each(
+            0..n,
+            |_worker_id| -> Result<(), String> {
+                let mut count_waiting = count_waiting.lock().unwrap();
+                *count_waiting += 1;
+                if *count_waiting >= n {
+                    cond.notify_all();
+                    Ok(())
+                } else {
+                    while let Some(timeout) = end.checked_duration_since(Instant::now()) {
+                        let result = cond.wait_timeout(count_waiting, timeout).unwrap();
+                        count_waiting = result.0;
+                        if result.1.timed_out() {
+                            return Err("Timed out".into());
+                        } else if *count_waiting >= n {
+                            return Ok(());
+                        }
+                    }
+                    Err("wtf?".into())
+                }
+            },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            worker_count,
+        )
+        .unwrap();
+
+        assert!(*count_waiting.lock().unwrap() == n);
+    }
+
+    /// Runs repeatedly with different worker counts, checks that every run uses exactly its
+    /// own new set of threads and that all worker states are dropped (so the threads have
+    /// finished) before the call returns.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn repeated_runs_worker_counts() {
+        struct AliveGuard<'a>(&'a AtomicUsize);
+        impl<'a> Drop for AliveGuard<'a> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        let alive = AtomicUsize::new(0);
+        let mut previous_threads = std::collections::HashSet::new();
+        for count in &[3, 1, 8, 2, 5] {
+            let threads = std::sync::Mutex::new(std::collections::HashSet::new());
+            parallel_for_each(
+                0..100,
+                |_worker_id| -> Result<_, ()> {
+                    threads.lock().unwrap().insert(std::thread::current().id());
+                    alive.fetch_add(1, Ordering::Relaxed);
+                    Ok(AliveGuard(&alive))
+                },
+                |_state, _i| -> Result<(), ()> { Ok(()) },
+                || -> Result<_, ()> { Ok(Continue::Continue) },
+                || {},
+                WorkerCount::Manual(NonZeroUsize::new(*count).unwrap()),
+            )
+            .unwrap();
+
+            let threads = threads.into_inner().unwrap();
+            assert!(threads.len() == *count);
+            assert!(!threads.contains(&std::thread::current().id()));
+            assert!(threads.is_disjoint(&previous_threads));
+            assert!(alive.load(Ordering::Relaxed) == 0);
+            previous_threads = threads;
+        }
+    }
+
+    /// Checks that the iteration stops when background function returns Stop and that finished
+    /// callback is correctly invoked.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[proptest]
+    fn stop_from_background(worker_count: WorkerCount) {
+        let helper = IterationCheckHelper::new();
+
+        parallel_for_each(
+            0..,
+            |_worker_id| helper.workers_running_check(),
+            |_state, _i| helper.workers_running_check(),
+            || -> Result<_, String> {
+                // Here we can check that the threads have not finished yet, because the
+                // iterator is infinite and only waiting for this method to return
+                helper.workers_running_check()?;
+                Ok(Continue::Stop)
+            },
+            || {
+                helper.finished_callback();
+            },
+            worker_count,
+        )
+        .unwrap();
+        assert!(helper.callback_called_check());
+    }
+
+    /// Checks that panics from thread init function are propagated
+    #[proptest]
+    fn propagates_panics_init(worker_count: WorkerCount) {
+        let helper = IterationCheckHelper::new();
+        let result = std::panic::catch_unwind(|| {
+            parallel_for_each(
+                0..,
+                |worker_id| -> Result<(), String> {
+                    helper.workers_running_check()?;
+                    if worker_id == 0 {
+                        panic_control::disable_hook_in_current_thread();
+                        panic!("Don't panic!");
+                    } else {
+                        Ok(())
+                    }
+                },
+                |_state, _i| -> Result<(), String> { helper.workers_running_check() },
+                || -> Result<_, ()> { Ok(Continue::Continue) },
+                || helper.finished_callback(),
+                worker_count,
+            )
+        });
+        match result {
+            Err(e) => {
+                if let Some(string) = e.downcast_ref::<&str>() {
+                    assert!(string == &"Don't panic!");
+                    assert!(helper.callback_called_check());
+                } else {
+                    panic!("Got non-string panic");
+                }
+            }
+            Ok(Ok(_)) => panic!("Didn't get panic"),
+            Ok(Err(e)) => panic!("Something went wrong: {}", e),
+        }
+    }
+
+    /// Checks that panics from thread init function are propagated
+    #[proptest]
+    fn propagates_panics_worker(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let helper = IterationCheckHelper::new();
+        let result = std::panic::catch_unwind(|| {
+            parallel_for_each(
+                0..,
+                |_worker_id| -> Result<(), String> {
+                    panic_control::disable_hook_in_current_thread();
+                    helper.workers_running_check()
+                },
+                |_state, i| -> Result<(), String> {
+                    helper.workers_running_check()?;
+                    if i == n {
+                        panic!("Don't panic!");
+                    } else {
+                        Ok(())
+                    }
+                },
+                || -> Result<_, ()> { Ok(Continue::Continue) },
+                || helper.finished_callback(),
+                worker_count,
+            )
+        });
+        match result {
+            Err(e) => {
+                if let Some(string) = e.downcast_ref::<&str>() {
+                    assert!(string == &"Don't panic!");
+                    assert!(helper.callback_called_check());
+                } else {
+                    panic!("Got non-string panic");
+                }
+            }
+            Ok(Ok(_)) => panic!("Didn't get panic"),
+            Ok(Err(e)) => panic!("Something went wrong: {}", e),
+        }
+    }
+
+    /// Checks that panics from thread init function are propagated
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[proptest]
+    fn propagates_panics_background(worker_count: WorkerCount) {
+        let helper = IterationCheckHelper::new();
+        let result = std::panic::catch_unwind(|| {
+            parallel_for_each(
+                0..,
+                |_worker_id| -> Result<(), String> { helper.workers_running_check() },
+                |_state, _i| -> Result<(), String> { helper.workers_running_check() },
+                || -> Result<_, String> {
+                    helper.workers_running_check()?;
+                    panic_control::disable_hook_in_current_thread();
+                    panic!("Don't panic!");
+                },
+                || helper.finished_callback(),
+                worker_count,
+            )
+        });
+        match result {
+            Err(e) => {
+                if let Some(string) = e.downcast_ref::<&str>() {
+                    assert!(string == &"Don't panic!");
+                    assert!(helper.callback_called_check());
+                } else {
+                    panic!("Got non-string panic");
+                }
+            }
+            Ok(Ok(_)) => panic!("Didn't get panic"),
+            Ok(Err(e)) => panic!("Something went wrong: {}", e),
+        }
+    }
+
+    /// Checks that panics from finished callback function are propagated
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[proptest]
+    fn propagates_panics_callback(worker_count: WorkerCount) {
+        let helper = IterationCheckHelper::new();
+        let result = std::panic::catch_unwind(|| {
+            parallel_for_each(
+                0..,
+                |_worker_id| -> Result<(), String> {
+                    panic_control::disable_hook_in_current_thread();
+                    helper.workers_running_check()
+                },
+                |_state, _i| -> Result<(), String> { helper.workers_running_check() },
+                || -> Result<_, String> {
+                    helper.workers_running_check()?;
+                    Ok(Continue::Stop)
+                },
+                || {
+                    helper.finished_callback();
+                    panic!("Don't panic!");
+                },
+                worker_count,
+            )
+        });
+        match result {
+            Err(e) => {
+                if let Some(string) = e.downcast_ref::<&str>() {
+                    assert!(string == &"Don't panic!");
+                    assert!(helper.callback_called_check());
+                } else {
+                    panic!("Got non-string panic");
+                }
+            }
+            Ok(Ok(_)) => panic!("Didn't get panic"),
+            Ok(Err(e)) => panic!("Something went wrong: {}", e),
+        }
+    }
+
+    /// Checks that a panic from the finished callback takes precedence over a worker error.
+    #[proptest]
+    fn callback_panic_over_worker_error(worker_count: WorkerCount) {
+        let helper = IterationCheckHelper::new();
+        let result = std::panic::catch_unwind(|| {
+            parallel_for_each(
+                0..,
+                |_worker_id| -> Result<(), String> {
+                    panic_control::disable_hook_in_current_thread();
+                    helper.workers_running_check()
+                },
+                |_state, i| -> Result<(), String> {
+                    helper.workers_running_check()?;
+                    if i == 10 {
+                        Err("Worker".to_string())
+                    } else {
+                        Ok(())
+                    }
+                },
+                || -> Result<_, String> {
+                    helper.workers_running_check()?;
+                    Ok(Continue::Continue)
+                },
+                || {
+                    helper.finished_callback();
+                    panic!("Don't panic!");
+                },
+                worker_count,
+            )
+        });
+        match result {
+            Err(e) => {
+                assert!(e.downcast_ref::<&str>() == Some(&"Don't panic!"));
+                assert!(helper.callback_called_check());
+            }
+            Ok(result) => panic!(
+                "Didn't get panic, got {:?}",
+                result.map_err(|e| e.to_string())
+            ),
+        }
+    }
+
+    /// Checks that a panicking finished callback in a worker that is already unwinding from
+    /// a captured panic doesn't abort, and the callback panic is propagated.
+    #[proptest]
+    fn callback_panic_while_worker_panicking(worker_count: WorkerCount) {
+        let helper = IterationCheckHelper::new();
+        let result = std::panic::catch_unwind(|| {
+            parallel_for_each_with_settings(
+                0..,
+                |_worker_id| -> Result<(), String> {
+                    panic_control::disable_hook_in_current_thread();
+                    Ok(())
+                },
+                |_state, i| -> Result<(), String> {
+                    if i == 10 {
+                        panic!("Worker panic");
+                    }
+                    Ok(())
+                },
+                || -> Result<_, String> { Ok(Continue::Continue) },
+                || {
+                    helper.finished_callback();
+                    panic!("Don't panic!");
+                },
+                Settings {
+                    worker_count,
+                    panic_policy: PanicPolicy::CaptureAsError,
+                    panic_hook: PanicHook::Silent,
+                    ..Default::default()
+                },
+            )
+        });
+        match result {
+            Err(e) => {
+                assert!(e.downcast_ref::<&str>() == Some(&"Don't panic!"));
+                assert!(helper.callback_called_check());
+            }
+            Ok(result) => panic!(
+                "Didn't get panic, got {:?}",
+                result.map_err(|e| e.to_string())
+            ),
+        }
+    }
+
+    /// Checks that the caller doesn't call the finished callback while a propagated worker panic
+    /// unwinds, where a panic from the callback would be a double panic.
+    #[test]
+    fn caller_callback_skipped_while_propagating() {
+        let calls = AtomicU32::new(0);
+        let result = std::panic::catch_unwind(|| {
+            parallel_for_each_with_settings(
+                0..,
+                |_worker_id| -> Result<(), String> {
+                    panic_control::disable_hook_in_current_thread();
+                    Ok(())
+                },
+                |_state, i| -> Result<(), String> {
+                    if i == 10 {
+                        panic!("Worker panic");
+                    }
+                    Ok(())
+                },
+                || -> Result<_, String> { Ok(Continue::Continue) },
+                || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    panic!("Don't panic!");
+                },
+                Settings {
+                    worker_count: WorkerCount::Manual(NonZeroUsize::new(3).unwrap()),
+                    panic_policy: PanicPolicy::Propagate,
+                    finished_callback_thread: FinishedCallbackThread::Caller,
+                    ..Default::default()
+                },
+            )
+        });
+        let e = result.unwrap_err();
+        assert!(e.downcast_ref::<&str>() == Some(&"Worker panic"));
+        assert!(calls.load(Ordering::SeqCst) == 0);
+    }
+
+    /// Checks that in the default mode the background function is called only once and the
+    /// workers still process everything.
+    #[proptest]
+    fn background_gate_then_join(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let processed = AtomicU32::new(0);
+        let background_calls = AtomicU32::new(0);
+
+        parallel_for_each(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<_, ()> {
+                background_calls.fetch_add(1, Ordering::Relaxed);
+                Ok(Continue::Continue)
+            },
+            || {},
+            worker_count,
+        )
+        .unwrap();
+
+        assert!(background_calls.load(Ordering::Relaxed) == 1);
+        assert!(processed.load(Ordering::Relaxed) == n);
+    }
+
+    /// Checks that polling until done keeps calling the background function and that the last
+    /// call sees all the items processed.
+    #[proptest]
+    fn background_poll_until_done(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let processed = AtomicU32::new(0);
+        let mut last_seen = None;
+
+        parallel_for_each_with_settings(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                std::thread::sleep(Duration::from_micros(100));
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<_, ()> {
+                last_seen = Some(processed.load(Ordering::Relaxed));
+                Ok(Continue::Continue)
+            },
+            || {},
+            Settings {
+                worker_count,
+                background_mode: BackgroundMode::PollUntilDone(Duration::from_micros(50)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(last_seen == Some(n));
+    }
+
+    /// Checks that polling until stop continues even after the workers are finished.
+    #[proptest]
+    fn background_poll_until_stop(worker_count: WorkerCount, n: u8, stop_after: u8) {
+        let n = n as u32;
+        let stop_after = stop_after as u32;
+        let helper = IterationCheckHelper::new();
+        let mut background_calls = 0;
+
+        parallel_for_each_with_settings(
+            0..n,
+            |_worker_id| helper.workers_running_check(),
+            |_state, _i| helper.workers_running_check(),
+            || -> Result<_, ()> {
+                background_calls += 1;
+                if background_calls > stop_after {
+                    Ok(Continue::Stop)
+                } else {
+                    Ok(Continue::Continue)
+                }
+            },
+            || helper.finished_callback(),
+            Settings {
+                worker_count,
+                background_mode: BackgroundMode::PollUntilStop(Duration::from_micros(10)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(background_calls == stop_after + 1);
+        assert!(helper.callback_called_check());
+    }
+
+    /// Walks a binary tree stored in an array, where children are discovered by the workers,
+    /// and checks that every node is visited exactly once.
+    #[proptest]
+    fn recursive_tree(worker_count: WorkerCount, node_count: u16) {
+        let node_count = node_count as usize % 2000;
+        let visits: Vec<_> = (0..node_count).map(|_| AtomicU32::new(0)).collect();
+
+        parallel_for_each_recursive(
+            if node_count > 0 { vec![0] } else { vec![] },
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, node, enqueue| -> Result<(), ()> {
+                visits[node].fetch_add(1, Ordering::Relaxed);
+                for child in &[2 * node + 1, 2 * node + 2] {
+                    if *child < node_count {
+                        enqueue.push(*child);
+                    }
+                }
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(visits.iter().all(|v| v.load(Ordering::Relaxed) == 1));
+    }
+
+    /// Checks that a worker waiting for enqueued items doesn't block the shared run state, which
+    /// the polling background function needs.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn recursive_wait_doesnt_block_background() {
+        let polls = AtomicU32::new(0);
+        let visits = AtomicU32::new(0);
+
+        parallel_for_each_recursive(
+            vec![0u32],
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, node, enqueue| -> Result<(), ()> {
+                visits.fetch_add(1, Ordering::Relaxed);
+                if node == 0 {
+                    // The other worker is waiting for this item to finish meanwhile
+                    let deadline = Instant::now() + Duration::from_secs(10);
+                    while polls.load(Ordering::Relaxed) < 3 {
+                        assert!(Instant::now() < deadline, "Background function is blocked");
+                        std::thread::yield_now();
+                    }
+                    enqueue.push(1);
+                    enqueue.push(2);
+                }
+                Ok(())
+            },
+            || -> Result<_, ()> {
+                polls.fetch_add(1, Ordering::Relaxed);
+                Ok(Continue::Continue)
+            },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(2).unwrap()),
+                background_mode: BackgroundMode::PollUntilDone(Duration::from_millis(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(visits.load(Ordering::Relaxed) == 3);
+    }
+
+    /// Checks that an error stops the run with items still in progress: the children they
+    /// enqueue afterwards are dropped and the waiting workers give up instead of taking them.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn recursive_error_drops_queued() {
+        let failed = AtomicBool::new(false);
+        let children_visited = AtomicU32::new(0);
+
+        let result = parallel_for_each_recursive(
+            vec![0u32, 1],
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, node, enqueue| -> Result<(), u32> {
+                match node {
+                    0 => {
+                        let deadline = Instant::now() + Duration::from_secs(10);
+                        while !failed.load(Ordering::SeqCst) {
+                            assert!(Instant::now() < deadline, "Item 1 didn't fail");
+                            std::thread::yield_now();
+                        }
+                        // Let the error stop the run
+                        std::thread::sleep(Duration::from_millis(20));
+                        for child in 2..100 {
+                            enqueue.push(child);
+                        }
+                        Ok(())
+                    }
+                    1 => {
+                        // Let the third worker start waiting
+                        std::thread::sleep(Duration::from_millis(20));
+                        failed.store(true, Ordering::SeqCst);
+                        Err(node)
+                    }
+                    _ => {
+                        children_visited.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
+                    }
+                }
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(3).unwrap()),
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err(ParallelForEachError::WorkerTaskError { source: 1 })
+        ));
+        assert!(children_visited.into_inner() == 0);
+    }
+
+    /// Checks that only the items count for the progress and the item cap, not the waits of
+    /// the workers for enqueued items.
+    #[proptest]
+    fn recursive_counts_items(worker_count: WorkerCount, node_count: u8, max_items: u8) {
+        let node_count = node_count as usize;
+        let max_items = max_items as usize;
+        let visits = AtomicUsize::new(0);
+        let progress = Arc::new(AtomicUsize::new(0));
+
+        parallel_for_each_recursive(
+            if node_count > 0 { vec![0] } else { vec![] },
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, node, enqueue| -> Result<(), ()> {
+                visits.fetch_add(1, Ordering::Relaxed);
+                if node < 3 {
+                    // Give the other workers time to start waiting
+                    std::thread::sleep(Duration::from_micros(100));
+                }
+                for child in &[2 * node + 1, 2 * node + 2] {
+                    if *child < node_count {
+                        enqueue.push(*child);
+                    }
+                }
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                progress_counter: Some(progress.clone()),
+                max_items: Some(max_items),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let visits = visits.into_inner();
+        assert!(visits == node_count.min(max_items));
+        assert!(progress.load(Ordering::Relaxed) == visits);
+    }
+
+    /// Checks that a single worker with LIFO order visits a tree depth-first, in the same order
+    /// as a sequential traversal using a stack.
+    #[proptest]
+    fn recursive_tree_lifo(node_count: u16) {
+        let node_count = node_count as usize % 2000;
+        let children = |node: usize| {
+            [2 * node + 1, 2 * node + 2]
+                .iter()
+                .copied()
+                .filter(|child| *child < node_count)
+                .collect::<Vec<_>>()
+        };
+        let visits = std::sync::Mutex::new(Vec::new());
+
+        parallel_for_each_recursive(
+            if node_count > 0 { vec![0] } else { vec![] },
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, node, enqueue| -> Result<(), ()> {
+                visits.lock().unwrap().push(node);
+                for child in children(node) {
+                    enqueue.push(child);
+                }
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                queue_order: QueueOrder::Lifo,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut expected = Vec::new();
+        let mut stack = if node_count > 0 { vec![0] } else { vec![] };
+        while let Some(node) = stack.pop() {
+            expected.push(node);
+            stack.extend(children(node));
+        }
+        assert!(visits.into_inner().unwrap() == expected);
+    }
+
+    /// Checks that map reduce gives the same result as doing the two phases sequentially.
+    #[proptest]
+    fn map_reduce(worker_count: WorkerCount, n: u16) {
+        let n = n as u64;
+        let result = parallel_map_reduce(
+            0..n,
+            || (0u64, Vec::new()),
+            |acc, i| {
+                acc.0 += i * i;
+                acc.1.push(i);
+            },
+            |mut a, b| {
+                a.0 += b.0;
+                a.1.extend(b.1);
+                a
+            },
+            worker_count,
+        );
+
+        let mut items = result.1;
+        items.sort();
+        assert!(result.0 == (0..n).map(|i| i * i).sum::<u64>());
+        assert!(items == (0..n).collect::<Vec<_>>());
+    }
+
+    /// Checks that a panic in the map phase is propagated and doesn't leave workers hanging
+    /// at the barrier.
+    #[proptest]
+    fn map_reduce_propagates_panics(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let result = std::panic::catch_unwind(|| {
+            parallel_map_reduce(
+                0..,
+                || {
+                    panic_control::disable_hook_in_current_thread();
+                    0u32
+                },
+                |_acc, i| {
+                    if i == n {
+                        panic!("Don't panic!");
+                    }
+                },
+                |a, b| a + b,
+                worker_count,
+            )
+        });
+        match result {
+            Err(e) => assert!(e.downcast_ref::<&str>() == Some(&"Don't panic!")),
+            Ok(_) => panic!("Didn't get panic"),
+        }
+    }
+
+    /// Checks that the windows are exactly the full windows of the item slice, for overlapping
+    /// windows as well as for windows with gaps between them.
+    #[proptest]
+    fn windows(worker_count: WorkerCount, n: u8, window_size: u8, step: u8) {
+        let n = n as usize;
+        let window_size = NonZeroUsize::new(window_size as usize % 16 + 1).unwrap();
+        let step = NonZeroUsize::new(step as usize % 16 + 1).unwrap();
+        let items: Vec<_> = (0..n).collect();
+        let expected: Vec<_> = (0..n)
+            .step_by(step.get())
+            .filter(|start| start + window_size.get() <= n)
+            .map(|start| items[start..start + window_size.get()].to_vec())
+            .collect();
+
+        let windows = std::sync::Mutex::new(Vec::new());
+        parallel_windows(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, window| -> Result<(), ()> {
+                windows.lock().unwrap().push(window);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            window_size,
+            step,
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut windows = windows.into_inner().unwrap();
+        windows.sort();
+        assert!(windows == expected);
+    }
+
+    /// Runs with a scheduler over items `0..n`, returns the processed items in order.
+    fn run_scheduler<S: Scheduler<Item = usize> + Send>(
+        scheduler: S,
+        worker_count: WorkerCount,
+        background_result: Continue,
+    ) -> Vec<usize> {
+        let processed = std::sync::Mutex::new(Vec::new());
+        parallel_for_each_with_scheduler(
+            scheduler,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i| -> Result<(), ()> {
+                processed.lock().unwrap().push(i);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(background_result) },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut processed = processed.into_inner().unwrap();
+        processed.sort();
+        processed
+    }
+
+    /// Checks that every scheduler hands out every item exactly once.
+    #[proptest]
+    fn schedulers_process_every_item(worker_count: WorkerCount, n: u16) {
+        let n = n as usize % 2000;
+        let expected: Vec<_> = (0..n).collect();
+        let go = Continue::Continue;
+        assert!(run_scheduler(MutexScheduler::new(0..n), worker_count, go) == expected);
+        assert!(run_scheduler(RoundRobinScheduler::new(0..n), worker_count, go) == expected);
+        assert!(run_scheduler(StaticRangeScheduler::new(0..n), worker_count, go) == expected);
+    }
+
+    /// Checks the item distribution of the round robin and static range schedulers.
+    #[test]
+    fn scheduler_distribution() {
+        let mut round_robin = RoundRobinScheduler::new(0..7);
+        round_robin.start(3);
+        let slot_items = |scheduler: &dyn Scheduler<Item = usize>, slot| {
+            std::iter::from_fn(|| scheduler.next(slot)).collect::<Vec<_>>()
+        };
+        assert!(slot_items(&round_robin, 1) == vec![1, 4]);
+        assert!(slot_items(&round_robin, 0) == vec![0, 3, 6]);
+        assert!(slot_items(&round_robin, 2) == vec![2, 5]);
+
+        let mut static_range = StaticRangeScheduler::new(10..17);
+        static_range.start(3);
+        assert!(slot_items(&static_range, 2) == vec![15, 16]);
+        assert!(slot_items(&static_range, 0) == vec![10, 11, 12]);
+        assert!(slot_items(&static_range, 1) == vec![13, 14]);
+    }
+
+    /// Checks that schedulers that were not started don't hand out any items.
+    #[test]
+    fn schedulers_before_start() {
+        assert!(RoundRobinScheduler::new(0..10).next(0).is_none());
+        assert!(StaticRangeScheduler::new(0..10).next(0).is_none());
+
+        let mut round_robin = RoundRobinScheduler::new(0..10);
+        round_robin.start(2);
+        assert!(round_robin.next(2).is_none());
+    }
+
+    /// Checks that batching and the item cap apply to runs with a scheduler, and that the run
+    /// is only completed when every worker ran out of items.
+    #[test]
+    fn scheduler_run_settings() {
+        let worker_count = WorkerCount::Manual(NonZeroUsize::new(3).unwrap());
+        let run = |max_items| {
+            let processed = AtomicUsize::new(0);
+            let report = run_threaded(
+                StaticRangeScheduler::new(0..100),
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, _i| -> Result<(), ()> {
+                    processed.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+                || -> Result<Continue, ()> { Ok(Continue::Continue) },
+                || {},
+                Settings {
+                    worker_count,
+                    batching: Batching::Fixed(NonZeroUsize::new(4).unwrap()),
+                    max_items,
+                    ..Default::default()
+                },
+                |_| {},
+            )
+            .unwrap();
+            (processed.into_inner(), report)
+        };
+
+        let (processed, report) = run(None);
+        assert!(processed == 100);
+        assert!(report.completed);
+        assert!(!report.item_cap_reached);
+
+        let (processed, report) = run(Some(10));
+        assert!(processed == 10);
+        assert!(!report.completed);
+        assert!(report.item_cap_reached);
+
+        let (processed, report) = run(Some(100));
+        assert!(processed == 100);
+        assert!(report.completed);
+        assert!(!report.item_cap_reached);
+    }
+
+    /// Checks that no scheduler hands out items after being stopped, and that a run stopped
+    /// by the background function doesn't process the rest of the items.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn schedulers_respect_stop() {
+        let mut schedulers: Vec<Box<dyn Scheduler<Item = usize>>> = vec![
+            Box::new(MutexScheduler::new(0..100)),
+            Box::new(RoundRobinScheduler::new(0..100)),
+            Box::new(StaticRangeScheduler::new(0..100)),
+        ];
+        for scheduler in &mut schedulers {
+            scheduler.start(2);
+            assert!(scheduler.next(0).is_some());
+            assert!(scheduler.next(1).is_some());
+            scheduler.stop();
+            assert!(scheduler.next(0).is_none());
+            assert!(scheduler.next(1).is_none());
+        }
+
+        let n = 100_000;
+        let worker_count = WorkerCount::Manual(NonZeroUsize::new(2).unwrap());
+        let stop = Continue::Stop;
+        for processed in [
+            run_scheduler(MutexScheduler::new(0..n), worker_count, stop),
+            run_scheduler(RoundRobinScheduler::new(0..n), worker_count, stop),
+            run_scheduler(StaticRangeScheduler::new(0..n), worker_count, stop),
+        ] {
+            let mut deduplicated = processed.clone();
+            deduplicated.dedup();
+            assert!(deduplicated == processed);
+            assert!(processed.len() < n);
+        }
+    }
+
+    #[test]
+    fn windows_overlap() {
+        let windows: Vec<_> = Windows {
+            iterator: 0..10,
+            buffer: std::collections::VecDeque::new(),
+            window_size: 4,
+            step: 3,
+            to_skip: 0,
+        }
+        .collect();
+        assert!(windows == vec![vec![0, 1, 2, 3], vec![3, 4, 5, 6], vec![6, 7, 8, 9]]);
+    }
+
+    /// Checks that try_fold without breaks folds all items.
+    #[proptest]
+    fn try_fold_complete(worker_count: WorkerCount, n: u16) {
+        let n = n as u64;
+        let result = parallel_try_fold(
+            0..n,
+            || 0u64,
+            |acc, i| -> Result<_, ()> {
+                *acc += i;
+                Ok(ControlFlow::Continue(()))
+            },
+            |a, b| a + b,
+            worker_count,
+        );
+        assert!(result == Ok(ControlFlow::Continue((0..n).sum::<u64>())));
+    }
+
+    /// Sums until a worker's partial sum exceeds a threshold, checks that the run stops early
+    /// and returns the sum of the items that were processed.
+    #[proptest]
+    fn try_fold_break(worker_count: WorkerCount) {
+        const THRESHOLD: u64 = 1000;
+        let processed = AtomicUsize::new(0);
+        let result = parallel_try_fold(
+            0..u64::MAX,
+            || 0u64,
+            |acc, i| -> Result<_, ()> {
+                processed.fetch_add(1, Ordering::Relaxed);
+                *acc += i;
+                Ok(if *acc > THRESHOLD {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                })
+            },
+            |a, b| a + b,
+            worker_count,
+        );
+        let processed = processed.load(Ordering::Relaxed) as u64;
+        match result {
+            Ok(ControlFlow::Break(sum)) => {
+                assert!(sum > THRESHOLD);
+                assert!(sum <= (0..processed).sum::<u64>());
+            }
+            _ => panic!("Didn't break: {:?}", result),
+        }
+    }
+
+    /// Worker loop of a run reduced to the shared state handling, for the loom tests: takes
+    /// batches of two items, processes them unlocked and drops the rest of the batch when
+    /// cancelled. Returns true if it was the last worker to leave.
+    /// This is a hand written model of the worker loop in `run_threaded`, not the loop itself,
+    /// so the loom tests check the locking protocol of the shared state (`RunState`), not the
+    /// real loop. Keep it in sync when the protocol changes.
+    #[cfg(loom)]
+    fn loom_worker(
+        worker_id: usize,
+        state: &lock::Mutex<RunState<MutexScheduler<std::ops::Range<usize>>>>,
+        cancelled: &sync::AtomicBool,
+        processed: &lock::Mutex<Vec<usize>>,
+    ) -> bool {
+        let mut state = state.lock();
+        loop {
+            let mut batch: std::collections::VecDeque<_> =
+                std::iter::from_fn(|| state.next(worker_id))
+                    .take(2)
+                    .collect();
+            if batch.is_empty() {
+                break;
+            }
+            lock::MutexGuard::unlocked(&mut state, || {
+                while let Some((index, _item)) = batch.pop_front() {
+                    processed.lock().push(index);
+                    if cancelled.load(sync::Ordering::Relaxed) {
+                        batch.clear();
+                    }
+                }
+            });
+        }
+        state.leave()
+    }
+
+    /// Explores the interleavings of two workers and a background stop. Checks that exactly
+    /// one worker is the last one (and would call the finished callback), that no item is
+    /// processed twice and that no item is taken after the stop.
+    /// Run with `RUSTFLAGS="--cfg loom" cargo test --release --bin minipath loom_`.
+    #[cfg(loom)]
+    #[test]
+    fn loom_two_workers_background_stop() {
+        loom::model(|| {
+            let shared = loom::sync::Arc::new((
+                lock::Mutex::new(RunState::new(
+                    MutexScheduler::with_leftover(0..3, true),
+                    None,
+                    2,
+                )),
+                sync::AtomicBool::new(false),
+                lock::Mutex::new(Vec::new()),
+            ));
+
+            let handles: Vec<_> = (0..2)
+                .map(|worker_id| {
+                    let shared = shared.clone();
+                    loom::thread::spawn(move || {
+                        loom_worker(worker_id, &shared.0, &shared.1, &shared.2)
+                    })
+                })
+                .collect();
+
+            let stop_index = {
+                let mut state = shared.0.lock();
+                state.cancel(&shared.1);
+                state.next_index
+            };
+
+            let last_workers = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(|last| *last)
+                .count();
+            assert!(last_workers == 1);
+
+            let mut processed = shared.2.lock().clone();
+            processed.sort();
+            let processed_count = processed.len();
+            processed.dedup();
+            assert!(processed.len() == processed_count);
+            assert!(processed.iter().all(|index| *index < stop_index));
+
+            let mut state = shared.0.lock();
+            assert!(state.threads_running == 0);
+            match state.scheduler.take_leftover() {
+                Some(leftover) => assert!(leftover.start == stop_index),
+                None => assert!(state.out_of_items.iter().any(|&out_of_items| out_of_items)),
+            }
+        });
+    }
+
+    /// Checks that parallel_find returns one of the matching items, or None if there is none.
+    #[proptest]
+    fn find_any(worker_count: WorkerCount, n: u16, divisor: u8) {
+        let divisor = divisor as u32 + 1;
+        let found = parallel_find(0..n as u32, |i| i % divisor == divisor - 1, worker_count);
+        match found {
+            Some(i) => assert!(i % divisor == divisor - 1 && i < n as u32),
+            None => assert!((n as u32) < divisor),
+        }
+    }
+
+    /// Checks that parallel_find_first returns the lowest matching item, even though the
+    /// matches are dense enough that other workers find later ones at the same time.
+    #[proptest]
+    fn find_first(worker_count: WorkerCount, n: u16, divisor: u8) {
+        let divisor = divisor as u32 + 1;
+        let found = parallel_find_first(0..n as u32, |i| i % divisor == divisor - 1, worker_count);
+        let expected = (0..n as u32).find(|i| i % divisor == divisor - 1);
+        assert!(found == expected);
+    }
+
+    /// Checks that an error from the fold function stops the run and is returned.
+    #[proptest]
+    fn try_fold_error(worker_count: WorkerCount, n: u8) {
+        let result = parallel_try_fold(
+            0..,
+            || (),
+            |_acc, i| {
+                if i == n as u32 {
+                    Err(i)
+                } else {
+                    Ok(ControlFlow::Continue(()))
+                }
+            },
+            |_, _| (),
+            worker_count,
+        );
+        assert!(result == Err(n as u32));
+    }
+
+    /// Checks that checkpoints contain all completed items and that the last checkpoint is
+    /// complete.
+    #[proptest]
+    fn checkpoints(worker_count: WorkerCount, n: u8, interval: NonZeroUsize) {
+        let n = n as usize;
+        let checkpoints = std::sync::Mutex::new(Vec::new());
+        parallel_for_each_checkpointed(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            interval,
+            |state| checkpoints.lock().unwrap().push(state.completed_count()),
+            None,
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let checkpoints = checkpoints.into_inner().unwrap();
+        let mut expected: Vec<_> = (1..=n / interval.get())
+            .map(|i| i * interval.get())
+            .collect();
+        if !n.is_multiple_of(interval.get()) {
+            expected.push(n);
+        }
+        assert!(checkpoints == expected);
+    }
+
+    /// Checks that a resumed run processes only the items that were not completed yet.
+    #[proptest]
+    fn checkpoint_resume(worker_count: WorkerCount, completed: Vec<bool>) {
+        let resume_from = CheckpointState::with_completed(
+            completed.len(),
+            completed
+                .iter()
+                .enumerate()
+                .filter_map(|(i, &done)| if done { Some(i) } else { None }),
+        );
+        let processed = std::sync::Mutex::new(Vec::new());
+        let last_checkpoint = std::sync::Mutex::new(None);
+        parallel_for_each_checkpointed(
+            (0..completed.len()).map(|i| i * 10),
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, item| -> Result<(), ()> {
+                processed.lock().unwrap().push(item / 10);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            NonZeroUsize::new(1).unwrap(),
+            |state| *last_checkpoint.lock().unwrap() = Some(state.clone()),
+            Some(resume_from),
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut processed = processed.into_inner().unwrap();
+        processed.sort();
+        let expected: Vec<_> = (0..completed.len()).filter(|&i| !completed[i]).collect();
+        assert!(processed == expected);
+
+        if let Some(last_checkpoint) = last_checkpoint.into_inner().unwrap() {
+            assert!(last_checkpoint.completed_count() == completed.len());
+        } else {
+            assert!(expected.is_empty());
+        }
+    }
+
+    /// Interrupts a run with an error, stores the last checkpoint as a list of indices (as a
+    /// program would save it to a file) and resumes from it, checks that every item is
+    /// processed exactly once over both runs.
+    #[proptest]
+    fn checkpoint_interrupted_resume(worker_count: WorkerCount, n: u8, fail_at: u8) {
+        let n = n as usize;
+        let fail_at = fail_at as usize % (n + 1);
+        let processed = std::sync::Mutex::new(Vec::new());
+        let saved = std::sync::Mutex::new(Vec::new());
+        let run = |resume_from: Option<CheckpointState>, fail_at: Option<usize>| {
+            parallel_for_each_checkpointed(
+                0..n,
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, i| -> Result<(), usize> {
+                    if Some(i) == fail_at {
+                        return Err(i);
+                    }
+                    processed.lock().unwrap().push(i);
+                    Ok(())
+                },
+                || -> Result<Continue, ()> { Ok(Continue::Continue) },
+                || {},
+                NonZeroUsize::new(1).unwrap(),
+                |state| *saved.lock().unwrap() = state.completed_indices().collect(),
+                resume_from,
+                Settings {
+                    worker_count,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let interrupted = run(None, Some(fail_at));
+        assert!(interrupted.is_ok() == (fail_at == n));
+
+        let resume_from = CheckpointState::with_completed(n, saved.lock().unwrap().clone());
+        assert!(resume_from.is_empty() == (n == 0));
+        assert!(resume_from.completed_count() == processed.lock().unwrap().len());
+        run(Some(resume_from), None).unwrap();
+
+        let mut processed = processed.into_inner().unwrap();
+        processed.sort();
+        assert!(processed == (0..n).collect::<Vec<_>>());
+        if n > 0 {
+            assert!(saved.into_inner().unwrap() == (0..n).collect::<Vec<_>>());
+        }
+    }
+
+    /// Iterator that counts the items it produced and lets others wait for the count.
+    struct CountingIterator<'a>(std::ops::Range<u32>, &'a (lock::Mutex<u32>, lock::Condvar));
+
+    impl<'a> Iterator for CountingIterator<'a> {
+        type Item = u32;
+        fn next(&mut self) -> Option<u32> {
+            let item = self.0.next()?;
+            *(self.1).0.lock() += 1;
+            (self.1).1.notify_all();
+            Some(item)
+        }
+    }
+
+    /// Checks that prefetching pulls items from the iterator while the worker is busy, which the
+    /// direct iterator path never does with a single worker.
+    #[test]
+    fn prefetch_overlaps_iterator_with_workers() {
+        const N: u32 = 20;
+        let run = |prefetch: bool| {
+            let produced = (lock::Mutex::new(0u32), lock::Condvar::new());
+            let ahead = AtomicU32::new(0);
+            let init_fun = |_worker_id| -> Result<(), ()> { Ok(()) };
+            let worker_fun = |_state: &mut (), i| -> Result<(), ()> {
+                let mut count = produced.0.lock();
+                if prefetch {
+                    // Only the prefetch thread can advance the iterator now, wait for it
+                    while *count < N.min(i + 2) {
+                        let timeout = produced.1.wait_for(&mut count, Duration::from_secs(10));
+                        assert!(!timeout.timed_out(), "Item {} was not prefetched", i + 1);
+                    }
+                }
+                if *count > i + 1 {
+                    ahead.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            };
+            let background_fun = || -> Result<_, ()> { Ok(Continue::Continue) };
+            let settings = Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                ..Default::default()
+            };
+            if prefetch {
+                parallel_for_each_prefetched(
+                    CountingIterator(0..N, &produced),
+                    init_fun,
+                    worker_fun,
+                    background_fun,
+                    || {},
+                    NonZeroUsize::new(4).unwrap(),
+                    settings,
+                )
+            } else {
+                parallel_for_each_with_settings(
+                    CountingIterator(0..N, &produced),
+                    init_fun,
+                    worker_fun,
+                    background_fun,
+                    || {},
+                    settings,
+                )
+            }
+            .unwrap();
+            assert!(*produced.0.lock() == N);
+            ahead.load(Ordering::Relaxed)
+        };
+
+        assert!(run(false) == 0);
+        assert!(run(true) == N - 1);
+    }
+
+    /// Checks that worker errors stop the prefetching of an infinite iterator.
+    #[proptest]
+    fn prefetch_error_from_worker(worker_count: WorkerCount, n: u8, depth: u8) {
+        let n = n as u32;
+        let helper = IterationCheckHelper::new();
+
+        let result = parallel_for_each_prefetched(
+            0..,
+            |_worker_id| -> Result<(), String> { helper.workers_running_check() },
+            |_state, i| -> Result<(), String> {
+                helper.workers_running_check()?;
+                if i == n {
+                    Err("None shall pass!".to_string())
+                } else {
+                    Ok(())
+                }
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || helper.finished_callback(),
+            NonZeroUsize::new(depth as usize + 1).unwrap(),
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        );
+
+        match result {
+            Err(ParallelForEachError::WorkerTaskError { source }) => {
+                assert!(source == "None shall pass!");
+                assert!(helper.callback_called_check());
+            }
+            Err(e) => panic!("We didn't get the right error ({})", e),
+            Ok(()) => panic!("We didn't get an error!"),
+        }
+    }
+
+    /// Checks that dropping the guard stops an infinite run promptly.
+    #[proptest]
+    fn spawn_drop_stops(worker_count: WorkerCount) {
+        let processed = Arc::new(AtomicU32::new(0));
+        let processed_clone = processed.clone();
+
+        let guard = spawn(
+            0..,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            move |_state, _i| -> Result<(), ()> {
+                processed_clone.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        );
+
+        while processed.load(Ordering::Relaxed) == 0 {
+            std::thread::yield_now();
+        }
+
+        let start = Instant::now();
+        drop(guard);
+        assert!(start.elapsed() < TIMEOUT);
+
+        let processed_after_drop = processed.load(Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(processed.load(Ordering::Relaxed) == processed_after_drop);
+    }
+
+    /// Same as spawn_drop_stops, but with the scoped variant borrowing the counter.
+    #[proptest]
+    fn spawn_scoped_drop_stops(worker_count: WorkerCount) {
+        let processed = AtomicU32::new(0);
+
+        std::thread::scope(|scope| {
+            let guard = spawn_scoped(
+                scope,
+                0..,
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, _i| -> Result<(), ()> {
+                    processed.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+                || {},
+                Settings {
+                    worker_count,
+                    ..Default::default()
+                },
+            );
+
+            while processed.load(Ordering::Relaxed) == 0 {
+                std::thread::yield_now();
+            }
+
+            let start = Instant::now();
+            drop(guard);
+            assert!(start.elapsed() < TIMEOUT);
+
+            let processed_after_drop = processed.load(Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(5));
+            assert!(processed.load(Ordering::Relaxed) == processed_after_drop);
+        });
+    }
+
+    /// Same as spawn_join, but with the scoped variant borrowing the counter.
+    #[proptest]
+    fn spawn_scoped_join(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let processed = AtomicU32::new(0);
+
+        let result = std::thread::scope(|scope| {
+            spawn_scoped(
+                scope,
+                0..n,
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, i| -> Result<(), String> {
+                    processed.fetch_add(1, Ordering::Relaxed);
+                    if i == 200 {
+                        Err("None shall pass!".to_string())
+                    } else {
+                        Ok(())
+                    }
+                },
+                || {},
+                Settings {
+                    worker_count,
+                    ..Default::default()
+                },
+            )
+            .join()
+        });
+
+        if n > 200 {
+            assert!(matches!(
+                result,
+                Err(ParallelForEachError::WorkerTaskError { .. })
+            ));
+        } else {
+            assert!(result.is_ok());
+            assert!(processed.load(Ordering::Relaxed) == n);
+        }
+    }
+
+    /// Checks that join waits for all items and returns worker errors.
+    #[proptest]
+    fn spawn_join(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let processed = Arc::new(AtomicU32::new(0));
+        let processed_clone = processed.clone();
+
+        let result = spawn(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            move |_state, i| -> Result<(), String> {
+                processed_clone.fetch_add(1, Ordering::Relaxed);
+                if i == 200 {
+                    Err("None shall pass!".to_string())
+                } else {
+                    Ok(())
+                }
+            },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .join();
+
+        if n > 200 {
+            assert!(matches!(
+                result,
+                Err(ParallelForEachError::WorkerTaskError { .. })
+            ));
+        } else {
+            assert!(result.is_ok());
+            assert!(processed.load(Ordering::Relaxed) == n);
+        }
+    }
+
+    /// Items that are not `Send` are fine, as long as the iterator producing them is.
+    #[test]
+    fn non_send_items() {
+        let sum = AtomicU32::new(0);
+
+        let result = parallel_for_each(
+            (0..100u32).map(std::rc::Rc::new),
+            |_worker_id| -> Result<std::rc::Rc<u32>, ()> { Ok(std::rc::Rc::new(0)) },
+            |_state, i| -> Result<(), ()> {
+                sum.fetch_add(*i, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            WorkerCount::Auto,
+        );
+
+        assert!(result.is_ok());
+        assert!(sum.load(Ordering::Relaxed) == 4950);
+    }
+
+    /// Checks that the items processed and the elapsed time seen by a polling background
+    /// function only grow, and that the last call sees all items within the time of the run.
+    #[proptest]
+    fn run_context_progress(worker_count: WorkerCount, n: u16) {
+        let n = n as usize % 2000;
+        let seen = std::cell::RefCell::new(Vec::new());
+        let start = std::time::Instant::now();
+
+        parallel_for_each_with_run_context(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                std::thread::sleep(Duration::from_micros(10));
+                Ok(())
+            },
+            |context| -> Result<_, ()> {
+                assert!(context.workers_active() <= resolve_worker_count(worker_count));
+                seen.borrow_mut()
+                    .push((context.items_processed(), context.elapsed()));
+                Ok(Continue::Continue)
+            },
+            || {},
+            Settings {
+                worker_count,
+                background_mode: BackgroundMode::PollUntilDone(Duration::from_micros(50)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let total = start.elapsed();
+
+        let seen = seen.into_inner();
+        assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]));
+        let &(items_processed, elapsed) = seen.last().unwrap();
+        assert!(items_processed == n);
+        assert!(elapsed <= total);
+    }
+
+    /// Checks that all workers can read a shared table while counting items in their own state.
+    #[proptest]
+    fn shared_context(worker_count: WorkerCount, n: u16) {
+        /// Per-worker counter that reports its count when the worker stops.
+        struct Counter<'a> {
+            count: u64,
+            counts: &'a std::sync::Mutex<Vec<u64>>,
+        }
+
+        impl<'a> Drop for Counter<'a> {
+            fn drop(&mut self) {
+                self.counts.lock().unwrap().push(self.count);
+            }
+        }
+
+        let n = n as usize % 1000;
+        let table: Vec<u64> = (0..n as u64).map(|i| i * 3).collect();
+        let sum = std::sync::atomic::AtomicU64::new(0);
+        let counts = std::sync::Mutex::new(Vec::new());
+
+        parallel_for_each_with_shared(
+            0..n,
+            table.as_slice(),
+            |table, _worker_id| -> Result<_, ()> {
+                assert!(table.len() == n);
+                Ok(Counter {
+                    count: 0,
+                    counts: &counts,
+                })
+            },
+            |table, counter, i| -> Result<(), ()> {
+                sum.fetch_add(table[i], Ordering::Relaxed);
+                counter.count += 1;
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(sum.load(Ordering::Relaxed) == table.iter().sum::<u64>());
+        assert!(counts.into_inner().unwrap().iter().sum::<u64>() == n as u64);
+    }
+
+    /// Checks that no more than the limit of workers hold the limited resource at a time.
+    #[proptest]
+    fn limited_resource(worker_count: WorkerCount, n: u8) {
+        let holding = AtomicUsize::new(0);
+        let max_holding = AtomicUsize::new(0);
+
+        parallel_for_each_with_limited_resource(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i, resource| -> Result<(), ()> {
+                let _guard = resource.acquire().unwrap();
+                let now_holding = holding.fetch_add(1, Ordering::SeqCst) + 1;
+                max_holding.fetch_max(now_holding, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_micros(20));
+                holding.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            NonZeroUsize::new(2).unwrap(),
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(max_holding.load(Ordering::SeqCst) <= 2);
+    }
+
+    /// Checks that closing the resource releases a waiting worker.
+    #[test]
+    fn limited_resource_close() {
+        let resource = LimitedResource::new(NonZeroUsize::new(1).unwrap());
+        let guard = resource.acquire();
+        assert!(guard.is_some());
+
+        crossbeam_utils::thread::scope(|scope| {
+            let waiting = scope.spawn(|_| resource.acquire().is_none());
+            std::thread::sleep(Duration::from_millis(10));
+            resource.close();
+            assert!(waiting.join().unwrap());
+        })
+        .unwrap();
+
+        drop(guard);
+        assert!(resource.acquire().is_none());
+    }
+
+    /// Checks that progress reported within an item shows up in the aggregate progress.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn item_progress() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let item_progress = ItemProgress::new();
+        let reported = std::sync::Barrier::new(2);
+        let checked = std::sync::Barrier::new(2);
+        let total = || counter.load(Ordering::Relaxed) as f64 + item_progress.in_progress();
+
+        parallel_for_each_with_item_progress(
+            0..2,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i, reporter| -> Result<(), ()> {
+                if i == 1 {
+                    for fraction in &[0.25, 0.5, 0.75] {
+                        reporter.report(*fraction);
+                        reported.wait();
+                        checked.wait();
+                    }
+                }
+                Ok(())
+            },
+            || -> Result<_, ()> {
+                for expected in &[1.25, 1.5, 1.75] {
+                    reported.wait();
+                    assert!(total() == *expected);
+                    checked.wait();
+                }
+                Ok(Continue::Continue)
+            },
+            || {},
+            &item_progress,
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                progress_counter: Some(counter.clone()),
+                progress_flush_interval: NonZeroUsize::new(1).unwrap(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(total() == 2.0);
+    }
+
+    /// Checks that the progress counter is exact by the time the finished callback is called.
+    #[proptest]
+    fn progress_counter_exact(worker_count: WorkerCount, n: u16, flush_interval: NonZeroUsize) {
+        let n = n as usize;
+        let flush_interval = NonZeroUsize::new(flush_interval.get() % 1000 + 1).unwrap();
+        let counter = Arc::new(AtomicUsize::new(0));
+        let count_when_finished = AtomicUsize::new(usize::MAX);
+
+        let result = parallel_for_each_with_settings(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || count_when_finished.store(counter.load(Ordering::Relaxed), Ordering::Relaxed),
+            Settings {
+                worker_count,
+                progress_counter: Some(counter.clone()),
+                progress_flush_interval: flush_interval,
+                ..Default::default()
+            },
+        );
+
+        assert!(result.is_ok());
+        assert!(count_when_finished.load(Ordering::Relaxed) == n);
+    }
+
+    fn bench_progress(b: &mut ::test::Bencher, flush_interval: usize) {
+        b.iter(|| {
+            parallel_for_each_with_settings(
+                0..100_000,
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, i| -> Result<(), ()> {
+                    ::test::black_box(i);
+                    Ok(())
+                },
+                || -> Result<Continue, ()> { Ok(Continue::Continue) },
+                || {},
+                Settings {
+                    progress_counter: Some(Arc::new(AtomicUsize::new(0))),
+                    progress_flush_interval: NonZeroUsize::new(flush_interval).unwrap(),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        });
+    }
+
+    #[bench]
+    fn bench_progress_flush_every_item(b: &mut ::test::Bencher) {
+        bench_progress(b, 1);
+    }
+
+    #[bench]
+    fn bench_progress_flush_batched(b: &mut ::test::Bencher) {
+        bench_progress(b, 64);
+    }
+
+    /// Checks that every item is processed exactly once with auto batching, for cheap and
+    /// slower worker functions.
+    #[proptest]
+    fn auto_batching(worker_count: WorkerCount, n: u16, slow: bool, max: NonZeroUsize) {
+        let n = if slow { n as usize % 256 } else { n as usize };
+        let max = NonZeroUsize::new(max.get() % 1024 + 1).unwrap();
+        let processed: Vec<_> = (0..n).map(|_| AtomicUsize::new(0)).collect();
+        parallel_for_each_with_settings(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i| -> Result<(), ()> {
+                if slow {
+                    std::thread::sleep(Duration::from_micros(50));
+                }
+                processed[i].fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                batching: Batching::Auto { max },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(processed
+            .iter()
+            .all(|count| count.load(Ordering::Relaxed) == 1));
+    }
+
+    /// Checks that the auto batch size grows for cheap items up to the cap, and shrinks back
+    /// for expensive ones.
+    #[test]
+    fn auto_batch_size() {
+        let mut batch_size = BatchSize::new(Batching::Auto {
+            max: NonZeroUsize::new(100).unwrap(),
+        });
+        assert!(batch_size.get() == 1);
+        for expected in &[2, 4, 8, 16, 32, 64, 100, 100] {
+            batch_size.record(batch_size.get(), Duration::from_nanos(10));
+            assert!(batch_size.get() == *expected);
+        }
+        // Incomplete batch
+        batch_size.record(3, Duration::from_secs(1));
+        assert!(batch_size.get() == 100);
+        batch_size.record(100, AUTO_BATCH_DURATION * 2);
+        assert!(batch_size.get() == 100);
+        batch_size.record(100, Duration::from_secs(1));
+        assert!(batch_size.get() == 50);
+
+        let mut batch_size = BatchSize::new(Batching::Fixed(NonZeroUsize::new(7).unwrap()));
+        batch_size.record(7, Duration::from_nanos(10));
+        assert!(batch_size.get() == 7);
+    }
+
+    /// Errors under `ErrorPolicy::StopAfter` keep the rest of the batch for processing.
+    #[test]
+    fn batching_error_keeps_batch() {
+        let processed = AtomicUsize::new(0);
+        let result = parallel_for_each_with_settings(
+            0..100,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i| -> Result<(), ()> {
+                if i == 5 {
+                    Err(())
+                } else {
+                    processed.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                batching: Batching::Fixed(NonZeroUsize::new(10).unwrap()),
+                error_policy: ErrorPolicy::StopAfter(NonZeroUsize::new(2).unwrap()),
+                ..Default::default()
+            },
+        );
+        assert!(result.unwrap_err().worker_errors().len() == 1);
+        assert!(processed.load(Ordering::Relaxed) == 99);
+    }
+
+    /// Stops the run while a worker is in the middle of a large batch, checks that the worker
+    /// function is not called on the rest of the batch.
+    #[test]
+    fn stop_mid_batch() {
+        let stop_requested = AtomicBool::new(false);
+        let stop_returned = AtomicBool::new(false);
+        let polled_after_stop = AtomicBool::new(false);
+        let calls_after_stop = AtomicUsize::new(0);
+        let result = parallel_for_each_with_settings(
+            0..1000,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i| -> Result<(), ()> {
+                if stop_requested.load(Ordering::Relaxed) {
+                    calls_after_stop.fetch_add(1, Ordering::Relaxed);
+                } else if i == 10 {
+                    stop_requested.store(true, Ordering::Relaxed);
+                    // The background function is polled again only after the stop was applied
+                    let deadline = Instant::now() + TIMEOUT;
+                    while !polled_after_stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+                        std::thread::yield_now();
+                    }
+                }
+                Ok(())
+            },
+            || -> Result<Continue, ()> {
+                if !stop_requested.load(Ordering::Relaxed) {
+                    Ok(Continue::Continue)
+                } else if stop_returned.swap(true, Ordering::Relaxed) {
+                    polled_after_stop.store(true, Ordering::Relaxed);
+                    Ok(Continue::Continue)
+                } else {
+                    Ok(Continue::Stop)
+                }
+            },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                background_mode: BackgroundMode::PollUntilDone(Duration::from_micros(100)),
+                batching: Batching::Fixed(NonZeroUsize::new(1000).unwrap()),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_ok());
+        assert!(polled_after_stop.load(Ordering::Relaxed));
+        assert!(calls_after_stop.load(Ordering::Relaxed) == 0);
+    }
+
+    /// Cheap worker function with different batching, to compare auto batching with
+    /// hand-tuned batch sizes (and with the single item overhead).
+    fn bench_batching(b: &mut ::test::Bencher, batching: Batching, item_work: u32) {
+        b.iter(|| {
+            parallel_for_each_with_settings(
+                0..100_000u32,
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, i| -> Result<(), ()> {
+                    let mut x = i;
+                    for _ in 0..item_work {
+                        x = ::test::black_box(x.wrapping_mul(31).wrapping_add(7));
+                    }
+                    ::test::black_box(x);
+                    Ok(())
+                },
+                || -> Result<Continue, ()> { Ok(Continue::Continue) },
+                || {},
+                Settings {
+                    batching,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        });
+    }
+
+    #[bench]
+    fn bench_batching_cheap_single(b: &mut ::test::Bencher) {
+        bench_batching(b, Batching::Single, 1);
+    }
+
+    #[bench]
+    fn bench_batching_cheap_fixed(b: &mut ::test::Bencher) {
+        bench_batching(b, Batching::Fixed(NonZeroUsize::new(256).unwrap()), 1);
+    }
+
+    #[bench]
+    fn bench_batching_cheap_auto(b: &mut ::test::Bencher) {
+        let max = NonZeroUsize::new(1024).unwrap();
+        bench_batching(b, Batching::Auto { max }, 1);
+    }
+
+    #[bench]
+    fn bench_batching_expensive_single(b: &mut ::test::Bencher) {
+        bench_batching(b, Batching::Single, 1000);
+    }
+
+    #[bench]
+    fn bench_batching_expensive_auto(b: &mut ::test::Bencher) {
+        let max = NonZeroUsize::new(1024).unwrap();
+        bench_batching(b, Batching::Auto { max }, 1000);
+    }
+
+    /// Checks that a worker reads as Working while in a long worker function and everything
+    /// is Done after the run.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn worker_states() {
+        let worker_states = Arc::new(WorkerStates::new());
+        let release = AtomicBool::new(false);
+        let observed_working = AtomicBool::new(false);
+
+        let result = parallel_for_each_with_settings(
+            0..1,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                while !release.load(Ordering::Relaxed) {
+                    std::thread::yield_now();
+                }
+                Ok(())
+            },
+            || -> Result<Continue, ()> {
+                let deadline = Instant::now() + TIMEOUT;
+                while !worker_states.get().contains(&WorkerState::Working) {
+                    if Instant::now() > deadline {
+                        break;
+                    }
+                    std::thread::yield_now();
+                }
+                observed_working.store(
+                    worker_states.get().contains(&WorkerState::Working),
+                    Ordering::Relaxed,
+                );
+                release.store(true, Ordering::Relaxed);
+                Ok(Continue::Continue)
+            },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(3).unwrap()),
+                worker_states: Some(worker_states.clone()),
+                ..Default::default()
+            },
+        );
+
+        assert!(result.is_ok());
+        assert!(observed_working.load(Ordering::Relaxed));
+        assert!(worker_states.get() == vec![WorkerState::Done; 3]);
+    }
+
+    /// Every third item fails, checks that the run stops after exactly `limit` errors.
+    #[proptest]
+    fn error_policy_stop_after(worker_count: WorkerCount, limit: NonZeroUsize) {
+        let limit = NonZeroUsize::new(limit.get() % 20 + 1).unwrap();
+        let finished = AtomicBool::new(false);
+
+        let result = parallel_for_each_with_settings(
+            0..,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i: u64| -> Result<(), u64> {
+                if i.is_multiple_of(3) {
+                    Err(i)
+                } else {
+                    Ok(())
+                }
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || finished.store(true, Ordering::Relaxed),
+            Settings {
+                worker_count,
+                error_policy: ErrorPolicy::StopAfter(limit),
+                ..Default::default()
+            },
+        );
+
+        match result {
+            Err(ParallelForEachError::WorkerTaskErrors { sources }) => {
+                assert!(sources.len() == limit.get());
+                assert!(sources.iter().all(|i| i % 3 == 0));
+            }
+            _ => panic!("We didn't get the collected errors"),
+        }
+        assert!(finished.load(Ordering::Relaxed));
+    }
+
+    /// Checks that runs below the parallel threshold stay on the calling thread and runs above
+    /// it (or with an unknown length) use worker threads.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[proptest]
+    fn parallel_threshold(len: u8, threshold: u8) {
+        let _auto = override_auto_worker_count(4);
+        let run = |iterator: Box<dyn Iterator<Item = u8> + Send>| {
+            let threads = lock::Mutex::new(Vec::new());
+            let processed = AtomicUsize::new(0);
+            let report = parallel_for_each_with_report(
+                iterator,
+                |_worker_id| -> Result<(), ()> {
+                    threads.lock().push(std::thread::current().id());
+                    Ok(())
+                },
+                |_state, _i| -> Result<(), ()> {
+                    processed.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+                || -> Result<Continue, ()> { Ok(Continue::Continue) },
+                || {},
+                Settings {
+                    parallel_threshold: threshold as usize,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            (threads.into_inner(), processed.into_inner(), report)
+        };
+        let current = std::thread::current().id();
+
+        let (threads, processed, report) = run(Box::new(0..len));
+        assert!(processed == len as usize);
+        if len < threshold {
+            assert!(threads == vec![current]);
+            assert!(report.worker_count == 1);
+        } else {
+            assert!(threads.len() == 4);
+            assert!(!threads.contains(&current));
+        }
+
+        let mut unknown_len = 0..len;
+        let (threads, processed, _report) =
+            run(Box::new(std::iter::from_fn(move || unknown_len.next())));
+        assert!(processed == len as usize);
+        assert!(!threads.contains(&current));
+    }
+
+    /// Checks that a polling background function that waits for the workers to make progress
+    /// works below the parallel threshold, which doesn't apply to the polling modes.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn parallel_threshold_polling_background() {
+        let processed = AtomicUsize::new(0);
+        let worker_threads = lock::Mutex::new(Vec::new());
+        let mut first_call = true;
+
+        parallel_for_each_with_settings(
+            0..3,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                worker_threads.lock().push(std::thread::current().id());
+                processed.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+            || -> Result<Continue, String> {
+                if std::mem::replace(&mut first_call, false) {
+                    let start = std::time::Instant::now();
+                    while processed.load(Ordering::SeqCst) == 0 {
+                        if start.elapsed() > Duration::from_secs(5) {
+                            return Err("No progress while waiting".to_string());
+                        }
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                Ok(Continue::Continue)
+            },
+            || {},
+            Settings {
+                parallel_threshold: 10,
+                background_mode: BackgroundMode::PollUntilDone(Duration::from_millis(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(processed.into_inner() == 3);
+        assert!(!worker_threads
+            .into_inner()
+            .contains(&std::thread::current().id()));
+    }
+
+    /// Checks that the uniform variant needs no annotations beyond the shared error type.
+    #[test]
+    fn uniform_anyhow_errors() {
+        let inputs = ["1", "2", "x", "4"];
+
+        let result = parallel_for_each_uniform(
+            inputs.iter(),
+            |_worker_id| Ok(0u32),
+            |sum, input| {
+                *sum += input.parse::<u32>()?;
+                Ok(())
+            },
+            || -> anyhow::Result<_> { Ok(Continue::Continue) },
+            || {},
+            WorkerCount::Auto,
+        );
+
+        let error: ParallelForEachErrorUniform<anyhow::Error> = result.unwrap_err();
+        let source = error.as_worker_error().unwrap();
+        assert!(source.downcast_ref::<std::num::ParseIntError>().is_some());
+
+        let result = parallel_for_each_uniform(
+            0..10,
+            |_worker_id| -> anyhow::Result<()> { Err(anyhow::anyhow!("No init for you")) },
+            |_state, _i| Ok(()),
+            || Ok(Continue::Continue),
+            || {},
+            WorkerCount::Auto,
+        );
+        let message = result.unwrap_err().as_init_error().unwrap().to_string();
+        assert!(message == "No init for you");
+    }
+
+    /// Checks that the collected errors are paired with the indices of the failed items.
+    #[proptest]
+    fn error_policy_collect_indices(worker_count: WorkerCount) {
+        let failing = [3usize, 17, 18, 42, 99];
+
+        let result = parallel_for_each_with_settings(
+            (0..100u32).map(|i| i * 2),
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, value| -> Result<(), u32> {
+                if failing.contains(&(value as usize / 2)) {
+                    Err(value)
+                } else {
+                    Ok(())
+                }
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                error_policy: ErrorPolicy::Collect(NonZeroUsize::new(10).unwrap()),
+                ..Default::default()
+            },
+        );
+
+        let error = result.unwrap_err();
+        let mut indexed: Vec<_> = error.indexed_worker_errors().to_vec();
+        indexed.sort();
+        let expected: Vec<_> = failing.iter().map(|&i| (i, i as u32 * 2)).collect();
+        assert!(indexed == expected);
+    }
+
+    /// Two workers fail at the same time, the error of the first item must be returned.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn error_selection_lowest_index() {
+        for _ in 0..20 {
+            let barrier = std::sync::Barrier::new(2);
+
+            let result = parallel_for_each_with_settings(
+                0..2u32,
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, i| -> Result<(), u32> {
+                    barrier.wait();
+                    if i == 0 {
+                        // Make the first item the slower one to fail
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(i)
+                },
+                || -> Result<Continue, ()> { Ok(Continue::Continue) },
+                || {},
+                Settings {
+                    worker_count: WorkerCount::Manual(NonZeroUsize::new(2).unwrap()),
+                    error_selection: ErrorSelection::LowestIndex,
+                    ..Default::default()
+                },
+            );
+
+            assert!(matches!(
+                result,
+                Err(ParallelForEachError::WorkerTaskError { source: 0 })
+            ));
+        }
+    }
+
+    fn lock_metrics_run(item_count: u32, work: Duration) -> RunReport {
+        parallel_for_each_with_report(
+            0..item_count,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                if work > Duration::default() {
+                    std::thread::sleep(work);
+                }
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(4).unwrap()),
+                collect_lock_metrics: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    /// Makes the lock metrics of runs started on this thread use a fake clock that advances by
+    /// 1 ms every time it is read, and by the returned counter of milliseconds.
+    fn override_lock_metrics_clock() -> (Arc<AtomicU32>, impl Drop) {
+        let start = Instant::now();
+        let millis = Arc::new(AtomicU32::new(0));
+        let clock_millis = millis.clone();
+        LOCK_METRICS_CLOCK_OVERRIDE.with(|clock| {
+            *clock.borrow_mut() = Some(Arc::new(move || {
+                start + Duration::from_millis(clock_millis.fetch_add(1, Ordering::Relaxed).into())
+            }))
+        });
+        let guard = scopeguard::guard((), |_| {
+            LOCK_METRICS_CLOCK_OVERRIDE.with(|clock| *clock.borrow_mut() = None);
+        });
+        (millis, guard)
+    }
+
+    /// Runs a single worker with the fake clock, in which each item takes 10 ms.
+    /// Returns the run report (if the run succeeded) and the final time of the fake clock.
+    fn fake_clock_run(item_count: u32, fail: bool) -> (Option<RunReport>, u32) {
+        let (millis, _clock) = override_lock_metrics_clock();
+        let result = parallel_for_each_with_report(
+            0..item_count,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                millis.fetch_add(10, Ordering::Relaxed);
+                if fail {
+                    Err(())
+                } else {
+                    Ok(())
+                }
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                collect_lock_metrics: true,
+                error_policy: ErrorPolicy::StopAfter(NonZeroUsize::new(usize::MAX).unwrap()),
+                ..Default::default()
+            },
+        );
+        (result.ok(), millis.load(Ordering::Relaxed))
+    }
+
+    /// Time spent in the worker function is not counted as lock wait: every wait (the first
+    /// lock, after init and after each item) takes exactly one tick of the fake clock.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn lock_metrics_slow_worker() {
+        let (report, _) = fake_clock_run(20, false);
+        let report = report.unwrap();
+        assert!(report.total_lock_wait == Duration::from_millis(22));
+        assert!(report.max_lock_wait == Duration::from_millis(1));
+    }
+
+    /// The lock is waited for after failed items the same way as after successful ones, so the
+    /// clock is read equally often.
+    #[test]
+    fn lock_metrics_failing_worker() {
+        let (report, succeeding_clock) = fake_clock_run(20, false);
+        assert!(report.is_some());
+        let (report, failing_clock) = fake_clock_run(20, true);
+        assert!(report.is_none());
+        assert!(failing_clock == succeeding_clock);
+    }
+
+    /// With cheap workers the workers keep waiting for each other.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn lock_metrics_cheap_worker() {
+        let report = lock_metrics_run(100_000, Duration::default());
+        assert!(report.total_lock_wait > Duration::default());
+        assert!(report.max_lock_wait <= report.total_lock_wait);
+    }
+
+    #[proptest]
+    fn worker_count_resolution(n: NonZeroUsize) {
+        assert!(resolve_worker_count(WorkerCount::Manual(n)) == n.get());
+        assert!(resolve_worker_count(WorkerCount::Auto) >= 1);
+        assert!(WorkerCount::Auto.resolve_with(|| n.get()) == n.get());
+        assert!(WorkerCount::Auto.resolve_with(|| 0) == 1);
+        assert!(WorkerCount::Manual(n).resolve_with(|| unreachable!()) == n.get());
+    }
+
+    /// Checks that `Auto` runs use the overridden worker count.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn auto_worker_count_override() {
+        let _override = override_auto_worker_count(5);
+        let report = parallel_for_each_with_report(
+            0..10,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings::default(),
+        )
+        .unwrap();
+        assert!(report.worker_count == 5);
+    }
+
+    /// Checks that the report contains the resolved worker count.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[proptest]
+    fn report_worker_count(worker_count: WorkerCount) {
+        let report = parallel_for_each_with_report(
+            0..10,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(report.worker_count == resolve_worker_count(worker_count));
+    }
+
+    /// Checks that an infinite iterator is stopped cleanly by the item cap.
+    #[proptest]
+    fn max_items_cap(worker_count: WorkerCount) {
+        let processed = AtomicU32::new(0);
+        let report = parallel_for_each_with_report(
+            0..,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                max_items: Some(1000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(processed.load(Ordering::Relaxed) == 1000);
+        assert!(report.item_cap_reached);
+        assert!(!report.completed);
+    }
+
+    /// Checks that a cap larger than the iterator doesn't change anything.
+    #[proptest]
+    fn max_items_not_reached(worker_count: WorkerCount, n: u8) {
+        let processed = AtomicU32::new(0);
+        let report = parallel_for_each_with_report(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                max_items: Some(1000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(processed.load(Ordering::Relaxed) == n as u32);
+        assert!(!report.item_cap_reached);
+        assert!(report.completed);
+    }
+
+    /// Checks that an iterator with exactly as many items as the cap is completed, and the cap
+    /// isn't reported as reached.
+    #[proptest]
+    fn max_items_exact(worker_count: WorkerCount, n: u8) {
+        let processed = AtomicU32::new(0);
+        let report = parallel_for_each_with_report(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                max_items: Some(n as usize),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(processed.load(Ordering::Relaxed) == n as u32);
+        assert!(!report.item_cap_reached);
+        assert!(report.completed);
+    }
+
+    /// Checks that a run that processed all items is reported as completed.
+    #[proptest]
+    fn report_completed(worker_count: WorkerCount, n: u8) {
+        let report = parallel_for_each_with_report(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(report.completed);
+    }
+
+    /// Checks that the warmup items are not counted in the report.
+    #[proptest]
+    fn report_warmup_items(worker_count: WorkerCount, n: u8, warmup_items: u8) {
+        let report = parallel_for_each_with_report(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                warmup_items: warmup_items as usize,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(report.items_processed == n.saturating_sub(warmup_items) as usize);
+        if warmup_items > n {
+            assert!(report.elapsed == Duration::default());
+        }
+    }
+
+    /// Checks that the time spent on slow warmup items is not included in the elapsed time.
+    #[test]
+    fn report_warmup_timing() {
+        const SLOW: Duration = Duration::from_millis(100);
+        let report = parallel_for_each_with_report(
+            0..10,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i| -> Result<(), ()> {
+                if i < 2 {
+                    std::thread::sleep(SLOW);
+                }
+                Ok(())
+            },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                warmup_items: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(report.items_processed == 8);
+        assert!(report.elapsed < SLOW);
+    }
+
+    /// Checks that a run stopped by the background function is not reported as completed.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[proptest]
+    fn report_not_completed_after_stop(worker_count: WorkerCount) {
+        let report = parallel_for_each_with_report(
+            0..,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Stop) },
+            || {},
+            Settings {
+                worker_count,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(!report.completed);
+    }
+
+    /// Stops the run midway, checks that the processed items and the leftovers together are
+    /// the whole range, with the leftovers being its unprocessed tail.
+    #[proptest]
+    fn leftover_after_stop(worker_count: WorkerCount, stop_after: u8) {
+        let n = 1000;
+        let stop_after = stop_after as usize;
+        let processed = std::sync::Mutex::new(Vec::new());
+        let (result, leftover) = parallel_for_each_with_leftover(
+            0..n,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i| -> Result<(), ()> {
+                processed.lock().unwrap().push(i);
+                Ok(())
+            },
+            || -> Result<Continue, ()> {
+                if processed.lock().unwrap().len() >= stop_after {
+                    Ok(Continue::Stop)
+                } else {
+                    Ok(Continue::Continue)
+                }
+            },
+            || {},
+            Settings {
+                worker_count,
+                background_mode: BackgroundMode::PollUntilStop(Duration::from_micros(10)),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_ok());
+
+        let mut processed = processed.into_inner().unwrap();
+        processed.sort();
+        let leftover: Vec<_> = leftover.into_iter().flatten().collect();
+        assert!(processed == (0..processed.len()).collect::<Vec<_>>());
+        assert!(leftover == (processed.len()..n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn leftover_after_cap() {
+        let (result, leftover) = parallel_for_each_with_leftover(
+            0..100,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                max_items: Some(30),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_ok());
+        assert!(leftover.unwrap().collect::<Vec<_>>() == (30..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn no_leftover_when_exhausted() {
+        let (result, leftover) = parallel_for_each_with_leftover(
+            0..100,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings::default(),
+        );
+        assert!(result.is_ok());
+        assert!(leftover.is_none());
     }
 
-    // Checks that each worker has the same thread id as the state
-    #[proptest]
-    fn stable_thread_id(worker_count: WorkerCount, n: u8) {
-        let n = n as u32;
-        parallel_for_each(
-            0..n,
-            |_worker_id| -> Result<_, ()> { Ok(std::thread::current().id()) },
-            |state_thread_id, _i| -> Result<(), ()> {
-                assert!(&std::thread::current().id() == state_thread_id);
-                Ok(())
-            },
-            || -> Result<_, ()> { Ok(Continue::Continue) },
+    #[test]
+    fn lock_metrics_disabled() {
+        let report = parallel_for_each_with_report(
+            0..1000,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
             || {},
-            worker_count,
+            Settings::default(),
         )
         .unwrap();
+        assert!(
+            report
+                == RunReport {
+                    worker_count: num_cpus::get(),
+                    completed: true,
+                    items_processed: 1000,
+                    elapsed: report.elapsed,
+                    ..Default::default()
+                }
+        );
     }
 
-    /// Sums a range using pralellel_for_each, checks that sum is as expected
+    /// Each worker records the items it got into its own list, checks that every item is
+    /// processed by exactly one worker function.
     #[proptest]
-    fn sum(worker_count: WorkerCount, n: u8) {
-        let n = n as u32;
-        let helper = IterationCheckHelper::new();
-        let sum = std::sync::atomic::AtomicU32::new(0);
+    fn per_worker_functions(worker_count: NonZeroUsize, n: u8) {
+        let worker_count = worker_count.get() % 8 + 1;
+        let seen: Vec<_> = (0..worker_count)
+            .map(|_| lock::Mutex::new(Vec::new()))
+            .collect();
 
-        parallel_for_each(
+        let worker_funs: Vec<_> = seen
+            .iter()
+            .map(|seen| {
+                Box::new(move |_state: &mut (), i: u8| -> Result<(), ()> {
+                    seen.lock().push(i);
+                    Ok(())
+                }) as Box<dyn Fn(&mut (), u8) -> Result<(), ()> + Sync + Send>
+            })
+            .collect();
+
+        let result = parallel_for_each_per_worker(
             0..n,
-            |_worker_id| helper.workers_running_check(),
-            |_state, i| -> Result<(), String> {
-                helper.workers_running_check()?;
-                sum.fetch_add(i, Ordering::Relaxed);
-                Ok(())
-            },
-            || -> Result<_, ()> { Ok(Continue::Continue) },
-            || helper.finished_callback(),
-            worker_count,
-        )
-        .unwrap();
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            worker_funs,
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings::default(),
+        );
+        assert!(result.is_ok());
 
-        assert!(helper.callback_called_check());
-        assert!(sum.load(Ordering::Relaxed) == if n > 0 { n * (n - 1) / 2 } else { 0 });
+        let mut all: Vec<u8> = seen.into_iter().flat_map(|v| v.into_inner()).collect();
+        all.sort();
+        assert!(all == (0..n).collect::<Vec<_>>());
     }
 
-    /// Sums a range using pralellel_for_each, keeping the partial sums in shared state, checks
-    /// that sum is as expected
-    #[proptest]
-    fn sum_in_state(worker_count: WorkerCount, n: u8) {
-        let n = n as u32;
-        let sum = AtomicU32::new(0);
+    #[test]
+    fn per_worker_functions_count_mismatch() {
+        let result = parallel_for_each_per_worker(
+            0..10,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            vec![|_state: &mut (), _i| -> Result<(), ()> { Ok(()) }; 3],
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(2).unwrap()),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(ParallelForEachError::WorkerCountMismatch {
+                worker_count: 2,
+                worker_fun_count: 3
+            })
+        ));
+    }
 
-        struct State<'a> {
-            local_sum: u32,
-            global_sum: &'a AtomicU32,
-        }
+    /// Checks that a finished callback consuming a non-clonable value is called exactly once.
+    #[proptest]
+    fn finished_once(worker_count: WorkerCount, n: u8) {
+        struct Token;
 
-        impl<'a> Drop for State<'a> {
-            fn drop(&mut self) {
-                self.global_sum.fetch_add(self.local_sum, Ordering::Relaxed);
-            }
-        }
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let token = Token;
 
-        parallel_for_each(
+        let result = parallel_for_each_with_finished_once(
             0..n,
-            |_worker_id| -> Result<_, ()> {
-                Ok(State {
-                    local_sum: 0,
-                    global_sum: &sum,
-                })
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            move || {
+                let Token = token;
+                sender.send(()).unwrap();
             },
-            |state, i| -> Result<(), ()> {
-                state.local_sum += i;
-                Ok(())
+            Settings {
+                worker_count,
+                ..Default::default()
             },
-            || -> Result<_, ()> { Ok(Continue::Continue) },
-            || {},
-            worker_count,
-        )
-        .unwrap();
+        );
 
-        assert!(sum.load(Ordering::Relaxed) == if n > 0 { n * (n - 1) / 2 } else { 0 });
+        assert!(result.is_ok());
+        assert!(receiver.iter().count() == 1);
     }
 
-    /// Checks that the jobs are actually running in different threads by
-    /// blocking as many threads as there are workers.
-    #[proptest]
-    fn actual_threads(worker_count: WorkerCount) {
-        let n = match worker_count {
-            WorkerCount::Auto => {
-                prop_assume!(
-                    false,
-                    "This test doesn't work with automatic number of threads"
-                );
-                unreachable!();
-            }
-            WorkerCount::Manual(n) => n.get(),
-        };
-
-        let count_waiting = std::sync::Mutex::new(0usize);
-        let cond = std::sync::Condvar::new();
+    /// Checks that a worker stuck on an item makes the run fail with a timeout that names
+    /// the worker and the item, and that no more items are started after it.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn item_hard_timeout() {
+        let worker_count = 3;
+        let stuck_item = std::sync::Mutex::new(None);
+        let processed = AtomicUsize::new(0);
+        let finished_calls = AtomicUsize::new(0);
+        let result = parallel_for_each_with_settings(
+            0..1_000_000,
+            |worker_id| -> Result<_, ()> { Ok(worker_id) },
+            |worker_id, i| -> Result<(), ()> {
+                if *worker_id == 1 && stuck_item.lock().unwrap().is_none() {
+                    *stuck_item.lock().unwrap() = Some(i);
+                    std::thread::sleep(Duration::from_millis(300));
+                }
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {
+                finished_calls.fetch_add(1, Ordering::Relaxed);
+            },
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(worker_count).unwrap()),
+                per_item_hard_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
 
-        let end = Instant::now() + TIMEOUT;
+        let stuck_item = stuck_item.into_inner().unwrap().unwrap();
+        match result {
+            Err(ParallelForEachError::Timeout {
+                worker_id,
+                item_index,
+            }) => {
+                assert!(worker_id == 1);
+                assert!(item_index == stuck_item);
+            }
+            _ => panic!("Expected a timeout, got {:?}", result),
+        }
+        assert!(processed.into_inner() < 1_000_000);
+        assert!(finished_calls.into_inner() == 1);
+    }
 
-        parallel_for_each(
-            0..n,
-            |_worker_id| -> Result<(), String> {
-                let mut count_waiting = count_waiting.lock().unwrap();
-                *count_waiting += 1;
-                if *count_waiting >= n {
-                    cond.notify_all();
-                    Ok(())
-                } else {
-                    while let Some(timeout) = end.checked_duration_since(Instant::now()) {
-                        let result = cond.wait_timeout(count_waiting, timeout).unwrap();
-                        count_waiting = result.0;
-                        if result.1.timed_out() {
-                            return Err("Timed out".into());
-                        } else if *count_waiting >= n {
-                            return Ok(());
-                        }
+    /// Checks that an error of another worker is not hidden by the timeout of a stuck worker.
+    #[test]
+    fn item_hard_timeout_other_error() {
+        let result = parallel_for_each_with_settings(
+            0..1_000_000,
+            |worker_id| -> Result<_, ()> { Ok(worker_id) },
+            |worker_id, i| -> Result<(), usize> {
+                match *worker_id {
+                    0 => {
+                        std::thread::sleep(Duration::from_millis(20));
+                        Err(i)
+                    }
+                    _ => {
+                        std::thread::sleep(Duration::from_millis(300));
+                        Ok(())
                     }
-                    Err("wtf?".into())
                 }
             },
-            |_state, _i| -> Result<(), ()> { Ok(()) },
             || -> Result<_, ()> { Ok(Continue::Continue) },
             || {},
-            worker_count,
-        )
-        .unwrap();
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(2).unwrap()),
+                per_item_hard_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
 
-        assert!(*count_waiting.lock().unwrap() == n);
+        match result {
+            Err(ParallelForEachError::WorkerTaskError { .. }) => {}
+            _ => panic!("Expected the worker error, got {:?}", result),
+        }
     }
 
-    /// Checks that the iteration stops when background function returns Stop and that finished
-    /// callback is correctly invoked.
+    /// Checks that the watchdog doesn't interfere with items that finish in time.
     #[proptest]
-    fn stop_from_background(worker_count: WorkerCount) {
-        let helper = IterationCheckHelper::new();
-
-        parallel_for_each(
-            0..,
-            |_worker_id| helper.workers_running_check(),
-            |_state, _i| helper.workers_running_check(),
-            || -> Result<_, String> {
-                // Here we can check that the threads have not finished yet, because the
-                // iterator is infinite and only waiting for this method to return
-                helper.workers_running_check()?;
-                Ok(Continue::Stop)
+    fn item_hard_timeout_not_reached(worker_count: WorkerCount, n: u16) {
+        let processed = AtomicUsize::new(0);
+        parallel_for_each_with_settings(
+            0..n,
+            |_worker_id| -> Result<_, ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> {
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
             },
-            || {
-                helper.finished_callback();
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                per_item_hard_timeout: Some(Duration::from_secs(10)),
+                ..Default::default()
             },
-            worker_count,
         )
         .unwrap();
-        assert!(helper.callback_called_check());
+        assert!(processed.into_inner() == n as usize);
     }
 
-    /// Checks that panics from thread init function are propagated
-    #[proptest]
-    fn propagates_panics_init(worker_count: WorkerCount) {
-        let helper = IterationCheckHelper::new();
-        let result = std::panic::catch_unwind(|| {
-            parallel_for_each(
-                0..,
-                |worker_id| -> Result<(), String> {
-                    helper.workers_running_check()?;
-                    if worker_id == 0 {
-                        panic_control::disable_hook_in_current_thread();
-                        panic!("Don't panic!");
-                    } else {
-                        Ok(())
+    /// Checks that all items are processed at every worker priority, whether the platform
+    /// supports changing it or not.
+    #[test]
+    fn thread_priority_smoke() {
+        for priority in &[
+            ThreadPriority::Normal,
+            ThreadPriority::Low,
+            ThreadPriority::High,
+        ] {
+            let processed = AtomicUsize::new(0);
+            parallel_for_each_with_settings(
+                0..100,
+                |_worker_id| -> Result<_, ()> {
+                    // Lowering the priority is always allowed
+                    #[cfg(feature = "thread-priority")]
+                    if *priority == ThreadPriority::Low {
+                        assert!(ThreadPriority::Low.apply());
                     }
+                    Ok(())
+                },
+                |_state, _i| -> Result<(), ()> {
+                    processed.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
                 },
-                |_state, _i| -> Result<(), String> { helper.workers_running_check() },
                 || -> Result<_, ()> { Ok(Continue::Continue) },
-                || helper.finished_callback(),
-                worker_count,
+                || {},
+                Settings {
+                    worker_count: WorkerCount::Manual(NonZeroUsize::new(4).unwrap()),
+                    priority: *priority,
+                    ..Default::default()
+                },
             )
-        });
-        match result {
-            Err(e) => {
-                if let Some(string) = e.downcast_ref::<&str>() {
-                    assert!(string == &"Don't panic!");
-                    assert!(helper.callback_called_check());
-                } else {
-                    panic!("Got non-string panic");
-                }
-            }
-            Ok(Ok(_)) => panic!("Didn't get panic"),
-            Ok(Err(e)) => panic!("Something went wrong: {}", e),
+            .unwrap();
+            assert!(processed.into_inner() == 100);
         }
     }
 
-    /// Checks that panics from thread init function are propagated
+    /// Simulates a spawn failure by requesting a stack larger than the address space.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn spawn_error() {
+        let finished = AtomicBool::new(false);
+
+        let result = parallel_for_each_with_settings(
+            0..100u32,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { panic!("No item should be processed") },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || finished.store(true, Ordering::Relaxed),
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(4).unwrap()),
+                stack_size: Some(1 << 60),
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err(ParallelForEachError::SpawnError { .. })
+        ));
+        assert!(finished.load(Ordering::Relaxed));
+    }
+
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn thread_names() {
+        let result = parallel_for_each_with_settings(
+            0..10u32,
+            |worker_id| -> Result<(), String> {
+                let expected = format!("pfe-worker-{}", worker_id);
+                match std::thread::current().name() {
+                    Some(name) if name == expected => Ok(()),
+                    name => Err(format!("Unexpected thread name {:?}", name)),
+                }
+            },
+            |_state, _i| -> Result<(), ()> { Ok(()) },
+            || -> Result<Continue, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count: WorkerCount::Manual(NonZeroUsize::new(3).unwrap()),
+                thread_name: Some("pfe-worker".to_string()),
+                ..Default::default()
+            },
+        );
+
+        assert!(result.is_ok());
+    }
+
+    /// Checks that with CaptureAsError and the silent hook a worker panic is returned as an error.
+    /// That the panic is not reported is checked by `silent_hook_keeps_stderr_clean`.
     #[proptest]
-    fn propagates_panics_worker(worker_count: WorkerCount, n: u8) {
+    fn captures_panics_silent(worker_count: WorkerCount, n: u8) {
         let n = n as u32;
         let helper = IterationCheckHelper::new();
-        let result = std::panic::catch_unwind(|| {
-            parallel_for_each(
-                0..,
-                |_worker_id| -> Result<(), String> {
-                    panic_control::disable_hook_in_current_thread();
-                    helper.workers_running_check()
-                },
-                |_state, i| -> Result<(), String> {
-                    helper.workers_running_check()?;
-                    if i == n {
-                        panic!("Don't panic!");
-                    } else {
-                        Ok(())
-                    }
-                },
-                || -> Result<_, ()> { Ok(Continue::Continue) },
-                || helper.finished_callback(),
-                worker_count,
-            )
-        });
-        match result {
-            Err(e) => {
-                if let Some(string) = e.downcast_ref::<&str>() {
-                    assert!(string == &"Don't panic!");
-                    assert!(helper.callback_called_check());
+        let result = parallel_for_each_with_settings(
+            0..,
+            |_worker_id| -> Result<(), String> { helper.workers_running_check() },
+            |_state, i| -> Result<(), String> {
+                helper.workers_running_check()?;
+                if i == n {
+                    panic!("Don't panic!");
                 } else {
-                    panic!("Got non-string panic");
+                    Ok(())
                 }
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || helper.finished_callback(),
+            Settings {
+                worker_count,
+                panic_policy: PanicPolicy::CaptureAsError,
+                panic_hook: PanicHook::Silent,
+                ..Default::default()
+            },
+        );
+
+        match result {
+            Err(ParallelForEachError::WorkerPanicked { message, .. }) => {
+                assert!(message == "Don't panic!");
+                assert!(helper.callback_called_check());
             }
-            Ok(Ok(_)) => panic!("Didn't get panic"),
-            Ok(Err(e)) => panic!("Something went wrong: {}", e),
+            Err(e) => panic!("We didn't get the right error ({})", e),
+            Ok(()) => panic!("We didn't get an error!"),
         }
     }
 
-    /// Checks that panics from thread init function are propagated
+    /// Checks that a custom panic hook is called exactly once for a captured panic, instead
+    /// of the default one.
     #[proptest]
-    fn propagates_panics_background(worker_count: WorkerCount) {
-        let helper = IterationCheckHelper::new();
-        let result = std::panic::catch_unwind(|| {
-            parallel_for_each(
-                0..,
-                |_worker_id| -> Result<(), String> { helper.workers_running_check() },
-                |_state, _i| -> Result<(), String> { helper.workers_running_check() },
-                || -> Result<_, String> {
-                    helper.workers_running_check()?;
-                    panic_control::disable_hook_in_current_thread();
+    fn captures_panics_custom_hook(worker_count: WorkerCount, n: u8) {
+        let n = n as u32;
+        let hook_calls = std::sync::Arc::new(AtomicU32::new(0));
+        let hook_calls_clone = hook_calls.clone();
+        let result = parallel_for_each_with_settings(
+            0..,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, i| -> Result<(), ()> {
+                if i == n {
                     panic!("Don't panic!");
-                },
-                || helper.finished_callback(),
-                worker_count,
-            )
-        });
-        match result {
-            Err(e) => {
-                if let Some(string) = e.downcast_ref::<&str>() {
-                    assert!(string == &"Don't panic!");
-                    assert!(helper.callback_called_check());
                 } else {
-                    panic!("Got non-string panic");
+                    Ok(())
                 }
-            }
-            Ok(Ok(_)) => panic!("Didn't get panic"),
-            Ok(Err(e)) => panic!("Something went wrong: {}", e),
-        }
+            },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                worker_count,
+                panic_policy: PanicPolicy::CaptureAsError,
+                panic_hook: PanicHook::Custom(Box::new(move |_info| {
+                    hook_calls_clone.fetch_add(1, Ordering::Relaxed);
+                })),
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err(ParallelForEachError::WorkerPanicked { .. })
+        ));
+        assert!(hook_calls.load(Ordering::Relaxed) == 1);
     }
 
-    /// Checks that panics from finished callback function are propagated
+    /// Environment variable that makes `panic_hook_child` run, with the name of the hook to use.
+    const PANIC_HOOK_CHILD_VAR: &str = "MINIPATH_PANIC_HOOK_CHILD";
+
+    /// Not a real test, only does something when started by `panic_hook_stderr`.
+    /// Captures a worker panic using the hook named in PANIC_HOOK_CHILD_VAR.
+    #[test]
+    fn panic_hook_child() {
+        let panic_hook = match std::env::var(PANIC_HOOK_CHILD_VAR).as_deref() {
+            Ok("silent") => PanicHook::Silent,
+            Ok("default") => PanicHook::Default,
+            _ => return,
+        };
+        let result = parallel_for_each_with_settings(
+            0..,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { panic!("Don't panic!") },
+            || -> Result<_, ()> { Ok(Continue::Continue) },
+            || {},
+            Settings {
+                panic_policy: PanicPolicy::CaptureAsError,
+                panic_hook,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(ParallelForEachError::WorkerPanicked { .. })
+        ));
+    }
+
+    /// Runs `panic_hook_child` in a new process of this test binary, returns its stderr.
+    fn panic_hook_stderr(hook: &str) -> String {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "parallel_for_each::test::panic_hook_child",
+                "--nocapture",
+            ])
+            .env(PANIC_HOOK_CHILD_VAR, hook)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    /// Checks on the real stderr that the silent hook hides captured panics, which the
+    /// default hook reports.
+    #[test]
+    fn silent_hook_keeps_stderr_clean() {
+        assert!(panic_hook_stderr("default").contains("Don't panic!"));
+        assert!(!panic_hook_stderr("silent").contains("Don't panic!"));
+    }
+
+    /// Checks that a background error is returned when a worker panic was captured before it,
+    /// instead of panicking in the thread scope.
     #[proptest]
-    fn propagates_panics_callback(worker_count: WorkerCount) {
-        let helper = IterationCheckHelper::new();
-        let result = std::panic::catch_unwind(|| {
-            parallel_for_each(
-                0..,
-                |_worker_id| -> Result<(), String> {
-                    panic_control::disable_hook_in_current_thread();
-                    helper.workers_running_check()
-                },
-                |_state, _i| -> Result<(), String> { helper.workers_running_check() },
-                || -> Result<_, String> {
-                    helper.workers_running_check()?;
-                    Ok(Continue::Stop)
-                },
-                || {
-                    helper.finished_callback();
-                    panic!("Don't panic!");
-                },
-                worker_count,
-            )
-        });
-        match result {
-            Err(e) => {
-                if let Some(string) = e.downcast_ref::<&str>() {
-                    assert!(string == &"Don't panic!");
-                    assert!(helper.callback_called_check());
-                } else {
-                    panic!("Got non-string panic");
+    fn captured_panic_with_background_error(worker_count: WorkerCount) {
+        let finished = AtomicBool::new(false);
+        let result = parallel_for_each_with_settings(
+            0..,
+            |_worker_id| -> Result<(), ()> { Ok(()) },
+            |_state, _i| -> Result<(), ()> { panic!("Don't panic!") },
+            || -> Result<Continue, &str> {
+                while !finished.load(Ordering::Acquire) {
+                    std::thread::yield_now();
                 }
-            }
-            Ok(Ok(_)) => panic!("Didn't get panic"),
-            Ok(Err(e)) => panic!("Something went wrong: {}", e),
-        }
+                Err("background")
+            },
+            || finished.store(true, Ordering::Release),
+            Settings {
+                worker_count,
+                panic_policy: PanicPolicy::CaptureAsError,
+                panic_hook: PanicHook::Silent,
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err(ParallelForEachError::BackgroundTaskError {
+                source: "background"
+            })
+        ));
+    }
+
+    /// Checks that the simple entry points accept a background function that consumes its state.
+    #[proptest]
+    fn fn_once_background(worker_count: WorkerCount) {
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let background = move || -> Result<_, ()> {
+            drop(sender);
+            Ok(Continue::Continue)
+        };
+        parallel_for_each_uniform(
+            0..10,
+            |_worker_id| Ok(()),
+            |_state, _i| Ok(()),
+            background,
+            || {},
+            worker_count,
+        )
+        .unwrap();
+        assert!(receiver.recv().is_err());
     }
 
     /// Tests that if iterator returns None once, it will stop the iteration completely
@@ -667,7 +6995,370 @@ mod test {
         }
     }
 
+    #[derive(Debug, PartialEq)]
+    enum InitError {
+        Init,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum WorkerError {
+        Worker(u32),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum BackgroundError {
+        Background,
+    }
+
+    type TypedError = ParallelForEachError<InitError, WorkerError, BackgroundError>;
+
+    /// Checks that the typed accessors return the concrete error for their variant only.
+    #[test]
+    fn typed_error_accessors() {
+        let init: TypedError = ParallelForEachError::InitTaskError {
+            source: InitError::Init,
+        };
+        assert!(init.as_init_error() == Some(&InitError::Init));
+        assert!(init.as_worker_error().is_none());
+        assert!(init.as_background_error().is_none());
+
+        let worker: TypedError = ParallelForEachError::WorkerTaskError {
+            source: WorkerError::Worker(3),
+        };
+        assert!(worker.as_init_error().is_none());
+        assert!(worker.as_worker_error() == Some(&WorkerError::Worker(3)));
+        assert!(worker.worker_errors() == &[WorkerError::Worker(3)][..]);
+        assert!(worker.as_background_error().is_none());
+
+        let workers: TypedError = ParallelForEachError::WorkerTaskErrors {
+            sources: vec![WorkerError::Worker(1), WorkerError::Worker(2)],
+        };
+        assert!(workers.as_worker_error() == Some(&WorkerError::Worker(1)));
+        assert!(workers.worker_errors().len() == 2);
+        assert!(workers.indexed_worker_errors().is_empty());
+
+        let indexed: TypedError = ParallelForEachError::IndexedWorkerTaskErrors {
+            errors: vec![(10, WorkerError::Worker(1)), (20, WorkerError::Worker(2))],
+        };
+        assert!(indexed.as_worker_error() == Some(&WorkerError::Worker(1)));
+        assert!(indexed.worker_errors().is_empty());
+        assert!(
+            indexed.indexed_worker_errors()
+                == &[(10, WorkerError::Worker(1)), (20, WorkerError::Worker(2))][..]
+        );
+        assert!(indexed.to_string() == "2 worker tasks failed");
+
+        let background: TypedError = ParallelForEachError::BackgroundTaskError {
+            source: BackgroundError::Background,
+        };
+        assert!(background.as_init_error().is_none());
+        assert!(background.as_worker_error().is_none());
+        assert!(background.as_background_error() == Some(&BackgroundError::Background));
+
+        let panicked: TypedError = ParallelForEachError::WorkerPanicked {
+            worker_id: 0,
+            message: "".to_string(),
+        };
+        assert!(panicked.as_worker_error().is_none());
+        assert!(panicked.worker_errors().is_empty());
+    }
+
+    /// Checks that a finished callback that accesses state shared with the workers and starts
+    /// a nested run is called exactly once, on the expected thread, for both settings.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn reentrant_finished_callback() {
+        for &callback_thread in &[
+            FinishedCallbackThread::LastWorker,
+            FinishedCallbackThread::Caller,
+        ] {
+            let shared = lock::Mutex::new(Vec::new());
+            let calls = AtomicU32::new(0);
+            let nested_sum = AtomicU32::new(0);
+            let caller = std::thread::current().id();
+
+            parallel_for_each_with_settings(
+                0..100u32,
+                |_worker_id| -> Result<(), ()> { Ok(()) },
+                |_state, i| -> Result<(), ()> {
+                    shared.lock().push(i);
+                    Ok(())
+                },
+                || -> Result<Continue, ()> { Ok(Continue::Continue) },
+                || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    assert!(
+                        (std::thread::current().id() == caller)
+                            == (callback_thread == FinishedCallbackThread::Caller)
+                    );
+                    let items = std::mem::take(&mut *shared.lock());
+                    assert!(items.len() == 100);
+                    items.into_iter().par_for_each(WorkerCount::Auto, |i| {
+                        nested_sum.fetch_add(i, Ordering::SeqCst);
+                    });
+                },
+                Settings {
+                    worker_count: WorkerCount::Manual(NonZeroUsize::new(4).unwrap()),
+                    finished_callback_thread: callback_thread,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            assert!(calls.load(Ordering::SeqCst) == 1);
+            assert!(nested_sum.load(Ordering::SeqCst) == 4950);
+        }
+    }
+
+    /// Checks that every worker reports finishing exactly once, before the finished callback,
+    /// both when the items run out and on error, and that the stop hooks run concurrently
+    /// (they would deadlock on the barrier if they were called with the internal lock held).
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn worker_stop_hook_before_finished() {
+        const WORKER_COUNT: usize = 5;
+
+        for &fail in &[false, true] {
+            let finished: Vec<_> = (0..WORKER_COUNT).map(|_| AtomicU32::new(0)).collect();
+            let barrier = std::sync::Barrier::new(WORKER_COUNT);
+            let callback_saw = AtomicU32::new(0);
+
+            let result = parallel_for_each_with_worker_hooks(
+                0..1000,
+                |_worker_id| -> Result<(), String> { Ok(()) },
+                |_state, i| -> Result<(), String> {
+                    if fail && i == 100 {
+                        Err("Worker".to_string())
+                    } else {
+                        Ok(())
+                    }
+                },
+                || -> Result<Continue, String> { Ok(Continue::Continue) },
+                || {
+                    let total: u32 = finished.iter().map(|n| n.load(Ordering::SeqCst)).sum();
+                    callback_saw.store(total, Ordering::SeqCst);
+                },
+                |_worker_id| {},
+                |worker_id, _state| {
+                    finished[worker_id].fetch_add(1, Ordering::SeqCst);
+                    barrier.wait();
+                },
+                Settings {
+                    worker_count: WorkerCount::Manual(NonZeroUsize::new(WORKER_COUNT).unwrap()),
+                    ..Default::default()
+                },
+            );
+
+            assert!(result.is_err() == fail);
+            for count in finished.iter() {
+                assert!(count.load(Ordering::SeqCst) == 1);
+            }
+            assert!(callback_saw.load(Ordering::SeqCst) == WORKER_COUNT as u32);
+        }
+    }
+
+    /// Checks that the start hook is called once per worker before any item, and the stop hook
+    /// once per worker after the last item, for all ways a run can end.
+    #[cfg_attr(feature = "sequential", ignore)]
+    #[test]
+    fn worker_hooks() {
+        #[derive(Copy, Clone, Debug, PartialEq)]
+        enum Exit {
+            Completed,
+            BackgroundStop,
+            InitError,
+            WorkerError,
+        }
+        const WORKER_COUNT: usize = 4;
+
+        for exit in [
+            Exit::Completed,
+            Exit::BackgroundStop,
+            Exit::InitError,
+            Exit::WorkerError,
+        ]
+        .iter()
+        .copied()
+        {
+            let starts: Vec<_> = (0..WORKER_COUNT).map(|_| AtomicU32::new(0)).collect();
+            let stops: Vec<_> = (0..WORKER_COUNT).map(|_| AtomicU32::new(0)).collect();
+            let stops_with_state = AtomicU32::new(0);
+
+            let result = parallel_for_each_with_worker_hooks(
+                0..1000,
+                |worker_id| -> Result<usize, String> {
+                    assert!(starts[worker_id].load(Ordering::SeqCst) == 1);
+                    if exit == Exit::InitError && worker_id == 1 {
+                        Err("Init".to_string())
+                    } else {
+                        Ok(worker_id)
+                    }
+                },
+                |worker_id, i| -> Result<(), String> {
+                    assert!(starts[*worker_id].load(Ordering::SeqCst) == 1);
+                    assert!(stops[*worker_id].load(Ordering::SeqCst) == 0);
+                    if exit == Exit::WorkerError && i == 50 {
+                        Err("Worker".to_string())
+                    } else {
+                        Ok(())
+                    }
+                },
+                || -> Result<Continue, String> {
+                    Ok(if exit == Exit::BackgroundStop {
+                        Continue::Stop
+                    } else {
+                        Continue::Continue
+                    })
+                },
+                || {},
+                |worker_id| {
+                    starts[worker_id].fetch_add(1, Ordering::SeqCst);
+                },
+                |worker_id, state| {
+                    assert!(state.is_none_or(|state| *state == worker_id));
+                    stops[worker_id].fetch_add(1, Ordering::SeqCst);
+                    if state.is_some() {
+                        stops_with_state.fetch_add(1, Ordering::SeqCst);
+                    }
+                },
+                Settings {
+                    worker_count: WorkerCount::Manual(NonZeroUsize::new(WORKER_COUNT).unwrap()),
+                    ..Default::default()
+                },
+            );
+
+            for worker_id in 0..WORKER_COUNT {
+                assert!(starts[worker_id].load(Ordering::SeqCst) == 1, "{:?}", exit);
+                assert!(stops[worker_id].load(Ordering::SeqCst) == 1, "{:?}", exit);
+            }
+            match exit {
+                Exit::Completed | Exit::BackgroundStop => {
+                    assert!(result.is_ok());
+                    assert!(stops_with_state.load(Ordering::SeqCst) == WORKER_COUNT as u32);
+                }
+                Exit::InitError => {
+                    assert!(result.unwrap_err().as_init_error() == Some(&"Init".to_string()));
+                    assert!(stops_with_state.load(Ordering::SeqCst) == WORKER_COUNT as u32 - 1);
+                }
+                Exit::WorkerError => {
+                    assert!(result.unwrap_err().as_worker_error() == Some(&"Worker".to_string()));
+                    assert!(stops_with_state.load(Ordering::SeqCst) == WORKER_COUNT as u32);
+                }
+            }
+        }
+    }
+
+    /// Checks that for every way a run can end, the finished callback has been called exactly
+    /// once and has completed by the time the function returns.
+    #[test]
+    fn finished_callback_completes_before_return() {
+        #[derive(Copy, Clone, Debug)]
+        enum Exit {
+            Completed,
+            BackgroundStop,
+            InitError,
+            WorkerError,
+            BackgroundError,
+            CapturedPanic,
+            PropagatedPanic,
+        }
+
+        for exit in [
+            Exit::Completed,
+            Exit::BackgroundStop,
+            Exit::InitError,
+            Exit::WorkerError,
+            Exit::BackgroundError,
+            Exit::CapturedPanic,
+            Exit::PropagatedPanic,
+        ]
+        .iter()
+        .copied()
+        {
+            let started = AtomicU32::new(0);
+            let completed = AtomicU32::new(0);
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                parallel_for_each_with_settings(
+                    0..100,
+                    |_worker_id| -> Result<(), String> {
+                        panic_control::disable_hook_in_current_thread();
+                        match exit {
+                            Exit::InitError => Err("Init".to_string()),
+                            _ => Ok(()),
+                        }
+                    },
+                    |_state, i| -> Result<(), String> {
+                        match exit {
+                            Exit::WorkerError if i == 50 => Err("Worker".to_string()),
+                            Exit::CapturedPanic | Exit::PropagatedPanic if i == 50 => {
+                                panic!("Don't panic!")
+                            }
+                            _ => Ok(()),
+                        }
+                    },
+                    || -> Result<Continue, String> {
+                        match exit {
+                            Exit::BackgroundStop => Ok(Continue::Stop),
+                            Exit::BackgroundError => Err("Background".to_string()),
+                            _ => Ok(Continue::Continue),
+                        }
+                    },
+                    || {
+                        started.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(20));
+                        completed.fetch_add(1, Ordering::SeqCst);
+                    },
+                    Settings {
+                        worker_count: WorkerCount::Manual(NonZeroUsize::new(4).unwrap()),
+                        panic_policy: match exit {
+                            Exit::CapturedPanic => PanicPolicy::CaptureAsError,
+                            _ => PanicPolicy::Propagate,
+                        },
+                        panic_hook: PanicHook::Silent,
+                        ..Default::default()
+                    },
+                )
+            }));
+
+            assert!(
+                completed.load(Ordering::SeqCst) == 1,
+                "Exit path {:?}",
+                exit
+            );
+            assert!(started.load(Ordering::SeqCst) == 1, "Exit path {:?}", exit);
+            match exit {
+                Exit::Completed | Exit::BackgroundStop => assert!(matches!(result, Ok(Ok(())))),
+                Exit::PropagatedPanic => assert!(result.is_err()),
+                _ => assert!(matches!(result, Ok(Err(_)))),
+            }
+        }
+    }
+
+    /// Checks that the concrete worker error is recovered from an actual run.
+    #[test]
+    fn typed_error_from_run() {
+        let result = parallel_for_each(
+            0..10,
+            |_worker_id| -> Result<(), InitError> { Ok(()) },
+            |_state, i| -> Result<(), WorkerError> {
+                if i == 5 {
+                    Err(WorkerError::Worker(i))
+                } else {
+                    Ok(())
+                }
+            },
+            || -> Result<Continue, BackgroundError> { Ok(Continue::Continue) },
+            || {},
+            WorkerCount::Auto,
+        );
+
+        let error = result.unwrap_err();
+        assert!(error.as_worker_error() == Some(&WorkerError::Worker(5)));
+    }
+
     /// Checks that the iteration stops when background function returns Stop.
+    #[cfg_attr(feature = "sequential", ignore)]
     #[proptest]
     fn error_from_background(worker_count: WorkerCount) {
         let helper = IterationCheckHelper::new();
@@ -693,4 +7384,327 @@ mod test {
             Ok(()) => panic!("We didn't get an error!"),
         }
     }
+
+    /// Outcome of run_backend.
+    struct BackendRun {
+        result: Result<RunReport, ParallelForEachError<String, String, String>>,
+        processed: Vec<u32>,
+        finished_calls: usize,
+        leftover: usize,
+    }
+
+    /// Runs 0..n through the threaded (with a single worker) or the sequential backend.
+    fn run_backend(
+        sequential: bool,
+        n: u32,
+        init_result: Result<(), String>,
+        worker_result: impl Fn(u32) -> Result<(), String> + Sync + Send,
+        background_fun: impl FnMut() -> Result<Continue, String>,
+        settings: Settings,
+    ) -> BackendRun {
+        let processed = lock::Mutex::new(Vec::new());
+        let finished_calls = AtomicUsize::new(0);
+        let mut leftover = None;
+        let init_fun = |_worker_id| init_result.clone();
+        let worker_fun = |_state: &mut (), i| {
+            worker_result(i)?;
+            processed.lock().push(i);
+            Ok(())
+        };
+        let finished_callback = || {
+            finished_calls.fetch_add(1, Ordering::SeqCst);
+        };
+        let settings = Settings {
+            worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+            panic_hook: PanicHook::Silent,
+            ..settings
+        };
+
+        let scheduler = MutexScheduler::with_leftover(0..n, true);
+        let store_leftover = |scheduler: &mut MutexScheduler<_>| {
+            leftover = scheduler.take_leftover();
+        };
+        let result = if sequential {
+            run_sequential(
+                scheduler,
+                init_fun,
+                worker_fun,
+                background_fun,
+                finished_callback,
+                settings,
+                store_leftover,
+            )
+        } else {
+            run_threaded(
+                scheduler,
+                init_fun,
+                worker_fun,
+                background_fun,
+                finished_callback,
+                settings,
+                store_leftover,
+            )
+        };
+
+        BackendRun {
+            result,
+            processed: processed.into_inner(),
+            finished_calls: finished_calls.into_inner(),
+            leftover: leftover.map_or(0, |leftover| leftover.count()),
+        }
+    }
+
+    /// Checks that both backends process every item once and call finished once.
+    #[proptest]
+    fn backends_process_every_item(n: u8) {
+        for &sequential in &[false, true] {
+            let run = run_backend(
+                sequential,
+                n.into(),
+                Ok(()),
+                |_i| Ok(()),
+                || Ok(Continue::Continue),
+                Settings::default(),
+            );
+
+            let report = run.result.unwrap();
+            assert!(report.completed);
+            assert!(report.worker_count == 1);
+            assert!(report.items_processed == n as usize);
+            assert!(run.processed == (0..n.into()).collect::<Vec<_>>());
+            assert!(run.finished_calls == 1);
+            assert!(run.leftover == 0);
+        }
+    }
+
+    /// Checks that both backends report the same errors, stop at the same item and call
+    /// finished once.
+    #[test]
+    fn backends_errors() {
+        type Case<'a> = (&'a str, Box<dyn Fn(bool) -> BackendRun + 'a>);
+        let cases: Vec<Case> = vec![
+            (
+                "init error",
+                Box::new(|sequential| {
+                    run_backend(
+                        sequential,
+                        10,
+                        Err("init".to_string()),
+                        |_i| Ok(()),
+                        || Ok(Continue::Continue),
+                        Settings::default(),
+                    )
+                }),
+            ),
+            (
+                "worker error",
+                Box::new(|sequential| {
+                    run_backend(
+                        sequential,
+                        10,
+                        Ok(()),
+                        |i| if i == 5 { Err(i.to_string()) } else { Ok(()) },
+                        || Ok(Continue::Continue),
+                        Settings::default(),
+                    )
+                }),
+            ),
+            (
+                "stop after two errors",
+                Box::new(|sequential| {
+                    run_backend(
+                        sequential,
+                        10,
+                        Ok(()),
+                        |i| {
+                            if i % 3 == 1 {
+                                Err(i.to_string())
+                            } else {
+                                Ok(())
+                            }
+                        },
+                        || Ok(Continue::Continue),
+                        Settings {
+                            error_policy: ErrorPolicy::StopAfter(NonZeroUsize::new(2).unwrap()),
+                            ..Settings::default()
+                        },
+                    )
+                }),
+            ),
+            (
+                "background error",
+                Box::new(|sequential| {
+                    run_backend(
+                        sequential,
+                        10,
+                        Ok(()),
+                        |_i| Ok(()),
+                        || Err("background".to_string()),
+                        Settings::default(),
+                    )
+                }),
+            ),
+            (
+                "captured panic",
+                Box::new(|sequential| {
+                    run_backend(
+                        sequential,
+                        10,
+                        Ok(()),
+                        |i| {
+                            if i == 3 {
+                                panic!("Don't panic!")
+                            } else {
+                                Ok(())
+                            }
+                        },
+                        || Ok(Continue::Continue),
+                        Settings {
+                            panic_policy: PanicPolicy::CaptureAsError,
+                            ..Settings::default()
+                        },
+                    )
+                }),
+            ),
+        ];
+
+        for (name, run) in cases {
+            let threaded = run(false);
+            let sequential = run(true);
+
+            assert!(sequential.result.is_err(), "{}", name);
+            assert!(
+                format!("{:?}", threaded.result) == format!("{:?}", sequential.result),
+                "{}",
+                name
+            );
+            assert!(sequential.finished_calls == 1, "{}", name);
+            assert!(threaded.finished_calls == 1, "{}", name);
+            if name != "background error" {
+                assert!(threaded.processed == sequential.processed, "{}", name);
+            }
+        }
+    }
+
+    /// Checks stopping from the background function and the item cap with both backends.
+    #[test]
+    fn backends_stop() {
+        for &sequential in &[false, true] {
+            let mut calls = 0;
+            let run = run_backend(
+                sequential,
+                1000,
+                Ok(()),
+                |_i| {
+                    std::thread::sleep(Duration::from_micros(100));
+                    Ok(())
+                },
+                || {
+                    calls += 1;
+                    Ok(if calls == 3 {
+                        Continue::Stop
+                    } else {
+                        Continue::Continue
+                    })
+                },
+                Settings {
+                    background_mode: BackgroundMode::PollUntilDone(Duration::from_millis(1)),
+                    ..Settings::default()
+                },
+            );
+
+            let report = run.result.unwrap();
+            assert!(!report.completed, "sequential: {}", sequential);
+            assert!(run.processed.len() + run.leftover == 1000);
+            assert!(run.finished_calls == 1);
+
+            let run = run_backend(
+                sequential,
+                1000,
+                Ok(()),
+                |_i| Ok(()),
+                || Ok(Continue::Continue),
+                Settings {
+                    max_items: Some(7),
+                    ..Settings::default()
+                },
+            );
+
+            let report = run.result.unwrap();
+            assert!(report.item_cap_reached, "sequential: {}", sequential);
+            assert!(report.items_processed == 7);
+            assert!(run.processed == (0..7).collect::<Vec<_>>());
+            assert!(run.leftover == 993);
+        }
+    }
+
+    /// Checks that both backends survive a finished callback that panics while a worker panic
+    /// unwinds, and propagate the same panic: the worker panic if it is propagated, the callback
+    /// panic if the worker panic is captured.
+    #[test]
+    fn backends_worker_and_callback_panic() {
+        for &sequential in &[false, true] {
+            for &(panic_policy, expected) in &[
+                (PanicPolicy::Propagate, "Worker panic"),
+                (PanicPolicy::CaptureAsError, "Don't panic!"),
+            ] {
+                let finished_calls = AtomicUsize::new(0);
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let init_fun = |_worker_id| -> Result<(), String> {
+                        panic_control::disable_hook_in_current_thread();
+                        Ok(())
+                    };
+                    let worker_fun = |_state: &mut (), i| -> Result<(), String> {
+                        if i == 3 {
+                            panic!("Worker panic");
+                        }
+                        Ok(())
+                    };
+                    let background_fun = || -> Result<_, String> { Ok(Continue::Continue) };
+                    let finished_callback = || {
+                        finished_calls.fetch_add(1, Ordering::SeqCst);
+                        panic!("Don't panic!");
+                    };
+                    let settings = Settings {
+                        worker_count: WorkerCount::Manual(NonZeroUsize::new(1).unwrap()),
+                        panic_policy,
+                        panic_hook: PanicHook::Silent,
+                        finished_callback_thread: FinishedCallbackThread::LastWorker,
+                        ..Default::default()
+                    };
+                    let scheduler = MutexScheduler::new(0..10);
+                    if sequential {
+                        run_sequential(
+                            scheduler,
+                            init_fun,
+                            worker_fun,
+                            background_fun,
+                            finished_callback,
+                            settings,
+                            |_| {},
+                        )
+                    } else {
+                        run_threaded(
+                            scheduler,
+                            init_fun,
+                            worker_fun,
+                            background_fun,
+                            finished_callback,
+                            settings,
+                            |_| {},
+                        )
+                    }
+                }));
+
+                let e = result.unwrap_err();
+                assert!(
+                    e.downcast_ref::<&str>() == Some(&expected),
+                    "sequential: {}, {:?}",
+                    sequential,
+                    panic_policy
+                );
+                assert!(finished_calls.load(Ordering::SeqCst) == 1);
+            }
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use crate::camera;
+use crate::console_progress;
 use crate::geometry::*;
 use crate::image_buffer;
 use crate::parallel_for_each;
@@ -7,6 +8,9 @@ use crate::util;
 
 use screen_block::ScreenBlockExt;
 
+/// How often the progress is checked when it is shown in the terminal.
+const CONSOLE_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
 #[derive(Copy, Clone, Debug)]
 pub struct RenderSettings {
     pub block_size: std::num::NonZeroU32,
@@ -26,8 +30,25 @@ where
     let block_iterator = ScreenBlock::from_size(camera.get_resolution()).spiral_chunks(block_size);
 
     let buffer_writer = buffer.make_writer();
+    let block_count = block_iterator.len();
+    let written_count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    // A headless buffer has no event loop to wait for, the progress is shown in the terminal
+    // instead while the background function polls.
+    let mut console_progress = if buffer.is_headless() {
+        Some(console_progress::ConsoleProgress::new(
+            written_count.clone(),
+            block_count,
+        ))
+    } else {
+        None
+    };
+    let background_mode = match console_progress {
+        Some(_) => parallel_for_each::BackgroundMode::PollUntilDone(CONSOLE_PROGRESS_INTERVAL),
+        None => parallel_for_each::BackgroundMode::GateThenJoin,
+    };
 
-    parallel_for_each::parallel_for_each(
+    let result = parallel_for_each::parallel_for_each_with_settings(
         block_iterator,
         |_worker_id| -> Result<_, util::NoError> {
             use rand::SeedableRng;
@@ -40,18 +61,42 @@ where
             let (ref mut rng, ref mut buffer) = state;
             render_block(block, camera, settings, rng, buffer);
             buffer_writer.write(block, buffer)?;
+            written_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
             Ok(())
         },
         || -> util::SimpleResult<_> {
-            buffer.run()?;
-            Ok(parallel_for_each::Continue::Stop)
+            match &mut console_progress {
+                Some(progress) => {
+                    progress.update()?;
+                    Ok(parallel_for_each::Continue::Continue)
+                }
+                None => {
+                    buffer.run()?;
+                    Ok(parallel_for_each::Continue::Stop)
+                }
+            }
         },
         || {
-            // TODO: Notify the background task that we are finished
+            // Also called after an error or when the output was closed early, the render is
+            // finished only if every block got written.
+            if written_count.load(std::sync::atomic::Ordering::Relaxed) == block_count {
+                // The output stays usable even if the notification fails, there is nowhere to report it
+                let _ = buffer_writer.finished();
+            }
         },
-        parallel_for_each::WorkerCount::Auto,
-    )?;
+        parallel_for_each::Settings {
+            worker_count: parallel_for_each::WorkerCount::Auto,
+            background_mode,
+            ..Default::default()
+        },
+    );
+
+    // Ends the progress line even if the render failed
+    if let Some(progress) = &mut console_progress {
+        progress.finish()?;
+    }
+    result?;
 
     Ok(())
 }
@@ -0,0 +1,7 @@
+/// Checks that parallel_for_each rejects closures and iterators that are not thread safe,
+/// with readable errors. See the tests/ui directory.
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
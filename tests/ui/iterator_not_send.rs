@@ -0,0 +1,23 @@
+#![feature(specialization)]
+#![allow(dead_code, incomplete_features, unexpected_cfgs)]
+
+#[path = "../../src/lock.rs"]
+mod lock;
+#[path = "../../src/parallel_for_each.rs"]
+mod parallel_for_each;
+#[path = "../../src/util.rs"]
+mod util;
+
+use parallel_for_each::{parallel_for_each, Continue, WorkerCount};
+
+fn main() {
+    let items = std::rc::Rc::new(vec![1, 2, 3]);
+    let _ = parallel_for_each(
+        (0..3).map(move |i| items[i]),
+        |_| -> Result<(), ()> { Ok(()) },
+        |_, _| -> Result<(), ()> { Ok(()) },
+        || -> Result<Continue, ()> { Ok(Continue::Continue) },
+        || {},
+        WorkerCount::Auto,
+    );
+}
@@ -0,0 +1,26 @@
+#![feature(specialization)]
+#![allow(dead_code, incomplete_features, unexpected_cfgs)]
+
+#[path = "../../src/lock.rs"]
+mod lock;
+#[path = "../../src/parallel_for_each.rs"]
+mod parallel_for_each;
+#[path = "../../src/util.rs"]
+mod util;
+
+use parallel_for_each::{parallel_for_each, Continue, WorkerCount};
+
+fn main() {
+    let counter = std::cell::Cell::new(0);
+    let _ = parallel_for_each(
+        0..3,
+        |_| -> Result<(), ()> { Ok(()) },
+        |_, _| -> Result<(), ()> {
+            counter.set(counter.get() + 1);
+            Ok(())
+        },
+        || -> Result<Continue, ()> { Ok(Continue::Continue) },
+        || {},
+        WorkerCount::Auto,
+    );
+}